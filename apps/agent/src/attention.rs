@@ -0,0 +1,286 @@
+//! "Needs attention" summary for the status response: everything the user should look at,
+//! derived from config and runtime state so the UI doesn't have to re-implement the rules.
+
+use crate::space::{is_space_warning, project_backups_until_full};
+use crate::state::{AgentRuntimeState, RunStatus};
+use serde::Serialize;
+
+/// A drive whose last backup is older than this is reported as overdue.
+pub const OVERDUE_AFTER_SECS: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct AttentionItem {
+    /// Stable machine-readable code, e.g. `backup_failed`.
+    pub code: &'static str,
+    pub severity: Severity,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub drive_id: Option<String>,
+}
+
+impl AttentionItem {
+    fn new(
+        code: &'static str,
+        severity: Severity,
+        message: String,
+        drive_id: Option<&str>,
+    ) -> Self {
+        Self {
+            code,
+            severity,
+            message,
+            drive_id: drive_id.map(String::from),
+        }
+    }
+}
+
+/// Collect attention items, errors first.
+pub fn collect_attention(
+    state: &AgentRuntimeState,
+    restic_available: bool,
+    now_epoch: u64,
+) -> Vec<AttentionItem> {
+    let mut items = Vec::new();
+    if !restic_available {
+        items.push(AttentionItem::new(
+            "restic_missing",
+            Severity::Error,
+            "restic is not installed or not found; backups cannot run".to_string(),
+            None,
+        ));
+    }
+    if let Some(run) = state
+        .last_run
+        .as_ref()
+        .filter(|r| r.finished_epoch.is_some())
+    {
+        // A failed verification is its own problem, whether or not the policy failed the run.
+        if run.verify_failed {
+            items.push(AttentionItem::new(
+                "verification_failed",
+                Severity::Error,
+                "The last backup could not be verified".to_string(),
+                None,
+            ));
+        } else if run.status == RunStatus::Failed && !run.interrupted {
+            items.push(AttentionItem::new(
+                "backup_failed",
+                Severity::Error,
+                "The last backup failed".to_string(),
+                None,
+            ));
+        }
+    }
+
+    let mut drives: Vec<_> = state.config.trusted_drives.values().collect();
+    drives.sort_by(|a, b| a.drive_id.cmp(&b.drive_id));
+    for drive in drives {
+        let id = drive.drive_id.as_str();
+        let label = drive.display_label();
+        if state.repository_mismatch_drive_ids.contains(id) {
+            items.push(AttentionItem::new(
+                "repository_changed",
+                Severity::Error,
+                format!(
+                    "The backup repository on \"{}\" has changed; set the drive up again",
+                    label
+                ),
+                Some(id),
+            ));
+        }
+        if state.restic_too_old_drive_ids.contains(id) {
+            items.push(AttentionItem::new(
+                "restic_too_old",
+                Severity::Error,
+                format!(
+                    "Your restic is too old for the repository on \"{}\"; update restic",
+                    label
+                ),
+                Some(id),
+            ));
+        }
+        if state.repository_locked_drive_ids.contains(id) {
+            items.push(AttentionItem::new(
+                "repository_locked",
+                Severity::Error,
+                format!(
+                    "The repository on \"{}\" is locked by another restic process; backups fail until it is unlocked",
+                    label
+                ),
+                Some(id),
+            ));
+        }
+        if state.keyring_waiting_drive_ids.contains(id) {
+            items.push(AttentionItem::new(
+                "keyring_locked",
+                Severity::Warning,
+                format!(
+                    "Backup to \"{}\" is waiting for the keyring to be unlocked",
+                    label
+                ),
+                Some(id),
+            ));
+        }
+        let backups_until_full = project_backups_until_full(&drive.free_space_history);
+        if is_space_warning(backups_until_full, state.config.space_warning_backups) {
+            items.push(AttentionItem::new(
+                "low_space",
+                Severity::Warning,
+                format!("\"{}\" is running out of space", label),
+                Some(id),
+            ));
+        }
+        if let Some(last) = drive.last_backup_epoch {
+            if now_epoch.saturating_sub(last) > OVERDUE_AFTER_SECS {
+                items.push(AttentionItem::new(
+                    "overdue",
+                    Severity::Warning,
+                    format!("\"{}\" has not been backed up for over a week", label),
+                    Some(id),
+                ));
+            }
+        }
+    }
+    // Stable sort: errors first, otherwise in the order collected.
+    items.sort_by_key(|item| std::cmp::Reverse(item.severity));
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AgentConfig, TrustedDrive};
+    use crate::space::FreeSpaceSample;
+    use crate::state::{RunPhase, RunResult};
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn drive(id: &str, last_backup_epoch: Option<u64>) -> TrustedDrive {
+        TrustedDrive {
+            drive_id: id.to_string(),
+            label: Some(format!("Drive {}", id)),
+            repository_path: ".aegis/repo".to_string(),
+            repository_id: None,
+            last_seen_epoch: None,
+            last_backup_epoch,
+            last_backup_snapshot_id: None,
+            backup_sources: None,
+            free_space_history: Vec::new(),
+            total_data_added: 0,
+            serial: None,
+            include_patterns: Vec::new(),
+            exclude_patterns: Vec::new(),
+            populated_sources: Vec::new(),
+            luks_uuid: None,
+            extra: serde_json::Map::new(),
+            backup_durations_secs: Vec::new(),
+        }
+    }
+
+    fn run(status: RunStatus, message: &str) -> RunResult {
+        RunResult {
+            status,
+            phase: RunPhase::Completed,
+            started_epoch: NOW - 60,
+            finished_epoch: Some(NOW),
+            message: message.to_string(),
+            interrupted: false,
+            snapshot_id: None,
+            repository_id: None,
+            data_added: None,
+            files_processed: None,
+            deep_verify_estimate_secs: None,
+            retention_removed_snapshots: None,
+            retention_freed_bytes: None,
+            slow_run: false,
+            files_new: None,
+            files_changed: None,
+            dry_run: false,
+            verify_failed: false,
+        }
+    }
+
+    fn codes(items: &[AttentionItem]) -> Vec<&'static str> {
+        items.iter().map(|i| i.code).collect()
+    }
+
+    #[test]
+    fn healthy_state_needs_no_attention() {
+        let mut state = AgentRuntimeState::new(AgentConfig::default());
+        state
+            .config
+            .trusted_drives
+            .insert("a".to_string(), drive("a", Some(NOW - 3600)));
+        state.last_run = Some(run(RunStatus::Success, "Backup completed"));
+        assert!(collect_attention(&state, true, NOW).is_empty());
+    }
+
+    #[test]
+    fn run_level_problems() {
+        let mut state = AgentRuntimeState::new(AgentConfig::default());
+        state.last_run = Some(run(RunStatus::Failed, "Backup failed"));
+        let items = collect_attention(&state, false, NOW);
+        assert_eq!(codes(&items), ["restic_missing", "backup_failed"]);
+
+        for status in [RunStatus::Partial, RunStatus::Failed] {
+            state.last_run = Some(RunResult {
+                verify_failed: true,
+                ..run(status, "Backup verification failed")
+            });
+            assert_eq!(
+                codes(&collect_attention(&state, true, NOW)),
+                ["verification_failed"]
+            );
+        }
+
+        // An unplugged drive is not a failure worth flagging.
+        let mut interrupted = run(RunStatus::Failed, "Interrupted (drive disconnected)");
+        interrupted.interrupted = true;
+        state.last_run = Some(interrupted);
+        assert!(collect_attention(&state, true, NOW).is_empty());
+    }
+
+    #[test]
+    fn drive_level_problems_sorted_errors_first() {
+        let mut state = AgentRuntimeState::new(AgentConfig::default());
+        let mut full = drive("a", Some(NOW - OVERDUE_AFTER_SECS - 1));
+        full.free_space_history = [300, 200, 100]
+            .iter()
+            .map(|&free_bytes| FreeSpaceSample {
+                epoch: 0,
+                free_bytes,
+                total_bytes: 1_000,
+            })
+            .collect();
+        state.config.trusted_drives.insert("a".to_string(), full);
+        state
+            .config
+            .trusted_drives
+            .insert("b".to_string(), drive("b", None));
+        state.keyring_waiting_drive_ids.insert("b".to_string());
+        state.set_repository_mismatch("b", true);
+        state.repository_locked_drive_ids.insert("b".to_string());
+
+        let items = collect_attention(&state, true, NOW);
+        assert_eq!(
+            codes(&items),
+            [
+                "repository_changed",
+                "repository_locked",
+                "low_space",
+                "overdue",
+                "keyring_locked"
+            ]
+        );
+        assert_eq!(items[0].drive_id.as_deref(), Some("b"));
+        assert_eq!(items[1].drive_id.as_deref(), Some("b"));
+        assert_eq!(items[2].drive_id.as_deref(), Some("a"));
+    }
+}
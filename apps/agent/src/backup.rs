@@ -1,52 +1,80 @@
-use crate::config::AgentConfig;
-use crate::drive::{read_marker, write_marker, DriveMarker};
-use crate::logging::Redact;
-use crate::notifications;
-use crate::restic::Restic;
-use crate::retention::RetentionPolicy;
-use crate::state::{BackupProgress, RunPhase, RunResult, RunStatus, SharedState};
-use crate::verify::{deep_verify, quick_verify};
+use crate::config::{is_removable_media_junk, AgentConfig, BackupSource};
+use crate::devices;
+use crate::drive::{
+    read_marker, read_protected, sync_descriptions, sync_protected, write_marker, DriveMarker,
+};
+use crate::errors::{
+    is_drive_full, is_drive_io_error, is_free_space_reserve, is_pre_backup_check_failed,
+    is_read_only_drive, is_repository_locked, is_repository_missing, is_restic_too_old, AgentError,
+};
+use crate::logging::{redact_message, Redact};
+use crate::notifications::{self, NotificationSettings};
+use crate::restic::{
+    bandwidth_args, dry_run_args, host_args, performance_args, source_tag_args, BackupSummary,
+    PruneProgress, PruneSummary, Restic,
+};
+use crate::retention::{self, describe_prune, RetentionPolicy};
+use crate::space::{self, FreeSpaceSample};
+use crate::state::{AgentEvent, BackupProgress, RunPhase, RunResult, RunStatus, SharedState};
+use crate::verify::{
+    deep_verify, describe_estimate, estimate_deep_verify_secs, pre_backup_check, quick_verify,
+    quick_verify_throughput, repository_size_async, verify_failed_message, verify_with_policy,
+    ASSUMED_READ_THROUGHPUT,
+};
 use anyhow::Context;
 use directories::BaseDirs;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::sync::mpsc;
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
 pub async fn run_backup(
     state: SharedState,
     drive_id: String,
     mount_path: PathBuf,
     passphrase: String,
+    dry_run: bool,
 ) -> anyhow::Result<RunResult> {
     debug!(
-        "backup: starting drive_id={} mount_path={}",
+        "backup: starting drive_id={} mount_path={} dry_run={}",
         drive_id,
-        mount_path.display()
+        mount_path.display(),
+        dry_run
     );
     let started_epoch = now_epoch();
+    let starting_message = if dry_run {
+        "Starting dry run"
+    } else {
+        "Starting backup"
+    };
     set_phase(
         &state,
+        &drive_id,
         RunPhase::BackingUp,
         RunStatus::Partial,
-        "Starting backup",
+        starting_message,
         started_epoch,
         None,
         false,
+        dry_run,
     )
     .await;
 
-    let drive_label = {
+    let (drive_label, notify_settings) = {
         let guard = state.read().await;
-        guard
+        let label = guard
             .config
             .trusted_drives
             .get(&drive_id)
             .and_then(|d| d.label.clone())
-            .unwrap_or_else(|| drive_id.chars().take(12).collect::<String>())
+            .unwrap_or_else(|| drive_id.chars().take(12).collect::<String>());
+        (label, NotificationSettings::from_config(&guard.config))
     };
-    notifications::notify_backup_started(&drive_label);
+    if !dry_run {
+        notifications::notify_backup_started(&notify_settings, &drive_label);
+    }
 
     let cancel = CancellationToken::new();
     {
@@ -56,7 +84,14 @@ pub async fn run_backup(
             .insert(drive_id.clone(), cancel.clone());
     }
 
+    // How long restic's backup step took, for the slow-run check. Not the checks, verification or
+    // retention around it, and not the drive's first backup, which copies everything.
+    let mut backup_secs: Option<u64> = None;
     let outcome: anyhow::Result<RunResult> = async {
+        if devices::is_read_only_mount_async(&mount_path).await {
+            return Err(anyhow::Error::new(AgentError::ReadOnlyDrive)
+                .context(format!("{} is mounted read-only", mount_path.display())));
+        }
         let config = { state.read().await.config.clone() };
         let restic = Restic::resolve(config.restic_path.as_deref()).context("resolve restic")?;
 
@@ -65,7 +100,50 @@ pub async fn run_backup(
             .ok_or_else(|| anyhow::anyhow!("unknown drive"))?;
         debug!("backup: repo_path={}", repo_path.display());
 
-        let mut repo_initialized = repo_path.join("config").exists();
+        let trusted = config
+            .trusted_drives
+            .get(&drive_id)
+            .ok_or_else(|| anyhow::anyhow!("unknown drive"))?;
+        // Ask restic rather than looking for the config file, which can misfire on a freshly
+        // formatted drive and lead to initializing over an existing repository.
+        let checked = restic.repository_id_checked(&repo_path, &passphrase).await;
+        let mut repo_initialized = !checked.as_ref().is_err_and(is_repository_missing);
+        let found_id = if repo_initialized {
+            let too_old = checked.as_ref().is_err_and(is_restic_too_old);
+            {
+                let mut guard = state.write().await;
+                if too_old {
+                    guard.restic_too_old_drive_ids.insert(drive_id.clone());
+                } else {
+                    guard.restic_too_old_drive_ids.remove(&drive_id);
+                }
+            }
+            if too_old {
+                error!("backup: repository format is newer than the installed restic supports");
+                return Err(anyhow::anyhow!(
+                    "your restic is too old for this repository; update restic"
+                ));
+            }
+            Some(checked?)
+        } else {
+            None
+        };
+        let changed = trusted.repository_changed(found_id.as_deref());
+        state
+            .write()
+            .await
+            .set_repository_mismatch(&drive_id, changed);
+        if changed {
+            error!("backup: repository on the drive does not match the one set up; refusing");
+            return Err(anyhow::anyhow!(
+                "repository on the drive changed; reconfiguration needed"
+            ));
+        }
+        if !repo_initialized && dry_run {
+            return Err(anyhow::anyhow!(
+                "a dry run needs an existing repository; run a backup first"
+            ));
+        }
         if !repo_initialized {
             debug!(
                 "backup: initializing restic repository at {}",
@@ -83,6 +161,40 @@ pub async fn run_backup(
         if !repo_initialized {
             return Err(anyhow::anyhow!("repository not initialized"));
         }
+        // A repository created just now has nothing to check yet.
+        if config.verify_before_backup && found_id.is_some() && !dry_run {
+            set_phase(
+                &state,
+                &drive_id,
+                RunPhase::VerifyingQuick,
+                RunStatus::Partial,
+                "Checking the repository before the backup",
+                started_epoch,
+                None,
+                false,
+                dry_run,
+            )
+            .await;
+            pre_backup_check(
+                &restic,
+                &repo_path,
+                &passphrase,
+                config.verify_failure_policy,
+            )
+            .await?;
+            set_phase(
+                &state,
+                &drive_id,
+                RunPhase::BackingUp,
+                RunStatus::Partial,
+                "Starting backup",
+                started_epoch,
+                None,
+                false,
+                dry_run,
+            )
+            .await;
+        }
 
         let sources = expand_sources(&config, &drive_id)?;
         if sources.is_empty() {
@@ -90,6 +202,60 @@ pub async fn run_backup(
                 "no backup sources configured for this drive"
             ));
         }
+        let (sources, skipped_sources) = if config.skip_unavailable_sources {
+            partition_available(sources).await
+        } else {
+            (sources, Vec::new())
+        };
+        if !skipped_sources.is_empty() {
+            warn!(
+                "backup: skipping {} unavailable source(s): {:?}",
+                skipped_sources.len(),
+                skipped_sources
+                    .iter()
+                    .map(|p| p.display().to_string())
+                    .collect::<Vec<_>>()
+            );
+        }
+        // A source that had files at the last backup but is empty now is often a share or disk
+        // that isn't mounted; backing up the empty mountpoint would look like a good backup.
+        let (empty_now, populated_now) = partition_empty(&sources).await;
+        let suspicious_empty = newly_empty_sources(&empty_now, &trusted.populated_sources);
+        if !suspicious_empty.is_empty() {
+            warn!(
+                "backup: {} source(s) had files at the last backup but are empty now: {:?}",
+                suspicious_empty.len(),
+                suspicious_empty
+                    .iter()
+                    .map(|p| p.display().to_string())
+                    .collect::<Vec<_>>()
+            );
+        }
+        let sources: Vec<PathBuf> = if config.refuse_empty_sources {
+            sources
+                .into_iter()
+                .filter(|p| !suspicious_empty.contains(p))
+                .collect()
+        } else {
+            sources
+        };
+        if sources.is_empty() {
+            return Err(anyhow::anyhow!("no backup sources are currently available"));
+        }
+        let repo_excludes = validate_sources(&sources, &mount_path, &repo_path)?;
+        if config.exclude_removable_media_junk {
+            for source in &sources {
+                if source
+                    .file_name()
+                    .is_some_and(|name| is_removable_media_junk(&name.to_string_lossy()))
+                {
+                    warn!(
+                        "backup: source {} is a trash/system folder and will be excluded",
+                        source.display()
+                    );
+                }
+            }
+        }
         debug!(
             "backup: sources count={} paths={:?}",
             sources.len(),
@@ -99,14 +265,62 @@ pub async fn run_backup(
                 .collect::<Vec<_>>()
         );
 
+        // A dry run writes nothing to the drive.
+        let reserve = if dry_run {
+            0
+        } else {
+            reserve_for(&config, &mount_path).await
+        };
+        if reserve > 0 && below_reserve(&mount_path, reserve).await {
+            return Err(reserve_error(reserve));
+        }
+
         let (progress_tx, mut progress_rx) = mpsc::channel(64);
         let restic_clone = restic.clone();
         let repo_path_clone = repo_path.clone();
         let passphrase_clone = passphrase.clone();
         let sources_clone = sources.clone();
-        let includes = config.include_patterns.clone();
-        let excludes = config.exclude_patterns.clone();
-        let cancel_backup = cancel.clone();
+        let includes = config.effective_include_patterns(&drive_id);
+        let mut excludes = config.effective_exclude_patterns(&drive_id);
+        excludes.extend(expand_source_excludes(&config, &drive_id)?);
+        excludes.extend(repo_excludes);
+        let mut extra_args = performance_args(
+            config.performance_profile,
+            &config.performance_overrides,
+            sources.len(),
+        );
+        extra_args.extend(host_args(config.backup_hostname.as_deref()));
+        extra_args.extend(bandwidth_args(config.bandwidth_limits()));
+        extra_args.extend(dry_run_args(dry_run));
+        let labeled_sources = expand_labeled_sources(&config, &drive_id)?;
+        extra_args.extend(source_tag_args(
+            labeled_sources
+                .iter()
+                .filter(|(_, path)| sources.contains(path))
+                .map(|(label, _)| label.as_str()),
+        ));
+        // A child token, so the reserve monitor can stop restic without touching the unplug token.
+        let cancel_backup = cancel.child_token();
+        let cancel_backup_check = cancel_backup.clone();
+        let reserve_monitor = (reserve > 0).then(|| {
+            let mount_path = mount_path.clone();
+            let cancel_backup = cancel_backup.clone();
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(RESERVE_CHECK_INTERVAL).await;
+                    if below_reserve(&mount_path, reserve).await {
+                        warn!("backup: free space reached the reserve; stopping restic");
+                        cancel_backup.cancel();
+                        return;
+                    }
+                }
+            })
+        });
+        let first_backup = config
+            .trusted_drives
+            .get(&drive_id)
+            .is_some_and(|d| d.last_backup_epoch.is_none());
+        let backup_started = Instant::now();
         let backup_handle = tokio::spawn(async move {
             restic_clone
                 .backup_with_progress(
@@ -115,6 +329,7 @@ pub async fn run_backup(
                     &sources_clone,
                     &includes,
                     &excludes,
+                    &extra_args,
                     progress_tx,
                     cancel_backup,
                 )
@@ -124,8 +339,16 @@ pub async fn run_backup(
         let state_progress = state.clone();
         let drive_id_progress = drive_id.clone();
         tokio::spawn(async move {
+            let mut debounce = ProgressDebounce::new(PROGRESS_WRITE_INTERVAL);
             while let Some(report) = progress_rx.recv().await {
+                if !debounce.should_write(Instant::now(), report.percent_done) {
+                    continue;
+                }
                 let pct = (report.percent_done * 100.0) as u32;
+                let current_source_label = report
+                    .current_file
+                    .as_deref()
+                    .and_then(|f| owning_source_label(&labeled_sources, f));
                 let progress = BackupProgress {
                     percent_done: report.percent_done,
                     message: format!(
@@ -136,18 +359,49 @@ pub async fn run_backup(
                     total_files: report.total_files,
                     bytes_done: report.bytes_done,
                     total_bytes: report.total_bytes,
+                    current_source_label,
+                    ..BackupProgress::default()
                 };
                 let mut guard = state_progress.write().await;
                 guard
                     .backup_progress
                     .insert(drive_id_progress.clone(), progress.clone());
+                guard.events.publish(AgentEvent::Progress {
+                    drive_id: drive_id_progress.clone(),
+                    progress: progress.clone(),
+                });
                 if let Some(ref mut last_run) = guard.last_run {
                     last_run.message = progress.message;
                 }
             }
         });
 
-        let summary = backup_handle.await.context("backup task join")??;
+        let backup_result = backup_handle.await.context("backup task join")?;
+        if !first_backup {
+            backup_secs = Some(backup_started.elapsed().as_secs());
+        }
+        if let Some(monitor) = reserve_monitor {
+            monitor.abort();
+        }
+        // Only the reserve monitor cancels the child token on its own; unplug cancels both.
+        let reserve_hit = cancel_backup_check.is_cancelled() && !cancel.is_cancelled();
+        if backup_result.is_err() && reserve_hit {
+            return Err(reserve_error(reserve));
+        }
+        let summary = backup_result?;
+        if !dry_run {
+            // Keep flagging a suspicious source until it has files again or is removed.
+            let populated: Vec<String> = populated_now
+                .iter()
+                .chain(&suspicious_empty)
+                .map(|p| p.to_string_lossy().to_string())
+                .collect();
+            state
+                .write()
+                .await
+                .config
+                .set_populated_sources(&drive_id, populated);
+        }
         debug!(
             "backup: restic backup completed snapshot_id={:?}",
             summary.snapshot_id
@@ -155,62 +409,182 @@ pub async fn run_backup(
 
         let mut interrupted = false;
         let mut status = RunStatus::Success;
-        let mut message = "Backup completed".to_string();
+        let mut verify_failed = false;
+        let mut message = if dry_run {
+            "Dry run completed".to_string()
+        } else {
+            "Backup completed".to_string()
+        };
+        let mut quick_verify_elapsed = None;
 
-        if config.quick_verify {
+        if config.quick_verify && !dry_run {
             set_phase(
                 &state,
+                &drive_id,
                 RunPhase::VerifyingQuick,
                 RunStatus::Partial,
                 "Quick verification",
                 started_epoch,
                 summary.snapshot_id.clone(),
                 false,
+                dry_run,
             )
             .await;
-            if let Err(err) = quick_verify(&restic, &repo_path, &passphrase).await {
-                error!("Quick verify failed: {}", Redact::new(err));
-                status = RunStatus::Partial;
-                message = "Backup completed, but verification failed".to_string();
+            let quick_started = Instant::now();
+            match verify_with_policy(config.verify_failure_policy, || {
+                quick_verify(&restic, &repo_path, &passphrase)
+            })
+            .await
+            {
+                Ok(()) => quick_verify_elapsed = Some(quick_started.elapsed()),
+                Err((err, failed_status)) => {
+                    state
+                        .write()
+                        .await
+                        .record_error(&drive_id, "verify", &err, now_epoch());
+                    error!("Quick verify failed: {}", Redact::new(err));
+                    status = failed_status;
+                    message = verify_failed_message(false, &status).to_string();
+                    verify_failed = true;
+                }
             }
         }
 
-        if config.deep_verify {
+        if config.deep_verify && !dry_run {
+            let estimate_secs = repository_size_async(&repo_path).await.map(|repo_bytes| {
+                let throughput = quick_verify_elapsed
+                    .and_then(|elapsed| quick_verify_throughput(repo_bytes, elapsed))
+                    .unwrap_or(ASSUMED_READ_THROUGHPUT);
+                estimate_deep_verify_secs(repo_bytes, throughput)
+            });
+            let phase_message = match estimate_secs {
+                Some(secs) => format!(
+                    "Deep verification (this may take {})",
+                    describe_estimate(secs)
+                ),
+                None => "Deep verification".to_string(),
+            };
+            debug!("backup: deep verify estimate_secs={:?}", estimate_secs);
             set_phase(
                 &state,
+                &drive_id,
                 RunPhase::VerifyingDeep,
                 RunStatus::Partial,
-                "Deep verification",
+                &phase_message,
                 started_epoch,
                 summary.snapshot_id.clone(),
                 false,
+                dry_run,
             )
             .await;
-            if let Err(err) = deep_verify(&restic, &repo_path, &passphrase).await {
+            if let Some(ref mut last_run) = state.write().await.last_run {
+                last_run.deep_verify_estimate_secs = estimate_secs;
+            }
+            if let Err((err, failed_status)) =
+                verify_with_policy(config.verify_failure_policy, || {
+                    deep_verify(&restic, &repo_path, &passphrase)
+                })
+                .await
+            {
+                state
+                    .write()
+                    .await
+                    .record_error(&drive_id, "verify", &err, now_epoch());
                 error!("Deep verify failed: {}", Redact::new(err));
-                status = RunStatus::Partial;
-                message = "Backup completed, but deep verification failed".to_string();
+                if status != RunStatus::Failed {
+                    status = failed_status;
+                }
+                message = verify_failed_message(true, &status).to_string();
+                verify_failed = true;
             }
         }
 
-        if status == RunStatus::Success && config.retention.enabled {
+        let mut prune_summary = None;
+        if status == RunStatus::Success && config.retention.enabled && !dry_run {
             set_phase(
                 &state,
+                &drive_id,
                 RunPhase::Pruning,
                 RunStatus::Partial,
                 "Applying retention",
                 started_epoch,
                 summary.snapshot_id.clone(),
                 false,
+                dry_run,
             )
             .await;
-            if let Err(err) =
-                apply_retention(&restic, &repo_path, &passphrase, &config.retention).await
-            {
-                error!("Retention failed: {}", Redact::new(err));
-                status = RunStatus::Partial;
-                message = "Backup completed, but retention failed".to_string();
+            let (prune_tx, mut prune_rx) = mpsc::channel::<PruneProgress>(32);
+            let state_prune = state.clone();
+            let drive_id_prune = drive_id.clone();
+            let prune_progress_task = tokio::spawn(async move {
+                let mut debounce = ProgressDebounce::new(PROGRESS_WRITE_INTERVAL);
+                while let Some(report) = prune_rx.recv().await {
+                    let progress = prune_progress(&report);
+                    if !debounce.should_write(Instant::now(), progress.percent_done) {
+                        continue;
+                    }
+                    let mut guard = state_prune.write().await;
+                    guard
+                        .backup_progress
+                        .insert(drive_id_prune.clone(), progress.clone());
+                    guard.events.publish(AgentEvent::Progress {
+                        drive_id: drive_id_prune.clone(),
+                        progress,
+                    });
+                }
+            });
+            let retention_result = apply_retention(
+                &restic,
+                &mount_path,
+                &repo_path,
+                &passphrase,
+                &config.retention,
+                &prune_tx,
+            )
+            .await;
+            drop(prune_tx);
+            let _ = prune_progress_task.await;
+            match retention_result {
+                Err(err) => {
+                    state
+                        .write()
+                        .await
+                        .record_error(&drive_id, "retention", &err, now_epoch());
+                    error!("Retention failed: {}", Redact::new(err));
+                    status = RunStatus::Partial;
+                    message = "Backup completed, but retention failed".to_string();
+                }
+                Ok(pruned) => {
+                    debug!(
+                        "backup: retention removed_snapshots={} freed_bytes={:?}",
+                        pruned.removed_snapshots, pruned.freed_bytes
+                    );
+                    if let Some(description) = describe_prune(&pruned) {
+                        message = format!("{}; {}", message, description);
+                    }
+                    prune_summary = Some(pruned);
+                }
+            }
+        }
+
+        let listing = if dry_run {
+            None
+        } else {
+            restic
+                .snapshots(&repo_path, &passphrase, config.no_lock_reads)
+                .await
+                .ok()
+        };
+        if let Some(mut snapshots) = listing {
+            if prune_summary.is_some() {
+                if let Err(err) = sync_descriptions(&mount_path, &mut snapshots) {
+                    debug!("backup: pruning snapshot descriptions failed: {}", err);
+                }
+                if let Err(err) = sync_protected(&mount_path, &mut snapshots) {
+                    debug!("backup: pruning protected snapshots failed: {}", err);
+                }
             }
+            state.write().await.record_snapshots(&drive_id, &snapshots);
         }
 
         let drive_connected = {
@@ -218,6 +592,17 @@ pub async fn run_backup(
             guard.drive_status.connected
                 && guard.drive_status.drive_id.as_deref() == Some(&drive_id)
         };
+        apply_incomplete_backup(&mut status, &mut message, summary.incomplete);
+        apply_skipped_sources(&mut status, &mut message, skipped_sources.len());
+        apply_empty_sources(
+            &mut status,
+            &mut message,
+            suspicious_empty.len(),
+            config.refuse_empty_sources,
+        );
+        if dry_run {
+            message = format!("{}; {}", message, describe_dry_run(&summary));
+        }
         if !drive_connected {
             interrupted = true;
             status = RunStatus::Failed;
@@ -244,25 +629,96 @@ pub async fn run_backup(
             repository_id,
             data_added: summary.data_added,
             files_processed: summary.files_processed,
+            deep_verify_estimate_secs: None,
+            retention_removed_snapshots: prune_summary.map(|p| p.removed_snapshots),
+            retention_freed_bytes: prune_summary.and_then(|p| p.freed_bytes),
+            slow_run: false,
+            files_new: summary.files_new,
+            files_changed: summary.files_changed,
+            dry_run,
+            verify_failed,
         })
     }
     .await;
 
     match outcome {
-        Ok(result) => {
-            notifications::notify_backup_finished(
-                &drive_label,
-                result.status == RunStatus::Success,
-                result.interrupted,
-            );
+        Ok(mut result) => {
+            if !dry_run {
+                notifications::notify_backup_finished(
+                    &notify_settings,
+                    &drive_label,
+                    result.status == RunStatus::Success,
+                    result.interrupted,
+                );
+            }
+            // Sampled before taking the lock: `statvfs` can stall on a slow drive.
+            let usage = if dry_run {
+                None
+            } else {
+                let mount_path = mount_path.clone();
+                tokio::task::spawn_blocking(move || space::disk_usage(&mount_path))
+                    .await
+                    .ok()
+            };
             let mut guard = state.write().await;
+            let epoch = result.finished_epoch.unwrap_or_else(now_epoch);
+            if let Some(secs) =
+                backup_secs.filter(|_| result.status == RunStatus::Success && !dry_run)
+            {
+                result.slow_run = guard.config.record_backup_duration(&drive_id, secs);
+                if result.slow_run {
+                    warn!(
+                        "backup: run took {}s, much longer than usual drive_id={}",
+                        secs, drive_id
+                    );
+                }
+            }
+            guard.repository_locked_drive_ids.remove(&drive_id);
             guard.last_run = Some(result.clone());
+            guard.events.publish(AgentEvent::Completed {
+                drive_id: drive_id.clone(),
+                run: result.clone(),
+            });
             guard.config.update_last_seen(&drive_id);
-            let epoch = result.finished_epoch.unwrap_or_else(now_epoch);
-            guard
+            if dry_run {
+                let _ = guard.config.save();
+                return Ok(result);
+            }
+            // A run failed by its verification left a snapshot that can't be trusted yet.
+            if result.status != RunStatus::Failed {
+                guard
+                    .config
+                    .update_last_backup(&drive_id, epoch, result.snapshot_id.clone());
+            }
+            if let Some(bytes) = result.data_added {
+                guard.config.add_data_added(&drive_id, bytes);
+            }
+            match usage {
+                Some(Ok(usage)) => guard.config.record_free_space(
+                    &drive_id,
+                    FreeSpaceSample {
+                        epoch,
+                        free_bytes: usage.free_bytes,
+                        total_bytes: usage.total_bytes,
+                    },
+                ),
+                Some(Err(err)) => debug!("backup: disk usage unavailable: {}", err),
+                None => {}
+            }
+            let backups_until_full = guard
                 .config
-                .update_last_backup(&drive_id, epoch, result.snapshot_id.clone());
+                .trusted_drives
+                .get(&drive_id)
+                .and_then(|d| space::project_backups_until_full(&d.free_space_history));
+            let warn_space =
+                space::is_space_warning(backups_until_full, guard.config.space_warning_backups);
             let _ = guard.config.save();
+            drop(guard);
+            if warn_space {
+                if let Some(n) = backups_until_full {
+                    notifications::notify_drive_filling(&notify_settings, &drive_label, n);
+                }
+            }
             Ok(result)
         }
         Err(err) => {
@@ -272,56 +728,495 @@ pub async fn run_backup(
                     && guard.drive_status.drive_id.as_deref() == Some(&drive_id)
             };
             let interrupted = !drive_connected;
-            let message = if interrupted {
-                "Interrupted (drive disconnected)"
+            let drive_full = !interrupted && is_drive_full(&err);
+            let reason = if interrupted {
+                Some("Interrupted (drive disconnected)")
+            } else if is_free_space_reserve(&err) {
+                Some("Backup stopped: free space on the drive reached the configured reserve")
+            } else if is_read_only_drive(&err) {
+                Some("Drive is mounted read-only; remount it read-write")
+            } else if drive_full {
+                let retention_enabled = state.read().await.config.retention.enabled;
+                Some(drive_full_message(retention_enabled))
+            } else if is_drive_io_error(&err) {
+                Some("Drive I/O error: check the cable and the drive's health")
+            } else if is_pre_backup_check_failed(&err) {
+                Some("Backup not started: the repository check before it failed")
+            } else if is_repository_locked(&err) {
+                Some("Repository is locked by another restic process")
             } else {
-                "Backup failed"
+                None
+            };
+            let message = match (dry_run, reason) {
+                (true, Some(reason)) => format!("Dry run failed: {}", reason),
+                (true, None) => {
+                    format!("Dry run failed: {}", redact_message(&format!("{:#}", err)))
+                }
+                (false, reason) => reason.unwrap_or("Backup failed").to_string(),
             };
             let result = RunResult {
                 status: RunStatus::Failed,
                 phase: RunPhase::Completed,
                 started_epoch,
                 finished_epoch: Some(now_epoch()),
-                message: message.to_string(),
+                message,
                 interrupted,
                 snapshot_id: None,
                 repository_id: None,
                 data_added: None,
                 files_processed: None,
+                deep_verify_estimate_secs: None,
+                retention_removed_snapshots: None,
+                retention_freed_bytes: None,
+                slow_run: false,
+                files_new: None,
+                files_changed: None,
+                dry_run,
+                verify_failed: false,
             };
-            notifications::notify_backup_finished(&drive_label, false, result.interrupted);
+            // Dry runs are previews started from the UI; they don't notify.
+            if drive_full && !dry_run {
+                notifications::notify_drive_full(&notify_settings, &drive_label);
+            } else if !dry_run {
+                notifications::notify_backup_finished(
+                    &notify_settings,
+                    &drive_label,
+                    false,
+                    result.interrupted,
+                );
+            }
             let mut guard = state.write().await;
+            guard.events.publish(AgentEvent::Completed {
+                drive_id: drive_id.clone(),
+                run: result.clone(),
+            });
+            if is_repository_locked(&err) {
+                guard.repository_locked_drive_ids.insert(drive_id.clone());
+            }
             guard.last_run = Some(result);
+            // A failed preview is reported in its run only; it isn't a backup error.
+            if !dry_run {
+                guard.record_error(&drive_id, "backup", &err, now_epoch());
+            }
             Err(err)
         }
     }
 }
 
-fn expand_sources(config: &AgentConfig, drive_id: &str) -> anyhow::Result<Vec<PathBuf>> {
-    let base_dirs = BaseDirs::new().context("resolve home dir")?;
-    let home = base_dirs.home_dir();
-    let sources_list = config.backup_sources_for_drive(drive_id);
+/// Minimum time between progress writes to the shared state; restic reports several times a
+/// second and each write blocks `GET /v1/status` readers.
+const PROGRESS_WRITE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Lets through the first progress report, then at most one per interval, plus completion.
+struct ProgressDebounce {
+    interval: Duration,
+    last_write: Option<Instant>,
+    completed: bool,
+}
+
+impl ProgressDebounce {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_write: None,
+            completed: false,
+        }
+    }
+
+    fn should_write(&mut self, now: Instant, percent_done: f64) -> bool {
+        let completes = percent_done >= 1.0 && !self.completed;
+        let due = self
+            .last_write
+            .is_none_or(|last| now.duration_since(last) >= self.interval);
+        if !(due || completes) {
+            return false;
+        }
+        self.last_write = Some(now);
+        self.completed |= percent_done >= 1.0;
+        true
+    }
+}
+
+/// How often free space is checked against the reserve while restic runs.
+const RESERVE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Free-space reserve for this drive in bytes (0 if none is configured or the size is unknown).
+async fn reserve_for(config: &AgentConfig, mount_path: &Path) -> u64 {
+    if config.min_free_bytes == 0 && config.min_free_percent == 0 {
+        return 0;
+    }
+    // `statvfs` can stall on a slow drive.
+    let mount_path = mount_path.to_path_buf();
+    let total_bytes = tokio::task::spawn_blocking(move || space::disk_usage(&mount_path))
+        .await
+        .ok()
+        .and_then(Result::ok)
+        .map_or(0, |usage| usage.total_bytes);
+    space::reserve_bytes(total_bytes, config.min_free_bytes, config.min_free_percent)
+}
+
+/// True if free space on the drive is at or below `reserve`. Unknown usage doesn't stop a backup.
+async fn below_reserve(mount_path: &Path, reserve: u64) -> bool {
+    let mount_path = mount_path.to_path_buf();
+    tokio::task::spawn_blocking(move || space::disk_usage(&mount_path))
+        .await
+        .ok()
+        .and_then(Result::ok)
+        .is_some_and(|usage| space::violates_reserve(&usage, reserve))
+}
+
+fn reserve_error(reserve: u64) -> anyhow::Error {
+    anyhow::Error::new(AgentError::FreeSpaceReserve).context(format!(
+        "less than {} free on the backup drive",
+        space::format_bytes(reserve)
+    ))
+}
+
+/// Run message when the drive ran out of space; points at retention, which frees space by
+/// removing old snapshots. The drive stays trusted: nothing is wrong with it but its capacity.
+fn drive_full_message(retention_enabled: bool) -> &'static str {
+    if retention_enabled {
+        "Drive full: free up space on the drive or keep fewer backups in the retention settings"
+    } else {
+        "Drive full: free up space on the drive or enable retention to remove old backups"
+    }
+}
+
+pub fn expand_sources(config: &AgentConfig, drive_id: &str) -> anyhow::Result<Vec<PathBuf>> {
+    Ok(expand_labeled_sources(config, drive_id)?
+        .into_iter()
+        .map(|(_, path)| path)
+        .collect())
+}
+
+/// Like `expand_sources`, keeping each source's label alongside its expanded path.
+fn expand_labeled_sources(
+    config: &AgentConfig,
+    drive_id: &str,
+) -> anyhow::Result<Vec<(String, PathBuf)>> {
+    // Some service contexts run without HOME; that only matters for sources that use ~/.
+    let home = BaseDirs::new().map(|dirs| dirs.home_dir().to_path_buf());
+    expand_source_paths(&config.backup_sources_for_drive(drive_id), home.as_deref())
+}
+
+/// Expand `~/` in source paths against `home`. Fails, naming the source by label, if a source
+/// needs the home directory and it couldn't be resolved. A source that is a symlink is replaced by
+/// the real path it points to when it has `follow_symlink`; otherwise restic backs up the link.
+fn expand_source_paths(
+    sources_list: &[BackupSource],
+    home: Option<&Path>,
+) -> anyhow::Result<Vec<(String, PathBuf)>> {
     let mut sources = Vec::new();
-    for source in &sources_list {
+    for source in sources_list {
         // Paths are only used for restic; never surface them in logs or UI.
-        let path = if let Some(stripped) = source.path.strip_prefix("~/") {
-            home.join(stripped)
-        } else {
-            PathBuf::from(&source.path)
-        };
-        sources.push(path);
+        sources.push((source.label.clone(), expand_source(source, home)?));
     }
     Ok(sources)
 }
 
+/// The path restic is given for `source`: `~/` expanded and, with `follow_symlink`, a symlink
+/// replaced by its target.
+fn expand_source(source: &BackupSource, home: Option<&Path>) -> anyhow::Result<PathBuf> {
+    let path = expand_home(source, home)?;
+    Ok(if source.follow_symlink {
+        symlink_target(&path).unwrap_or(path)
+    } else {
+        path
+    })
+}
+
+/// Each source's own exclude patterns for this drive, anchored under the path restic is given
+/// for that source so they don't apply to the other sources.
+pub fn expand_source_excludes(config: &AgentConfig, drive_id: &str) -> anyhow::Result<Vec<String>> {
+    let home = BaseDirs::new().map(|dirs| dirs.home_dir().to_path_buf());
+    source_excludes(&config.backup_sources_for_drive(drive_id), home.as_deref())
+}
+
+fn source_excludes(sources: &[BackupSource], home: Option<&Path>) -> anyhow::Result<Vec<String>> {
+    let mut excludes = Vec::new();
+    for source in sources.iter().filter(|s| !s.exclude_patterns.is_empty()) {
+        let path = expand_source(source, home)?;
+        excludes.extend(
+            source
+                .exclude_patterns
+                .iter()
+                .map(|pattern| anchor_pattern(&path, pattern)),
+        );
+    }
+    Ok(excludes)
+}
+
+/// `pattern` limited to what is under `source`. A relative pattern matches at any depth below the
+/// source, as a global one does anywhere; one starting with `/` is taken from the source's root,
+/// so a source's pattern can never reach outside it. A leading `!` (restic's negation) stays in
+/// front. Glob characters in the source path itself aren't escaped.
+fn anchor_pattern(source: &Path, pattern: &str) -> String {
+    let (negation, pattern) = match pattern.strip_prefix('!') {
+        Some(rest) => ("!", rest),
+        None => ("", pattern),
+    };
+    let source = source.to_string_lossy();
+    let source = source.trim_end_matches('/');
+    match pattern.strip_prefix('/') {
+        Some(rooted) => format!("{}{}/{}", negation, source, rooted.trim_start_matches('/')),
+        None => format!("{}{}/**/{}", negation, source, pattern),
+    }
+}
+
+fn expand_home(source: &BackupSource, home: Option<&Path>) -> anyhow::Result<PathBuf> {
+    match source.path.strip_prefix("~/") {
+        Some(stripped) => {
+            let home = home.ok_or_else(|| {
+                anyhow::anyhow!(
+                    "source \"{}\" is relative to the home directory, which could not be resolved (HOME is not set)",
+                    source.label
+                )
+            })?;
+            Ok(home.join(stripped))
+        }
+        None => Ok(PathBuf::from(&source.path)),
+    }
+}
+
+/// The real path `path` points to if it is itself a symlink; None if it isn't one or the target
+/// doesn't exist.
+fn symlink_target(path: &Path) -> Option<PathBuf> {
+    let is_link = std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink());
+    is_link.then(|| std::fs::canonicalize(path).ok()).flatten()
+}
+
+/// Real paths of each drive's sources that are symlinks, keyed by drive ID and then by label.
+/// For the UI only, so the user can see where a link leads; never logged. Touches the
+/// filesystem, so async callers run it in `spawn_blocking`.
+pub fn source_link_targets(
+    drives: &[(String, Vec<BackupSource>)],
+) -> HashMap<String, HashMap<String, String>> {
+    let home = BaseDirs::new().map(|dirs| dirs.home_dir().to_path_buf());
+    drives
+        .iter()
+        .map(|(drive_id, sources)| (drive_id.clone(), link_targets(sources, home.as_deref())))
+        .collect()
+}
+
+fn link_targets(sources: &[BackupSource], home: Option<&Path>) -> HashMap<String, String> {
+    sources
+        .iter()
+        .filter_map(|source| {
+            let target = symlink_target(&expand_home(source, home).ok()?)?;
+            Some((source.label.clone(), target.to_string_lossy().to_string()))
+        })
+        .collect()
+}
+
+/// Label of the source that `current_file` falls under. With nested sources the deepest
+/// (longest) matching path wins.
+fn owning_source_label(sources: &[(String, PathBuf)], current_file: &str) -> Option<String> {
+    let file = Path::new(current_file);
+    sources
+        .iter()
+        .filter(|(_, path)| file.starts_with(path))
+        .max_by_key(|(_, path)| path.components().count())
+        .map(|(label, _)| label.clone())
+}
+
+/// Make sure no source would back up the Aegis repository into itself. A source inside the
+/// repository is rejected; a source containing the drive's `.aegis` directory (e.g. the mount
+/// root or a parent of it) gets that directory added as an exclude, which is returned.
+fn validate_sources(
+    sources: &[PathBuf],
+    mount_path: &Path,
+    repo_path: &Path,
+) -> anyhow::Result<Vec<String>> {
+    let marker_dir = canonical_or_lexical(&mount_path.join(".aegis"));
+    let repo_path = canonical_or_lexical(repo_path);
+    let mut excludes = Vec::new();
+    for source in sources {
+        let source = canonical_or_lexical(source);
+        if source.starts_with(&marker_dir) {
+            return Err(anyhow::anyhow!(
+                "a backup source is inside the Aegis repository on the backup drive; remove it from the source list"
+            ));
+        }
+        if repo_path.starts_with(&source) {
+            let pattern = marker_dir.to_string_lossy().to_string();
+            if !excludes.contains(&pattern) {
+                debug!("backup: source contains the backup drive; excluding .aegis");
+                excludes.push(pattern);
+            }
+        }
+    }
+    Ok(excludes)
+}
+
+/// Split sources into (available, unavailable), keeping order. A source is available if it can
+/// be stat'ed; that can hang on a dead network share, so it goes through `tokio::fs`.
+async fn partition_available(sources: Vec<PathBuf>) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let mut available = Vec::new();
+    let mut unavailable = Vec::new();
+    for source in sources {
+        if tokio::fs::metadata(&source).await.is_ok() {
+            available.push(source);
+        } else {
+            unavailable.push(source);
+        }
+    }
+    (available, unavailable)
+}
+
+/// A run where restic couldn't read some files (exit code 3) is at best Partial; the snapshot it
+/// saved is kept.
+fn apply_incomplete_backup(status: &mut RunStatus, message: &mut String, incomplete: bool) {
+    if !incomplete {
+        return;
+    }
+    if *status == RunStatus::Success {
+        *status = RunStatus::Partial;
+        *message =
+            "Backup completed, but some files could not be read and were skipped".to_string();
+    } else {
+        message.push_str(" (some files could not be read)");
+    }
+}
+
+/// What a dry run found, e.g. "would back up 3 new and 1 changed file(s), 2.0 KB".
+fn describe_dry_run(summary: &BackupSummary) -> String {
+    format!(
+        "would back up {} new and {} changed file(s), {}",
+        summary.files_new.unwrap_or(0),
+        summary.files_changed.unwrap_or(0),
+        space::format_bytes(summary.data_added.unwrap_or(0))
+    )
+}
+
+/// A run that skipped sources is at best Partial, with a message saying so.
+fn apply_skipped_sources(status: &mut RunStatus, message: &mut String, skipped: usize) {
+    if skipped == 0 {
+        return;
+    }
+    if *status == RunStatus::Success {
+        *status = RunStatus::Partial;
+        *message = format!(
+            "Backup completed, but {} unavailable source(s) were skipped",
+            skipped
+        );
+    } else {
+        message.push_str(&format!(" ({} unavailable source(s) skipped)", skipped));
+    }
+}
+
+/// True for a directory with no entries. Files and unreadable paths are not "empty". Like
+/// `partition_available`, this goes through `tokio::fs` since a dead network share can hang it.
+async fn dir_is_empty(path: &Path) -> bool {
+    match tokio::fs::read_dir(path).await {
+        Ok(mut entries) => matches!(entries.next_entry().await, Ok(None)),
+        Err(_) => false,
+    }
+}
+
+/// Split sources into (empty, not empty) directories, keeping order.
+async fn partition_empty(sources: &[PathBuf]) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let mut empty = Vec::new();
+    let mut populated = Vec::new();
+    for source in sources {
+        if dir_is_empty(source).await {
+            empty.push(source.clone());
+        } else {
+            populated.push(source.clone());
+        }
+    }
+    (empty, populated)
+}
+
+/// Sources that are empty now but had files at the last successful backup.
+fn newly_empty_sources(empty_now: &[PathBuf], previously_populated: &[String]) -> Vec<PathBuf> {
+    empty_now
+        .iter()
+        .filter(|p| {
+            previously_populated
+                .iter()
+                .any(|prev| Path::new(prev) == p.as_path())
+        })
+        .cloned()
+        .collect()
+}
+
+/// A run with sources that unexpectedly became empty is at best Partial, saying whether they
+/// were skipped or backed up empty.
+fn apply_empty_sources(status: &mut RunStatus, message: &mut String, empty: usize, skipped: bool) {
+    if empty == 0 {
+        return;
+    }
+    let what = if skipped {
+        "were skipped"
+    } else {
+        "were backed up empty"
+    };
+    if *status == RunStatus::Success {
+        *status = RunStatus::Partial;
+        *message = format!(
+            "Backup completed, but {} source(s) that had files before are empty now and {}",
+            empty, what
+        );
+    } else {
+        message.push_str(&format!(
+            " ({} previously populated source(s) empty and {})",
+            empty, what
+        ));
+    }
+}
+
+fn canonical_or_lexical(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.components().collect())
+}
+
+/// Protected snapshots are never removed: when any exist, ask restic for the removal plan
+/// and forget only the unprotected snapshots in it.
 async fn apply_retention(
     restic: &Restic,
+    mount_path: &Path,
     repo_path: &Path,
     passphrase: &str,
     retention: &RetentionPolicy,
-) -> anyhow::Result<()> {
+    progress_tx: &mpsc::Sender<PruneProgress>,
+) -> anyhow::Result<PruneSummary> {
     let args = retention.to_forget_args();
-    restic.forget_prune(repo_path, passphrase, &args).await
+    let protected = read_protected(mount_path).context("read protected snapshots")?;
+    if protected.is_empty() {
+        return restic
+            .forget_prune(repo_path, passphrase, &args, progress_tx)
+            .await;
+    }
+    let remove = restic.forget_plan(repo_path, passphrase, &args).await?;
+    let remove = retention::unprotected(remove, &protected);
+    restic
+        .forget_snapshots_prune(repo_path, passphrase, &remove, progress_tx)
+        .await
+}
+
+/// Progress shown while retention prunes: the current restic counter as the percentage,
+/// plus the snapshot and reclaimed-space totals.
+fn prune_progress(report: &PruneProgress) -> BackupProgress {
+    let percent_done = if report.stage_total > 0 {
+        (report.stage_done as f64 / report.stage_total as f64).min(1.0)
+    } else {
+        0.0
+    };
+    let message = if report.stage.is_empty() {
+        "Applying retention".to_string()
+    } else {
+        format!(
+            "Pruning: {} / {} {}",
+            report.stage_done, report.stage_total, report.stage
+        )
+    };
+    BackupProgress {
+        percent_done,
+        message,
+        snapshots_removed: Some(report.snapshots_removed),
+        snapshots_to_remove: Some(report.snapshots_to_remove),
+        bytes_reclaimed: report.bytes_freed,
+        ..BackupProgress::default()
+    }
 }
 
 async fn update_repo_id(state: &SharedState, drive_id: &str, repo_id: &str) -> anyhow::Result<()> {
@@ -333,17 +1228,19 @@ async fn update_repo_id(state: &SharedState, drive_id: &str, repo_id: &str) -> a
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn set_phase(
     state: &SharedState,
+    drive_id: &str,
     phase: RunPhase,
     status: RunStatus,
     message: &str,
     started_epoch: u64,
     snapshot_id: Option<String>,
     interrupted: bool,
+    dry_run: bool,
 ) {
-    let mut guard = state.write().await;
-    guard.last_run = Some(RunResult {
+    let run = RunResult {
         status,
         phase,
         started_epoch,
@@ -354,7 +1251,21 @@ async fn set_phase(
         repository_id: None,
         data_added: None,
         files_processed: None,
+        deep_verify_estimate_secs: None,
+        retention_removed_snapshots: None,
+        retention_freed_bytes: None,
+        slow_run: false,
+        files_new: None,
+        files_changed: None,
+        dry_run,
+        verify_failed: false,
+    };
+    let mut guard = state.write().await;
+    guard.events.publish(AgentEvent::Phase {
+        drive_id: drive_id.to_string(),
+        run: run.clone(),
     });
+    guard.last_run = Some(run);
 }
 
 fn now_epoch() -> u64 {
@@ -370,3 +1281,331 @@ fn update_marker_repo_id(mount_path: &Path, repo_id: &str) -> anyhow::Result<()>
     marker.repository_id = Some(repo_id.to_string());
     write_marker(mount_path, &marker)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(label: &str, path: &str) -> BackupSource {
+        BackupSource {
+            label: label.to_string(),
+            path: path.to_string(),
+            follow_symlink: false,
+            exclude_patterns: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn absolute_sources_expand_without_home() {
+        let sources = [source("Data", "/srv/data"), source("Etc", "/etc")];
+        let expanded = expand_source_paths(&sources, None).unwrap();
+        assert_eq!(
+            expanded,
+            [
+                ("Data".to_string(), PathBuf::from("/srv/data")),
+                ("Etc".to_string(), PathBuf::from("/etc")),
+            ]
+        );
+    }
+
+    #[test]
+    fn tilde_source_needs_home() {
+        let sources = [
+            source("Data", "/srv/data"),
+            source("Pictures", "~/Pictures"),
+        ];
+        let err = expand_source_paths(&sources, None).unwrap_err().to_string();
+        assert!(err.contains("\"Pictures\""), "{}", err);
+        assert!(!err.contains("~/Pictures"));
+
+        let expanded = expand_source_paths(&sources, Some(Path::new("/home/u"))).unwrap();
+        assert_eq!(expanded[1].1, PathBuf::from("/home/u/Pictures"));
+    }
+
+    #[test]
+    fn absolute_symlink_source_follows_only_when_asked() {
+        let root = std::env::temp_dir().join(format!("aegis-symlink-src-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let target = root.join("elsewhere").join("Projects");
+        std::fs::create_dir_all(&target).unwrap();
+        let link = root.join("home").join("Projects");
+        std::fs::create_dir_all(link.parent().unwrap()).unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        let dangling = root.join("home").join("Gone");
+        std::os::unix::fs::symlink(root.join("missing"), &dangling).unwrap();
+        let real_target = std::fs::canonicalize(&target).unwrap();
+
+        let link_str = link.to_string_lossy();
+        let mut follow = source("Projects", &link_str);
+        follow.follow_symlink = true;
+        let mut follow_dangling = source("Gone", &dangling.to_string_lossy());
+        follow_dangling.follow_symlink = true;
+        let sources = [
+            source("Link", &link_str),
+            follow,
+            follow_dangling,
+            source("Plain", &target.to_string_lossy()),
+        ];
+        let expanded = expand_source_paths(&sources, None).unwrap();
+        assert_eq!(expanded[0].1, link);
+        assert_eq!(expanded[1].1, real_target);
+        // A link to nothing is left for restic to back up as a link.
+        assert_eq!(expanded[2].1, dangling);
+        assert_eq!(expanded[3].1, target);
+
+        let targets = link_targets(&sources, None);
+        let _ = std::fs::remove_dir_all(&root);
+        let expected = real_target.to_string_lossy().to_string();
+        assert_eq!(targets.len(), 2);
+        assert_eq!(targets["Link"], expected);
+        assert_eq!(targets["Projects"], expected);
+    }
+
+    #[test]
+    fn source_excludes_apply_only_under_their_source() {
+        let mut projects = source("Projects", "~/Projects");
+        projects.exclude_patterns = vec!["node_modules".to_string(), "!keep.iso".to_string()];
+        let mut downloads = source("Downloads", "/data/Downloads/");
+        downloads.exclude_patterns = vec!["*.iso".to_string(), "/etc/passwd".to_string()];
+        let sources = [projects, downloads, source("Pictures", "~/Pictures")];
+        let excludes = source_excludes(&sources, Some(Path::new("/home/u"))).unwrap();
+        assert_eq!(
+            excludes,
+            [
+                "/home/u/Projects/**/node_modules",
+                "!/home/u/Projects/**/keep.iso",
+                "/data/Downloads/**/*.iso",
+                "/data/Downloads/etc/passwd",
+            ]
+        );
+
+        let excluded = |path: &str| crate::estimate::is_excluded(Path::new(path), &excludes);
+        assert!(excluded("/home/u/Projects/node_modules"));
+        // Like restic, an excluded directory isn't descended into.
+        assert!(excluded("/home/u/Projects/app/node_modules"));
+        assert!(!excluded("/home/u/Pictures/node_modules"));
+        assert!(excluded("/data/Downloads/ubuntu.iso"));
+        assert!(excluded("/data/Downloads/old/debian.iso"));
+        assert!(!excluded("/home/u/Projects/disk.iso"));
+        // A rooted pattern starts at its source, never at the filesystem root.
+        assert!(excluded("/data/Downloads/etc/passwd"));
+        assert!(!excluded("/etc/passwd"));
+        assert!(!excluded("/data/Downloads/old/etc/passwd"));
+
+        // Sources without their own patterns don't need the home directory resolved.
+        assert!(source_excludes(&sources[2..], None).unwrap().is_empty());
+        assert!(source_excludes(&sources[..1], None).is_err());
+    }
+
+    #[test]
+    fn owning_source_label_picks_deepest_match() {
+        let sources = vec![
+            ("Home".to_string(), PathBuf::from("/home/user")),
+            (
+                "Documents".to_string(),
+                PathBuf::from("/home/user/Documents"),
+            ),
+            ("Photos".to_string(), PathBuf::from("/mnt/photos")),
+        ];
+        assert_eq!(
+            owning_source_label(&sources, "/home/user/Documents/a.txt").as_deref(),
+            Some("Documents")
+        );
+        assert_eq!(
+            owning_source_label(&sources, "/home/user/.bashrc").as_deref(),
+            Some("Home")
+        );
+        assert_eq!(
+            owning_source_label(&sources, "/mnt/photos").as_deref(),
+            Some("Photos")
+        );
+        // Component-wise match only: /mnt/photos2 is not under /mnt/photos.
+        assert_eq!(owning_source_label(&sources, "/mnt/photos2/x.jpg"), None);
+    }
+
+    #[test]
+    fn validate_sources_unrelated_source_ok() {
+        let excludes = validate_sources(
+            &[PathBuf::from("/nonexistent-aegis/home/user/Documents")],
+            Path::new("/nonexistent-aegis/media/usb"),
+            Path::new("/nonexistent-aegis/media/usb/.aegis/repo"),
+        )
+        .unwrap();
+        assert!(excludes.is_empty());
+    }
+
+    #[test]
+    fn validate_sources_excludes_repo_when_source_contains_mount() {
+        let excludes = validate_sources(
+            &[
+                PathBuf::from("/nonexistent-aegis/media"),
+                PathBuf::from("/nonexistent-aegis/media/usb"),
+            ],
+            Path::new("/nonexistent-aegis/media/usb"),
+            Path::new("/nonexistent-aegis/media/usb/.aegis/repo"),
+        )
+        .unwrap();
+        assert_eq!(excludes, vec!["/nonexistent-aegis/media/usb/.aegis"]);
+    }
+
+    #[tokio::test]
+    async fn partition_available_drops_unreadable() {
+        let root = std::env::temp_dir().join(format!("aegis-available-{}", std::process::id()));
+        let (a, down, b) = (root.join("a"), root.join("net/down"), root.join("b"));
+        std::fs::create_dir_all(&a).unwrap();
+        std::fs::create_dir_all(&b).unwrap();
+        let (ok, skipped) = partition_available(vec![a.clone(), down.clone(), b.clone()]).await;
+        let _ = std::fs::remove_dir_all(&root);
+        assert_eq!(ok, vec![a, b]);
+        assert_eq!(skipped, vec![down]);
+    }
+
+    #[test]
+    fn newly_empty_only_if_previously_populated() {
+        let empty_now = vec![PathBuf::from("/mnt/share"), PathBuf::from("/home/u/New")];
+        let previously = vec!["/mnt/share".to_string(), "/home/u/Documents".to_string()];
+        assert_eq!(
+            newly_empty_sources(&empty_now, &previously),
+            vec![PathBuf::from("/mnt/share")]
+        );
+        // A source that was always empty (or never backed up) isn't suspicious.
+        assert!(newly_empty_sources(&empty_now, &[]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn dir_is_empty_detects_empty_directories() {
+        let dir = std::env::temp_dir().join(format!("aegis-empty-src-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let was_empty = dir_is_empty(&dir).await;
+        std::fs::write(dir.join("f"), b"x").unwrap();
+        let populated = dir_is_empty(&dir).await;
+        let file = dir_is_empty(&dir.join("f")).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(was_empty);
+        assert!(!populated);
+        assert!(!file);
+        assert!(!dir_is_empty(&dir).await);
+    }
+
+    #[test]
+    fn empty_sources_make_run_partial() {
+        let mut status = RunStatus::Success;
+        let mut message = "Backup completed".to_string();
+        apply_empty_sources(&mut status, &mut message, 1, true);
+        assert_eq!(status, RunStatus::Partial);
+        assert!(
+            message.contains("1 source(s) that had files before are empty now and were skipped")
+        );
+
+        let mut status = RunStatus::Success;
+        let mut message = "Backup completed".to_string();
+        apply_empty_sources(&mut status, &mut message, 0, false);
+        assert_eq!(status, RunStatus::Success);
+    }
+
+    #[test]
+    fn skipped_sources_make_run_partial() {
+        let mut status = RunStatus::Success;
+        let mut message = "Backup completed".to_string();
+        apply_skipped_sources(&mut status, &mut message, 2);
+        assert_eq!(status, RunStatus::Partial);
+        assert_eq!(
+            message,
+            "Backup completed, but 2 unavailable source(s) were skipped"
+        );
+    }
+
+    #[test]
+    fn progress_debounce_coalesces_rapid_reports() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut debounce = ProgressDebounce::new(Duration::from_millis(250));
+        assert!(debounce.should_write(at(0), 0.10));
+        assert!(!debounce.should_write(at(50), 0.11));
+        assert!(!debounce.should_write(at(249), 0.12));
+        assert!(debounce.should_write(at(250), 0.13));
+        assert!(!debounce.should_write(at(300), 0.14));
+        // Reaching 100% is written right away, once.
+        assert!(debounce.should_write(at(310), 1.0));
+        assert!(!debounce.should_write(at(320), 1.0));
+        assert!(debounce.should_write(at(600), 1.0));
+    }
+
+    #[test]
+    fn incomplete_backup_makes_run_partial() {
+        let mut status = RunStatus::Success;
+        let mut message = "Backup completed".to_string();
+        apply_incomplete_backup(&mut status, &mut message, true);
+        assert_eq!(status, RunStatus::Partial);
+        assert_eq!(
+            message,
+            "Backup completed, but some files could not be read and were skipped"
+        );
+
+        let mut status = RunStatus::Partial;
+        let mut message = "Backup completed, but verification failed".to_string();
+        apply_incomplete_backup(&mut status, &mut message, true);
+        assert!(message.ends_with("(some files could not be read)"));
+
+        let mut status = RunStatus::Success;
+        let mut message = "Backup completed".to_string();
+        apply_incomplete_backup(&mut status, &mut message, false);
+        assert_eq!(status, RunStatus::Success);
+    }
+
+    #[test]
+    fn dry_run_describes_what_would_be_added() {
+        let summary = BackupSummary {
+            snapshot_id: None,
+            data_added: Some(2048),
+            files_processed: Some(10),
+            files_new: Some(3),
+            files_changed: Some(1),
+            incomplete: false,
+        };
+        assert_eq!(
+            describe_dry_run(&summary),
+            "would back up 3 new and 1 changed file(s), 2.0 KB"
+        );
+        let nothing = BackupSummary {
+            data_added: None,
+            files_new: None,
+            files_changed: None,
+            ..summary
+        };
+        assert_eq!(
+            describe_dry_run(&nothing),
+            "would back up 0 new and 0 changed file(s), 0.0 B"
+        );
+    }
+
+    #[test]
+    fn no_skipped_sources_leaves_status() {
+        let mut status = RunStatus::Success;
+        let mut message = "Backup completed".to_string();
+        apply_skipped_sources(&mut status, &mut message, 0);
+        assert_eq!(status, RunStatus::Success);
+        assert_eq!(message, "Backup completed");
+    }
+
+    #[test]
+    fn skipped_sources_append_to_existing_partial() {
+        let mut status = RunStatus::Partial;
+        let mut message = "Backup completed, but verification failed".to_string();
+        apply_skipped_sources(&mut status, &mut message, 1);
+        assert_eq!(status, RunStatus::Partial);
+        assert!(message.ends_with("(1 unavailable source(s) skipped)"));
+    }
+
+    #[test]
+    fn validate_sources_rejects_source_inside_repo() {
+        let err = validate_sources(
+            &[PathBuf::from(
+                "/nonexistent-aegis/media/usb/.aegis/repo/data",
+            )],
+            Path::new("/nonexistent-aegis/media/usb"),
+            Path::new("/nonexistent-aegis/media/usb/.aegis/repo"),
+        );
+        assert!(err.is_err());
+    }
+}
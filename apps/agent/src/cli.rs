@@ -0,0 +1,533 @@
+//! Headless control: `aegis-agent status`, `aegis-agent backup <drive>` and
+//! `aegis-agent snapshots <drive>` talk to the running agent over its local IPC port, so the agent
+//! can be scripted without the UI. Without a subcommand the binary runs the agent itself.
+
+use crate::ipc::LISTEN_ADDR;
+use serde_json::{json, Value};
+use std::io::BufRead;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+pub const USAGE: &str = "usage: aegis-agent [--profile <name>]
+       aegis-agent <command>
+
+Without a command, runs the agent (for the profile, if given). Commands talk to the running agent,
+whichever profile it runs:
+  status [--json]                          drives, last run and problems
+  backup <drive> [--passphrase-stdin]      start a backup (drive ID, ID prefix or name)
+  snapshots <drive> [--json] [--passphrase-stdin]
+                                           list the drive's snapshots";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CliCommand {
+    Status {
+        json: bool,
+    },
+    Backup {
+        drive: String,
+        passphrase_stdin: bool,
+    },
+    Snapshots {
+        drive: String,
+        json: bool,
+        passphrase_stdin: bool,
+    },
+}
+
+/// Why the arguments didn't name something to run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArgsError {
+    /// `-h`/`--help`: print the usage and exit successfully.
+    Help,
+    /// Malformed arguments, with what was wrong.
+    Invalid(String),
+}
+
+impl From<String> for ArgsError {
+    fn from(message: String) -> Self {
+        ArgsError::Invalid(message)
+    }
+}
+
+/// The command in `args` (without the program name); None means "run the agent". `--profile` is
+/// read by `profile::init_from_env` and only applies to running the agent: every profile's agent
+/// listens on the same port, so a command can't pick one.
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Option<CliCommand>, ArgsError> {
+    let mut positional = Vec::new();
+    let mut json = false;
+    let mut passphrase_stdin = false;
+    let mut profile = false;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--profile" => {
+                args.next();
+                profile = true;
+            }
+            _ if arg.starts_with("--profile=") => profile = true,
+            "--json" => json = true,
+            "--passphrase-stdin" => passphrase_stdin = true,
+            "-h" | "--help" => return Err(ArgsError::Help),
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg).into()),
+            _ => positional.push(arg),
+        }
+    }
+    let mut positional = positional.into_iter();
+    let Some(command) = positional.next() else {
+        return Ok(None);
+    };
+    if profile {
+        return Err(
+            "--profile only applies when starting the agent; commands talk to the running agent"
+                .to_string()
+                .into(),
+        );
+    }
+    let drive = positional.next();
+    if let Some(extra) = positional.next() {
+        return Err(format!("unexpected argument {}", extra).into());
+    }
+    let needs_drive = || {
+        drive
+            .clone()
+            .ok_or_else(|| format!("{} needs a drive", command))
+    };
+    let parsed = match command.as_str() {
+        "status" if drive.is_none() => CliCommand::Status { json },
+        "status" => return Err("status takes no drive".to_string().into()),
+        "backup" => CliCommand::Backup {
+            drive: needs_drive()?,
+            passphrase_stdin,
+        },
+        "snapshots" => CliCommand::Snapshots {
+            drive: needs_drive()?,
+            json,
+            passphrase_stdin,
+        },
+        other => return Err(format!("unknown command {}", other).into()),
+    };
+    Ok(Some(parsed))
+}
+
+/// One IPC call.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IpcRequest {
+    pub method: &'static str,
+    pub path: &'static str,
+    pub body: Option<Value>,
+}
+
+/// The IPC call a command makes once its drive is resolved to a drive ID.
+pub fn ipc_request(
+    command: &CliCommand,
+    drive_id: Option<&str>,
+    passphrase: Option<&str>,
+) -> IpcRequest {
+    match command {
+        CliCommand::Status { .. } => IpcRequest {
+            method: "GET",
+            path: "/v1/status",
+            body: None,
+        },
+        CliCommand::Backup { .. } => IpcRequest {
+            method: "POST",
+            path: "/v1/backup/run",
+            body: Some(json!({"drive_id": drive_id, "passphrase": passphrase})),
+        },
+        CliCommand::Snapshots { .. } => IpcRequest {
+            method: "POST",
+            path: "/v1/snapshots",
+            body: Some(json!({"drive_id": drive_id, "passphrase": passphrase})),
+        },
+    }
+}
+
+/// Find the trusted drive `query` names in a status response: exact drive ID, name
+/// (case-insensitive) or a unique drive ID prefix.
+pub fn resolve_drive(status: &Value, query: &str) -> Result<String, String> {
+    let drives = status["trusted_drives"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    let id = |d: &Value| d["drive_id"].as_str().unwrap_or_default().to_string();
+    if let Some(drive) = drives.iter().find(|d| d["drive_id"] == query) {
+        return Ok(id(drive));
+    }
+    let by_name: Vec<&Value> = drives
+        .iter()
+        .filter(|d| {
+            d["label"]
+                .as_str()
+                .is_some_and(|l| l.eq_ignore_ascii_case(query))
+        })
+        .collect();
+    if let [drive] = by_name[..] {
+        return Ok(id(drive));
+    }
+    let by_prefix: Vec<&Value> = drives
+        .iter()
+        .filter(|d| query.len() >= 4 && id(d).starts_with(query))
+        .collect();
+    match (by_name.len(), &by_prefix[..]) {
+        (0, [drive]) => Ok(id(drive)),
+        (0, []) => Err(format!("no trusted drive matches \"{}\"", query)),
+        _ => Err(format!(
+            "\"{}\" matches more than one drive; use the drive ID",
+            query
+        )),
+    }
+}
+
+/// Run a command against the agent and return the process exit code.
+pub async fn run(command: CliCommand) -> i32 {
+    match dispatch(&command).await {
+        Ok(output) => {
+            println!("{}", output);
+            0
+        }
+        Err(message) => {
+            eprintln!("aegis-agent: {}", message);
+            1
+        }
+    }
+}
+
+async fn dispatch(command: &CliCommand) -> Result<String, String> {
+    let status = call(&ipc_request(&CliCommand::Status { json: true }, None, None)).await?;
+    let (drive, passphrase_stdin) = match command {
+        CliCommand::Status { json: true } => return pretty(&status),
+        CliCommand::Status { json: false } => return Ok(format_status(&status)),
+        CliCommand::Backup {
+            drive,
+            passphrase_stdin,
+        }
+        | CliCommand::Snapshots {
+            drive,
+            passphrase_stdin,
+            ..
+        } => (drive, *passphrase_stdin),
+    };
+    let drive_id = resolve_drive(&status, drive)?;
+    let passphrase = if passphrase_stdin {
+        Some(read_passphrase()?)
+    } else {
+        None
+    };
+    let response = call(&ipc_request(
+        command,
+        Some(&drive_id),
+        passphrase.as_deref(),
+    ))
+    .await?;
+    match command {
+        CliCommand::Snapshots { json: false, .. } => Ok(format_snapshots(&response)),
+        CliCommand::Backup { .. } => Ok(format!(
+            "backup started on {}{}",
+            drive_id,
+            if response["is_first_backup"] == true {
+                " (first backup, this takes longest)"
+            } else {
+                ""
+            }
+        )),
+        _ => pretty(&response),
+    }
+}
+
+fn pretty(value: &Value) -> Result<String, String> {
+    serde_json::to_string_pretty(value).map_err(|e| e.to_string())
+}
+
+fn read_passphrase() -> Result<String, String> {
+    let mut line = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut line)
+        .map_err(|e| format!("read passphrase: {}", e))?;
+    let passphrase = line.trim_end_matches(['\r', '\n']).to_string();
+    if passphrase.is_empty() {
+        return Err("empty passphrase on stdin".to_string());
+    }
+    Ok(passphrase)
+}
+
+/// One line per trusted drive plus the last run and attention items.
+fn format_status(status: &Value) -> String {
+    let mut lines = Vec::new();
+    let running: Vec<&str> = status["running_drive_ids"]
+        .as_array()
+        .map(|ids| ids.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+    for drive in status["trusted_drives"].as_array().into_iter().flatten() {
+        let id = drive["drive_id"].as_str().unwrap_or_default();
+        let state = if running.contains(&id) {
+            "backing up"
+        } else if drive["is_connected"] == true {
+            "connected"
+        } else {
+            "not connected"
+        };
+        let last_backup = match drive["last_backup_epoch"].as_u64() {
+            Some(epoch) => format!("last backup at {}", epoch),
+            None => "never backed up".to_string(),
+        };
+        lines.push(format!(
+            "{}  {}  {}, {}",
+            id,
+            drive["label"].as_str().unwrap_or_default(),
+            state,
+            last_backup
+        ));
+    }
+    if lines.is_empty() {
+        lines.push("no trusted drives".to_string());
+    }
+    if let Some(run) = status["last_run"].as_object() {
+        lines.push(format!(
+            "last run: {} {}",
+            run.get("status").and_then(Value::as_str).unwrap_or("?"),
+            run.get("message")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+        ));
+    }
+    for item in status["attention"].as_array().into_iter().flatten() {
+        lines.push(format!(
+            "{}: {}",
+            item["severity"].as_str().unwrap_or("warning"),
+            item["message"].as_str().unwrap_or_default()
+        ));
+    }
+    lines.join("\n")
+}
+
+fn format_snapshots(response: &Value) -> String {
+    let snapshots = response["snapshots"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    if snapshots.is_empty() {
+        return "no snapshots".to_string();
+    }
+    snapshots
+        .iter()
+        .map(|s| {
+            let id = s["id"].as_str().unwrap_or_default();
+            let mut line = format!(
+                "{}  {}",
+                &id[..id.len().min(8)],
+                s["time"].as_str().unwrap_or_default()
+            );
+            if let Some(description) = s["description"].as_str() {
+                line.push_str("  ");
+                line.push_str(description);
+            }
+            line
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Send one request to the agent and parse the JSON reply. Errors carry the agent's message.
+async fn call(request: &IpcRequest) -> Result<Value, String> {
+    let mut stream = TcpStream::connect(LISTEN_ADDR)
+        .await
+        .map_err(|e| format!("agent not reachable at {} ({})", LISTEN_ADDR, e))?;
+    let body = request
+        .body
+        .as_ref()
+        .map(Value::to_string)
+        .unwrap_or_default();
+    let head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        request.method,
+        request.path,
+        LISTEN_ADDR,
+        body.len()
+    );
+    let io_error = |e: std::io::Error| format!("talking to the agent failed: {}", e);
+    stream
+        .write_all(format!("{}{}", head, body).as_bytes())
+        .await
+        .map_err(io_error)?;
+    let mut raw = Vec::new();
+    stream.read_to_end(&mut raw).await.map_err(io_error)?;
+    let (code, body) = parse_http_response(&raw)?;
+    if !(200..300).contains(&code) {
+        return Err(format!("agent refused ({}): {}", code, body.trim()));
+    }
+    serde_json::from_str(&body).map_err(|e| format!("unexpected reply from the agent: {}", e))
+}
+
+/// Status code and body of a raw HTTP/1.1 response (plain or chunked). Chunks are joined as bytes
+/// and decoded once, so a UTF-8 character split across two chunks survives.
+fn parse_http_response(raw: &[u8]) -> Result<(u16, String), String> {
+    let split = raw
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| "malformed reply from the agent".to_string())?;
+    let head = String::from_utf8_lossy(&raw[..split]);
+    let body = &raw[split + 4..];
+    let code = head
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| "malformed reply from the agent".to_string())?;
+    let chunked = head.lines().any(|line| {
+        let line = line.to_ascii_lowercase();
+        line.starts_with("transfer-encoding:") && line.contains("chunked")
+    });
+    let body = if chunked {
+        dechunk(body)
+    } else {
+        body.to_vec()
+    };
+    Ok((code, String::from_utf8_lossy(&body).into_owned()))
+}
+
+fn dechunk(mut body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    while let Some(line_end) = body.windows(2).position(|w| w == b"\r\n") {
+        let size_line = String::from_utf8_lossy(&body[..line_end]);
+        let size = usize::from_str_radix(size_line.split(';').next().unwrap_or("").trim(), 16)
+            .unwrap_or(0);
+        let rest = &body[line_end + 2..];
+        if size == 0 || rest.len() < size {
+            break;
+        }
+        out.extend_from_slice(&rest[..size]);
+        body = rest[size..].strip_prefix(b"\r\n").unwrap_or(&rest[size..]);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Option<CliCommand>, ArgsError> {
+        parse_args(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn parse_args_commands_and_options() {
+        assert_eq!(parse(&[]), Ok(None));
+        assert_eq!(parse(&["--profile", "work"]), Ok(None));
+        assert_eq!(
+            parse(&["status", "--json"]),
+            Ok(Some(CliCommand::Status { json: true }))
+        );
+        assert!(matches!(
+            parse(&["--profile=work", "status"]),
+            Err(ArgsError::Invalid(_))
+        ));
+        assert!(matches!(
+            parse(&["backup", "Photos", "--profile", "work"]),
+            Err(ArgsError::Invalid(_))
+        ));
+        assert_eq!(
+            parse(&["backup", "Photos", "--passphrase-stdin"]),
+            Ok(Some(CliCommand::Backup {
+                drive: "Photos".to_string(),
+                passphrase_stdin: true
+            }))
+        );
+        assert_eq!(
+            parse(&["snapshots", "abcd1234"]),
+            Ok(Some(CliCommand::Snapshots {
+                drive: "abcd1234".to_string(),
+                json: false,
+                passphrase_stdin: false
+            }))
+        );
+        assert!(parse(&["backup"]).is_err());
+        assert!(parse(&["status", "Photos"]).is_err());
+        assert!(parse(&["backup", "a", "b"]).is_err());
+        assert!(parse(&["status", "--verbose"]).is_err());
+        assert_eq!(parse(&["status", "--help"]), Err(ArgsError::Help));
+        assert_eq!(
+            parse(&["restore"]),
+            Err(ArgsError::Invalid("unknown command restore".to_string()))
+        );
+    }
+
+    #[test]
+    fn ipc_request_per_command() {
+        let status = ipc_request(&CliCommand::Status { json: false }, None, None);
+        assert_eq!((status.method, status.path), ("GET", "/v1/status"));
+        assert_eq!(status.body, None);
+
+        let backup = CliCommand::Backup {
+            drive: "Photos".to_string(),
+            passphrase_stdin: false,
+        };
+        let request = ipc_request(&backup, Some("abc"), None);
+        assert_eq!((request.method, request.path), ("POST", "/v1/backup/run"));
+        assert_eq!(
+            request.body,
+            Some(json!({"drive_id": "abc", "passphrase": null}))
+        );
+
+        let snapshots = CliCommand::Snapshots {
+            drive: "Photos".to_string(),
+            json: true,
+            passphrase_stdin: true,
+        };
+        let request = ipc_request(&snapshots, Some("abc"), Some("pw"));
+        assert_eq!(request.path, "/v1/snapshots");
+        assert_eq!(request.body.unwrap()["passphrase"], "pw");
+    }
+
+    #[test]
+    fn resolve_drive_by_id_name_or_prefix() {
+        let status = json!({"trusted_drives": [
+            {"drive_id": "abcd1111", "label": "Photos"},
+            {"drive_id": "abcd2222", "label": "Work"},
+        ]});
+        assert_eq!(
+            resolve_drive(&status, "abcd2222"),
+            Ok("abcd2222".to_string())
+        );
+        assert_eq!(resolve_drive(&status, "photos"), Ok("abcd1111".to_string()));
+        assert_eq!(resolve_drive(&status, "abcd1"), Ok("abcd1111".to_string()));
+        assert!(resolve_drive(&status, "abcd").is_err());
+        assert!(resolve_drive(&status, "abc").is_err());
+        assert!(resolve_drive(&status, "Music").is_err());
+    }
+
+    #[test]
+    fn parse_http_response_plain_and_chunked() {
+        let plain = b"HTTP/1.1 409 Conflict\r\ncontent-length: 5\r\n\r\nbusy!";
+        assert_eq!(parse_http_response(plain), Ok((409, "busy!".to_string())));
+        let chunked =
+            b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n4\r\n{\"a\"\r\n3\r\n:1}\r\n0\r\n\r\n";
+        assert_eq!(
+            parse_http_response(chunked),
+            Ok((200, "{\"a\":1}".to_string()))
+        );
+        // "é" is split across the two chunks.
+        let split = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\n\"\xc3\r\n2\r\n\xa9\"\r\n0\r\n\r\n";
+        assert_eq!(parse_http_response(split), Ok((200, "\"é\"".to_string())));
+        assert!(parse_http_response(b"garbage").is_err());
+    }
+
+    #[test]
+    fn format_status_lists_drives() {
+        let status = json!({
+            "running_drive_ids": ["abcd2222"],
+            "trusted_drives": [
+                {"drive_id": "abcd1111", "label": "Photos", "is_connected": true, "last_backup_epoch": 1700000000},
+                {"drive_id": "abcd2222", "label": "Work", "is_connected": true, "last_backup_epoch": null},
+            ],
+            "last_run": null,
+            "attention": [{"severity": "warning", "message": "Photos is filling up"}],
+        });
+        assert_eq!(
+            format_status(&status),
+            "abcd1111  Photos  connected, last backup at 1700000000\n\
+             abcd2222  Work  backing up, never backed up\n\
+             warning: Photos is filling up"
+        );
+    }
+}
@@ -1,4 +1,12 @@
+use crate::config_backups;
+use crate::durations::{self, DURATION_HISTORY_MAX};
+use crate::profile;
+use crate::recovery::{compare_repository_ids, RepoIdMatch};
+use crate::restic::{BandwidthLimits, PerformanceOverrides, PerformanceProfile};
 use crate::retention::RetentionPolicy;
+use crate::schedule::ScheduleConfig;
+use crate::space::{FreeSpaceSample, FREE_SPACE_HISTORY_MAX};
+use crate::verify::VerifyFailurePolicy;
 use anyhow::Context;
 use directories::ProjectDirs;
 use serde::{Deserialize, Serialize};
@@ -7,9 +15,55 @@ use std::fs;
 use std::path::{Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
 
+/// Env var that replaces the platform config dir (e.g. for tests or a sandbox).
+pub const CONFIG_DIR_ENV: &str = "AEGIS_CONFIG_DIR";
+
+/// Env var that replaces the platform data dir (logs).
+pub const DATA_DIR_ENV: &str = "AEGIS_DATA_DIR";
+
+/// The directory an override env var names, if it is set and not empty.
+pub fn dir_override(value: Option<std::ffi::OsString>) -> Option<PathBuf> {
+    value.filter(|v| !v.is_empty()).map(PathBuf::from)
+}
+
+/// The config directory: `value` of `AEGIS_CONFIG_DIR` when set, else the platform's.
+fn config_dir(value: Option<std::ffi::OsString>) -> anyhow::Result<PathBuf> {
+    match dir_override(value) {
+        Some(dir) => Ok(dir),
+        None => Ok(ProjectDirs::from("com", "aegis", "Aegis")
+            .context("resolve config dir")?
+            .config_dir()
+            .to_path_buf()),
+    }
+}
+
 /// Max length for in-app drive/source labels (stored on drive and in config). Prevents abuse from untrusted marker files.
 pub const LABEL_MAX_LEN: usize = 512;
 
+/// restic exclude patterns for the OS bookkeeping folders that accumulate on removable media
+/// (trash, recycle bin, indexing). `$` is written as `[$]` so restic doesn't expand it as a variable.
+/// restic matches case-sensitively and Windows writes both `$RECYCLE.BIN` and `$Recycle.Bin`, so
+/// that name spells out both cases of every letter.
+pub const REMOVABLE_MEDIA_JUNK_EXCLUDES: &[&str] = &[
+    ".Trash-*",
+    ".Trashes",
+    "[$][Rr][Ee][Cc][Yy][Cc][Ll][Ee].[Bb][Ii][Nn]",
+    "System Volume Information",
+    ".Spotlight-V100",
+    ".fseventsd",
+];
+
+/// True if a file or folder name is removable-media junk (see [`REMOVABLE_MEDIA_JUNK_EXCLUDES`]),
+/// matched as restic matches the patterns.
+pub fn is_removable_media_junk(name: &str) -> bool {
+    REMOVABLE_MEDIA_JUNK_EXCLUDES
+        .iter()
+        .any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix) && name.len() > prefix.len(),
+            None => crate::estimate::glob_match(pattern, name),
+        })
+}
+
 /// Sanitize a label from user input or from disk (untrusted): trim, remove control chars, limit length. Returns None if empty after sanitize.
 pub fn sanitize_label(s: &str) -> Option<String> {
     let t = s.trim();
@@ -26,11 +80,56 @@ pub fn sanitize_label(s: &str) -> Option<String> {
     }
 }
 
+/// Validate a notification icon from user input: either a themed icon name (`drive-harddisk`)
+/// or an absolute path to an image file. Empty input clears the icon.
+pub fn validate_notification_icon(s: &str) -> Result<Option<String>, String> {
+    let t = s.trim();
+    if t.is_empty() {
+        return Ok(None);
+    }
+    let is_name = t
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    let is_path = Path::new(t).is_absolute() && !t.chars().any(|c| c.is_control());
+    if is_name || is_path {
+        Ok(Some(t.to_string()))
+    } else {
+        Err(format!(
+            "invalid notification icon {:?}; use an icon name or an absolute path",
+            t
+        ))
+    }
+}
+
+/// Sanitize include/exclude patterns from user input: trim, drop empty entries and ones with
+/// control characters (restic would read a newline as two patterns), and remove duplicates.
+pub fn sanitize_patterns(patterns: &[String]) -> Vec<String> {
+    let mut out: Vec<String> = Vec::new();
+    for pattern in patterns {
+        let pattern = pattern.trim();
+        if pattern.is_empty() || pattern.chars().any(char::is_control) {
+            continue;
+        }
+        if !out.iter().any(|p| p == pattern) {
+            out.push(pattern.to_string());
+        }
+    }
+    out
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BackupSource {
     pub label: String,
     /// Absolute or user-relative path. Never display this in logs/UI.
     pub path: String,
+    /// When the path is a symlink, back up the directory or file it points to. Off by default:
+    /// restic then stores the link itself, not what it leads to.
+    #[serde(default)]
+    pub follow_symlink: bool,
+    /// Exclude patterns that apply only under this source (e.g. `node_modules` under Projects),
+    /// on top of the global and per-drive ones.
+    #[serde(default)]
+    pub exclude_patterns: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -50,6 +149,73 @@ pub struct TrustedDrive {
     /// If set, backup only these sources to this drive; otherwise use global backup_sources.
     #[serde(default)]
     pub backup_sources: Option<Vec<BackupSource>>,
+    /// Free space on the drive after each recent backup (oldest first), for the fill-up projection.
+    #[serde(default)]
+    pub free_space_history: Vec<FreeSpaceSample>,
+    /// Bytes restic added to this drive over all backups since setup (before dedup across runs).
+    #[serde(default)]
+    pub total_data_added: u64,
+    /// Hardware serial of the physical drive (udev ID_SERIAL) at setup, to spot the same media set up twice.
+    #[serde(default)]
+    pub serial: Option<String>,
+    /// Include/exclude patterns for this drive only, added to the global ones (see
+    /// `AgentConfig::effective_include_patterns`).
+    #[serde(default)]
+    pub include_patterns: Vec<String>,
+    #[serde(default)]
+    pub exclude_patterns: Vec<String>,
+    /// Source paths that had files at the last successful backup, to spot one that is suddenly empty.
+    #[serde(default)]
+    pub populated_sources: Vec<String>,
+    /// UUID of the LUKS container when the drive is encrypted; its unlock passphrase is stored
+    /// in the keychain under it.
+    #[serde(default)]
+    pub luks_uuid: Option<String>,
+    /// How long recent successful backups took, in seconds (oldest first).
+    #[serde(default)]
+    pub backup_durations_secs: Vec<u64>,
+    /// Fields this version doesn't know (written by a newer one), kept so a save doesn't drop them.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl TrustedDrive {
+    /// Name shown in the UI: the label, or `drive-` plus the first 8 characters of the ID.
+    pub fn display_label(&self) -> String {
+        self.label.clone().unwrap_or_else(|| {
+            format!(
+                "drive-{}",
+                self.drive_id.chars().take(8).collect::<String>()
+            )
+        })
+    }
+
+    /// Whether `input` confirms removing this drive: the label typed exactly. A drive without a
+    /// label is confirmed by its displayed name (`drive-xxxxxxxx`) or a drive_id prefix of at
+    /// least 8 characters.
+    pub fn confirms_removal(&self, input: &str) -> bool {
+        let input = input.trim();
+        match self.label.as_deref().filter(|l| !l.is_empty()) {
+            Some(label) => input == label,
+            None => {
+                input == self.display_label()
+                    || (input.len() >= 8 && self.drive_id.starts_with(input))
+            }
+        }
+    }
+
+    /// True if the repository found on the drive is not the one this drive was set up with
+    /// (reformatted or re-initialized out of band). `None` means no repository was found.
+    /// Drives set up before the ID was recorded never report a change.
+    pub fn repository_changed(&self, found_id: Option<&str>) -> bool {
+        let Some(expected) = self.repository_id.as_deref() else {
+            return false;
+        };
+        match found_id {
+            Some(found) => compare_repository_ids(expected, Some(found)) == RepoIdMatch::Mismatch,
+            None => true,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -67,6 +233,123 @@ pub struct AgentConfig {
     pub paranoid_mode: bool,
     /// Optional override for the restic binary path.
     pub restic_path: Option<String>,
+    /// Named preset for compression / pack size / read concurrency.
+    #[serde(default)]
+    pub performance_profile: PerformanceProfile,
+    /// Individual flags that take precedence over the profile.
+    #[serde(default)]
+    pub performance_overrides: PerformanceOverrides,
+    /// Warn when the drive is projected to fill within this many backups (0 disables).
+    #[serde(default = "default_space_warning_backups")]
+    pub space_warning_backups: u32,
+    /// If false, no desktop notifications are shown.
+    #[serde(default = "default_true")]
+    pub notifications_enabled: bool,
+    /// App name passed to the desktop notifier (used for grouping).
+    #[serde(default = "default_notification_app_name")]
+    pub notification_app_name: String,
+    /// Optional icon name or path for desktop notifications.
+    #[serde(default)]
+    pub notification_icon: Option<String>,
+    /// Locale for notification text (e.g. `de_DE`); unset uses the system locale.
+    #[serde(default)]
+    pub notification_locale: Option<String>,
+    /// If true, sources that can't be read at backup time are skipped (run is Partial) instead of failing the backup.
+    #[serde(default)]
+    pub skip_unavailable_sources: bool,
+    /// Timestamped copies of the config kept in `backups/` next to it (0 disables).
+    #[serde(default = "default_config_backup_count")]
+    pub config_backup_count: u32,
+    /// How many times eject tries to power the drive off before reporting it as safe to remove anyway.
+    #[serde(default = "default_eject_power_off_attempts")]
+    pub eject_power_off_attempts: u32,
+    /// Keep a passphrase typed into the UI in memory for this many seconds so the next operation
+    /// on that drive doesn't prompt again (0 disables; ignored in paranoid mode).
+    #[serde(default)]
+    pub passphrase_cache_secs: u64,
+    /// Exclude trash, recycle-bin and indexing folders (see `REMOVABLE_MEDIA_JUNK_EXCLUDES`) from every backup.
+    #[serde(default = "default_true")]
+    pub exclude_removable_media_junk: bool,
+    /// Keychain service the passphrases were stored under at the last start; when the current one
+    /// differs, they are migrated to it.
+    #[serde(default)]
+    pub keychain_service: Option<String>,
+    /// Free space to always leave on the backup drive; a backup stops before going below the larger
+    /// of the two (both 0 disables). Protects exFAT volumes, which can corrupt when full.
+    #[serde(default)]
+    pub min_free_bytes: u64,
+    #[serde(default)]
+    pub min_free_percent: u8,
+    /// Enables the `/v1/debug/*` support endpoints (process list and termination). Off by default;
+    /// can only be turned on by editing the config file, not over IPC.
+    #[serde(default)]
+    pub debug_endpoints: bool,
+    /// Host name recorded in snapshots (restic `--host`); None uses the machine's hostname.
+    #[serde(default)]
+    pub backup_hostname: Option<String>,
+    /// Skip (instead of only warning about) a source that had files at the last backup but is empty now.
+    #[serde(default)]
+    pub refuse_empty_sources: bool,
+    /// Run read-only restic commands (snapshot list, stats) with `--no-lock`, so they don't wait
+    /// for a running backup's lock.
+    #[serde(default = "default_true")]
+    pub no_lock_reads: bool,
+    /// What a failed quick/deep verification does to the run: warn (Partial), fail or retry.
+    #[serde(default)]
+    pub verify_failure_policy: VerifyFailurePolicy,
+    /// Quick-check the existing repository before each backup and don't back up if it fails, so
+    /// a failing drive doesn't get more data written to it.
+    #[serde(default)]
+    pub verify_before_backup: bool,
+    /// Hardware serials (udev ID_SERIAL) or drive IDs of the only drives treated as trusted when
+    /// plugged in; empty allows every trusted drive. For admins of shared machines, so like
+    /// `debug_endpoints` it can only be set by editing the config file.
+    #[serde(default)]
+    pub allowed_devices: Vec<String>,
+    /// Per-drive history kept in the config (free-space samples): at most this many entries, and
+    /// none older than `history_max_age_days` (0 keeps them regardless of age). Enforced when a
+    /// new entry is appended.
+    #[serde(default = "default_history_max_entries")]
+    pub history_max_entries: u32,
+    #[serde(default)]
+    pub history_max_age_days: u32,
+    /// Caps on restic's repository I/O in KiB/s (`--limit-upload` for backups writing to the
+    /// drive, `--limit-download` for restores reading from it); 0 is unlimited.
+    #[serde(default)]
+    pub limit_upload_kb: u32,
+    #[serde(default)]
+    pub limit_download_kb: u32,
+    /// Weekly times to back up the connected trusted drive (see `schedule.rs`).
+    #[serde(default)]
+    pub schedule: ScheduleConfig,
+    /// Settings this version doesn't know (written by a newer one, e.g. before a downgrade), kept
+    /// as-is so saving the config doesn't silently strip them.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_notification_app_name() -> String {
+    "Aegis".to_string()
+}
+
+fn default_space_warning_backups() -> u32 {
+    5
+}
+
+fn default_config_backup_count() -> u32 {
+    10
+}
+
+fn default_eject_power_off_attempts() -> u32 {
+    2
+}
+
+fn default_history_max_entries() -> u32 {
+    FREE_SPACE_HISTORY_MAX as u32
 }
 
 impl Default for AgentConfig {
@@ -77,14 +360,20 @@ impl Default for AgentConfig {
                 BackupSource {
                     label: "Documents".to_string(),
                     path: "~/Documents".to_string(),
+                    follow_symlink: false,
+                    exclude_patterns: Vec::new(),
                 },
                 BackupSource {
                     label: "Pictures".to_string(),
                     path: "~/Pictures".to_string(),
+                    follow_symlink: false,
+                    exclude_patterns: Vec::new(),
                 },
                 BackupSource {
                     label: "Desktop".to_string(),
                     path: "~/Desktop".to_string(),
+                    follow_symlink: false,
+                    exclude_patterns: Vec::new(),
                 },
             ],
             include_patterns: Vec::new(),
@@ -96,6 +385,34 @@ impl Default for AgentConfig {
             remember_passphrase: true,
             paranoid_mode: false,
             restic_path: None,
+            performance_profile: PerformanceProfile::default(),
+            performance_overrides: PerformanceOverrides::default(),
+            space_warning_backups: default_space_warning_backups(),
+            notifications_enabled: true,
+            notification_app_name: default_notification_app_name(),
+            notification_icon: None,
+            notification_locale: None,
+            skip_unavailable_sources: false,
+            config_backup_count: default_config_backup_count(),
+            eject_power_off_attempts: default_eject_power_off_attempts(),
+            passphrase_cache_secs: 0,
+            exclude_removable_media_junk: true,
+            keychain_service: None,
+            min_free_bytes: 0,
+            min_free_percent: 0,
+            debug_endpoints: false,
+            backup_hostname: None,
+            refuse_empty_sources: false,
+            no_lock_reads: true,
+            verify_failure_policy: VerifyFailurePolicy::default(),
+            verify_before_backup: false,
+            allowed_devices: Vec::new(),
+            history_max_entries: default_history_max_entries(),
+            history_max_age_days: 0,
+            limit_upload_kb: 0,
+            limit_download_kb: 0,
+            schedule: ScheduleConfig::default(),
+            extra: serde_json::Map::new(),
         }
     }
 }
@@ -109,7 +426,12 @@ impl AgentConfig {
             return Ok(config);
         }
         let content = fs::read_to_string(&path).context("read config")?;
-        let mut config: AgentConfig = serde_json::from_str(&content).context("parse config")?;
+        Self::from_json(&content)
+    }
+
+    /// Parse a config file body, sanitizing untrusted fields and enforcing invariants.
+    fn from_json(content: &str) -> anyhow::Result<Self> {
+        let mut config: AgentConfig = serde_json::from_str(content).context("parse config")?;
         for drive in config.trusted_drives.values_mut() {
             if let Some(l) = &drive.label {
                 drive.label = sanitize_label(l);
@@ -127,19 +449,77 @@ impl AgentConfig {
         Ok(config)
     }
 
+    pub fn bandwidth_limits(&self) -> BandwidthLimits {
+        BandwidthLimits {
+            upload_kb: self.limit_upload_kb,
+            download_kb: self.limit_download_kb,
+        }
+    }
+
+    /// Why the configured `restic_path` can't be used, if it is set and unusable.
+    pub fn restic_path_error(&self) -> Option<String> {
+        let path = self.restic_path.as_deref()?;
+        crate::restic::check_binary(Path::new(path)).err()
+    }
+
+    /// Write the config. For what the agent records on its own (last seen, free space, backup
+    /// times); a change the user made goes through [`Self::save_with_backup`].
     pub fn save(&self) -> anyhow::Result<()> {
+        self.write().map(|_| ())
+    }
+
+    /// Write a change the user made and keep a copy of it in the config backups, so it can be
+    /// undone.
+    pub fn save_with_backup(&self) -> anyhow::Result<()> {
+        let (path, content) = self.write()?;
+        if self.config_backup_count > 0 {
+            // A failed backup must not fail the save itself.
+            if let Err(err) = config_backups::write_backup(
+                &config_backups::backup_dir(&path),
+                &content,
+                now_epoch(),
+                self.config_backup_count as usize,
+            ) {
+                tracing::warn!("config: could not write backup: {}", err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the config file; its path and the JSON written.
+    fn write(&self) -> anyhow::Result<(PathBuf, String)> {
+        if let Some(err) = self.restic_path_error() {
+            tracing::warn!(
+                "config: {}; falling back to the bundled restic or PATH",
+                err
+            );
+        }
         let path = Self::config_path()?;
         if let Some(dir) = path.parent() {
             fs::create_dir_all(dir).context("create config dir")?;
         }
         let content = serde_json::to_string_pretty(self).context("serialize config")?;
-        fs::write(&path, content).context("write config")?;
-        Ok(())
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, &content).context("write config")?;
+        fs::rename(&tmp, &path).context("replace config")?;
+        Ok((path, content))
+    }
+
+    /// Names of the available config backups, newest first.
+    pub fn list_backups() -> anyhow::Result<Vec<String>> {
+        let dir = config_backups::backup_dir(&Self::config_path()?);
+        Ok(config_backups::list_backups(&dir))
+    }
+
+    /// Load the named config backup. The caller saves it to make it the active config.
+    pub fn load_backup(name: &str) -> anyhow::Result<Self> {
+        let dir = config_backups::backup_dir(&Self::config_path()?);
+        Self::from_json(&config_backups::read_backup(&dir, name)?)
     }
 
     pub fn config_path() -> anyhow::Result<PathBuf> {
-        let proj = ProjectDirs::from("com", "aegis", "Aegis").context("resolve config dir")?;
-        Ok(proj.config_dir().join("config.json"))
+        let dir = config_dir(std::env::var_os(CONFIG_DIR_ENV))?;
+        Ok(profile::config_path(&dir, profile::active()))
     }
 
     pub fn update_last_seen(&mut self, drive_id: &str) {
@@ -156,6 +536,51 @@ impl AgentConfig {
         }
     }
 
+    pub fn set_populated_sources(&mut self, drive_id: &str, sources: Vec<String>) {
+        if let Some(drive) = self.trusted_drives.get_mut(drive_id) {
+            drive.populated_sources = sources;
+        }
+    }
+
+    /// Add a backup's `data_added` to the drive's lifetime total.
+    pub fn add_data_added(&mut self, drive_id: &str, bytes: u64) {
+        if let Some(drive) = self.trusted_drives.get_mut(drive_id) {
+            drive.total_data_added = drive.total_data_added.saturating_add(bytes);
+        }
+    }
+
+    /// Append a free-space sample for this drive, then trim the history to `history_max_entries`
+    /// and `history_max_age_days` (age measured from the new sample).
+    pub fn record_free_space(&mut self, drive_id: &str, sample: FreeSpaceSample) {
+        let max_entries = self.history_max_entries.max(1) as usize;
+        let max_age_secs = u64::from(self.history_max_age_days) * 86_400;
+        if let Some(drive) = self.trusted_drives.get_mut(drive_id) {
+            drive.free_space_history.push(sample);
+            if max_age_secs > 0 {
+                let cutoff = sample.epoch.saturating_sub(max_age_secs);
+                drive.free_space_history.retain(|s| s.epoch >= cutoff);
+            }
+            let excess = drive.free_space_history.len().saturating_sub(max_entries);
+            drive.free_space_history.drain(..excess);
+        }
+    }
+
+    /// Record how long a successful backup took, keeping only the most recent durations. True if
+    /// the run was anomalously slow compared to the earlier ones.
+    pub fn record_backup_duration(&mut self, drive_id: &str, secs: u64) -> bool {
+        let Some(drive) = self.trusted_drives.get_mut(drive_id) else {
+            return false;
+        };
+        let slow = durations::is_slow_run(&drive.backup_durations_secs, secs);
+        drive.backup_durations_secs.push(secs);
+        let excess = drive
+            .backup_durations_secs
+            .len()
+            .saturating_sub(DURATION_HISTORY_MAX);
+        drive.backup_durations_secs.drain(..excess);
+        slow
+    }
+
     pub fn enforce_security_invariants(&mut self) {
         if self.paranoid_mode {
             self.remember_passphrase = false;
@@ -179,6 +604,94 @@ impl AgentConfig {
             .unwrap_or_else(|| self.backup_sources.clone())
     }
 
+    /// Include patterns for a backup to this drive: the global ones followed by the drive's own.
+    /// Per-drive patterns extend the global lists rather than replacing them, so a pattern set
+    /// globally applies to every drive.
+    pub fn effective_include_patterns(&self, drive_id: &str) -> Vec<String> {
+        let mut includes = self.include_patterns.clone();
+        if let Some(drive) = self.trusted_drives.get(drive_id) {
+            extend_unique(&mut includes, &drive.include_patterns);
+        }
+        includes
+    }
+
+    /// Exclude patterns for a backup to this drive: global, then the drive's own, then the
+    /// removable-media junk folders unless that is turned off.
+    pub fn effective_exclude_patterns(&self, drive_id: &str) -> Vec<String> {
+        let mut excludes = self.exclude_patterns.clone();
+        if let Some(drive) = self.trusted_drives.get(drive_id) {
+            extend_unique(&mut excludes, &drive.exclude_patterns);
+        }
+        if self.exclude_removable_media_junk {
+            extend_unique(&mut excludes, REMOVABLE_MEDIA_JUNK_EXCLUDES);
+        }
+        excludes
+    }
+
+    /// Replace a drive's own include and/or exclude patterns (sanitized); None leaves that list
+    /// unchanged and an empty list clears it. False if the drive isn't trusted.
+    pub fn set_drive_patterns(
+        &mut self,
+        drive_id: &str,
+        includes: Option<&[String]>,
+        excludes: Option<&[String]>,
+    ) -> bool {
+        let Some(drive) = self.trusted_drives.get_mut(drive_id) else {
+            return false;
+        };
+        if let Some(includes) = includes {
+            drive.include_patterns = sanitize_patterns(includes);
+        }
+        if let Some(excludes) = excludes {
+            drive.exclude_patterns = sanitize_patterns(excludes);
+        }
+        true
+    }
+
+    /// Trust `drive` unless it already is. An existing entry for the same drive keeps its label,
+    /// sources and history, and takes the repository ID only if it had none. Ok(true) if anything
+    /// changed; an error if the entry is for a different repository (the drive has to be set up
+    /// again, see `set_repository_mismatch`).
+    pub fn ensure_trusted_drive(&mut self, drive: TrustedDrive) -> Result<bool, &'static str> {
+        match self.trusted_drives.get_mut(&drive.drive_id) {
+            Some(existing) if existing.repository_id == drive.repository_id => Ok(false),
+            Some(existing) if existing.repository_id.is_none() => {
+                existing.repository_id = drive.repository_id;
+                Ok(true)
+            }
+            Some(_) => Err("the repository on this drive has changed; set the drive up again"),
+            None => {
+                self.trusted_drives.insert(drive.drive_id.clone(), drive);
+                Ok(true)
+            }
+        }
+    }
+
+    /// True if this drive has no sources of its own and inherits the global backup_sources.
+    pub fn drive_uses_global_sources(&self, drive_id: &str) -> bool {
+        self.trusted_drives
+            .get(drive_id)
+            .map(|d| d.backup_sources.is_none())
+            .unwrap_or(true)
+    }
+
+    /// Another trusted drive (not `exclude_drive_id`) recorded with the same hardware serial.
+    pub fn drive_with_serial(&self, serial: &str, exclude_drive_id: &str) -> Option<&TrustedDrive> {
+        self.trusted_drives
+            .values()
+            .find(|d| d.drive_id != exclude_drive_id && d.serial.as_deref() == Some(serial))
+    }
+
+    /// True unless the device allowlist is set and names neither this drive nor its serial.
+    pub fn device_allowed(&self, drive_id: &str, serial: Option<&str>) -> bool {
+        self.allowed_devices.is_empty()
+            || self
+                .allowed_devices
+                .iter()
+                .map(|entry| entry.trim())
+                .any(|entry| entry == drive_id || Some(entry) == serial)
+    }
+
     /// True if another trusted drive already has this label (case-insensitive).
     pub fn label_exists(&self, label: &str, exclude_drive_id: Option<&str>) -> bool {
         let label_lower = label.trim().to_lowercase();
@@ -199,6 +712,15 @@ impl AgentConfig {
     }
 }
 
+fn extend_unique(patterns: &mut Vec<String>, extra: &[impl AsRef<str>]) {
+    for pattern in extra {
+        let pattern = pattern.as_ref();
+        if !patterns.iter().any(|p| p == pattern) {
+            patterns.push(pattern.to_string());
+        }
+    }
+}
+
 fn now_epoch() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -206,11 +728,389 @@ fn now_epoch() -> u64 {
         .as_secs()
 }
 
+pub fn ensure_marker_dir(root: &Path) -> anyhow::Result<PathBuf> {
+    let path = root.join(".aegis");
+    fs::create_dir_all(&path).context("create marker dir")?;
+    Ok(path)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::path::Path;
 
+    fn drive(drive_id: &str, label: Option<&str>) -> TrustedDrive {
+        TrustedDrive {
+            drive_id: drive_id.to_string(),
+            label: label.map(String::from),
+            repository_path: ".aegis/repo".to_string(),
+            repository_id: None,
+            last_seen_epoch: None,
+            last_backup_epoch: None,
+            last_backup_snapshot_id: None,
+            backup_sources: None,
+            free_space_history: Vec::new(),
+            total_data_added: 0,
+            serial: None,
+            include_patterns: Vec::new(),
+            exclude_patterns: Vec::new(),
+            populated_sources: Vec::new(),
+            luks_uuid: None,
+            extra: serde_json::Map::new(),
+            backup_durations_secs: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn junk_excludes_applied_unless_opted_out() {
+        let mut config = AgentConfig {
+            exclude_patterns: vec!["*.tmp".to_string(), ".Trashes".to_string()],
+            ..AgentConfig::default()
+        };
+        let effective = config.effective_exclude_patterns("none");
+        assert_eq!(effective[0], "*.tmp");
+        for pattern in REMOVABLE_MEDIA_JUNK_EXCLUDES {
+            assert_eq!(effective.iter().filter(|e| e == pattern).count(), 1);
+        }
+
+        config.exclude_removable_media_junk = false;
+        assert_eq!(
+            config.effective_exclude_patterns("none"),
+            config.exclude_patterns
+        );
+    }
+
+    #[test]
+    fn set_drive_patterns_updates_sanitizes_and_clears() {
+        let mut config = AgentConfig::default();
+        config
+            .trusted_drives
+            .insert("a".to_string(), drive("a", Some("A")));
+        let excludes = vec![
+            " *.mp4 ".to_string(),
+            "".to_string(),
+            "*.mp4".to_string(),
+            "bad\npattern".to_string(),
+            "*.mkv".to_string(),
+        ];
+        assert!(config.set_drive_patterns("a", None, Some(&excludes)));
+        assert_eq!(
+            config.trusted_drives["a"].exclude_patterns,
+            ["*.mp4", "*.mkv"]
+        );
+        assert!(config.trusted_drives["a"].include_patterns.is_empty());
+
+        // Absent list is left alone; an empty one clears.
+        let includes = vec!["*.odt".to_string()];
+        assert!(config.set_drive_patterns("a", Some(&includes), None));
+        assert_eq!(config.trusted_drives["a"].include_patterns, ["*.odt"]);
+        assert_eq!(config.trusted_drives["a"].exclude_patterns.len(), 2);
+        assert!(config.set_drive_patterns("a", Some(&[]), Some(&[])));
+        assert!(config.trusted_drives["a"].include_patterns.is_empty());
+        assert!(config.trusted_drives["a"].exclude_patterns.is_empty());
+
+        assert!(!config.set_drive_patterns("missing", Some(&includes), None));
+    }
+
+    #[test]
+    fn drive_patterns_extend_global_ones() {
+        let mut config = AgentConfig {
+            include_patterns: vec!["*.doc".to_string()],
+            exclude_patterns: vec!["*.tmp".to_string()],
+            exclude_removable_media_junk: false,
+            ..AgentConfig::default()
+        };
+        let mut videos = drive("a", Some("A"));
+        videos.exclude_patterns = vec!["*.mp4".to_string(), "*.tmp".to_string()];
+        videos.include_patterns = vec!["*.odt".to_string()];
+        config.trusted_drives.insert("a".to_string(), videos);
+        config
+            .trusted_drives
+            .insert("b".to_string(), drive("b", Some("B")));
+
+        assert_eq!(config.effective_exclude_patterns("a"), ["*.tmp", "*.mp4"]);
+        assert_eq!(config.effective_include_patterns("a"), ["*.doc", "*.odt"]);
+        // Drive B has none of its own and backs up the videos A skips.
+        assert_eq!(config.effective_exclude_patterns("b"), ["*.tmp"]);
+        assert_eq!(config.effective_include_patterns("b"), ["*.doc"]);
+    }
+
+    #[test]
+    fn recognizes_removable_media_junk() {
+        for name in [
+            ".Trash-1000",
+            "$RECYCLE.BIN",
+            "$Recycle.Bin",
+            "$recycle.bin",
+            "System Volume Information",
+        ] {
+            assert!(is_removable_media_junk(name), "{name}");
+        }
+        for name in [".Trash-", "RECYCLE.BIN", "Documents", ".trash"] {
+            assert!(!is_removable_media_junk(name), "{name}");
+        }
+    }
+
+    fn sample(epoch: u64) -> FreeSpaceSample {
+        FreeSpaceSample {
+            epoch,
+            free_bytes: 100,
+            total_bytes: 1000,
+        }
+    }
+
+    #[test]
+    fn free_space_history_trimmed_by_count() {
+        let mut config = AgentConfig::default();
+        config
+            .trusted_drives
+            .insert("abc".to_string(), drive("abc", Some("Backup")));
+        for epoch in 0..(FREE_SPACE_HISTORY_MAX as u64 + 5) {
+            config.record_free_space("abc", sample(epoch));
+        }
+        let history = &config.trusted_drives["abc"].free_space_history;
+        assert_eq!(history.len(), FREE_SPACE_HISTORY_MAX);
+        assert_eq!(history[0].epoch, 5);
+
+        config.history_max_entries = 3;
+        config.record_free_space("abc", sample(100));
+        let epochs: Vec<u64> = config.trusted_drives["abc"]
+            .free_space_history
+            .iter()
+            .map(|s| s.epoch)
+            .collect();
+        assert_eq!(epochs, vec![33, 34, 100]);
+    }
+
+    #[test]
+    fn free_space_history_trimmed_by_age() {
+        let day = 86_400;
+        let mut config = AgentConfig::default();
+        config
+            .trusted_drives
+            .insert("abc".to_string(), drive("abc", Some("Backup")));
+        for epoch in [0, 10 * day, 20 * day, 25 * day] {
+            config.record_free_space("abc", sample(epoch));
+        }
+        assert_eq!(config.trusted_drives["abc"].free_space_history.len(), 4);
+
+        config.history_max_age_days = 7;
+        config.record_free_space("abc", sample(30 * day));
+        let epochs: Vec<u64> = config.trusted_drives["abc"]
+            .free_space_history
+            .iter()
+            .map(|s| s.epoch)
+            .collect();
+        assert_eq!(epochs, vec![25 * day, 30 * day]);
+    }
+
+    #[test]
+    fn record_backup_duration_flags_slow_run_and_caps_history() {
+        let mut config = AgentConfig::default();
+        config
+            .trusted_drives
+            .insert("abc".to_string(), drive("abc", Some("Backup")));
+        for secs in [300, 320, 280] {
+            assert!(!config.record_backup_duration("abc", secs));
+        }
+        assert!(config.record_backup_duration("abc", 900));
+        assert!(!config.record_backup_duration("missing", 900));
+        for _ in 0..DURATION_HISTORY_MAX {
+            config.record_backup_duration("abc", 300);
+        }
+        let durations = &config.trusted_drives["abc"].backup_durations_secs;
+        assert_eq!(durations.len(), DURATION_HISTORY_MAX);
+        assert!(durations.iter().all(|&d| d == 300));
+    }
+
+    #[test]
+    fn dir_override_used_when_set() {
+        assert_eq!(
+            dir_override(Some("/tmp/aegis-test-config".into())),
+            Some(PathBuf::from("/tmp/aegis-test-config"))
+        );
+        assert_eq!(dir_override(Some("".into())), None);
+        assert_eq!(dir_override(None), None);
+        assert_eq!(
+            profile::config_path(&dir_override(Some("/tmp/c".into())).unwrap(), Some("work")),
+            PathBuf::from("/tmp/c/profiles/work/config.json")
+        );
+    }
+
+    #[test]
+    fn config_dir_honors_the_override() {
+        assert_eq!(
+            config_dir(Some("/tmp/aegis-config-env".into())).unwrap(),
+            PathBuf::from("/tmp/aegis-config-env")
+        );
+        // Empty is the same as unset: the platform directory.
+        if let Ok(platform) = config_dir(None) {
+            assert_eq!(config_dir(Some("".into())).unwrap(), platform);
+        }
+    }
+
+    #[test]
+    fn ensure_trusted_drive_is_idempotent() {
+        let mut config = AgentConfig::default();
+        let mut new = drive("abc", Some("Backup"));
+        new.repository_id = Some("repo-1".to_string());
+        assert_eq!(config.ensure_trusted_drive(new.clone()), Ok(true));
+        config
+            .trusted_drives
+            .get_mut("abc")
+            .unwrap()
+            .last_backup_epoch = Some(1_000);
+
+        assert_eq!(config.ensure_trusted_drive(new.clone()), Ok(false));
+        let mut renamed = new.clone();
+        renamed.label = Some("Other".to_string());
+        assert_eq!(config.ensure_trusted_drive(renamed), Ok(false));
+        let kept = &config.trusted_drives["abc"];
+        assert_eq!(config.trusted_drives.len(), 1);
+        assert_eq!(kept.last_backup_epoch, Some(1_000));
+        assert_eq!(kept.label.as_deref(), Some("Backup"));
+
+        // A different repository on the drive is not silently adopted.
+        new.repository_id = Some("repo-2".to_string());
+        assert!(config.ensure_trusted_drive(new.clone()).is_err());
+        assert_eq!(
+            config.trusted_drives["abc"].repository_id.as_deref(),
+            Some("repo-1")
+        );
+
+        // An entry that never learned its repository takes it over.
+        config.trusted_drives.get_mut("abc").unwrap().repository_id = None;
+        assert_eq!(config.ensure_trusted_drive(new), Ok(true));
+        assert_eq!(
+            config.trusted_drives["abc"].repository_id.as_deref(),
+            Some("repo-2")
+        );
+        assert_eq!(config.trusted_drives["abc"].last_backup_epoch, Some(1_000));
+    }
+
+    #[test]
+    fn device_allowlist_matches_serial_or_drive_id() {
+        let mut config = AgentConfig::default();
+        assert!(config.device_allowed("any", None));
+        config.allowed_devices = vec!["SanDisk_Ultra_4C53".to_string(), " drive-2 ".to_string()];
+        assert!(config.device_allowed("drive-1", Some("SanDisk_Ultra_4C53")));
+        assert!(config.device_allowed("drive-2", None));
+        assert!(!config.device_allowed("drive-1", Some("Kingston_1234")));
+        assert!(!config.device_allowed("drive-1", None));
+    }
+
+    #[test]
+    fn drive_with_serial_finds_other_drive_on_same_media() {
+        let mut config = AgentConfig::default();
+        let mut old = drive("old", Some("Old"));
+        old.serial = Some("SanDisk_Ultra_4C53".to_string());
+        config.trusted_drives.insert("old".to_string(), old);
+        config
+            .trusted_drives
+            .insert("other".to_string(), drive("other", Some("Other")));
+
+        let found = config
+            .drive_with_serial("SanDisk_Ultra_4C53", "new")
+            .unwrap();
+        assert_eq!(found.drive_id, "old");
+        // Re-running setup for the same marker is not a duplicate.
+        assert!(config
+            .drive_with_serial("SanDisk_Ultra_4C53", "old")
+            .is_none());
+        assert!(config.drive_with_serial("Kingston_1234", "new").is_none());
+    }
+
+    #[test]
+    fn total_data_added_accumulates_across_runs() {
+        let mut config = AgentConfig::default();
+        config
+            .trusted_drives
+            .insert("d1".to_string(), drive("d1", None));
+        config.add_data_added("d1", 1_000);
+        config.add_data_added("d1", 250);
+        config.add_data_added("unknown", 5);
+        assert_eq!(config.trusted_drives["d1"].total_data_added, 1_250);
+    }
+
+    #[test]
+    fn confirms_removal_labelled_drive_needs_exact_label() {
+        let d = drive("0123456789abcdef", Some("Backup"));
+        assert!(d.confirms_removal(" Backup "));
+        assert!(!d.confirms_removal("backup"));
+        // The ID prefix is only an alternative for unnamed drives.
+        assert!(!d.confirms_removal("01234567"));
+    }
+
+    #[test]
+    fn confirms_removal_labelless_drive_by_display_name_or_id_prefix() {
+        let d = drive("0123456789abcdef", None);
+        assert!(d.confirms_removal("drive-01234567"));
+        assert!(d.confirms_removal("01234567"));
+        assert!(d.confirms_removal("0123456789abcdef"));
+        assert!(!d.confirms_removal("0123456"));
+        assert!(!d.confirms_removal("11234567"));
+        assert!(!d.confirms_removal(""));
+    }
+
+    #[test]
+    fn config_backup_restores_deleted_drive() {
+        let dir = std::env::temp_dir().join(format!("aegis-config-restore-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut config = AgentConfig::default();
+        config
+            .trusted_drives
+            .insert("d1".to_string(), drive("d1", Some("Backup\u{0}Drive")));
+        let content = serde_json::to_string_pretty(&config).unwrap();
+        config_backups::write_backup(&dir, &content, 1_700_000_000, 3).unwrap();
+        let name = config_backups::list_backups(&dir).remove(0);
+        let restored =
+            AgentConfig::from_json(&config_backups::read_backup(&dir, &name).unwrap()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let drive = restored.trusted_drives.get("d1").unwrap();
+        // Restored configs go through the same sanitizing as a normal load.
+        assert_eq!(drive.label.as_deref(), Some("BackupDrive"));
+    }
+
+    #[test]
+    fn unknown_fields_survive_load_and_save() {
+        let mut config = AgentConfig::default();
+        config
+            .trusted_drives
+            .insert("d1".to_string(), drive("d1", Some("Backup")));
+        let mut value = serde_json::to_value(&config).unwrap();
+        value["future_setting"] = serde_json::json!({"mode": "turbo", "level": 3});
+        value["trusted_drives"]["d1"]["future_drive_flag"] = serde_json::json!(true);
+
+        let mut loaded = AgentConfig::from_json(&value.to_string()).unwrap();
+        assert!(loaded.extra.contains_key("future_setting"));
+        loaded.quick_verify = false;
+        let saved: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string_pretty(&loaded).unwrap()).unwrap();
+        assert_eq!(saved["future_setting"]["mode"], "turbo");
+        assert_eq!(saved["future_setting"]["level"], 3);
+        assert_eq!(saved["trusted_drives"]["d1"]["future_drive_flag"], true);
+        assert_eq!(saved["quick_verify"], false);
+        // Known fields aren't duplicated into the catch-all.
+        assert!(!loaded.extra.contains_key("quick_verify"));
+    }
+
+    #[test]
+    fn sources_without_their_own_excludes_still_load() {
+        let mut value = serde_json::to_value(AgentConfig::default()).unwrap();
+        value["backup_sources"] = serde_json::json!([
+            {"label": "Docs", "path": "~/Documents"},
+            {"label": "Projects", "path": "~/Projects", "exclude_patterns": ["node_modules"]}
+        ]);
+        let config = AgentConfig::from_json(&value.to_string()).unwrap();
+        assert!(config.backup_sources[0].exclude_patterns.is_empty());
+        assert_eq!(config.backup_sources[1].exclude_patterns, ["node_modules"]);
+        let saved = serde_json::to_value(&config).unwrap();
+        assert_eq!(
+            saved["backup_sources"][1]["exclude_patterns"][0],
+            "node_modules"
+        );
+    }
+
     #[test]
     fn sanitize_label_empty_and_whitespace() {
         assert_eq!(sanitize_label(""), None);
@@ -237,6 +1137,22 @@ mod tests {
         assert!(out.chars().all(|c| c == 'a'));
     }
 
+    #[test]
+    fn validate_notification_icon_accepts_names_and_absolute_paths() {
+        assert_eq!(
+            validate_notification_icon(" drive-harddisk "),
+            Ok(Some("drive-harddisk".to_string()))
+        );
+        assert_eq!(
+            validate_notification_icon("/usr/share/icons/aegis icon.png"),
+            Ok(Some("/usr/share/icons/aegis icon.png".to_string()))
+        );
+        assert_eq!(validate_notification_icon("  "), Ok(None));
+        assert!(validate_notification_icon("icons/aegis.png").is_err());
+        assert!(validate_notification_icon("my icon").is_err());
+        assert!(validate_notification_icon("/tmp/a\nb.png").is_err());
+    }
+
     #[test]
     fn sanitize_label_unicode_ok() {
         assert_eq!(sanitize_label("ドライブ"), Some("ドライブ".to_string()));
@@ -251,6 +1167,82 @@ mod tests {
         );
     }
 
+    #[test]
+    fn drive_uses_global_sources_none_vs_some() {
+        let mut config = AgentConfig::default();
+        let drive = TrustedDrive {
+            drive_id: "drive-1".to_string(),
+            label: None,
+            repository_path: ".aegis/repo".to_string(),
+            repository_id: None,
+            last_seen_epoch: None,
+            last_backup_epoch: None,
+            last_backup_snapshot_id: None,
+            backup_sources: None,
+            free_space_history: Vec::new(),
+            total_data_added: 0,
+            serial: None,
+            include_patterns: Vec::new(),
+            exclude_patterns: Vec::new(),
+            populated_sources: Vec::new(),
+            luks_uuid: None,
+            extra: serde_json::Map::new(),
+            backup_durations_secs: Vec::new(),
+        };
+        config
+            .trusted_drives
+            .insert("drive-1".to_string(), drive.clone());
+        assert!(config.drive_uses_global_sources("drive-1"));
+
+        let mut own = drive;
+        own.backup_sources = Some(vec![BackupSource {
+            label: "Music".to_string(),
+            path: "~/Music".to_string(),
+            follow_symlink: false,
+            exclude_patterns: Vec::new(),
+        }]);
+        config.trusted_drives.insert("drive-1".to_string(), own);
+        assert!(!config.drive_uses_global_sources("drive-1"));
+        // An explicit empty list is still drive-specific.
+        config
+            .trusted_drives
+            .get_mut("drive-1")
+            .unwrap()
+            .backup_sources = Some(Vec::new());
+        assert!(!config.drive_uses_global_sources("drive-1"));
+    }
+
+    #[test]
+    fn repository_changed_detection() {
+        let mut drive = TrustedDrive {
+            drive_id: "drive-1".to_string(),
+            label: None,
+            repository_path: ".aegis/repo".to_string(),
+            repository_id: Some("abcdef0123456789".to_string()),
+            last_seen_epoch: None,
+            last_backup_epoch: None,
+            last_backup_snapshot_id: None,
+            backup_sources: None,
+            free_space_history: Vec::new(),
+            total_data_added: 0,
+            serial: None,
+            include_patterns: Vec::new(),
+            exclude_patterns: Vec::new(),
+            populated_sources: Vec::new(),
+            luks_uuid: None,
+            extra: serde_json::Map::new(),
+            backup_durations_secs: Vec::new(),
+        };
+        assert!(!drive.repository_changed(Some("abcdef0123456789")));
+        assert!(drive.repository_changed(Some("0000000000000000")));
+        // Repository gone from the drive.
+        assert!(drive.repository_changed(None));
+        // Legacy drive without a recorded ID: nothing to compare.
+        drive.repository_id = None;
+        assert!(!drive.repository_changed(Some("0000000000000000")));
+        assert!(!drive.repository_changed(None));
+    }
+
     #[test]
     fn repository_path_for_known_drive() {
         let mut config = AgentConfig::default();
@@ -265,6 +1257,15 @@ mod tests {
                 last_backup_epoch: None,
                 last_backup_snapshot_id: None,
                 backup_sources: None,
+                free_space_history: Vec::new(),
+                total_data_added: 0,
+                serial: None,
+                include_patterns: Vec::new(),
+                exclude_patterns: Vec::new(),
+                populated_sources: Vec::new(),
+                luks_uuid: None,
+                extra: serde_json::Map::new(),
+                backup_durations_secs: Vec::new(),
             },
         );
         let path = config
@@ -273,9 +1274,3 @@ mod tests {
         assert_eq!(path, Path::new("/media/usb/backup"));
     }
 }
-
-pub fn ensure_marker_dir(root: &Path) -> anyhow::Result<PathBuf> {
-    let path = root.join(".aegis");
-    fs::create_dir_all(&path).context("create marker dir")?;
-    Ok(path)
-}
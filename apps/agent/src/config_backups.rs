@@ -0,0 +1,154 @@
+//! Rolling timestamped copies of the config (`backups/config-YYYYMMDD-HHMMSS.json` next to
+//! `config.json`), written when the user changes the config so an accidental change can be
+//! undone. What the agent records on its own (last seen, free space) doesn't write one.
+
+use anyhow::Context;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const PREFIX: &str = "config-";
+const SUFFIX: &str = ".json";
+
+/// Directory holding the backups for the config file at `config_path`.
+pub fn backup_dir(config_path: &Path) -> PathBuf {
+    config_path
+        .parent()
+        .unwrap_or_else(|| Path::new("."))
+        .join("backups")
+}
+
+/// `config-YYYYMMDD-HHMMSS.json` for a UNIX timestamp (UTC). Names sort chronologically.
+pub fn backup_file_name(epoch: u64) -> String {
+    let days = (epoch / 86_400) as i64;
+    let secs = epoch % 86_400;
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{}{:04}{:02}{:02}-{:02}{:02}{:02}{}",
+        PREFIX,
+        year,
+        month,
+        day,
+        secs / 3600,
+        (secs / 60) % 60,
+        secs % 60,
+        SUFFIX
+    )
+}
+
+/// True for names produced by `backup_file_name`; anything else (including paths) is rejected.
+pub fn is_backup_name(name: &str) -> bool {
+    let Some(stamp) = name
+        .strip_prefix(PREFIX)
+        .and_then(|rest| rest.strip_suffix(SUFFIX))
+    else {
+        return false;
+    };
+    let bytes = stamp.as_bytes();
+    bytes.len() == 15
+        && bytes[8] == b'-'
+        && bytes
+            .iter()
+            .enumerate()
+            .all(|(i, b)| i == 8 || b.is_ascii_digit())
+}
+
+/// Write `content` as the backup for `epoch`, then delete the oldest so at most `keep` remain.
+pub fn write_backup(dir: &Path, content: &str, epoch: u64, keep: usize) -> anyhow::Result<()> {
+    fs::create_dir_all(dir).context("create config backup dir")?;
+    let path = dir.join(backup_file_name(epoch));
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, content).context("write config backup")?;
+    fs::rename(&tmp, &path).context("finalize config backup")?;
+    rotate(dir, keep)
+}
+
+/// Backup file names, newest first.
+pub fn list_backups(dir: &Path) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().into_string().ok())
+        .filter(|n| is_backup_name(n))
+        .collect();
+    names.sort_unstable_by(|a, b| b.cmp(a));
+    names
+}
+
+/// Contents of the named backup. The name must be one listed by `list_backups`.
+pub fn read_backup(dir: &Path, name: &str) -> anyhow::Result<String> {
+    if !is_backup_name(name) {
+        return Err(anyhow::anyhow!("invalid config backup name"));
+    }
+    fs::read_to_string(dir.join(name)).context("read config backup")
+}
+
+fn rotate(dir: &Path, keep: usize) -> anyhow::Result<()> {
+    for name in list_backups(dir).into_iter().skip(keep) {
+        fs::remove_file(dir.join(&name)).context("remove old config backup")?;
+    }
+    Ok(())
+}
+
+/// Days since 1970-01-01 to (year, month, day) in the proleptic Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn backup_file_name_formats_utc() {
+        assert_eq!(backup_file_name(0), "config-19700101-000000.json");
+        // 2024-02-29 13:45:07 UTC
+        assert_eq!(
+            backup_file_name(1_709_214_307),
+            "config-20240229-134507.json"
+        );
+    }
+
+    #[test]
+    fn is_backup_name_rejects_other_files() {
+        assert!(is_backup_name("config-20240229-134507.json"));
+        assert!(!is_backup_name("config.json"));
+        assert!(!is_backup_name("config-20240229-134507.json.tmp"));
+        assert!(!is_backup_name("config-2024022x-134507.json"));
+        assert!(!is_backup_name("../config-20240229-134507.json"));
+    }
+
+    #[test]
+    fn write_backup_keeps_newest_n() {
+        let dir = temp_dir("config-backups-rotate");
+        for i in 0..5u64 {
+            write_backup(&dir, &format!("{{\"n\":{}}}", i), 1_700_000_000 + i, 3).unwrap();
+        }
+        let names = list_backups(&dir);
+        assert_eq!(names.len(), 3);
+        assert_eq!(names[0], backup_file_name(1_700_000_004));
+        assert_eq!(names[2], backup_file_name(1_700_000_002));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_backup_returns_contents_and_rejects_bad_names() {
+        let dir = temp_dir("config-backups-read");
+        write_backup(&dir, "{\"n\":1}", 1_700_000_000, 5).unwrap();
+        let name = list_backups(&dir).remove(0);
+        assert_eq!(read_backup(&dir, &name).unwrap(), "{\"n\":1}");
+        assert!(read_backup(&dir, "../config.json").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,11 +1,15 @@
 use anyhow::Context;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
-use std::process::Command;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
 use std::sync::{Mutex, OnceLock};
 use tracing::{debug, error, info, warn};
 
 /// Volume label used when Aegis formats a drive: "aegis" + 6 hex chars = 11 chars (exFAT max).
+/// The label is cosmetic: drives are found by devnode (lsblk/udev) and recognised by the drive ID
+/// in their marker file, never by volume label, so two drives with the same label can't be mixed up.
 fn generate_aegis_disk_name() -> String {
     let n = rand::thread_rng().gen::<u32>() & 0xFF_FFFF; // 24 bits = exactly 6 hex digits
     format!("aegis{:06x}", n)
@@ -79,19 +83,30 @@ pub fn list_removable_devices() -> anyhow::Result<Vec<DeviceInfo>> {
         "device scan: parsed {} block device(s)",
         parsed.blockdevices.len()
     );
+    let devices = removable_devices(parsed, |devnode| {
+        udev_properties(devnode).and_then(|props| parse_udev_property(&props, "ID_BUS"))
+    });
+    log_devices_if_changed(&devices);
+    debug!("device scan: returning {} removable disk(s)", devices.len());
+    Ok(devices)
+}
+
+/// Removable disks from lsblk output. `id_bus` looks up udev's ID_BUS for a devnode; it is only
+/// asked about disks lsblk doesn't already flag.
+fn removable_devices(
+    parsed: LsblkOutput,
+    id_bus: impl Fn(&str) -> Option<String>,
+) -> Vec<DeviceInfo> {
     let mut devices = Vec::new();
     for dev in parsed.blockdevices {
         if dev.devtype.as_deref() != Some("disk") {
             continue;
         }
-        let removable = dev.rm.unwrap_or(false)
-            || dev.hotplug.unwrap_or(false)
-            || dev.tran.as_deref() == Some("usb");
-        if !removable {
+        let name = dev.name.clone().unwrap_or_else(|| "unknown".to_string());
+        let path = dev.path.clone().unwrap_or_else(|| format!("/dev/{}", name));
+        if !is_removable_disk(&dev, || id_bus(&path)) {
             continue;
         }
-        let name = dev.name.unwrap_or_else(|| "unknown".to_string());
-        let path = dev.path.unwrap_or_else(|| format!("/dev/{}", name));
         let size = dev.size.unwrap_or_else(|| "unknown".to_string());
         let partitions = dev
             .children
@@ -117,13 +132,23 @@ pub fn list_removable_devices() -> anyhow::Result<Vec<DeviceInfo>> {
             name,
             size,
             model: dev.model,
-            removable,
+            removable: true,
             partitions,
         });
     }
-    log_devices_if_changed(&devices);
-    debug!("device scan: returning {} removable disk(s)", devices.len());
-    Ok(devices)
+    devices
+}
+
+/// True if the disk looks removable. Some USB enclosures and card readers report rm=0 and
+/// hotplug=0 and no transport on the disk itself; then the USB transport or removable flag may
+/// only show on a partition, or only udev's ID_BUS knows the disk is on USB.
+fn is_removable_disk(dev: &LsblkDevice, id_bus: impl FnOnce() -> Option<String>) -> bool {
+    fn flagged(dev: &LsblkDevice) -> bool {
+        dev.rm.unwrap_or(false)
+            || dev.hotplug.unwrap_or(false)
+            || dev.tran.as_deref() == Some("usb")
+    }
+    flagged(dev) || dev.children.iter().flatten().any(flagged) || id_bus().as_deref() == Some("usb")
 }
 
 fn log_devices_if_changed(devices: &[DeviceInfo]) {
@@ -279,13 +304,215 @@ pub fn mount_partition(devnode: &str) -> anyhow::Result<String> {
     Err(anyhow::anyhow!("mount failed"))
 }
 
-/// Format partition as exFAT with a fixed Aegis volume label (aegis-xxxxxxxx). In-app name is stored only in the marker file on the drive.
-pub fn format_partition_exfat(devnode: &str) -> anyhow::Result<()> {
-    let disk_label = generate_aegis_disk_name();
-    debug!(
-        "format: request devnode={} disk_label={}",
-        devnode, disk_label
-    );
+/// What a format request operates on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FormatTarget {
+    /// Format the given partition in place; the partition table is left alone.
+    #[default]
+    Partition,
+    /// Wipe the given disk, create a GPT with one partition spanning it, then format that partition.
+    WholeDisk,
+}
+
+/// Tool used to write the partition table for `FormatTarget::WholeDisk`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionTool {
+    Sgdisk,
+    Parted,
+}
+
+/// One step of a format operation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FormatStep {
+    /// Run a command (program + args).
+    Run(Vec<String>),
+    /// Format this partition as exFAT.
+    FormatPartition(String),
+}
+
+/// Commands needed to format `devnode` for the given target. `tool` is only needed for WholeDisk.
+pub fn format_plan(
+    target: FormatTarget,
+    devnode: &str,
+    tool: Option<PartitionTool>,
+) -> anyhow::Result<Vec<FormatStep>> {
+    let to_strings = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+    match target {
+        FormatTarget::Partition => Ok(vec![FormatStep::FormatPartition(devnode.to_string())]),
+        FormatTarget::WholeDisk => {
+            let tool = tool.ok_or_else(|| {
+                anyhow::anyhow!("sgdisk or parted is required to partition a whole disk")
+            })?;
+            let mut steps = match tool {
+                PartitionTool::Sgdisk => vec![
+                    FormatStep::Run(to_strings(&["sgdisk", "--zap-all", devnode])),
+                    // 0700 = Microsoft basic data, the type exFAT volumes use.
+                    FormatStep::Run(to_strings(&[
+                        "sgdisk", "-n", "1:0:0", "-t", "1:0700", "-c", "1:aegis", devnode,
+                    ])),
+                ],
+                PartitionTool::Parted => vec![FormatStep::Run(to_strings(&[
+                    "parted", "-s", devnode, "mklabel", "gpt", "mkpart", "aegis", "1MiB", "100%",
+                    "set", "1", "msftdata", "on",
+                ]))],
+            };
+            steps.push(FormatStep::FormatPartition(first_partition_devnode(
+                devnode,
+            )));
+            Ok(steps)
+        }
+    }
+}
+
+/// Refuse to format anything but a removable disk (WholeDisk) or one of its partitions
+/// (Partition), so a request can't name an internal disk.
+pub fn check_format_target(
+    devices: &[DeviceInfo],
+    devnode: &str,
+    target: FormatTarget,
+) -> Result<(), &'static str> {
+    let is_disk = devices.iter().any(|d| d.path == devnode);
+    let is_partition = devices
+        .iter()
+        .flat_map(|d| &d.partitions)
+        .any(|p| p.path == devnode);
+    match target {
+        FormatTarget::WholeDisk if is_disk => Ok(()),
+        FormatTarget::Partition if is_partition => Ok(()),
+        FormatTarget::WholeDisk if is_partition => Err("whole_disk needs a disk, not a partition"),
+        FormatTarget::Partition if is_disk => Err("partition needs a partition, not a disk"),
+        _ => Err("not a removable drive"),
+    }
+}
+
+/// Device node of partition 1 on `disk`: /dev/sdb -> /dev/sdb1, /dev/nvme0n1 -> /dev/nvme0n1p1.
+pub fn first_partition_devnode(disk: &str) -> String {
+    if disk.ends_with(|c: char| c.is_ascii_digit()) {
+        format!("{}p1", disk)
+    } else {
+        format!("{}1", disk)
+    }
+}
+
+fn find_partition_tool() -> Option<PartitionTool> {
+    if which::which("sgdisk").is_ok() {
+        Some(PartitionTool::Sgdisk)
+    } else if which::which("parted").is_ok() {
+        Some(PartitionTool::Parted)
+    } else {
+        None
+    }
+}
+
+/// Format `devnode` as exFAT according to `target` (see `FormatTarget`); with `encryption`, the
+/// exFAT file system goes inside a LUKS container.
+pub fn format_drive(
+    devnode: &str,
+    target: FormatTarget,
+    encryption: Option<&LuksParams>,
+) -> anyhow::Result<()> {
+    let tool = match target {
+        FormatTarget::Partition => None,
+        FormatTarget::WholeDisk => find_partition_tool(),
+    };
+    let steps = format_plan(target, devnode, tool)?;
+    if target == FormatTarget::WholeDisk {
+        for partition in mounted_partitions(devnode)? {
+            unmount_with_retries(&partition)?;
+        }
+    }
+    for step in steps {
+        match step {
+            FormatStep::Run(argv) => run_privileged(&argv)?,
+            FormatStep::FormatPartition(partition) => match encryption {
+                Some(params) => format_partition_luks_exfat(&partition, params)?,
+                None => format_partition_exfat(&partition)?,
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Run a partitioning command, through pkexec when available.
+fn run_privileged(argv: &[String]) -> anyhow::Result<()> {
+    run_privileged_with_input(argv, None)?;
+    wait_for_udev_after_format();
+    Ok(())
+}
+
+/// Run a command through pkexec when available, writing `input` (e.g. a passphrase) to its stdin.
+fn run_privileged_with_input(argv: &[String], input: Option<&str>) -> anyhow::Result<()> {
+    let (program, args) = argv
+        .split_first()
+        .ok_or_else(|| anyhow::anyhow!("empty command"))?;
+    debug!("format: running {} {:?}", program, args);
+    let mut command = if which::which("pkexec").is_ok() {
+        let mut command = Command::new("pkexec");
+        command.arg(program);
+        command
+    } else {
+        Command::new(program)
+    };
+    command
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let mut child = command
+        .spawn()
+        .with_context(|| format!("run {}", program))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        if let Some(input) = input {
+            stdin
+                .write_all(input.as_bytes())
+                .with_context(|| format!("write to {}", program))?;
+        }
+    }
+    let output = child
+        .wait_with_output()
+        .with_context(|| format!("run {}", program))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        error!(
+            "format: {} failed status={:?} stderr={}",
+            program,
+            output.status.code(),
+            stderr.trim()
+        );
+        return Err(anyhow::anyhow!("{} failed: {}", program, stderr.trim()));
+    }
+    Ok(())
+}
+
+/// Mounted partitions of `disk` (they must be unmounted before repartitioning).
+fn mounted_partitions(disk: &str) -> anyhow::Result<Vec<String>> {
+    let output = Command::new("lsblk")
+        .args(["-J", "-o", "NAME,PATH,TYPE,FSTYPE,MOUNTPOINTS"])
+        .output()
+        .context("run lsblk")?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!("lsblk failed"));
+    }
+    let parsed: LsblkOutput =
+        serde_json::from_slice(&output.stdout).context("parse lsblk output")?;
+    Ok(mounted_children(&parsed.blockdevices, disk))
+}
+
+fn mounted_children(devices: &[LsblkDevice], disk: &str) -> Vec<String> {
+    let Some(device) = devices.iter().find(|d| d.path.as_deref() == Some(disk)) else {
+        return Vec::new();
+    };
+    device
+        .children
+        .iter()
+        .flatten()
+        .filter(|c| !normalize_mountpoints(c.mountpoints.clone()).is_empty())
+        .filter_map(|c| c.path.clone())
+        .collect()
+}
+
+fn unmount_with_retries(devnode: &str) -> anyhow::Result<()> {
     for attempt in 1..=3 {
         let mountpoint = match find_mountpoint(devnode)? {
             None => break,
@@ -312,6 +539,17 @@ pub fn format_partition_exfat(devnode: &str) -> anyhow::Result<()> {
         "format: devnode={} is unmounted (or was not mounted), proceeding",
         devnode
     );
+    Ok(())
+}
+
+/// Format partition as exFAT with a fixed Aegis volume label (aegis-xxxxxxxx). In-app name is stored only in the marker file on the drive.
+fn format_partition_exfat(devnode: &str) -> anyhow::Result<()> {
+    let disk_label = generate_aegis_disk_name();
+    debug!(
+        "format: request devnode={} disk_label={}",
+        devnode, disk_label
+    );
+    unmount_with_retries(devnode)?;
     if udisksctl_supports_format() {
         ensure_udisksctl()?;
         debug!("format: using udisksctl format (devnode={})", devnode);
@@ -353,6 +591,185 @@ pub fn format_partition_exfat(devnode: &str) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Passphrase for an encrypted (LUKS) format.
+#[derive(Clone, Deserialize)]
+pub struct LuksParams {
+    pub passphrase: String,
+}
+
+impl std::fmt::Debug for LuksParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LuksParams")
+            .field("passphrase", &"<redacted>")
+            .finish()
+    }
+}
+
+/// cryptsetup commands of an encrypted format, each reading the passphrase from stdin.
+#[derive(Debug, PartialEq, Eq)]
+struct LuksCommands {
+    /// Write a LUKS2 header, destroying what was on the partition.
+    format: Vec<String>,
+    /// Open the container as `/dev/mapper/<name>` so the file system can be created inside.
+    open: Vec<String>,
+    close: Vec<String>,
+}
+
+fn luks_commands(devnode: &str, mapper_name: &str) -> LuksCommands {
+    let to_strings = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+    LuksCommands {
+        format: to_strings(&[
+            "cryptsetup",
+            "luksFormat",
+            "--batch-mode",
+            "--type",
+            "luks2",
+            "--key-file=-",
+            devnode,
+        ]),
+        open: to_strings(&["cryptsetup", "open", "--key-file=-", devnode, mapper_name]),
+        close: to_strings(&["cryptsetup", "close", mapper_name]),
+    }
+}
+
+/// Device-mapper name used while formatting: /dev/sdb1 -> aegis-format-sdb1.
+fn luks_mapper_name(devnode: &str) -> String {
+    let name = Path::new(devnode)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    format!("aegis-format-{}", name)
+}
+
+/// Whether `cryptsetup` is installed (needed for encrypted formats).
+pub fn cryptsetup_available() -> bool {
+    which::which("cryptsetup").is_ok()
+}
+
+/// Encrypt `devnode` with LUKS2 and create an exFAT file system (with the usual Aegis volume label)
+/// inside it. The container is left closed; see `unlock_luks`.
+pub fn format_partition_luks_exfat(devnode: &str, params: &LuksParams) -> anyhow::Result<()> {
+    if params.passphrase.is_empty() {
+        return Err(anyhow::anyhow!("an encryption passphrase is required"));
+    }
+    which::which("cryptsetup").context("cryptsetup not found")?;
+    let formatter = find_exfat_formatter().context("mkfs.exfat not found")?;
+    let disk_label = generate_aegis_disk_name();
+    debug!(
+        "format: encrypted request devnode={} disk_label={}",
+        devnode, disk_label
+    );
+    unmount_with_retries(devnode)?;
+    let mapper_name = luks_mapper_name(devnode);
+    let commands = luks_commands(devnode, &mapper_name);
+    run_privileged_with_input(&commands.format, Some(&params.passphrase))?;
+    run_privileged_with_input(&commands.open, Some(&params.passphrase))?;
+    let created = run_mkfs_exfat(
+        &formatter,
+        &format!("/dev/mapper/{}", mapper_name),
+        &disk_label,
+    );
+    // Close the container even if mkfs failed, so the mapping doesn't linger.
+    let closed = run_privileged_with_input(&commands.close, None);
+    created?;
+    closed?;
+    wait_for_udev_after_format();
+    info!("format: success for devnode={} (luks)", devnode);
+    Ok(())
+}
+
+/// True if `devnode` holds a LUKS container (from udev, so no root is needed).
+pub fn is_luks(devnode: &str) -> bool {
+    udev_properties(devnode)
+        .and_then(|props| parse_udev_property(&props, "ID_FS_TYPE"))
+        .is_some_and(|fs_type| fs_type == "crypto_LUKS")
+}
+
+/// UUID of the LUKS container on `devnode`; its unlock passphrase is saved under it.
+pub fn luks_uuid(devnode: &str) -> Option<String> {
+    udev_properties(devnode).and_then(|props| parse_udev_property(&props, "ID_FS_UUID"))
+}
+
+/// The LUKS partition `device` is the unlocked view of; None for anything but an unlocked
+/// LUKS container.
+pub fn luks_backing_device(device: &str) -> Option<String> {
+    let output = Command::new("lsblk")
+        .args(["-ndo", "PKNAME", device])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let parent = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .map(|name| format!("/dev/{}", name))?;
+    is_luks(&parent).then_some(parent)
+}
+
+/// The unlocked device of the LUKS container on `devnode`, if something already opened it.
+pub fn cleartext_device(devnode: &str) -> Option<String> {
+    let output = Command::new("lsblk")
+        .args(["-ln", "-o", "PATH,TYPE", devnode])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    parse_crypt_child(&String::from_utf8_lossy(&output.stdout))
+}
+
+fn parse_crypt_child(lsblk: &str) -> Option<String> {
+    lsblk.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let path = fields.next()?;
+        (fields.next()? == "crypt").then(|| path.to_string())
+    })
+}
+
+/// Open the LUKS container on `devnode` through udisks, so the desktop session can mount it
+/// without root. Returns the unlocked device.
+pub fn unlock_luks(devnode: &str, passphrase: &str) -> anyhow::Result<String> {
+    ensure_udisksctl()?;
+    debug!("unlock: request devnode={}", devnode);
+    let mut child = Command::new("udisksctl")
+        .args(["unlock", "-b", devnode, "--key-file", "/dev/stdin"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("run udisksctl unlock")?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(passphrase.as_bytes())
+            .context("write to udisksctl")?;
+    }
+    let output = child.wait_with_output().context("run udisksctl unlock")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        warn!(
+            "unlock: udisksctl unlock failed devnode={} status={:?} stderr={}",
+            devnode,
+            output.status.code(),
+            stderr.trim()
+        );
+        return Err(anyhow::anyhow!("unlock failed: {}", stderr.trim()));
+    }
+    let cleartext = parse_unlocked_device(&String::from_utf8_lossy(&output.stdout))
+        .or_else(|| cleartext_device(devnode))
+        .ok_or_else(|| anyhow::anyhow!("unlocked device not found"))?;
+    info!("unlock: success for devnode={}", devnode);
+    Ok(cleartext)
+}
+
+/// The device in udisksctl's "Unlocked /dev/sdb1 as /dev/dm-3." message.
+fn parse_unlocked_device(stdout: &str) -> Option<String> {
+    let (_, device) = stdout.trim().rsplit_once(" as ")?;
+    let device = device.trim_end_matches('.');
+    device.starts_with("/dev/").then(|| device.to_string())
+}
+
 fn ensure_udisksctl() -> anyhow::Result<()> {
     which::which("udisksctl").context("udisksctl not found")?;
     Ok(())
@@ -374,6 +791,109 @@ pub fn udisksctl_supports_format() -> bool {
     })
 }
 
+/// Hardware serial of the device behind `devnode` (udev ID_SERIAL; partitions inherit it from the disk).
+pub fn device_serial(devnode: &str) -> Option<String> {
+    parse_udev_serial(&udev_properties(devnode)?)
+}
+
+/// `udevadm info` properties (KEY=value lines) of `devnode`.
+fn udev_properties(devnode: &str) -> Option<String> {
+    let output = Command::new("udevadm")
+        .args(["info", "--query=property", "--name", devnode])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn parse_udev_serial(properties: &str) -> Option<String> {
+    parse_udev_property(properties, "ID_SERIAL")
+}
+
+fn parse_udev_property(properties: &str, key: &str) -> Option<String> {
+    properties
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(String::from)
+}
+
+/// Why udisksctl/pkexec refused an operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthFailure {
+    /// polkit would allow it after authentication, but there is no agent to ask the user.
+    NoAgent,
+    /// The user was asked and refused/failed, or policy denies it outright.
+    NotAuthorized,
+}
+
+impl AuthFailure {
+    /// Message safe to show in the UI.
+    pub fn guidance(self) -> &'static str {
+        match self {
+            AuthFailure::NoAgent => {
+                "no authorization agent available; run from a desktop session or configure polkit"
+            }
+            AuthFailure::NotAuthorized => "authorization required",
+        }
+    }
+}
+
+/// Classify a udisksctl/pkexec error message. None if it is not an authorization failure.
+pub fn classify_auth_failure(msg: &str) -> Option<AuthFailure> {
+    let lower = msg.to_lowercase();
+    // udisks reports NotAuthorizedCanObtain when authentication was possible but nobody could be asked.
+    if lower.contains("no authentication agent")
+        || lower.contains("notauthorizedcanobtain")
+        || lower.contains("no polkit agent")
+    {
+        return Some(AuthFailure::NoAgent);
+    }
+    if lower.contains("not authorized") || lower.contains("authentication") {
+        return Some(AuthFailure::NotAuthorized);
+    }
+    None
+}
+
+/// True if the agent runs as root (real uid 0, from /proc/self/status).
+pub fn running_as_root() -> bool {
+    std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| parse_real_uid(&status))
+        == Some(0)
+}
+
+fn parse_real_uid(status: &str) -> Option<u32> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Uid:"))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Best-effort check for a running polkit authentication agent in this system.
+pub fn polkit_agent_running() -> bool {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return false;
+    };
+    let comms: Vec<String> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| std::fs::read_to_string(e.path().join("comm")).ok())
+        .collect();
+    has_polkit_agent(comms.iter().map(|c| c.trim()))
+}
+
+/// True if one of the process names (`/proc/<pid>/comm`, max 15 chars) looks like a polkit agent.
+/// GNOME Shell ships its own agent; the polkit daemon itself does not count.
+fn has_polkit_agent<'a>(mut comms: impl Iterator<Item = &'a str>) -> bool {
+    comms.any(|comm| comm == "gnome-shell" || (comm.contains("polkit") && comm != "polkitd"))
+}
+
 pub fn unmount_partition(devnode: &str) -> anyhow::Result<()> {
     debug!("unmount: devnode={}", devnode);
     ensure_udisksctl()?;
@@ -397,27 +917,59 @@ pub fn unmount_partition(devnode: &str) -> anyhow::Result<()> {
     Ok(())
 }
 
-/// Securely wipe a block device (partition or disk) by overwriting with zeros.
+/// What to leave on a drive after a secure wipe.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PostWipeAction {
+    /// No file system; the drive has to be formatted before it can be used again.
+    #[default]
+    LeaveBlank,
+    /// Create a plain exFAT file system so the drive is usable right away.
+    FormatExfat,
+}
+
+/// Steps of a secure wipe of `devnode`: overwrite it with zeros, then optionally format it.
+pub fn wipe_plan(devnode: &str, after: PostWipeAction) -> Vec<FormatStep> {
+    let mut steps = vec![FormatStep::Run(vec![
+        "dd".to_string(),
+        "if=/dev/zero".to_string(),
+        format!("of={}", devnode),
+        "bs=4M".to_string(),
+        "status=progress".to_string(),
+    ])];
+    if after == PostWipeAction::FormatExfat {
+        steps.push(FormatStep::FormatPartition(devnode.to_string()));
+    }
+    steps
+}
+
+/// Securely wipe a block device (partition or disk) by overwriting with zeros, then apply `after`.
 /// Requires root (e.g. pkexec). Use only when the drive is discontinuing and unmounted.
-pub fn secure_wipe_block_device(devnode: &str) -> anyhow::Result<()> {
+pub fn secure_wipe_block_device(devnode: &str, after: PostWipeAction) -> anyhow::Result<()> {
     if which::which("pkexec").is_err() {
         return Err(anyhow::anyhow!("pkexec not found; cannot run secure wipe"));
     }
     info!("wipe: starting secure wipe of {}", devnode);
-    let status = Command::new("pkexec")
-        .args([
-            "dd",
-            "if=/dev/zero",
-            &format!("of={}", devnode),
-            "bs=4M",
-            "status=progress",
-        ])
-        .status()
-        .context("run pkexec dd")?;
-    if !status.success() {
-        return Err(anyhow::anyhow!("secure wipe failed"));
+    for step in wipe_plan(devnode, after) {
+        match step {
+            FormatStep::Run(argv) => {
+                let status = Command::new("pkexec")
+                    .args(&argv)
+                    .status()
+                    .context("run pkexec dd")?;
+                if !status.success() {
+                    return Err(anyhow::anyhow!("secure wipe failed"));
+                }
+                info!("wipe: completed for {}", devnode);
+            }
+            FormatStep::FormatPartition(partition) => {
+                wait_for_udev_after_format();
+                format_partition_exfat(&partition)
+                    .context("wiped, but creating the new file system failed")?;
+                info!("wipe: formatted {} as exFAT", partition);
+            }
+        }
     }
-    info!("wipe: completed for {}", devnode);
     Ok(())
 }
 
@@ -524,3 +1076,380 @@ fn wait_for_udev_after_format() {
     debug!("format: sleeping 1200ms for udev/udisks2");
     std::thread::sleep(std::time::Duration::from_millis(1200));
 }
+
+/// Guidance returned when a drive can't be written to.
+pub const READ_ONLY_MESSAGE: &str = "drive is mounted read-only; remount read-write (if it was unplugged without ejecting, repair the file system first)";
+
+/// True if the file system at `mount_path` is mounted read-only (e.g. an exFAT volume the kernel
+/// found dirty after an unclean unplug). Uses the mount table, falling back to a probe write.
+pub fn is_read_only_mount(mount_path: &Path) -> bool {
+    let from_table = std::fs::read_to_string("/proc/mounts")
+        .ok()
+        .and_then(|mounts| mount_is_read_only(&mounts, mount_path));
+    from_table.unwrap_or_else(|| !probe_writable(mount_path))
+}
+
+/// [`is_read_only_mount`] off the runtime: the probe write can stall on a failing drive.
+pub async fn is_read_only_mount_async(mount_path: &Path) -> bool {
+    let mount_path = mount_path.to_path_buf();
+    tokio::task::spawn_blocking(move || is_read_only_mount(&mount_path))
+        .await
+        .unwrap_or_default()
+}
+
+/// Look up `mount_path` in a /proc/mounts-style table; None if it isn't listed. The last entry
+/// wins, since a later mount on the same path hides earlier ones.
+fn mount_is_read_only(mounts: &str, mount_path: &Path) -> Option<bool> {
+    mounts.lines().rev().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let target = crate::usb::unescape_mount(fields.nth(1)?);
+        let options = fields.nth(1)?;
+        (Path::new(&target) == mount_path).then(|| options.split(',').any(|option| option == "ro"))
+    })
+}
+
+/// Create and remove a small file at the root of `dir`.
+fn probe_writable(dir: &Path) -> bool {
+    let probe = dir.join(format!(".aegis-write-probe-{}", std::process::id()));
+    let written = std::fs::write(&probe, b"").is_ok();
+    let _ = std::fs::remove_file(&probe);
+    written
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lsblk(json: &str) -> LsblkOutput {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn paths(devices: &[DeviceInfo]) -> Vec<&str> {
+        devices.iter().map(|d| d.path.as_str()).collect()
+    }
+
+    #[test]
+    fn parse_real_uid_reads_first_uid_field() {
+        assert_eq!(
+            parse_real_uid("Name:\tagent\nUid:\t1000\t1000\t1000\t1000\nGid:\t1000\n"),
+            Some(1000)
+        );
+        assert_eq!(parse_real_uid("Uid:\t0\t0\t0\t0\n"), Some(0));
+        assert_eq!(parse_real_uid("Name:\tagent\n"), None);
+    }
+
+    #[test]
+    fn removable_devices_uses_disk_flags() {
+        let parsed = lsblk(
+            r#"{"blockdevices": [
+                {"name": "nvme0n1", "path": "/dev/nvme0n1", "rm": false, "hotplug": false,
+                 "tran": "nvme", "type": "disk", "children": [
+                    {"name": "nvme0n1p1", "path": "/dev/nvme0n1p1", "rm": false, "hotplug": false,
+                     "tran": null, "type": "part", "fstype": "ext4", "mountpoints": ["/"]}]},
+                {"name": "sdb", "path": "/dev/sdb", "size": "58G", "rm": true, "hotplug": true,
+                 "tran": "usb", "type": "disk", "children": [
+                    {"name": "sdb1", "path": "/dev/sdb1", "size": "58G", "type": "part",
+                     "fstype": "exfat", "mountpoints": [null]}]},
+                {"name": "loop0", "path": "/dev/loop0", "rm": false, "type": "loop"}
+            ]}"#,
+        );
+        let devices = removable_devices(parsed, |_| None);
+        assert_eq!(paths(&devices), ["/dev/sdb"]);
+        assert_eq!(devices[0].partitions[0].path, "/dev/sdb1");
+        assert!(devices[0].partitions[0].mountpoints.is_empty());
+    }
+
+    #[test]
+    fn removable_devices_uses_child_transport() {
+        let parsed = lsblk(
+            r#"{"blockdevices": [
+                {"name": "sdc", "path": "/dev/sdc", "rm": false, "hotplug": false, "tran": null,
+                 "type": "disk", "children": [
+                    {"name": "sdc1", "path": "/dev/sdc1", "rm": false, "hotplug": false,
+                     "tran": "usb", "type": "part"}]}
+            ]}"#,
+        );
+        let devices = removable_devices(parsed, |_| panic!("udev not needed"));
+        assert_eq!(paths(&devices), ["/dev/sdc"]);
+    }
+
+    #[test]
+    fn removable_devices_falls_back_to_udev_bus() {
+        let parsed = lsblk(
+            r#"{"blockdevices": [
+                {"name": "sda", "path": "/dev/sda", "rm": false, "hotplug": false, "tran": "sata",
+                 "type": "disk"},
+                {"name": "sdd", "path": "/dev/sdd", "rm": false, "hotplug": false, "tran": "sata",
+                 "type": "disk"}
+            ]}"#,
+        );
+        let devices = removable_devices(parsed, |devnode| {
+            (devnode == "/dev/sdd").then(|| "usb".to_string())
+        });
+        assert_eq!(paths(&devices), ["/dev/sdd"]);
+        assert_eq!(
+            parse_udev_property("ID_BUS=usb\nID_BUS_X=ata\n", "ID_BUS").as_deref(),
+            Some("usb")
+        );
+    }
+
+    fn argv(step: &FormatStep) -> Vec<&str> {
+        match step {
+            FormatStep::Run(argv) => argv.iter().map(String::as_str).collect(),
+            FormatStep::FormatPartition(_) => panic!("expected a command"),
+        }
+    }
+
+    #[test]
+    fn parse_udev_serial_reads_id_serial() {
+        let props = "DEVNAME=/dev/sdb1\nID_SERIAL_SHORT=4C53\nID_SERIAL=SanDisk_Ultra_4C53-0:0\n";
+        assert_eq!(
+            parse_udev_serial(props).as_deref(),
+            Some("SanDisk_Ultra_4C53-0:0")
+        );
+        assert_eq!(parse_udev_serial("DEVNAME=/dev/loop0\nID_SERIAL=\n"), None);
+    }
+
+    #[test]
+    fn format_plan_partition_formats_in_place() {
+        assert_eq!(
+            format_plan(FormatTarget::Partition, "/dev/sdb1", None).unwrap(),
+            vec![FormatStep::FormatPartition("/dev/sdb1".to_string())]
+        );
+    }
+
+    #[test]
+    fn format_plan_whole_disk_sgdisk() {
+        let steps = format_plan(
+            FormatTarget::WholeDisk,
+            "/dev/sdb",
+            Some(PartitionTool::Sgdisk),
+        )
+        .unwrap();
+        assert_eq!(steps.len(), 3);
+        assert_eq!(argv(&steps[0]), ["sgdisk", "--zap-all", "/dev/sdb"]);
+        assert_eq!(
+            argv(&steps[1]),
+            ["sgdisk", "-n", "1:0:0", "-t", "1:0700", "-c", "1:aegis", "/dev/sdb"]
+        );
+        assert_eq!(
+            steps[2],
+            FormatStep::FormatPartition("/dev/sdb1".to_string())
+        );
+    }
+
+    #[test]
+    fn format_plan_whole_disk_parted_nvme() {
+        let steps = format_plan(
+            FormatTarget::WholeDisk,
+            "/dev/nvme0n1",
+            Some(PartitionTool::Parted),
+        )
+        .unwrap();
+        assert_eq!(steps.len(), 2);
+        assert_eq!(
+            argv(&steps[0])[..5],
+            ["parted", "-s", "/dev/nvme0n1", "mklabel", "gpt"]
+        );
+        assert_eq!(
+            steps[1],
+            FormatStep::FormatPartition("/dev/nvme0n1p1".to_string())
+        );
+    }
+
+    #[test]
+    fn luks_format_passes_the_passphrase_on_stdin() {
+        let mapper = luks_mapper_name("/dev/sdb1");
+        assert_eq!(mapper, "aegis-format-sdb1");
+        let commands = luks_commands("/dev/sdb1", &mapper);
+        assert_eq!(
+            commands.format,
+            [
+                "cryptsetup",
+                "luksFormat",
+                "--batch-mode",
+                "--type",
+                "luks2",
+                "--key-file=-",
+                "/dev/sdb1"
+            ]
+        );
+        assert_eq!(
+            commands.open,
+            [
+                "cryptsetup",
+                "open",
+                "--key-file=-",
+                "/dev/sdb1",
+                "aegis-format-sdb1"
+            ]
+        );
+        assert_eq!(commands.close, ["cryptsetup", "close", "aegis-format-sdb1"]);
+    }
+
+    #[test]
+    fn finds_the_unlocked_luks_device() {
+        assert_eq!(
+            parse_unlocked_device("Unlocked /dev/sdb1 as /dev/dm-3.\n").as_deref(),
+            Some("/dev/dm-3")
+        );
+        assert_eq!(parse_unlocked_device("Error unlocking"), None);
+        let lsblk = "/dev/sdb1 part\n/dev/mapper/luks-1234 crypt\n";
+        assert_eq!(
+            parse_crypt_child(lsblk).as_deref(),
+            Some("/dev/mapper/luks-1234")
+        );
+        assert_eq!(parse_crypt_child("/dev/sdb1 part\n"), None);
+    }
+
+    #[test]
+    fn format_target_must_be_a_removable_disk_or_partition() {
+        let devices = vec![DeviceInfo {
+            path: "/dev/sdb".to_string(),
+            name: "sdb".to_string(),
+            size: "16G".to_string(),
+            model: None,
+            removable: true,
+            partitions: vec![PartitionInfo {
+                path: "/dev/sdb1".to_string(),
+                name: "sdb1".to_string(),
+                size: "16G".to_string(),
+                fstype: Some("exfat".to_string()),
+                mountpoints: Vec::new(),
+            }],
+        }];
+        assert!(check_format_target(&devices, "/dev/sdb", FormatTarget::WholeDisk).is_ok());
+        assert!(check_format_target(&devices, "/dev/sdb1", FormatTarget::Partition).is_ok());
+        assert!(check_format_target(&devices, "/dev/sdb1", FormatTarget::WholeDisk).is_err());
+        assert!(check_format_target(&devices, "/dev/sdb", FormatTarget::Partition).is_err());
+        assert!(check_format_target(&devices, "/dev/sda", FormatTarget::WholeDisk).is_err());
+        assert!(check_format_target(&devices, "/dev/sda1", FormatTarget::Partition).is_err());
+    }
+
+    #[test]
+    fn format_plan_whole_disk_needs_tool() {
+        assert!(format_plan(FormatTarget::WholeDisk, "/dev/sdb", None).is_err());
+    }
+
+    #[test]
+    fn wipe_plan_formats_only_when_asked() {
+        let blank = wipe_plan("/dev/sdb1", PostWipeAction::LeaveBlank);
+        assert_eq!(blank.len(), 1);
+        assert_eq!(
+            argv(&blank[0]),
+            [
+                "dd",
+                "if=/dev/zero",
+                "of=/dev/sdb1",
+                "bs=4M",
+                "status=progress"
+            ]
+        );
+        let formatted = wipe_plan("/dev/sdb1", PostWipeAction::FormatExfat);
+        assert_eq!(formatted.len(), 2);
+        assert_eq!(formatted[0], blank[0]);
+        assert_eq!(
+            formatted[1],
+            FormatStep::FormatPartition("/dev/sdb1".to_string())
+        );
+        assert_eq!(PostWipeAction::default(), PostWipeAction::LeaveBlank);
+    }
+
+    #[test]
+    fn read_only_mount_detected_from_mount_table() {
+        let mounts = "/dev/sda2 / ext4 rw,relatime 0 0
+/dev/sdb1 /media/u/Backup\\040Drive exfat ro,nosuid,nodev,relatime,uid=1000 0 0
+/dev/sdc1 /media/u/Other vfat rw,nosuid,nodev,errors=remount-ro 0 0
+";
+        assert_eq!(
+            mount_is_read_only(mounts, Path::new("/media/u/Backup Drive")),
+            Some(true)
+        );
+        // "errors=remount-ro" is not "ro".
+        assert_eq!(
+            mount_is_read_only(mounts, Path::new("/media/u/Other")),
+            Some(false)
+        );
+        assert_eq!(mount_is_read_only(mounts, Path::new("/media/u")), None);
+    }
+
+    #[test]
+    fn later_mount_on_same_path_wins() {
+        let mounts = "/dev/sdb1 /media/u/A exfat ro 0 0\n/dev/sdb1 /media/u/A exfat rw 0 0\n";
+        assert_eq!(
+            mount_is_read_only(mounts, Path::new("/media/u/A")),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn writable_dir_passes_probe() {
+        let dir = std::env::temp_dir();
+        assert!(probe_writable(&dir));
+        assert!(!probe_writable(&dir.join("aegis-no-such-dir")));
+    }
+
+    #[test]
+    fn mounted_children_lists_only_mounted_partitions() {
+        let json = r#"{"blockdevices":[{"name":"sdb","path":"/dev/sdb","type":"disk","mountpoints":[null],"children":[
+            {"name":"sdb1","path":"/dev/sdb1","type":"part","mountpoints":["/media/u/A"]},
+            {"name":"sdb2","path":"/dev/sdb2","type":"part","mountpoints":[null]}]}]}"#;
+        let parsed: LsblkOutput = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            mounted_children(&parsed.blockdevices, "/dev/sdb"),
+            vec!["/dev/sdb1".to_string()]
+        );
+        assert!(mounted_children(&parsed.blockdevices, "/dev/sdc").is_empty());
+    }
+
+    #[test]
+    fn classify_auth_failure_no_agent() {
+        assert_eq!(
+            classify_auth_failure(
+                "Error executing command as another user: No authentication agent found."
+            ),
+            Some(AuthFailure::NoAgent)
+        );
+        assert_eq!(
+            classify_auth_failure(
+                "mount failed: Error mounting /dev/sdb1: GDBus.Error:org.freedesktop.UDisks2.Error.NotAuthorizedCanObtain: Not authorized to perform operation"
+            ),
+            Some(AuthFailure::NoAgent)
+        );
+    }
+
+    #[test]
+    fn classify_auth_failure_denied() {
+        assert_eq!(
+            classify_auth_failure(
+                "GDBus.Error:org.freedesktop.UDisks2.Error.NotAuthorized: Not authorized to perform operation"
+            ),
+            Some(AuthFailure::NotAuthorized)
+        );
+        assert_eq!(
+            classify_auth_failure("Error executing command as another user: Not authorized"),
+            Some(AuthFailure::NotAuthorized)
+        );
+        assert_eq!(
+            classify_auth_failure("pkexec: authentication dismissed"),
+            Some(AuthFailure::NotAuthorized)
+        );
+    }
+
+    #[test]
+    fn classify_auth_failure_other_errors() {
+        assert_eq!(
+            classify_auth_failure("mount failed: /dev/sdb1 is not a mountable filesystem"),
+            None
+        );
+    }
+
+    #[test]
+    fn has_polkit_agent_matches_known_agents() {
+        assert!(has_polkit_agent(["bash", "polkit-gnome-au"].into_iter()));
+        assert!(has_polkit_agent(["lxpolkit"].into_iter()));
+        assert!(has_polkit_agent(["gnome-shell"].into_iter()));
+        assert!(!has_polkit_agent(["systemd", "polkitd"].into_iter()));
+        assert!(!has_polkit_agent(std::iter::empty()));
+    }
+}
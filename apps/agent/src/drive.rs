@@ -1,8 +1,11 @@
 use crate::config::{ensure_marker_dir, sanitize_label};
+use crate::restic::{is_snapshot_id, SnapshotInfo};
+use crate::retention::is_protected;
 use anyhow::Context;
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
@@ -53,6 +56,125 @@ pub fn write_marker(root: &Path, marker: &DriveMarker) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Free-text snapshot descriptions (snapshot_id -> description), kept next to the marker since
+/// restic has no field for them.
+pub fn descriptions_path(root: &Path) -> PathBuf {
+    root.join(".aegis").join("descriptions.json")
+}
+
+pub fn read_descriptions(root: &Path) -> anyhow::Result<HashMap<String, String>> {
+    let path = descriptions_path(root);
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    let content = fs::read_to_string(&path).context("read descriptions")?;
+    let raw: HashMap<String, String> =
+        serde_json::from_str(&content).context("parse descriptions")?;
+    // Sanitize from disk (untrusted removable media).
+    Ok(raw
+        .into_iter()
+        .filter_map(|(id, d)| sanitize_label(&d).map(|d| (id, d)))
+        .collect())
+}
+
+pub fn write_descriptions(
+    root: &Path,
+    descriptions: &HashMap<String, String>,
+) -> anyhow::Result<()> {
+    let dir = ensure_marker_dir(root)?;
+    let content = serde_json::to_string_pretty(descriptions).context("serialize descriptions")?;
+    fs::write(dir.join("descriptions.json"), content).context("write descriptions")?;
+    Ok(())
+}
+
+/// Index of the one snapshot `id` (full or short) names; None if the ID is too short (see
+/// `is_snapshot_id`) or names no snapshot or several.
+fn named_snapshot(id: &str, snapshots: &[SnapshotInfo]) -> Option<usize> {
+    if !is_snapshot_id(id) {
+        return None;
+    }
+    let mut matches = snapshots
+        .iter()
+        .enumerate()
+        .filter(|(_, s)| s.id.starts_with(id))
+        .map(|(i, _)| i);
+    match (matches.next(), matches.next()) {
+        (Some(i), None) => Some(i),
+        _ => None,
+    }
+}
+
+/// Fill `description` on each snapshot from the sidecar (keys may be full or short IDs, and
+/// count only if they name exactly one snapshot).
+pub fn merge_descriptions(snapshots: &mut [SnapshotInfo], descriptions: &HashMap<String, String>) {
+    for snapshot in snapshots.iter_mut() {
+        snapshot.description = None;
+    }
+    for (id, description) in descriptions {
+        if let Some(i) = named_snapshot(id, snapshots) {
+            snapshots[i].description = Some(description.clone());
+        }
+    }
+}
+
+/// Drop descriptions whose snapshot no longer exists (e.g. removed by retention). Returns true if any were removed.
+pub fn prune_descriptions(
+    descriptions: &mut HashMap<String, String>,
+    snapshots: &[SnapshotInfo],
+) -> bool {
+    let before = descriptions.len();
+    descriptions.retain(|id, _| named_snapshot(id, snapshots).is_some());
+    descriptions.len() != before
+}
+
+/// Prune descriptions for snapshots that no longer exist, then merge the rest into `snapshots`.
+pub fn sync_descriptions(root: &Path, snapshots: &mut [SnapshotInfo]) -> anyhow::Result<()> {
+    let mut descriptions = read_descriptions(root)?;
+    if prune_descriptions(&mut descriptions, snapshots) {
+        write_descriptions(root, &descriptions)?;
+    }
+    merge_descriptions(snapshots, &descriptions);
+    Ok(())
+}
+
+/// Snapshot IDs the user protected from retention, kept next to the marker like descriptions.
+pub fn protected_path(root: &Path) -> PathBuf {
+    root.join(".aegis").join("protected.json")
+}
+
+pub fn read_protected(root: &Path) -> anyhow::Result<Vec<String>> {
+    let path = protected_path(root);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let content = fs::read_to_string(&path).context("read protected snapshots")?;
+    let raw: Vec<String> = serde_json::from_str(&content).context("parse protected snapshots")?;
+    // Only snapshot IDs (untrusted removable media; they end up as restic arguments).
+    Ok(raw.into_iter().filter(|id| is_snapshot_id(id)).collect())
+}
+
+pub fn write_protected(root: &Path, protected: &[String]) -> anyhow::Result<()> {
+    let dir = ensure_marker_dir(root)?;
+    let content =
+        serde_json::to_string_pretty(protected).context("serialize protected snapshots")?;
+    fs::write(dir.join("protected.json"), content).context("write protected snapshots")?;
+    Ok(())
+}
+
+/// Drop protected IDs whose snapshot no longer exists, then flag the protected snapshots.
+pub fn sync_protected(root: &Path, snapshots: &mut [SnapshotInfo]) -> anyhow::Result<()> {
+    let mut protected = read_protected(root)?;
+    let before = protected.len();
+    protected.retain(|id| snapshots.iter().any(|s| s.id.starts_with(id.as_str())));
+    if protected.len() != before {
+        write_protected(root, &protected)?;
+    }
+    for snapshot in snapshots.iter_mut() {
+        snapshot.protected = is_protected(&snapshot.id, &protected);
+    }
+    Ok(())
+}
+
 fn now_epoch() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -63,6 +185,7 @@ fn now_epoch() -> u64 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::temp_dir;
     use std::path::Path;
 
     #[test]
@@ -70,4 +193,92 @@ mod tests {
         let p = marker_path(Path::new("/media/usb"));
         assert_eq!(p, Path::new("/media/usb/.aegis/drive.json"));
     }
+
+    fn snapshot(id: &str) -> SnapshotInfo {
+        SnapshotInfo {
+            id: id.to_string(),
+            time: "2024-01-01T00:00:00Z".to_string(),
+            time_epoch: None,
+            hostname: None,
+            tags: None,
+            parent: None,
+            description: None,
+            protected: false,
+        }
+    }
+
+    #[test]
+    fn descriptions_roundtrip_and_missing_file() {
+        let root = temp_dir("descriptions-roundtrip");
+        assert!(read_descriptions(&root).unwrap().is_empty());
+        let mut map = HashMap::new();
+        map.insert("abc123".to_string(), "before OS upgrade".to_string());
+        write_descriptions(&root, &map).unwrap();
+        assert_eq!(read_descriptions(&root).unwrap(), map);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn descriptions_read_sanitizes() {
+        let root = temp_dir("descriptions-sanitize");
+        fs::create_dir_all(root.join(".aegis")).unwrap();
+        fs::write(descriptions_path(&root), r#"{"a":"  ok\u0007 ","b":"   "}"#).unwrap();
+        let map = read_descriptions(&root).unwrap();
+        assert_eq!(map.get("a").map(String::as_str), Some("ok"));
+        assert!(!map.contains_key("b"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn merge_descriptions_matches_full_and_short_ids() {
+        let mut snapshots = vec![snapshot("aaaa1111ffff"), snapshot("bbbb2222ffff")];
+        let mut map = HashMap::new();
+        map.insert("aaaa1111ffff".to_string(), "full".to_string());
+        map.insert("bbbb2222".to_string(), "short".to_string());
+        merge_descriptions(&mut snapshots, &map);
+        assert_eq!(snapshots[0].description.as_deref(), Some("full"));
+        assert_eq!(snapshots[1].description.as_deref(), Some("short"));
+    }
+
+    #[test]
+    fn descriptions_need_an_id_naming_one_snapshot() {
+        let mut snapshots = vec![snapshot("aaaa1111ffff"), snapshot("aaaa1111eeee")];
+        let mut map = HashMap::new();
+        map.insert("aaaa1111".to_string(), "ambiguous".to_string());
+        map.insert("aaaa".to_string(), "too short".to_string());
+        merge_descriptions(&mut snapshots, &map);
+        assert!(snapshots.iter().all(|s| s.description.is_none()));
+        assert!(prune_descriptions(&mut map, &snapshots));
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn prune_descriptions_drops_deleted_snapshots() {
+        let snapshots = vec![snapshot("aaaa1111ffff")];
+        let mut map = HashMap::new();
+        map.insert("aaaa1111".to_string(), "kept".to_string());
+        map.insert("dead0000".to_string(), "gone".to_string());
+        assert!(prune_descriptions(&mut map, &snapshots));
+        assert_eq!(map.len(), 1);
+        assert!(map.contains_key("aaaa1111"));
+        assert!(!prune_descriptions(&mut map, &snapshots));
+    }
+
+    #[test]
+    fn sync_protected_flags_and_forgets_deleted_snapshots() {
+        let root = temp_dir("protected-sync");
+        assert!(read_protected(&root).unwrap().is_empty());
+        fs::create_dir_all(root.join(".aegis")).unwrap();
+        fs::write(
+            protected_path(&root),
+            r#"["aaaa1111", "dead0000", "--keep-none", "aaaa"]"#,
+        )
+        .unwrap();
+        let mut snapshots = vec![snapshot("aaaa1111ffff"), snapshot("bbbb2222ffff")];
+        sync_protected(&root, &mut snapshots).unwrap();
+        assert!(snapshots[0].protected);
+        assert!(!snapshots[1].protected);
+        assert_eq!(read_protected(&root).unwrap(), ["aaaa1111"]);
+        fs::remove_dir_all(&root).unwrap();
+    }
 }
@@ -0,0 +1,58 @@
+//! Backup durations per drive, to spot a run that is much slower than usual (often the first
+//! sign of a failing drive or a bad cable).
+
+/// Max durations kept per drive (one per successful backup).
+pub const DURATION_HISTORY_MAX: usize = 10;
+
+/// Successful runs needed before a slow run is flagged.
+const MIN_SAMPLES: usize = 3;
+
+/// A run is flagged when it takes more than this many times the average...
+const SLOWDOWN_FACTOR: u64 = 2;
+
+/// ...and at least this many seconds longer, so short backups don't trip on noise.
+const MIN_SLOWDOWN_SECS: u64 = 60;
+
+/// Rounded mean of the recorded durations; None without any.
+pub fn average_duration(durations: &[u64]) -> Option<u64> {
+    if durations.is_empty() {
+        return None;
+    }
+    let total: u128 = durations.iter().map(|&d| d as u128).sum();
+    let count = durations.len() as u128;
+    Some(((total + count / 2) / count) as u64)
+}
+
+/// True if a run of `secs` is anomalously slow compared to the earlier `durations`.
+pub fn is_slow_run(durations: &[u64], secs: u64) -> bool {
+    if durations.len() < MIN_SAMPLES {
+        return false;
+    }
+    let Some(average) = average_duration(durations) else {
+        return false;
+    };
+    secs > average.saturating_mul(SLOWDOWN_FACTOR)
+        && secs.saturating_sub(average) >= MIN_SLOWDOWN_SECS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn average_duration_rounds() {
+        assert_eq!(average_duration(&[]), None);
+        assert_eq!(average_duration(&[100]), Some(100));
+        assert_eq!(average_duration(&[100, 101]), Some(101));
+        assert_eq!(average_duration(&[10, 20, 30]), Some(20));
+    }
+
+    #[test]
+    fn slow_run_needs_history_and_a_real_slowdown() {
+        assert!(!is_slow_run(&[300, 300], 3_000));
+        assert!(is_slow_run(&[300, 320, 280], 700));
+        assert!(!is_slow_run(&[300, 320, 280], 590));
+        // Twice as slow but only seconds longer: not worth a warning.
+        assert!(!is_slow_run(&[10, 12, 8], 50));
+    }
+}
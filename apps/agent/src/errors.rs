@@ -21,4 +21,131 @@ pub enum AgentError {
     Ipc,
     #[error("io error")]
     Io,
+    #[error("operation cancelled")]
+    Cancelled,
+    #[error("wrong passphrase for repository")]
+    WrongPassword,
+    #[error("restic is too old for this repository")]
+    ResticTooOld,
+    #[error("backup drive is full")]
+    DriveFull,
+    #[error("backup drive is mounted read-only")]
+    ReadOnlyDrive,
+    #[error("free space on the backup drive reached the reserve")]
+    FreeSpaceReserve,
+    #[error("backup drive reported an I/O error")]
+    DriveIoError,
+    #[error("no restic repository at this location")]
+    RepositoryMissing,
+    #[error("repository check before the backup failed")]
+    PreBackupCheckFailed,
+    #[error("repository is locked by another restic process")]
+    RepositoryLocked,
+}
+
+/// Stable identifier for the kind of failure, for support reports. "unknown" for errors that
+/// aren't an [`AgentError`].
+pub fn error_code(err: &anyhow::Error) -> &'static str {
+    match err.downcast_ref::<AgentError>() {
+        Some(AgentError::Config) => "config",
+        Some(AgentError::Keychain) => "keychain",
+        Some(AgentError::Restic) => "restic",
+        Some(AgentError::Backup) => "backup",
+        Some(AgentError::Verify) => "verify",
+        Some(AgentError::Retention) => "retention",
+        Some(AgentError::Usb) => "usb",
+        Some(AgentError::Ipc) => "ipc",
+        Some(AgentError::Io) => "io",
+        Some(AgentError::Cancelled) => "cancelled",
+        Some(AgentError::WrongPassword) => "wrong_password",
+        Some(AgentError::ResticTooOld) => "restic_too_old",
+        Some(AgentError::DriveFull) => "drive_full",
+        Some(AgentError::ReadOnlyDrive) => "read_only_drive",
+        Some(AgentError::FreeSpaceReserve) => "free_space_reserve",
+        Some(AgentError::DriveIoError) => "drive_io_error",
+        Some(AgentError::RepositoryMissing) => "repository_missing",
+        Some(AgentError::PreBackupCheckFailed) => "pre_backup_check_failed",
+        Some(AgentError::RepositoryLocked) => "repository_locked",
+        None => "unknown",
+    }
+}
+
+/// True if `err` is (or wraps) a user/unplug cancellation rather than a real failure.
+pub fn is_cancelled(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<AgentError>(),
+        Some(AgentError::Cancelled)
+    )
+}
+
+/// True if `err` is (or wraps) restic rejecting the repository passphrase.
+pub fn is_wrong_password(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<AgentError>(),
+        Some(AgentError::WrongPassword)
+    )
+}
+
+/// True if `err` is (or wraps) restic refusing a repository format newer than it supports.
+pub fn is_restic_too_old(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<AgentError>(),
+        Some(AgentError::ResticTooOld)
+    )
+}
+
+/// True if `err` is (or wraps) restic running out of space on the backup drive.
+pub fn is_drive_full(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<AgentError>(),
+        Some(AgentError::DriveFull)
+    )
+}
+
+/// True if `err` is (or wraps) the backup drive being mounted read-only.
+pub fn is_read_only_drive(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<AgentError>(),
+        Some(AgentError::ReadOnlyDrive)
+    )
+}
+
+/// True if `err` is (or wraps) a backup stopped to keep the drive's free-space reserve.
+pub fn is_free_space_reserve(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<AgentError>(),
+        Some(AgentError::FreeSpaceReserve)
+    )
+}
+
+/// True if `err` is (or wraps) a hardware-level I/O error, pointing at the cable or the drive.
+pub fn is_drive_io_error(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<AgentError>(),
+        Some(AgentError::DriveIoError)
+    )
+}
+
+/// True if `err` is (or wraps) restic finding no repository where one was expected.
+pub fn is_repository_missing(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<AgentError>(),
+        Some(AgentError::RepositoryMissing)
+    )
+}
+
+/// True if `err` is (or wraps) a backup aborted because the repository failed its pre-check.
+pub fn is_pre_backup_check_failed(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<AgentError>(),
+        Some(AgentError::PreBackupCheckFailed)
+    )
+}
+
+/// True if `err` is (or wraps) restic finding the repository locked by another process.
+pub fn is_repository_locked(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<AgentError>(),
+        Some(AgentError::RepositoryLocked)
+    )
 }
@@ -0,0 +1,217 @@
+use serde::Serialize;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Stop the walk after this many entries, so a huge tree still answers quickly.
+pub const ESTIMATE_MAX_ENTRIES: u64 = 500_000;
+
+/// Stop the walk after this long.
+pub const ESTIMATE_TIME_LIMIT: Duration = Duration::from_secs(10);
+
+/// Rough size of what a backup would read, from walking the sources without restic.
+#[derive(Debug, Clone, Copy, Default, Serialize, PartialEq, Eq)]
+pub struct SourceEstimate {
+    pub files: u64,
+    pub bytes: u64,
+    /// False if the walk hit the entry or time limit; the numbers are then a lower bound.
+    pub complete: bool,
+}
+
+/// Count the regular files under `sources` and add up their sizes, skipping what `excludes`
+/// (restic exclude patterns) match. Symlinks are counted as entries but not followed, like
+/// restic does; unreadable directories are skipped.
+pub fn estimate_sources(
+    sources: &[PathBuf],
+    excludes: &[String],
+    max_entries: u64,
+    deadline: Instant,
+) -> SourceEstimate {
+    let mut estimate = SourceEstimate {
+        complete: true,
+        ..SourceEstimate::default()
+    };
+    let mut entries = 0u64;
+    let mut stack: Vec<PathBuf> = sources.to_vec();
+    while let Some(path) = stack.pop() {
+        entries += 1;
+        if entries > max_entries || Instant::now() >= deadline {
+            estimate.complete = false;
+            break;
+        }
+        if is_excluded(&path, excludes) {
+            continue;
+        }
+        let Ok(meta) = std::fs::symlink_metadata(&path) else {
+            continue;
+        };
+        if meta.is_dir() {
+            if let Ok(children) = std::fs::read_dir(&path) {
+                stack.extend(children.filter_map(|entry| entry.ok().map(|e| e.path())));
+            }
+        } else if meta.is_file() {
+            estimate.files += 1;
+            estimate.bytes += meta.len();
+        }
+    }
+    estimate
+}
+
+/// True if any exclude pattern matches `path`. Follows restic: a pattern starting with `/` is
+/// matched from the root, any other pattern against the trailing components (so `*.tmp` matches
+/// a file of that name anywhere), and `**` spans any number of directories.
+pub fn is_excluded(path: &Path, excludes: &[String]) -> bool {
+    let components: Vec<String> = path
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect();
+    excludes.iter().any(|pattern| {
+        let anchored = pattern.starts_with('/');
+        let parts: Vec<&str> = pattern.split('/').filter(|p| !p.is_empty()).collect();
+        if parts.is_empty() {
+            return false;
+        }
+        if anchored {
+            return match_components(&parts, &components);
+        }
+        (0..components.len()).any(|start| match_components(&parts, &components[start..]))
+    })
+}
+
+/// Whole-path match of pattern components against path components.
+fn match_components(parts: &[&str], components: &[String]) -> bool {
+    match parts.split_first() {
+        None => components.is_empty(),
+        Some((&"**", rest)) => {
+            (0..=components.len()).any(|skip| match_components(rest, &components[skip..]))
+        }
+        Some((part, rest)) => match components.split_first() {
+            Some((name, tail)) => glob_match(part, name) && match_components(rest, tail),
+            None => false,
+        },
+    }
+}
+
+/// Shell-style match of one path component: `*`, `?` and `[...]` classes (with `^`/`!`
+/// negation and ranges).
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    glob_match_at(&pattern, &name)
+}
+
+fn glob_match_at(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|skip| glob_match_at(&pattern[1..], &name[skip..])),
+        Some('?') => !name.is_empty() && glob_match_at(&pattern[1..], &name[1..]),
+        Some('[') => {
+            let Some(close) = pattern
+                .iter()
+                .skip(2)
+                .position(|&c| c == ']')
+                .map(|i| i + 2)
+            else {
+                // No closing bracket: a literal '['.
+                return name.first() == Some(&'[') && glob_match_at(&pattern[1..], &name[1..]);
+            };
+            let Some(&c) = name.first() else {
+                return false;
+            };
+            let mut class = &pattern[1..close];
+            let negated = matches!(class.first(), Some('^') | Some('!'));
+            if negated {
+                class = &class[1..];
+            }
+            let mut matched = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == '-' {
+                    matched |= class[i] <= c && c <= class[i + 2];
+                    i += 3;
+                } else {
+                    matched |= class[i] == c;
+                    i += 1;
+                }
+            }
+            matched != negated && glob_match_at(&pattern[close + 1..], &name[1..])
+        }
+        Some(&literal) => {
+            name.first() == Some(&literal) && glob_match_at(&pattern[1..], &name[1..])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn excluded(path: &str, pattern: &str) -> bool {
+        is_excluded(Path::new(path), &[pattern.to_string()])
+    }
+
+    #[test]
+    fn patterns_follow_restic_rules() {
+        assert!(excluded("/home/u/a/b.tmp", "*.tmp"));
+        assert!(!excluded("/home/u/a/b.tmpx", "*.tmp"));
+        assert!(excluded("/home/u/node_modules", "node_modules"));
+        assert!(excluded("/home/u/Documents/cache", "Documents/cache"));
+        assert!(excluded(
+            "/home/u/Documents/cache",
+            "/home/*/Documents/cache"
+        ));
+        assert!(!excluded(
+            "/srv/home/u/Documents/cache",
+            "/home/*/Documents/cache"
+        ));
+        assert!(excluded("/home/u/x/y/z.log", "/home/**/*.log"));
+        assert!(excluded("/media/d/$RECYCLE.BIN", "[$]RECYCLE.BIN"));
+        for name in ["$RECYCLE.BIN", "$Recycle.Bin"] {
+            assert!(excluded(
+                &format!("/media/d/{}", name),
+                "[$][Rr][Ee][Cc][Yy][Cc][Ll][Ee].[Bb][Ii][Nn]"
+            ));
+        }
+        assert!(excluded("/media/d/.Trash-1000", ".Trash-*"));
+        assert!(excluded("/a/file1", "file[0-9]"));
+        assert!(!excluded("/a/filex", "file[0-9]"));
+        assert!(excluded("/a/filex", "file[!0-9]"));
+        assert!(!excluded("/a/b", "/"));
+    }
+
+    #[test]
+    fn walker_counts_files_and_honors_excludes() {
+        let root = std::env::temp_dir().join(format!("aegis-estimate-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("docs/cache")).unwrap();
+        std::fs::create_dir_all(root.join("pics")).unwrap();
+        std::fs::write(root.join("docs/a.txt"), "12345").unwrap();
+        std::fs::write(root.join("docs/b.tmp"), "123").unwrap();
+        std::fs::write(root.join("docs/cache/big.bin"), vec![0u8; 1000]).unwrap();
+        std::fs::write(root.join("pics/p.jpg"), "1234567").unwrap();
+        let sources = [root.join("docs"), root.join("pics")];
+        let far = Instant::now() + Duration::from_secs(60);
+
+        let all = estimate_sources(&sources, &[], 1000, far);
+        assert_eq!(
+            all,
+            SourceEstimate {
+                files: 4,
+                bytes: 1015,
+                complete: true
+            }
+        );
+        let excludes = ["*.tmp".to_string(), "docs/cache".to_string()];
+        let some = estimate_sources(&sources, &excludes, 1000, far);
+        assert_eq!((some.files, some.bytes, some.complete), (2, 12, true));
+
+        let capped = estimate_sources(&sources, &[], 3, far);
+        assert!(!capped.complete);
+        assert!(capped.files < 4);
+        let late = estimate_sources(&sources, &[], 1000, Instant::now());
+        assert!(!late.complete);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,136 @@
+//! Export of a single snapshot to a tar or tar.gz archive (for moving it to cold storage).
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+}
+
+/// Check where an export may be written and pick the format from the file name (`.tar`,
+/// `.tar.gz` or `.tgz`). The target must be a new file in an existing directory, and not on the
+/// backup drive itself (`mount_path`), which would fill the drive with a copy of its own data.
+pub fn validate_export_target(target: &Path, mount_path: &Path) -> Result<ArchiveFormat, String> {
+    if !target.is_absolute() {
+        return Err("target path must be absolute".to_string());
+    }
+    let name = target
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    let format = if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        ArchiveFormat::TarGz
+    } else if name.ends_with(".tar") {
+        ArchiveFormat::Tar
+    } else {
+        return Err("target file name must end in .tar, .tar.gz or .tgz".to_string());
+    };
+    if target.starts_with(mount_path) {
+        return Err("cannot export onto the backup drive itself".to_string());
+    }
+    if target.exists() {
+        return Err("target file already exists".to_string());
+    }
+    if !target.parent().is_some_and(Path::is_dir) {
+        return Err("target directory does not exist".to_string());
+    }
+    Ok(format)
+}
+
+/// Archive file being written; compresses on the fly for tar.gz.
+pub enum ArchiveWriter {
+    Tar(BufWriter<File>),
+    TarGz(GzEncoder<BufWriter<File>>),
+}
+
+impl ArchiveWriter {
+    /// Create the target file. Fails if it already exists.
+    pub fn create(target: &Path, format: ArchiveFormat) -> std::io::Result<Self> {
+        let file = BufWriter::new(File::create_new(target)?);
+        Ok(match format {
+            ArchiveFormat::Tar => ArchiveWriter::Tar(file),
+            ArchiveFormat::TarGz => {
+                ArchiveWriter::TarGz(GzEncoder::new(file, Compression::default()))
+            }
+        })
+    }
+
+    /// Write the gzip trailer (if any) and flush everything to disk.
+    pub fn finish(self) -> std::io::Result<()> {
+        let file = match self {
+            ArchiveWriter::Tar(file) => file,
+            ArchiveWriter::TarGz(encoder) => encoder.finish()?,
+        };
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()
+    }
+}
+
+impl Write for ArchiveWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            ArchiveWriter::Tar(file) => file.write(buf),
+            ArchiveWriter::TarGz(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            ArchiveWriter::Tar(file) => file.flush(),
+            ArchiveWriter::TarGz(encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+    use std::io::Read;
+
+    #[test]
+    fn validate_export_target_picks_format_and_rejects_bad_targets() {
+        let dir = temp_dir("export-validate");
+        let mount = Path::new("/media/user/aegis-1");
+        assert_eq!(
+            validate_export_target(&dir.join("snap.tar"), mount),
+            Ok(ArchiveFormat::Tar)
+        );
+        assert_eq!(
+            validate_export_target(&dir.join("snap.TAR.GZ"), mount),
+            Ok(ArchiveFormat::TarGz)
+        );
+        assert_eq!(
+            validate_export_target(&dir.join("snap.tgz"), mount),
+            Ok(ArchiveFormat::TarGz)
+        );
+        assert!(validate_export_target(Path::new("snap.tar"), mount).is_err());
+        assert!(validate_export_target(&dir.join("snap.zip"), mount).is_err());
+        assert!(validate_export_target(&dir.join("missing/snap.tar"), mount).is_err());
+        assert!(validate_export_target(&mount.join("snap.tar"), mount).is_err());
+        std::fs::write(dir.join("taken.tar"), b"").unwrap();
+        assert!(validate_export_target(&dir.join("taken.tar"), mount).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn tar_gz_writer_compresses() {
+        let dir = temp_dir("export-writer");
+        let target = dir.join("snap.tar.gz");
+        let mut writer = ArchiveWriter::create(&target, ArchiveFormat::TarGz).unwrap();
+        writer.write_all(b"archive contents").unwrap();
+        writer.finish().unwrap();
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(File::open(&target).unwrap())
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "archive contents");
+        assert!(ArchiveWriter::create(&target, ArchiveFormat::Tar).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,191 @@
+//! Message catalog for notification text.
+//! Templates are keyed by message and language and use `{name}` placeholders; a key a
+//! language doesn't translate falls back to English.
+
+type Catalog = &'static [(&'static str, &'static str)];
+
+/// English; the fallback for every key.
+const EN: Catalog = &[
+    ("backup_started.title", "Backup started"),
+    ("backup_started.body", "Backing up to \"{drive}\"…"),
+    ("backup_interrupted.title", "Backup interrupted"),
+    (
+        "backup_interrupted.body",
+        "Backup to \"{drive}\" was interrupted (drive disconnected).",
+    ),
+    ("backup_completed.title", "Backup completed"),
+    (
+        "backup_completed.body",
+        "Backup to \"{drive}\" completed successfully.",
+    ),
+    ("backup_failed.title", "Backup failed"),
+    ("backup_failed.body", "Backup to \"{drive}\" failed."),
+    ("drive_full.title", "Backup drive full"),
+    (
+        "drive_full.body",
+        "Backup to \"{drive}\" failed because the drive is full. Free up space or remove old backups.",
+    ),
+    ("trusted_device.title", "Aegis drive connected"),
+    (
+        "trusted_device.body",
+        "\"{drive}\" is connected and ready to back up.",
+    ),
+    ("drive_filling.title", "Backup drive filling up"),
+    (
+        "drive_filling.body",
+        "\"{drive}\" will be full in about {count} more backup(s) at the current rate.",
+    ),
+    ("test.title", "Aegis test notification"),
+    ("test.body", "Notifications are working."),
+];
+
+const DE: Catalog = &[
+    ("backup_started.title", "Sicherung gestartet"),
+    ("backup_started.body", "Sicherung auf \"{drive}\" läuft…"),
+    ("backup_interrupted.title", "Sicherung unterbrochen"),
+    (
+        "backup_interrupted.body",
+        "Die Sicherung auf \"{drive}\" wurde unterbrochen (Laufwerk getrennt).",
+    ),
+    ("backup_completed.title", "Sicherung abgeschlossen"),
+    (
+        "backup_completed.body",
+        "Die Sicherung auf \"{drive}\" wurde erfolgreich abgeschlossen.",
+    ),
+    ("backup_failed.title", "Sicherung fehlgeschlagen"),
+    (
+        "backup_failed.body",
+        "Die Sicherung auf \"{drive}\" ist fehlgeschlagen.",
+    ),
+    ("drive_full.title", "Sicherungslaufwerk voll"),
+    (
+        "drive_full.body",
+        "Die Sicherung auf \"{drive}\" ist fehlgeschlagen, weil das Laufwerk voll ist. Geben Sie Speicherplatz frei oder entfernen Sie alte Sicherungen.",
+    ),
+    ("trusted_device.title", "Aegis-Laufwerk verbunden"),
+    (
+        "trusted_device.body",
+        "\"{drive}\" ist verbunden und bereit für die Sicherung.",
+    ),
+    ("drive_filling.title", "Sicherungslaufwerk wird voll"),
+    (
+        "drive_filling.body",
+        "\"{drive}\" ist beim aktuellen Verbrauch in etwa {count} weiteren Sicherung(en) voll.",
+    ),
+    ("test.title", "Aegis-Testbenachrichtigung"),
+    ("test.body", "Benachrichtigungen funktionieren."),
+];
+
+const FALLBACK_LANGUAGE: &str = "en";
+
+const CATALOGS: &[(&str, Catalog)] = &[("en", EN), ("de", DE)];
+
+/// Language part of a locale, lowercased: `de_DE.UTF-8` and `de-AT` are both `de`.
+pub fn language(locale: &str) -> String {
+    locale
+        .split(['_', '-', '.', '@'])
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// The user's locale from `LC_ALL`, `LC_MESSAGES` or `LANG`, in that order; None for the
+/// `C`/`POSIX` locale or when none is set.
+pub fn system_locale() -> Option<String> {
+    ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|value| !value.trim().is_empty())
+        .filter(|value| !matches!(language(value).as_str(), "c" | "posix"))
+}
+
+/// Template for `key` in the locale's language, else in English.
+fn lookup(catalogs: &[(&str, Catalog)], locale: &str, key: &str) -> Option<&'static str> {
+    let find = |language: &str| {
+        catalogs
+            .iter()
+            .find(|(name, _)| *name == language)
+            .and_then(|(_, catalog)| catalog.iter().find(|(k, _)| *k == key))
+            .map(|(_, template)| *template)
+    };
+    find(&language(locale)).or_else(|| find(FALLBACK_LANGUAGE))
+}
+
+fn fill(template: &str, args: &[(&str, &str)]) -> String {
+    args.iter()
+        .fold(template.to_string(), |text, (name, value)| {
+            text.replace(&format!("{{{}}}", name), value)
+        })
+}
+
+/// Localized text for `key` with its `{name}` placeholders filled from `args`. A key missing
+/// from every catalog comes back as the key itself, so a gap shows up instead of blank text.
+pub fn message(locale: &str, key: &str, args: &[(&str, &str)]) -> String {
+    fill(lookup(CATALOGS, locale, key).unwrap_or(key), args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn looks_up_the_locale_language_and_fills_placeholders() {
+        assert_eq!(
+            message("de_DE.UTF-8", "backup_started.body", &[("drive", "Work")]),
+            "Sicherung auf \"Work\" läuft…"
+        );
+        assert_eq!(
+            message(
+                "en_US",
+                "drive_filling.body",
+                &[("drive", "Work"), ("count", "3")]
+            ),
+            "\"Work\" will be full in about 3 more backup(s) at the current rate."
+        );
+    }
+
+    #[test]
+    fn falls_back_to_english() {
+        const PARTIAL: Catalog = &[("backup_started.title", "Copia iniciada")];
+        let catalogs: &[(&str, Catalog)] = &[("en", EN), ("es", PARTIAL)];
+        assert_eq!(
+            lookup(catalogs, "es_ES", "backup_started.title"),
+            Some("Copia iniciada")
+        );
+        // Missing from the translation: English.
+        assert_eq!(
+            lookup(catalogs, "es_ES", "backup_failed.title"),
+            Some("Backup failed")
+        );
+        // Unknown language: English.
+        assert_eq!(
+            message("fr_FR", "test.title", &[]),
+            "Aegis test notification"
+        );
+        // Missing everywhere: the key.
+        assert_eq!(message("en", "no.such.key", &[]), "no.such.key");
+    }
+
+    #[test]
+    fn every_translation_key_exists_in_english() {
+        for (language, catalog) in CATALOGS {
+            for (key, _) in catalog.iter() {
+                assert!(
+                    EN.iter().any(|(k, _)| k == key),
+                    "{} has unknown key {}",
+                    language,
+                    key
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn language_strips_region_and_encoding() {
+        assert_eq!(language("de_DE.UTF-8"), "de");
+        assert_eq!(language("pt-BR"), "pt");
+        assert_eq!(language("sr_RS@latin"), "sr");
+        assert_eq!(language("C"), "c");
+    }
+}
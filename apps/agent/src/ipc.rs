@@ -1,25 +1,52 @@
-use crate::backup::run_backup;
-use crate::config::sanitize_label;
+use crate::attention::{collect_attention, AttentionItem};
+use crate::backup::{expand_source_excludes, expand_sources, run_backup, source_link_targets};
+use crate::config::{sanitize_label, sanitize_patterns, validate_notification_icon};
 use crate::config::{AgentConfig, BackupSource, TrustedDrive};
-use crate::devices;
-use crate::drive::{read_marker, write_marker, DriveMarker};
+use crate::devices::{self, PostWipeAction};
+use crate::drive::{
+    read_descriptions, read_marker, read_protected, sync_descriptions, sync_protected,
+    write_descriptions, write_marker, write_protected, DriveMarker,
+};
+use crate::durations;
+use crate::errors::{is_cancelled, is_repository_missing, is_wrong_password};
+use crate::estimate;
+use crate::export::{validate_export_target, ArchiveWriter};
 use crate::keychain;
-use crate::logging::Redact;
-use crate::recovery::export_recovery_kit;
-use crate::restic::Restic;
-use crate::state::{BackupProgress, DriveStatus, RunResult, SharedState};
+use crate::logging::{self, Redact};
+use crate::notifications::{self, ChannelResult, NotificationSettings};
+use crate::processes::{self, TrackedProcess};
+use crate::recovery::{compare_repository_ids, export_recovery_kit, RepoIdMatch};
+use crate::restic::{
+    is_snapshot_id, repository_is_empty, resolve_snapshot_id, Elevation, PerformanceOverrides,
+    PerformanceProfile, RepairSummary, Restic,
+};
+use crate::schedule::{self, ScheduleConfig, MAX_SCHEDULE_ENTRIES};
+use crate::selftest::{run_restore_self_test, SelfTestResult};
+use crate::space::{is_space_warning, project_backups_until_full};
+use crate::state::{
+    AgentRuntimeState, AutoBackupState, BackupProgress, DriveStatus, ExportProgress, LastError,
+    RunResult, SharedState, VerifyProgress,
+};
+use crate::support;
 use crate::usb::resolve_device_for_mount;
-use anyhow::Context;
+use crate::verify::{
+    estimate_deep_verify_secs, repository_size_async, verify, VerifyFailurePolicy, VerifyMode,
+    ASSUMED_READ_THROUGHPUT,
+};
 use axum::extract::State;
-use axum::http::StatusCode;
+use axum::http::{header, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::IntoResponse;
 use axum::routing::{get, post};
 use axum::{Json, Router};
+use futures_util::Stream;
 use rand::distributions::Alphanumeric;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::path::{Path as FsPath, PathBuf};
 use std::process::Stdio;
-use tokio_util::sync::CancellationToken;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::error::RecvError;
 use tower_http::cors::{Any, CorsLayer};
 use tracing::{debug, error};
 
@@ -49,16 +76,101 @@ struct TrustedDriveSummary {
     backup_source_labels: Vec<String>,
     /// Full sources (label + path) for UI display and open-folder.
     backup_sources: Vec<BackupSource>,
+    /// Real path of each source that is a symlink, by label; the source's `follow_symlink` says
+    /// whether the link or this target is backed up.
+    source_link_targets: std::collections::HashMap<String, String>,
+    /// True if the repository on the drive is not the one it was set up with; backups are refused until it is set up again.
+    repository_changed: bool,
+    /// restic repository ID recorded at setup (not secret); matches the one in the recovery kit.
+    repository_id: Option<String>,
+    /// True if backup_sources are inherited from the global default (drive has none of its own).
+    uses_global_sources: bool,
+    /// This drive's own patterns, applied on top of the global include/exclude lists.
+    include_patterns: Vec<String>,
+    exclude_patterns: Vec<String>,
+    /// The repository format is newer than the installed restic can open.
+    restic_too_old: bool,
+    /// Bytes added to this drive over all backups since setup.
+    total_data_added: u64,
+    /// Free/total bytes on the drive as of the last backup; None if never measured.
+    free_bytes: Option<u64>,
+    total_bytes: Option<u64>,
+    /// Projected number of backups until the drive is full, from the free-space trend.
+    backups_until_full: Option<u64>,
+    /// True when backups_until_full is within the configured warning window.
+    space_warning: bool,
+    /// Average duration of recent successful backups in seconds; None before the first one.
+    average_backup_secs: Option<u64>,
+    /// When the passphrase kept in memory for this drive expires (epoch seconds); after that the
+    /// next operation prompts again unless the keychain has it. None if none is cached.
+    passphrase_cache_expires_epoch: Option<u64>,
+    /// The most recent failure on this drive (paths redacted); None if nothing has failed.
+    last_error: Option<LastError>,
+    /// What the auto-backup did when the drive was last inserted; None if it hasn't been since the
+    /// agent started.
+    auto_backup: Option<AutoBackupState>,
+    /// Number of snapshots and the oldest/newest snapshot time (Unix seconds) as of the last
+    /// listing since the agent started; None until the drive's snapshots have been listed.
+    snapshot_count: Option<u64>,
+    oldest_snapshot_epoch: Option<u64>,
+    newest_snapshot_epoch: Option<u64>,
 }
 
 #[derive(Debug, Deserialize)]
 struct DiscontinueDriveRequest {
     drive_id: String,
-    /// User must type the drive label to confirm, e.g. "backup-abc123"
+    /// User must type the drive label to confirm, e.g. "backup-abc123". For a drive without a
+    /// label, its displayed name ("drive-xxxxxxxx") or the first 8+ characters of drive_id.
     confirm_label: String,
     /// If true and the drive is currently connected, unmount and securely wipe it (overwrite with zeros).
     #[serde(default)]
     wipe: bool,
+    /// What to leave on the drive after wiping: nothing (default) or a fresh exFAT file system.
+    #[serde(default)]
+    after_wipe: PostWipeAction,
+}
+
+#[derive(Debug, Deserialize)]
+struct DiscontinuePreviewRequest {
+    drive_id: String,
+    #[serde(default)]
+    wipe: bool,
+    #[serde(default)]
+    after_wipe: PostWipeAction,
+}
+
+/// One consequence of discontinuing a drive, for the confirmation dialog.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+struct DiscontinueEffect {
+    code: String,
+    message: String,
+}
+
+#[derive(Debug, Serialize)]
+struct DiscontinuePreviewResponse {
+    drive_id: String,
+    /// What the user must type as `confirm_label` to go ahead.
+    confirm_label: String,
+    effects: Vec<DiscontinueEffect>,
+    /// Why discontinuing with these options would be refused right now, if it would.
+    blocked_reason: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct DriveErrorReport {
+    drive_id: String,
+    label: String,
+    #[serde(flatten)]
+    error: LastError,
+}
+
+#[derive(Debug, Serialize)]
+struct LastErrorsResponse {
+    agent_version: String,
+    generated_epoch: u64,
+    drives: Vec<DriveErrorReport>,
+    /// The same information as plain text, ready to paste into a bug report.
+    report: String,
 }
 
 #[derive(Debug, Serialize)]
@@ -70,16 +182,30 @@ struct StatusResponse {
     running: bool,
     /// Drive IDs with a backup in progress (enables UI to show per-drive state).
     running_drive_ids: Vec<String>,
+    /// Drive IDs whose auto-backup is waiting for the OS keyring to be unlocked.
+    keyring_waiting_drive_ids: Vec<String>,
     restic_available: bool,
     config: ConfigSummary,
     trusted_drives: Vec<TrustedDriveSummary>,
     /// Progress per drive (key = drive_id).
     backup_progress: std::collections::HashMap<String, BackupProgress>,
+    /// On-demand verifications in progress (key = drive_id).
+    verify_progress: std::collections::HashMap<String, VerifyProgress>,
+    /// Snapshot exports in progress (key = drive_id exported from).
+    export_progress: std::collections::HashMap<String, ExportProgress>,
+    /// Problems the user should look at, errors first.
+    attention: Vec<AttentionItem>,
+    /// Changes whenever a USB drive is plugged in or removed.
+    devices_revision: u64,
+    /// Next scheduled backup time (Unix seconds); None without a schedule.
+    next_scheduled_backup_epoch: Option<u64>,
 }
 
 #[derive(Debug, Serialize)]
 struct DevicesResponse {
     devices: Vec<devices::DeviceInfo>,
+    /// `devices_revision` at the time of the scan.
+    revision: u64,
 }
 
 #[derive(Debug, Serialize)]
@@ -89,7 +215,17 @@ struct PreflightResponse {
     udisksctl: bool,
     mkfs_exfat: bool,
     pkexec: bool,
+    /// Needed for encrypted formats.
+    cryptsetup: bool,
     udisksctl_format: bool,
+    /// Whether a polkit authentication agent appears to be running (needed for mount/format prompts).
+    polkit_agent: bool,
+    /// Whether a restore can keep original ownership: the agent is root, or pkexec and a polkit
+    /// agent are available to ask for authorization.
+    privileged_restore: bool,
+    /// Why the configured `restic_path` is being ignored (missing, not a file, not executable);
+    /// `restic` then reflects the bundled binary or PATH.
+    restic_path_error: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -103,6 +239,21 @@ struct ConfigSummary {
     auto_backup_on_insert: bool,
     remember_passphrase: bool,
     paranoid_mode: bool,
+    performance_profile: PerformanceProfile,
+    performance_overrides: PerformanceOverrides,
+    notifications_enabled: bool,
+    notification_app_name: String,
+    notification_icon: Option<String>,
+    notification_locale: Option<String>,
+    skip_unavailable_sources: bool,
+    /// Host name new snapshots are recorded under (the configured one, else this machine's).
+    backup_hostname: Option<String>,
+    /// History limits in effect (see `AgentConfig::history_max_entries`).
+    history_max_entries: u32,
+    history_max_age_days: u32,
+    /// KiB/s, 0 = unlimited.
+    limit_upload_kb: u32,
+    limit_download_kb: u32,
 }
 
 #[derive(Debug, Deserialize)]
@@ -116,6 +267,109 @@ struct ConfigUpdateRequest {
     auto_backup_on_insert: bool,
     remember_passphrase: bool,
     paranoid_mode: bool,
+    /// If absent, the current profile is kept.
+    #[serde(default)]
+    performance_profile: Option<PerformanceProfile>,
+    /// If absent, the current overrides are kept.
+    #[serde(default)]
+    performance_overrides: Option<PerformanceOverrides>,
+    #[serde(default)]
+    notifications_enabled: Option<bool>,
+    #[serde(default)]
+    notification_app_name: Option<String>,
+    /// Empty string clears the icon.
+    #[serde(default)]
+    notification_icon: Option<String>,
+    /// Empty string goes back to the system locale.
+    #[serde(default)]
+    notification_locale: Option<String>,
+    #[serde(default)]
+    skip_unavailable_sources: Option<bool>,
+    #[serde(default)]
+    config_backup_count: Option<u32>,
+    #[serde(default)]
+    eject_power_off_attempts: Option<u32>,
+    #[serde(default)]
+    passphrase_cache_secs: Option<u64>,
+    #[serde(default)]
+    exclude_removable_media_junk: Option<bool>,
+    #[serde(default)]
+    min_free_bytes: Option<u64>,
+    #[serde(default)]
+    min_free_percent: Option<u8>,
+    /// Empty string goes back to the machine's hostname.
+    #[serde(default)]
+    backup_hostname: Option<String>,
+    #[serde(default)]
+    refuse_empty_sources: Option<bool>,
+    #[serde(default)]
+    no_lock_reads: Option<bool>,
+    #[serde(default)]
+    verify_failure_policy: Option<VerifyFailurePolicy>,
+    #[serde(default)]
+    verify_before_backup: Option<bool>,
+    #[serde(default)]
+    history_max_entries: Option<u32>,
+    #[serde(default)]
+    history_max_age_days: Option<u32>,
+    /// KiB/s; 0 removes the limit, absent keeps the current one.
+    #[serde(default)]
+    limit_upload_kb: Option<u32>,
+    #[serde(default)]
+    limit_download_kb: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfigRestoreBackupRequest {
+    /// Backup to restore; omit to only list the available backups.
+    #[serde(default)]
+    name: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ProcessListResponse {
+    processes: Vec<TrackedProcess>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TerminateProcessRequest {
+    pid: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct TerminateProcessResponse {
+    /// "terminating": the signal was sent; the entry disappears once restic exits.
+    status: String,
+}
+
+#[derive(Debug, Serialize)]
+struct NotificationTestResponse {
+    /// One entry per notification channel, enabled or not.
+    channels: Vec<ChannelResult>,
+}
+
+#[derive(Debug, Serialize)]
+struct ConfigRestoreBackupResponse {
+    /// Available backups, newest first.
+    backups: Vec<String>,
+    /// The backup that was restored, if one was requested.
+    restored: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ScheduleResponse {
+    #[serde(flatten)]
+    schedule: ScheduleConfig,
+    next_run_epoch: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct ConfigUpdateResponse {
+    #[serde(flatten)]
+    status: StatusResponse,
+    /// Stored passphrases deleted because paranoid mode was turned on (auto-backup needs manual
+    /// entry again).
+    passphrases_removed: usize,
 }
 
 #[derive(Debug, Deserialize)]
@@ -127,34 +381,81 @@ struct SetupDriveRequest {
     passphrase: String,
     remember_passphrase: bool,
     paranoid_mode: bool,
+    /// If true, back up and restore a small test file right after setup to prove restore works.
+    #[serde(default)]
+    self_test: bool,
+    /// Replace an existing trusted drive on the same physical media (matched by serial) instead of refusing.
+    #[serde(default)]
+    replace_existing: bool,
 }
 
 #[derive(Debug, Serialize)]
 struct SetupDriveResponse {
     drive_id: String,
     repository_id: String,
+    /// Present when a self-test was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    self_test: Option<SelfTestResult>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EnsureDriveRequest {
+    mount_path: String,
+    passphrase: String,
+    /// Name for a drive that isn't set up yet; an existing drive keeps its name.
+    label: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct EnsureDriveResponse {
+    drive_id: String,
+    repository_id: String,
+    label: String,
 }
 
 #[derive(Debug, Deserialize)]
 struct BackupRequest {
     drive_id: String,
     passphrase: Option<String>,
+    /// Only report what the backup would add; no snapshot is created.
+    #[serde(default)]
+    dry_run: bool,
 }
 
 #[derive(Debug, Serialize)]
 struct BackupStartResponse {
     status: String,
+    /// True if the drive has no snapshots yet, so this backup copies everything and takes longest.
+    is_first_backup: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct EjectResponse {
+    /// "ejected", or "safe_to_remove" when the drive was unmounted but could not be powered off.
+    status: String,
+    powered_off: bool,
 }
 
 #[derive(Debug, Deserialize)]
 struct SnapshotsRequest {
     drive_id: String,
     passphrase: Option<String>,
+    /// Number of snapshots to skip (after ordering).
+    #[serde(default)]
+    offset: usize,
+    /// Max snapshots to return; None returns all remaining.
+    #[serde(default)]
+    limit: Option<usize>,
+    /// Order newest first instead of restic's oldest-first order.
+    #[serde(default)]
+    newest_first: bool,
 }
 
 #[derive(Debug, Serialize)]
 struct SnapshotsResponse {
     snapshots: Vec<crate::restic::SnapshotInfo>,
+    /// Total snapshots in the repository, regardless of offset/limit.
+    total: usize,
 }
 
 #[derive(Debug, Deserialize)]
@@ -170,6 +471,33 @@ struct SnapshotStatsResponse {
     total_file_count: u64,
 }
 
+#[derive(Debug, Deserialize)]
+struct SnapshotDescriptionRequest {
+    drive_id: String,
+    snapshot_id: String,
+    /// Sanitized like labels; empty clears the description.
+    #[serde(default)]
+    description: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct SnapshotDescriptionResponse {
+    snapshot_id: String,
+    description: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SnapshotProtectRequest {
+    drive_id: String,
+    snapshot_id: String,
+}
+
+#[derive(Debug, Serialize)]
+struct SnapshotProtectResponse {
+    snapshot_id: String,
+    protected: bool,
+}
+
 #[derive(Debug, Deserialize)]
 struct RestoreRequest {
     drive_id: String,
@@ -177,11 +505,93 @@ struct RestoreRequest {
     target_path: String,
     include_paths: Vec<String>,
     passphrase: Option<String>,
+    /// Restore as root so files keep their original owner, group and permissions.
+    #[serde(default)]
+    preserve_ownership: bool,
+    /// Must be true with `preserve_ownership`: the user agreed to run restic as root.
+    #[serde(default)]
+    confirm_privileged: bool,
 }
 
 #[derive(Debug, Serialize)]
 struct RestoreResponse {
+    /// "completed" or "cancelled".
+    status: String,
+    /// When cancelled: files that were already written under the target (may need cleanup or a retry).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    partial_files: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RestoreCancelRequest {
+    drive_id: String,
+}
+
+#[derive(Debug, Serialize)]
+struct CancelWaitingResponse {
+    /// "cancelled".
+    status: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct EstimateRequest {
+    drive_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReverifyRequest {
+    drive_id: String,
+    passphrase: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ReverifyResponse {
+    /// "passed" or "failed".
+    status: String,
+    duration_secs: u64,
+    /// Why it failed (paths redacted); None when it passed.
+    message: Option<String>,
+    /// True if the last run went from Partial to Success.
+    last_run_upgraded: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct VerifyRequest {
+    drive_id: String,
+    mode: VerifyMode,
+    passphrase: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RepairRequest {
+    drive_id: String,
+    passphrase: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct VerifyResponse {
+    mode: VerifyMode,
+    /// "passed" or "failed".
+    status: String,
+    duration_secs: u64,
+    /// Why it failed (paths redacted); None when it passed.
+    message: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportRequest {
+    drive_id: String,
+    snapshot_id: String,
+    /// Absolute path of the archive to create; `.tar`, `.tar.gz` or `.tgz`.
+    target_path: String,
+    passphrase: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ExportResponse {
+    /// "completed", "cancelled" or "cancelling".
     status: String,
+    bytes_written: u64,
 }
 
 #[derive(Debug, Deserialize)]
@@ -195,6 +605,25 @@ struct RecoveryKitResponse {
     status: String,
 }
 
+#[derive(Debug, Deserialize)]
+struct RepoIdentifyRequest {
+    mount_path: String,
+    /// repository_id from the recovery kit.
+    expected_repository_id: String,
+    /// repository_path from the recovery kit (relative to the mount); defaults to ".aegis/repo".
+    repository_path: Option<String>,
+    /// If given, the ID is read from the restic repository itself instead of the drive marker.
+    passphrase: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct RepoIdentifyResponse {
+    result: RepoIdMatch,
+    /// Where the drive's ID came from: "restic" (authenticated) or "marker" (unauthenticated hint).
+    source: Option<String>,
+    repository_id: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 struct EjectRequest {
     mount_path: String,
@@ -217,54 +646,149 @@ struct UpdateDriveRequest {
     label: Option<String>,
     /// New backup sources for this drive only; if absent, leave unchanged.
     backup_sources: Option<Vec<BackupSource>>,
+    /// Patterns added to the global include/exclude lists for this drive; if absent, leave
+    /// unchanged. An empty list removes the drive's own patterns.
+    include_patterns: Option<Vec<String>>,
+    exclude_patterns: Option<Vec<String>>,
 }
 
 #[derive(Debug, Deserialize)]
 struct FormatRequest {
     devnode: String,
+    /// Format `devnode` in place (default) or repartition the whole disk first.
+    #[serde(default)]
+    target: devices::FormatTarget,
     /// Ignored: disk volume label is always aegis-xxxxxxxx; in-app name is stored in the marker on the drive.
     #[allow(dead_code)]
     label: Option<String>,
+    /// Encrypt the partition with LUKS and put the exFAT file system inside it.
+    #[serde(default)]
+    encryption: Option<devices::LuksParams>,
 }
 
 #[derive(Debug, Serialize)]
 struct FormatResponse {
     status: String,
+    /// Encrypted formats: where the unlocked file system is mounted, ready for setup.
+    mount_path: Option<String>,
+}
+
+/// Local address the IPC server listens on (also used by the CLI).
+pub const LISTEN_ADDR: &str = "127.0.0.1:7878";
+
+/// Interval of the keep-alive comment on `/v1/events` when nothing else is sent.
+const EVENT_HEARTBEAT: Duration = Duration::from_secs(15);
+
+/// Upper bound accepted for `history_max_entries` over IPC.
+const HISTORY_MAX_ENTRIES_LIMIT: u32 = 1000;
+
+/// Take the IPC port. Every profile uses the same one, so this fails while another agent runs.
+pub async fn bind() -> anyhow::Result<tokio::net::TcpListener> {
+    tokio::net::TcpListener::bind(LISTEN_ADDR)
+        .await
+        .map_err(|err| match err.kind() {
+            std::io::ErrorKind::AddrInUse => anyhow::anyhow!(
+                "{} is in use; another Aegis agent is probably running (only one profile can run at a time)",
+                LISTEN_ADDR
+            ),
+            _ => anyhow::Error::new(err).context(format!("listen on {}", LISTEN_ADDR)),
+        })
 }
 
-pub async fn serve(state: SharedState) -> anyhow::Result<()> {
+pub async fn serve(state: SharedState, listener: tokio::net::TcpListener) -> anyhow::Result<()> {
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods(Any)
         .allow_headers(Any);
     let app = Router::new()
         .route("/v1/status", get(get_status))
+        .route("/v1/events", get(events))
         .route("/v1/devices", get(list_devices))
         .route("/v1/preflight", get(preflight))
         .route("/v1/config", post(update_config))
+        .route("/v1/config/restore-backup", post(restore_config_backup))
+        .route("/v1/schedule", get(get_schedule).post(update_schedule))
+        .route("/v1/notifications/test", post(test_notifications))
+        .route("/v1/support/last-errors", get(get_last_errors))
+        .route("/v1/support-bundle", get(get_support_bundle))
+        .route("/v1/debug/processes", get(list_processes))
+        .route("/v1/debug/processes/terminate", post(terminate_process))
         .route("/v1/drives/setup", post(setup_drive))
+        .route("/v1/drives/ensure", post(ensure_drive))
         .route("/v1/drives/mount", post(mount_drive))
         .route("/v1/drives/format", post(format_drive))
         .route("/v1/backup/run", post(start_backup))
+        .route("/v1/backup/cancel-waiting", post(cancel_waiting_backup))
+        .route("/v1/backup/reverify", post(reverify_last_backup))
+        .route("/v1/backup/estimate", post(estimate_backup))
         .route("/v1/snapshots", post(list_snapshots))
         .route("/v1/snapshots/stats", post(snapshot_stats))
+        .route("/v1/snapshots/description", post(set_snapshot_description))
+        .route(
+            "/v1/snapshots/description/clear",
+            post(clear_snapshot_description),
+        )
+        .route("/v1/snapshots/protect", post(protect_snapshot))
+        .route("/v1/snapshots/unprotect", post(unprotect_snapshot))
         .route("/v1/restore", post(restore_snapshot))
+        .route("/v1/restore/cancel", post(cancel_restore))
+        .route("/v1/snapshots/export", post(export_snapshot))
+        .route("/v1/snapshots/export/cancel", post(cancel_export))
         .route("/v1/recovery-kit", post(export_recovery))
+        .route("/v1/repo/identify", post(identify_repo))
+        .route("/v1/repo/verify", post(verify_repo))
+        .route("/v1/repair", post(repair_repo))
         .route("/v1/drives/eject", post(eject_drive))
         .route("/v1/drives/discontinue", post(discontinue_drive))
+        .route(
+            "/v1/drives/discontinue/preview",
+            post(preview_discontinue_drive),
+        )
         .route("/v1/drives/update", post(update_drive))
         .with_state(state)
         .layer(cors);
 
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:7878").await?;
     axum::serve(listener, app).await?;
     Ok(())
 }
 
+/// Server-sent backup progress, phase changes and completions (see `AgentEvent`). The
+/// subscription ends when the client disconnects and axum drops the stream.
+async fn events(
+    State(state): State<SharedState>,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let rx = {
+        let guard = state.read().await;
+        let rx = guard.events.subscribe();
+        debug!(
+            "events: subscriber connected ({} open)",
+            guard.events.subscriber_count()
+        );
+        rx
+    };
+    let stream = futures_util::stream::unfold(rx, |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) => {
+                    let sse = Event::default().event(event.name()).json_data(&event);
+                    return Some((sse, rx));
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    debug!("events: subscriber lagged, skipped {} event(s)", skipped);
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    Sse::new(stream).keep_alive(KeepAlive::new().interval(EVENT_HEARTBEAT))
+}
+
 async fn list_devices(
-    State(_state): State<SharedState>,
+    State(state): State<SharedState>,
 ) -> Result<Json<DevicesResponse>, (StatusCode, String)> {
     debug!("list_devices: request");
+    // Read before scanning, so a device arriving mid-scan bumps the revision past this one.
+    let revision = state.read().await.devices_revision;
     let devices = devices::list_removable_devices().map_err(|e| {
         tracing::error!("list_devices: scan failed error={}", e);
         (
@@ -273,31 +797,101 @@ async fn list_devices(
         )
     })?;
     debug!("list_devices: returning {} device(s)", devices.len());
-    Ok(Json(DevicesResponse { devices }))
+    Ok(Json(DevicesResponse { devices, revision }))
 }
 
 async fn preflight(State(state): State<SharedState>) -> Json<PreflightResponse> {
-    let guard = state.read().await;
-    let restic = Restic::resolve(guard.config.restic_path.as_deref()).is_ok();
+    let config = state.read().await.config.clone();
+    Json(preflight_checks(&config))
+}
+
+fn preflight_checks(config: &AgentConfig) -> PreflightResponse {
+    let restic = Restic::resolve(config.restic_path.as_deref()).is_ok();
     let lsblk = which::which("lsblk").is_ok();
     let udisksctl = which::which("udisksctl").is_ok();
     let mkfs_exfat = which::which("mkfs.exfat").is_ok() || which::which("mkfs.exfatfs").is_ok();
     let pkexec = which::which("pkexec").is_ok();
     let udisksctl_format = devices::udisksctl_supports_format();
-    Json(PreflightResponse {
+    let polkit_agent = devices::polkit_agent_running();
+    let privileged_restore =
+        restore_elevation(true, devices::running_as_root(), pkexec, polkit_agent).is_ok();
+    PreflightResponse {
         restic,
         lsblk,
         udisksctl,
         mkfs_exfat,
         pkexec,
+        cryptsetup: devices::cryptsetup_available(),
         udisksctl_format,
+        polkit_agent,
+        privileged_restore,
+        restic_path_error: config.restic_path_error(),
+    }
+}
+
+/// A redacted zip of logs, config and status to attach to a bug report (see `support.rs`).
+async fn get_support_bundle(
+    State(state): State<SharedState>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let config = state.read().await.config.clone();
+    let restic_version = match Restic::resolve(config.restic_path.as_deref()) {
+        Ok(restic) => restic.version().await.ok().map(|v| v.to_string()),
+        Err(_) => None,
+    };
+    let (preflight, log_lines) = tokio::task::spawn_blocking(move || {
+        (
+            preflight_checks(&config),
+            logging::recent_log_lines(support::LOG_LINES),
+        )
     })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let inputs = support::BundleInputs {
+        agent_version: env!("CARGO_PKG_VERSION").to_string(),
+        generated_epoch: now_epoch(),
+        restic_version,
+        preflight: serde_json::to_value(preflight).unwrap_or_default(),
+        log_lines,
+    };
+    let bundle = support::build_bundle(&*state.read().await, &inputs).map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("support bundle failed: {}", e),
+        )
+    })?;
+    let filename = format!("aegis-support-{}.zip", inputs.generated_epoch);
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", filename),
+            ),
+        ],
+        bundle,
+    ))
 }
 
 async fn get_status(State(state): State<SharedState>) -> Json<StatusResponse> {
+    // Resolving symlinks touches the filesystem, so it happens off the runtime and outside the
+    // lock; a source edited in between just shows its target on the next poll.
+    let drive_sources: Vec<(String, Vec<BackupSource>)> = {
+        let guard = state.read().await;
+        let config = &guard.config;
+        config
+            .trusted_drives
+            .keys()
+            .map(|id| (id.clone(), config.backup_sources_for_drive(id)))
+            .collect()
+    };
+    let mut link_targets = tokio::task::spawn_blocking(move || source_link_targets(&drive_sources))
+        .await
+        .unwrap_or_default();
+    let offset_secs = schedule::local_offset_secs().await;
     let guard = state.read().await;
     let config = &guard.config;
     let restic_available = Restic::resolve(config.restic_path.as_deref()).is_ok();
+    let now = now_epoch();
     let summary = ConfigSummary {
         backup_sources: config
             .backup_sources
@@ -312,19 +906,32 @@ async fn get_status(State(state): State<SharedState>) -> Json<StatusResponse> {
         auto_backup_on_insert: config.auto_backup_on_insert,
         remember_passphrase: config.remember_passphrase,
         paranoid_mode: config.paranoid_mode,
+        performance_profile: config.performance_profile,
+        performance_overrides: config.performance_overrides.clone(),
+        notifications_enabled: config.notifications_enabled,
+        notification_app_name: config.notification_app_name.clone(),
+        notification_icon: config.notification_icon.clone(),
+        notification_locale: config.notification_locale.clone(),
+        skip_unavailable_sources: config.skip_unavailable_sources,
+        backup_hostname: config.backup_hostname.clone().or_else(system_hostname),
+        history_max_entries: config.history_max_entries.max(1),
+        history_max_age_days: config.history_max_age_days,
+        limit_upload_kb: config.limit_upload_kb,
+        limit_download_kb: config.limit_download_kb,
     };
     let current_drive_id = guard.drive_status.drive_id.as_ref();
     let trusted_drives: Vec<TrustedDriveSummary> = config
         .trusted_drives
         .iter()
         .map(|(id, d)| {
-            let label = d.label.clone().unwrap_or_else(|| {
-                format!("drive-{}", d.drive_id.chars().take(8).collect::<String>())
-            });
+            let label = d.display_label();
             let is_connected = current_drive_id == Some(id) && guard.drive_status.connected;
             let sources = config.backup_sources_for_drive(id);
             let backup_source_labels = sources.iter().map(|s| s.label.clone()).collect();
             let backup_sources = sources;
+            let last_sample = d.free_space_history.last();
+            let backups_until_full = project_backups_until_full(&d.free_space_history);
+            let snapshot_stats = guard.snapshot_stats.get(id);
             TrustedDriveSummary {
                 drive_id: id.clone(),
                 label,
@@ -332,26 +939,119 @@ async fn get_status(State(state): State<SharedState>) -> Json<StatusResponse> {
                 last_backup_epoch: d.last_backup_epoch,
                 backup_source_labels,
                 backup_sources,
+                source_link_targets: link_targets.remove(id).unwrap_or_default(),
+                repository_changed: guard.repository_mismatch_drive_ids.contains(id),
+                repository_id: d.repository_id.clone(),
+                uses_global_sources: config.drive_uses_global_sources(id),
+                include_patterns: d.include_patterns.clone(),
+                exclude_patterns: d.exclude_patterns.clone(),
+                restic_too_old: guard.restic_too_old_drive_ids.contains(id),
+                total_data_added: d.total_data_added,
+                free_bytes: last_sample.map(|s| s.free_bytes),
+                total_bytes: last_sample.map(|s| s.total_bytes),
+                backups_until_full,
+                space_warning: is_space_warning(backups_until_full, config.space_warning_backups),
+                average_backup_secs: durations::average_duration(&d.backup_durations_secs),
+                passphrase_cache_expires_epoch: guard.passphrase_cache_expires(id, now),
+                last_error: guard.last_errors.get(id).cloned(),
+                auto_backup: guard.auto_backup.get(id).cloned(),
+                snapshot_count: snapshot_stats.map(|s| s.snapshot_count),
+                oldest_snapshot_epoch: snapshot_stats.and_then(|s| s.oldest_snapshot_epoch),
+                newest_snapshot_epoch: snapshot_stats.and_then(|s| s.newest_snapshot_epoch),
             }
         })
         .collect();
     Json(StatusResponse {
         first_run: config.is_first_run(),
-        drive: guard.drive_status.clone(),
+        drive: guard.drive_status_view(),
         last_run: guard.last_run.clone(),
         running: !guard.running_drive_ids.is_empty(),
         running_drive_ids: guard.running_drive_ids.iter().cloned().collect(),
+        keyring_waiting_drive_ids: guard.keyring_waiting_drive_ids.iter().cloned().collect(),
         restic_available,
         config: summary,
         trusted_drives,
         backup_progress: guard.backup_progress.clone(),
+        verify_progress: guard.verify_progress.clone(),
+        export_progress: guard.export_progress.clone(),
+        attention: collect_attention(&guard, restic_available, now),
+        devices_revision: guard.devices_revision,
+        next_scheduled_backup_epoch: schedule::next_run(&config.schedule.entries, now, offset_secs),
     })
 }
 
+fn schedule_response(schedule: &ScheduleConfig, offset_secs: i64) -> ScheduleResponse {
+    ScheduleResponse {
+        schedule: schedule.clone(),
+        next_run_epoch: schedule::next_run(&schedule.entries, now_epoch(), offset_secs),
+    }
+}
+
+async fn get_schedule(State(state): State<SharedState>) -> Json<ScheduleResponse> {
+    let offset_secs = schedule::local_offset_secs().await;
+    Json(schedule_response(
+        &state.read().await.config.schedule,
+        offset_secs,
+    ))
+}
+
+/// Replace the backup schedule. Duplicate entries are dropped.
+async fn update_schedule(
+    State(state): State<SharedState>,
+    Json(mut req): Json<ScheduleConfig>,
+) -> Result<Json<ScheduleResponse>, (StatusCode, String)> {
+    if let Some(bad) = req.entries.iter().find(|e| !e.is_valid()) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "invalid schedule time {:02}:{:02}; hour must be 0-23 and minute 0-59",
+                bad.hour, bad.minute
+            ),
+        ));
+    }
+    let mut entries = Vec::new();
+    for entry in req.entries {
+        if !entries.contains(&entry) {
+            entries.push(entry);
+        }
+    }
+    if entries.len() > MAX_SCHEDULE_ENTRIES {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "a schedule can have at most {} entries",
+                MAX_SCHEDULE_ENTRIES
+            ),
+        ));
+    }
+    req.entries = entries;
+    debug!(
+        "schedule: update entries={} catch_up_missed={}",
+        req.entries.len(),
+        req.catch_up_missed
+    );
+    let offset_secs = schedule::local_offset_secs().await;
+    let mut guard = state.write().await;
+    guard.config.schedule = req;
+    guard.config.save_with_backup().map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "config save failed".to_string(),
+        )
+    })?;
+    Ok(Json(schedule_response(&guard.config.schedule, offset_secs)))
+}
+
 async fn update_config(
     State(state): State<SharedState>,
     Json(req): Json<ConfigUpdateRequest>,
-) -> Result<Json<StatusResponse>, (StatusCode, String)> {
+) -> Result<Json<ConfigUpdateResponse>, (StatusCode, String)> {
+    let notification_icon = req
+        .notification_icon
+        .as_deref()
+        .map(validate_notification_icon)
+        .transpose()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
     let mut guard = state.write().await;
     guard.config.backup_sources = req
         .backup_sources
@@ -359,6 +1059,8 @@ async fn update_config(
         .map(|s| BackupSource {
             label: sanitize_label(&s.label).unwrap_or_else(|| "Source".to_string()),
             path: s.path,
+            follow_symlink: s.follow_symlink,
+            exclude_patterns: sanitize_patterns(&s.exclude_patterns),
         })
         .collect();
     guard.config.include_patterns = req.include_patterns;
@@ -368,25 +1070,250 @@ async fn update_config(
     guard.config.deep_verify = req.deep_verify;
     guard.config.auto_backup_on_insert = req.auto_backup_on_insert;
     guard.config.remember_passphrase = req.remember_passphrase;
+    let was_paranoid = guard.config.paranoid_mode;
     guard.config.paranoid_mode = req.paranoid_mode;
-    guard.config.enforce_security_invariants();
-
-    if guard.config.paranoid_mode {
-        for drive_id in guard.config.trusted_drives.keys() {
-            let _ = keychain::delete_passphrase(drive_id);
-        }
+    if let Some(profile) = req.performance_profile {
+        guard.config.performance_profile = profile;
     }
-
-    // Avoid returning raw errors to the UI to prevent leaking paths.
-    guard.config.save().map_err(|_| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "config save failed".to_string(),
-        )
-    })?;
-    drop(guard);
-    Ok(get_status(State(state)).await)
-}
+    if let Some(overrides) = req.performance_overrides {
+        guard.config.performance_overrides = overrides;
+    }
+    if let Some(enabled) = req.notifications_enabled {
+        guard.config.notifications_enabled = enabled;
+    }
+    if let Some(name) = req
+        .notification_app_name
+        .as_deref()
+        .and_then(sanitize_label)
+    {
+        guard.config.notification_app_name = name;
+    }
+    if let Some(icon) = notification_icon {
+        guard.config.notification_icon = icon;
+    }
+    if let Some(locale) = req.notification_locale {
+        guard.config.notification_locale = sanitize_label(&locale);
+    }
+    if let Some(skip) = req.skip_unavailable_sources {
+        guard.config.skip_unavailable_sources = skip;
+    }
+    if let Some(count) = req.config_backup_count {
+        guard.config.config_backup_count = count;
+    }
+    if let Some(attempts) = req.eject_power_off_attempts {
+        guard.config.eject_power_off_attempts = attempts.clamp(1, 10);
+    }
+    if let Some(secs) = req.passphrase_cache_secs {
+        guard.config.passphrase_cache_secs = secs;
+    }
+    if let Some(exclude) = req.exclude_removable_media_junk {
+        guard.config.exclude_removable_media_junk = exclude;
+    }
+    if let Some(bytes) = req.min_free_bytes {
+        guard.config.min_free_bytes = bytes;
+    }
+    if let Some(percent) = req.min_free_percent {
+        guard.config.min_free_percent = percent.min(50);
+    }
+    if let Some(hostname) = req.backup_hostname {
+        guard.config.backup_hostname = sanitize_label(&hostname);
+    }
+    if let Some(refuse) = req.refuse_empty_sources {
+        guard.config.refuse_empty_sources = refuse;
+    }
+    if let Some(no_lock) = req.no_lock_reads {
+        guard.config.no_lock_reads = no_lock;
+    }
+    if let Some(policy) = req.verify_failure_policy {
+        guard.config.verify_failure_policy = policy;
+    }
+    if let Some(verify) = req.verify_before_backup {
+        guard.config.verify_before_backup = verify;
+    }
+    if let Some(entries) = req.history_max_entries {
+        guard.config.history_max_entries = entries.clamp(1, HISTORY_MAX_ENTRIES_LIMIT);
+    }
+    if let Some(days) = req.history_max_age_days {
+        guard.config.history_max_age_days = days;
+    }
+    if let Some(kb) = req.limit_upload_kb {
+        guard.config.limit_upload_kb = kb;
+    }
+    if let Some(kb) = req.limit_download_kb {
+        guard.config.limit_download_kb = kb;
+    }
+    guard.config.enforce_security_invariants();
+    if guard.config.paranoid_mode || guard.config.passphrase_cache_secs == 0 {
+        guard.passphrase_cache.clear();
+    }
+
+    // Paranoid mode never keeps passphrases: when it is turned on, remove the stored ones.
+    let purge = (!was_paranoid && guard.config.paranoid_mode).then(|| {
+        let drives = &guard.config.trusted_drives;
+        let drive_ids: Vec<String> = drives.keys().cloned().collect();
+        let luks_uuids: Vec<String> = drives
+            .values()
+            .filter_map(|d| d.luks_uuid.clone())
+            .collect();
+        (drive_ids, luks_uuids)
+    });
+
+    // Avoid returning raw errors to the UI to prevent leaking paths.
+    guard.config.save_with_backup().map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "config save failed".to_string(),
+        )
+    })?;
+    drop(guard);
+
+    // Report how many were removed so the UI can tell the user.
+    let passphrases_removed = match purge {
+        Some((drive_ids, luks_uuids)) => tokio::task::spawn_blocking(move || {
+            keychain::purge_passphrases(&drive_ids) + keychain::purge_luks_passphrases(&luks_uuids)
+        })
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?,
+        None => 0,
+    };
+    if passphrases_removed > 0 {
+        tracing::info!(
+            "update config: paranoid mode removed {} stored passphrase(s)",
+            passphrases_removed
+        );
+    }
+    let Json(status) = get_status(State(state)).await;
+    Ok(Json(ConfigUpdateResponse {
+        status,
+        passphrases_removed,
+    }))
+}
+
+async fn restore_config_backup(
+    State(state): State<SharedState>,
+    Json(req): Json<ConfigRestoreBackupRequest>,
+) -> Result<Json<ConfigRestoreBackupResponse>, (StatusCode, String)> {
+    let list = || {
+        AgentConfig::list_backups().map_err(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "config backups unavailable".to_string(),
+            )
+        })
+    };
+    let Some(name) = req.name else {
+        return Ok(Json(ConfigRestoreBackupResponse {
+            backups: list()?,
+            restored: None,
+        }));
+    };
+    if !list()?.contains(&name) {
+        return Err((StatusCode::NOT_FOUND, "config backup not found".to_string()));
+    }
+    let restored = AgentConfig::load_backup(&name).map_err(|e| {
+        tracing::error!("restore config backup: load failed error={}", e);
+        (
+            StatusCode::BAD_REQUEST,
+            "config backup could not be read".to_string(),
+        )
+    })?;
+    let mut guard = state.write().await;
+    if !guard.running_drive_ids.is_empty() {
+        return Err((
+            StatusCode::CONFLICT,
+            "cannot restore config while a backup is running".to_string(),
+        ));
+    }
+    guard.config = restored;
+    // Saving also snapshots the restored config, so the restore itself can be undone.
+    guard.config.save_with_backup().map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "config save failed".to_string(),
+        )
+    })?;
+    drop(guard);
+    tracing::info!("restore config backup: restored {}", name);
+    Ok(Json(ConfigRestoreBackupResponse {
+        backups: list()?,
+        restored: Some(name),
+    }))
+}
+
+async fn test_notifications(
+    State(state): State<SharedState>,
+) -> Result<Json<NotificationTestResponse>, (StatusCode, String)> {
+    let settings = NotificationSettings::from_config(&state.read().await.config);
+    let channels = tokio::task::spawn_blocking(move || notifications::send_test(&settings))
+        .await
+        .map_err(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "notification test failed".to_string(),
+            )
+        })?;
+    Ok(Json(NotificationTestResponse { channels }))
+}
+
+/// This machine's hostname (what restic records when no `backup_hostname` is set).
+fn system_hostname() -> Option<String> {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+}
+
+/// Debug endpoints answer 404 unless `debug_endpoints` is set in the config file.
+async fn require_debug_endpoints(state: &SharedState) -> Result<(), (StatusCode, String)> {
+    if state.read().await.config.debug_endpoints {
+        Ok(())
+    } else {
+        Err((StatusCode::NOT_FOUND, "not found".to_string()))
+    }
+}
+
+async fn list_processes(
+    State(state): State<SharedState>,
+) -> Result<Json<ProcessListResponse>, (StatusCode, String)> {
+    require_debug_endpoints(&state).await?;
+    Ok(Json(ProcessListResponse {
+        processes: processes::registry().list(),
+    }))
+}
+
+async fn terminate_process(
+    State(state): State<SharedState>,
+    Json(req): Json<TerminateProcessRequest>,
+) -> Result<Json<TerminateProcessResponse>, (StatusCode, String)> {
+    require_debug_endpoints(&state).await?;
+    let outcome = tokio::task::spawn_blocking(move || {
+        processes::registry().terminate(req.pid, processes::send_terminate)
+    })
+    .await
+    .map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "terminate failed".to_string(),
+        )
+    })?;
+    match outcome {
+        None => Err((
+            StatusCode::NOT_FOUND,
+            "no restic process with this pid".to_string(),
+        )),
+        Some(false) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "could not signal the process".to_string(),
+        )),
+        Some(true) => {
+            tracing::warn!("debug: sent SIGTERM to restic pid={}", req.pid);
+            Ok(Json(TerminateProcessResponse {
+                status: "terminating".to_string(),
+            }))
+        }
+    }
+}
 
 async fn setup_drive(
     State(state): State<SharedState>,
@@ -406,7 +1333,7 @@ async fn setup_drive(
         );
         return Err((StatusCode::BAD_REQUEST, "mount path not found".to_string()));
     }
-    if resolve_device_for_mount(&mount_path).is_none() {
+    if resolve_device_for_mount(&mount_path).await.is_none() {
         tracing::warn!(
             "setup drive: mount path is not a mounted drive path={}",
             req.mount_path
@@ -416,11 +1343,18 @@ async fn setup_drive(
             "mount path is not a mounted drive".to_string(),
         ));
     }
+    if devices::is_read_only_mount_async(&mount_path).await {
+        tracing::warn!("setup drive: mount is read-only path={}", req.mount_path);
+        return Err((StatusCode::CONFLICT, devices::READ_ONLY_MESSAGE.to_string()));
+    }
     if req.passphrase.trim().is_empty() {
         tracing::warn!("setup drive: empty passphrase");
         return Err((StatusCode::BAD_REQUEST, "passphrase required".to_string()));
     }
 
+    let device = resolve_device_for_mount(&mount_path).await;
+    let (serial, luks_uuid) = drive_identity(device.clone()).await;
+
     let (final_label, backup_sources) = {
         let guard = state.read().await;
         let config = &guard.config;
@@ -439,7 +1373,13 @@ async fn setup_drive(
         let final_label = sanitize_label(&raw_label)
             .or_else(|| sanitize_label(&default_drive_label(config)))
             .unwrap_or_else(|| "backup".to_string());
-        if config.label_exists(&final_label, None) {
+        // When replacing the entry for this physical drive, its old name may be reused.
+        let replacing = serial
+            .as_deref()
+            .filter(|_| req.replace_existing)
+            .and_then(|serial| config.drive_with_serial(serial, ""))
+            .map(|d| d.drive_id.as_str());
+        if config.label_exists(&final_label, replacing) {
             return Err((
                 StatusCode::BAD_REQUEST,
                 "A drive with this name already exists.".to_string(),
@@ -454,6 +1394,8 @@ async fn setup_drive(
                     .map(|s| BackupSource {
                         label: sanitize_label(&s.label).unwrap_or_else(|| "Source".to_string()),
                         path: s.path,
+                        follow_symlink: s.follow_symlink,
+                        exclude_patterns: sanitize_patterns(&s.exclude_patterns),
                     })
                     .collect()
             });
@@ -470,94 +1412,43 @@ async fn setup_drive(
         })?
     };
 
-    debug!("setup drive: reading/writing marker at mount_path");
-    let mut marker = read_marker(&mount_path).map_err(|e| {
-        tracing::error!(
-            "setup drive: marker read failed path={} error={}",
-            req.mount_path,
-            e
-        );
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("marker read failed: {}", e),
-        )
-    })?;
-    let marker = match marker.take() {
-        Some(marker) => {
-            debug!(
-                "setup drive: found existing marker drive_id={}",
-                marker.drive_id
-            );
-            marker
-        }
-        None => {
-            let marker = DriveMarker::new(Some(final_label.clone()));
-            write_marker(&mount_path, &marker).map_err(|e| {
-                tracing::error!(
-                    "setup drive: marker write failed path={} error={}",
-                    req.mount_path,
-                    e
-                );
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("marker write failed: {}", e),
-                )
-            })?;
-            debug!("setup drive: wrote new marker drive_id={}", marker.drive_id);
-            marker
+    // The marker is written last, once the drive passed every check and has a repository, so a
+    // refused setup leaves nothing behind on the drive.
+    let marker = read_drive_marker(&mount_path)?
+        .unwrap_or_else(|| DriveMarker::new(Some(final_label.clone())));
+
+    // The same physical drive may already be trusted under another marker (formatted twice or cloned).
+    let replaced_drive_id = match serial.as_deref() {
+        Some(serial) => {
+            let guard = state.read().await;
+            match guard.config.drive_with_serial(serial, &marker.drive_id) {
+                Some(existing) if !req.replace_existing => {
+                    tracing::warn!(
+                        "setup drive: same physical drive already set up drive_id={}",
+                        existing.drive_id
+                    );
+                    return Err((
+                        StatusCode::CONFLICT,
+                        format!(
+                            "This physical drive is already set up as \"{}\". Replace that entry to continue.",
+                            existing.display_label()
+                        ),
+                    ));
+                }
+                Some(existing) => Some(existing.drive_id.clone()),
+                None => None,
+            }
         }
+        None => None,
     };
 
     let repo_rel = ".aegis/repo".to_string();
     let repo_path = mount_path.join(&repo_rel);
-    debug!(
-        "setup drive: creating repo dir path={}",
-        repo_path.display()
-    );
-    std::fs::create_dir_all(&repo_path).map_err(|e| {
-        tracing::error!(
-            "setup drive: repo create_dir_all failed path={} error={}",
-            repo_path.display(),
-            e
-        );
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("repo create failed: {}", e),
-        )
-    })?;
-
-    let repo_id = if repo_path.join("config").exists() {
-        debug!("setup drive: existing repo config found, checking passphrase");
-        restic
-            .repository_id(&repo_path, &req.passphrase)
-            .await
-            .map_err(|e| {
-                tracing::error!("setup drive: repository_id failed error={}", e);
-                (
-                    StatusCode::BAD_REQUEST,
-                    "invalid passphrase or repo".to_string(),
-                )
-            })?
-    } else {
-        debug!(
-            "setup drive: initializing new restic repo path={}",
-            repo_path.display()
-        );
-        restic
-            .init_repo(&repo_path, &req.passphrase)
-            .await
-            .map_err(|e| {
-                tracing::error!("setup drive: init_repo failed error={}", e);
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("failed to init repo: {}", e),
-                )
-            })?
-    };
+    let (repo_id, _) = open_or_init_repo(&restic, &repo_path, &req.passphrase).await?;
 
     let mut updated_marker = marker.clone();
     updated_marker.repository_id = Some(repo_id.clone());
-    let _ = write_marker(&mount_path, &updated_marker);
+    write_drive_marker(&mount_path, &updated_marker)?;
 
     let mut guard = state.write().await;
     guard.config.remember_passphrase = req.remember_passphrase;
@@ -573,12 +1464,27 @@ async fn setup_drive(
         last_backup_epoch: None,
         last_backup_snapshot_id: None,
         backup_sources,
+        free_space_history: Vec::new(),
+        total_data_added: 0,
+        serial,
+        include_patterns: Vec::new(),
+        exclude_patterns: Vec::new(),
+        populated_sources: Vec::new(),
+        luks_uuid,
+        extra: serde_json::Map::new(),
+        backup_durations_secs: Vec::new(),
     };
+    if let Some(ref old_id) = replaced_drive_id {
+        tracing::info!("setup drive: replacing drive_id={} (same serial)", old_id);
+        guard.config.trusted_drives.remove(old_id);
+        let _ = keychain::delete_passphrase(old_id);
+    }
     guard
         .config
         .trusted_drives
         .insert(marker.drive_id.clone(), trusted);
-    guard.config.save().map_err(|_| {
+    guard.set_repository_mismatch(&marker.drive_id, false);
+    guard.config.save_with_backup().map_err(|_| {
         (
             StatusCode::INTERNAL_SERVER_ERROR,
             "config save failed".to_string(),
@@ -592,7 +1498,7 @@ async fn setup_drive(
     guard.drive_status.drive_id = Some(marker.drive_id.clone());
     guard.drive_status.label = Some(final_label);
     guard.drive_status.mount_path = Some(mount_str.clone());
-    if let Some(device) = crate::usb::resolve_device_for_mount(&mount_path) {
+    if let Some(device) = device {
         guard.drive_status.devnode = Some(device.to_string_lossy().to_string());
     }
 
@@ -608,81 +1514,353 @@ async fn setup_drive(
         marker.drive_id,
         repo_id
     );
+    let self_test = if req.self_test {
+        let result = run_restore_self_test(&restic, &repo_path, &req.passphrase).await;
+        tracing::info!("setup drive: self-test passed={}", result.passed);
+        Some(result)
+    } else {
+        None
+    };
     Ok(Json(SetupDriveResponse {
         drive_id: marker.drive_id,
         repository_id: repo_id,
+        self_test,
     }))
 }
 
-async fn discontinue_drive(
+/// Idempotent setup for scripts: trust the drive at `mount_path`, setting it up first if needed.
+/// Repeated calls with the same drive return the same body and leave the entry alone.
+async fn ensure_drive(
     State(state): State<SharedState>,
-    Json(req): Json<DiscontinueDriveRequest>,
-) -> Result<Json<StatusResponse>, (StatusCode, String)> {
-    debug!(
-        "discontinue drive: request drive_id={} wipe={}",
-        req.drive_id, req.wipe
-    );
-    let mut guard = state.write().await;
-    let drive = guard
-        .config
-        .trusted_drives
-        .get(&req.drive_id)
-        .ok_or_else(|| {
-            tracing::warn!(
-                "discontinue drive: drive not found drive_id={}",
-                req.drive_id
-            );
-            (StatusCode::NOT_FOUND, "Drive not found.".to_string())
-        })?;
-    let expected = drive.label.as_deref().unwrap_or("");
-    if expected.is_empty() {
+    Json(req): Json<EnsureDriveRequest>,
+) -> Result<Json<EnsureDriveResponse>, (StatusCode, String)> {
+    debug!("ensure drive: request mount_path={}", req.mount_path);
+    let mount_path = PathBuf::from(&req.mount_path);
+    let Some(device) = resolve_device_for_mount(&mount_path)
+        .await
+        .filter(|_| mount_path.exists())
+    else {
+        tracing::warn!(
+            "ensure drive: mount path is not a mounted drive path={}",
+            req.mount_path
+        );
         return Err((
             StatusCode::BAD_REQUEST,
-            "Drive has no label; cannot discontinue by name.".to_string(),
+            "mount path is not a mounted drive".to_string(),
         ));
+    };
+    if devices::is_read_only_mount_async(&mount_path).await {
+        return Err((StatusCode::CONFLICT, devices::READ_ONLY_MESSAGE.to_string()));
+    }
+    if req.passphrase.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "passphrase required".to_string()));
+    }
+    let (serial, luks_uuid) = drive_identity(Some(device.clone())).await;
+
+    let (label, restic) = {
+        let guard = state.read().await;
+        let label = req
+            .label
+            .as_deref()
+            .and_then(sanitize_label)
+            .unwrap_or_else(|| default_drive_label(&guard.config));
+        let restic = Restic::resolve(guard.config.restic_path.as_deref()).map_err(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "restic not available".to_string(),
+            )
+        })?;
+        (label, restic)
+    };
+
+    // A new marker is only written once the drive passed the checks below.
+    let existing_marker = read_drive_marker(&mount_path)?;
+    let marker = existing_marker
+        .clone()
+        .unwrap_or_else(|| DriveMarker::new(Some(label.clone())));
+    {
+        let guard = state.read().await;
+        let config = &guard.config;
+        if !config.trusted_drives.contains_key(&marker.drive_id) {
+            if let Some(existing) = serial
+                .as_deref()
+                .and_then(|serial| config.drive_with_serial(serial, &marker.drive_id))
+            {
+                return Err((
+                    StatusCode::CONFLICT,
+                    format!(
+                        "This physical drive is already set up as \"{}\".",
+                        existing.display_label()
+                    ),
+                ));
+            }
+            if config.label_exists(&label, None) {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    "A drive with this name already exists.".to_string(),
+                ));
+            }
+        }
     }
-    let confirmed = req.confirm_label.trim();
-    if confirmed != expected {
+
+    let repo_rel = ".aegis/repo".to_string();
+    let (repo_id, created) =
+        open_or_init_repo(&restic, &mount_path.join(&repo_rel), &req.passphrase).await?;
+    let repository_changed = |known: Option<&str>| known.is_some_and(|known| known != repo_id);
+    if repository_changed(
+        state
+            .read()
+            .await
+            .config
+            .trusted_drives
+            .get(&marker.drive_id)
+            .and_then(|d| d.repository_id.as_deref()),
+    ) {
+        tracing::warn!(
+            "ensure drive: repository changed drive_id={}",
+            marker.drive_id
+        );
         return Err((
-            StatusCode::BAD_REQUEST,
-            "Confirmation does not match. Type the drive name exactly to confirm.".to_string(),
+            StatusCode::CONFLICT,
+            "the repository on this drive has changed; set the drive up again".to_string(),
         ));
     }
-    let drive_id = req.drive_id.clone();
-    let devnode_to_wipe: Option<String> = if req.wipe {
-        if guard.drive_status.drive_id.as_deref() != Some(&drive_id)
-            || guard.drive_status.devnode.is_none()
-        {
-            return Err((
-                StatusCode::BAD_REQUEST,
-                "Drive must be connected to wipe. Plug in the drive and try again.".to_string(),
-            ));
+    if existing_marker.is_none() || marker.repository_id.as_deref() != Some(repo_id.as_str()) {
+        let mut updated_marker = marker.clone();
+        updated_marker.repository_id = Some(repo_id.clone());
+        write_drive_marker(&mount_path, &updated_marker)?;
+    }
+
+    let mut guard = state.write().await;
+    let changed = guard
+        .config
+        .ensure_trusted_drive(TrustedDrive {
+            drive_id: marker.drive_id.clone(),
+            label: Some(label),
+            repository_path: repo_rel,
+            repository_id: Some(repo_id.clone()),
+            last_seen_epoch: None,
+            last_backup_epoch: None,
+            last_backup_snapshot_id: None,
+            backup_sources: None,
+            free_space_history: Vec::new(),
+            total_data_added: 0,
+            serial,
+            include_patterns: Vec::new(),
+            exclude_patterns: Vec::new(),
+            populated_sources: Vec::new(),
+            luks_uuid,
+            extra: serde_json::Map::new(),
+            backup_durations_secs: Vec::new(),
+        })
+        .map_err(|msg| (StatusCode::CONFLICT, msg.to_string()))?;
+    if changed {
+        guard.config.save_with_backup().map_err(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "config save failed".to_string(),
+            )
+        })?;
+    }
+    let label = guard.config.trusted_drives[&marker.drive_id].display_label();
+    guard.drive_status.connected = true;
+    guard.drive_status.trusted = true;
+    guard.drive_status.drive_id = Some(marker.drive_id.clone());
+    guard.drive_status.label = Some(label.clone());
+    guard.drive_status.mount_path = Some(mount_path.to_string_lossy().to_string());
+    guard.drive_status.devnode = Some(device.to_string_lossy().to_string());
+    if guard.config.remember_passphrase {
+        if let Err(err) = keychain::store_passphrase(&marker.drive_id, &req.passphrase) {
+            error!("Keychain store failed: {}", Redact::new(err));
         }
-        guard.drive_status.devnode.clone()
-    } else {
-        None
-    };
-    guard.config.trusted_drives.remove(&drive_id);
-    guard.config.save().map_err(|_| {
+    }
+    drop(guard);
+    tracing::info!(
+        "ensure drive: drive_id={} repo_created={} config_changed={}",
+        marker.drive_id,
+        created,
+        changed
+    );
+    Ok(Json(EnsureDriveResponse {
+        drive_id: marker.drive_id,
+        repository_id: repo_id,
+        label,
+    }))
+}
+
+/// The drive's marker, if it has one.
+fn read_drive_marker(mount_path: &FsPath) -> Result<Option<DriveMarker>, (StatusCode, String)> {
+    let marker = read_marker(mount_path).map_err(|e| {
+        tracing::error!(
+            "drive marker: read failed path={} error={}",
+            mount_path.display(),
+            e
+        );
         (
             StatusCode::INTERNAL_SERVER_ERROR,
-            "config save failed".to_string(),
+            format!("marker read failed: {}", e),
         )
     })?;
-    drop(guard);
-    let _ = keychain::delete_passphrase(&drive_id);
-    if let Some(devnode) = devnode_to_wipe {
-        if let Err(e) = devices::unmount_partition(&devnode) {
-            tracing::warn!("discontinue wipe: unmount failed: {}", e);
-        }
-        if let Err(e) = devices::secure_wipe_block_device(&devnode) {
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Secure wipe failed: {}", e),
-            ));
-        }
-        let mut guard = state.write().await;
-        guard.drive_status.connected = false;
+    if let Some(marker) = &marker {
+        debug!("drive marker: found drive_id={}", marker.drive_id);
+    }
+    Ok(marker)
+}
+
+fn write_drive_marker(
+    mount_path: &FsPath,
+    marker: &DriveMarker,
+) -> Result<(), (StatusCode, String)> {
+    write_marker(mount_path, marker).map_err(|e| {
+        tracing::error!(
+            "drive marker: write failed path={} error={}",
+            mount_path.display(),
+            e
+        );
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("marker write failed: {}", e),
+        )
+    })?;
+    debug!("drive marker: wrote drive_id={}", marker.drive_id);
+    Ok(())
+}
+
+/// Open the repository at `repo_path`, or initialize one if there is none yet. Returns its ID
+/// and whether it was just created.
+async fn open_or_init_repo(
+    restic: &Restic,
+    repo_path: &FsPath,
+    passphrase: &str,
+) -> Result<(String, bool), (StatusCode, String)> {
+    debug!("drive repo: creating repo dir path={}", repo_path.display());
+    tokio::fs::create_dir_all(repo_path).await.map_err(|e| {
+        tracing::error!(
+            "drive repo: create_dir_all failed path={} error={}",
+            repo_path.display(),
+            e
+        );
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("repo create failed: {}", e),
+        )
+    })?;
+
+    // restic decides whether a repository exists; a config file check can misfire on a freshly
+    // formatted drive and lead to a second init.
+    match restic.repository_id(repo_path, passphrase).await {
+        Ok(repo_id) => {
+            debug!("drive repo: existing repo opened with the passphrase");
+            return Ok((repo_id, false));
+        }
+        Err(e) if is_repository_missing(&e) => {}
+        Err(e) => {
+            tracing::error!("drive repo: repository_id failed error={}", e);
+            if is_wrong_password(&e) {
+                return Err((
+                    StatusCode::UNAUTHORIZED,
+                    "wrong passphrase for existing repository".to_string(),
+                ));
+            }
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "invalid passphrase or repo".to_string(),
+            ));
+        }
+    }
+    debug!(
+        "drive repo: initializing new restic repo path={}",
+        repo_path.display()
+    );
+    let repo_id = restic.init_repo(repo_path, passphrase).await.map_err(|e| {
+        tracing::error!("drive repo: init_repo failed error={}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed to init repo: {}", e),
+        )
+    })?;
+    Ok((repo_id, true))
+}
+
+async fn discontinue_drive(
+    State(state): State<SharedState>,
+    Json(req): Json<DiscontinueDriveRequest>,
+) -> Result<Json<StatusResponse>, (StatusCode, String)> {
+    debug!(
+        "discontinue drive: request drive_id={} wipe={} after_wipe={:?}",
+        req.drive_id, req.wipe, req.after_wipe
+    );
+    let mut guard = state.write().await;
+    let drive = guard
+        .config
+        .trusted_drives
+        .get(&req.drive_id)
+        .ok_or_else(|| {
+            tracing::warn!(
+                "discontinue drive: drive not found drive_id={}",
+                req.drive_id
+            );
+            (StatusCode::NOT_FOUND, "Drive not found.".to_string())
+        })?;
+    if !drive.confirms_removal(&req.confirm_label) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Confirmation does not match. Type the drive name exactly to confirm.".to_string(),
+        ));
+    }
+    let drive_id = req.drive_id.clone();
+    let devnode_to_wipe: Option<String> = if req.wipe {
+        if guard.drive_status.drive_id.as_deref() != Some(&drive_id)
+            || guard.drive_status.devnode.is_none()
+        {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Drive must be connected to wipe. Plug in the drive and try again.".to_string(),
+            ));
+        }
+        guard.drive_status.devnode.clone()
+    } else {
+        None
+    };
+    let removed = guard.config.trusted_drives.remove(&drive_id);
+    guard.passphrase_cache.remove(&drive_id);
+    guard.config.save_with_backup().map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "config save failed".to_string(),
+        )
+    })?;
+    drop(guard);
+    let wiping = devnode_to_wipe.is_some();
+    let after_wipe = req.after_wipe;
+    let luks_uuid = removed.and_then(|drive| drive.luks_uuid);
+    let keychain_drive_id = drive_id.clone();
+    // The keyring, unmount, wipe and format all block (the wipe for as long as the drive takes
+    // to overwrite), so they run off the runtime and without the state lock.
+    let wiped = tokio::task::spawn_blocking(move || {
+        let _ = keychain::delete_passphrase(&keychain_drive_id);
+        if let Some(uuid) = luks_uuid {
+            let _ = keychain::delete_luks_passphrase(&uuid);
+        }
+        let Some(devnode) = devnode_to_wipe else {
+            return Ok(());
+        };
+        if let Err(e) = devices::unmount_partition(&devnode) {
+            tracing::warn!("discontinue wipe: unmount failed: {}", e);
+        }
+        devices::secure_wipe_block_device(&devnode, after_wipe)
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if let Err(e) = wiped {
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Secure wipe failed: {}", e),
+        ));
+    }
+    if wiping {
+        let mut guard = state.write().await;
+        guard.drive_status.connected = false;
         guard.drive_status.trusted = false;
         guard.drive_status.drive_id = None;
         guard.drive_status.label = None;
@@ -692,6 +1870,152 @@ async fn discontinue_drive(
     Ok(get_status(State(state)).await)
 }
 
+/// The last (redacted) error of every drive that has one, for copying into a bug report.
+async fn get_last_errors(State(state): State<SharedState>) -> Json<LastErrorsResponse> {
+    let guard = state.read().await;
+    let mut drives: Vec<DriveErrorReport> = guard
+        .last_errors
+        .iter()
+        .map(|(id, error)| DriveErrorReport {
+            drive_id: id.clone(),
+            label: guard
+                .config
+                .trusted_drives
+                .get(id)
+                .map(|d| d.display_label())
+                .unwrap_or_else(|| id.clone()),
+            error: error.clone(),
+        })
+        .collect();
+    drives.sort_by_key(|d| std::cmp::Reverse(d.error.epoch));
+    let agent_version = env!("CARGO_PKG_VERSION").to_string();
+    let generated_epoch = now_epoch();
+    let report = format_error_report(&agent_version, generated_epoch, &drives);
+    Json(LastErrorsResponse {
+        agent_version,
+        generated_epoch,
+        drives,
+        report,
+    })
+}
+
+fn format_error_report(
+    agent_version: &str,
+    generated_epoch: u64,
+    drives: &[DriveErrorReport],
+) -> String {
+    let mut report = format!(
+        "Aegis agent {}, report at {}\n",
+        agent_version, generated_epoch
+    );
+    if drives.is_empty() {
+        report.push_str("No errors recorded.\n");
+    }
+    for drive in drives {
+        report.push_str(&format!(
+            "{} ({}): {} failed at {} [{}] {}\n",
+            drive.label,
+            drive.drive_id,
+            drive.error.operation,
+            drive.error.epoch,
+            drive.error.code,
+            drive.error.message
+        ));
+    }
+    report
+}
+
+/// What discontinuing a drive would do, without doing it.
+async fn preview_discontinue_drive(
+    State(state): State<SharedState>,
+    Json(req): Json<DiscontinuePreviewRequest>,
+) -> Result<Json<DiscontinuePreviewResponse>, (StatusCode, String)> {
+    let (drive, connected) = {
+        let guard = state.read().await;
+        let drive = guard
+            .config
+            .trusted_drives
+            .get(&req.drive_id)
+            .cloned()
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "Drive not found.".to_string()))?;
+        let connected = guard.drive_status.drive_id.as_deref() == Some(req.drive_id.as_str())
+            && guard.drive_status.devnode.is_some();
+        (drive, connected)
+    };
+    // Unknown (e.g. locked keyring) counts as stored: discontinue deletes it either way.
+    let passphrase_stored = !matches!(keychain::get_passphrase(&req.drive_id), Ok(None));
+    let blocked_reason = (req.wipe && !connected)
+        .then(|| "Drive must be connected to wipe. Plug in the drive and try again.".to_string());
+    Ok(Json(DiscontinuePreviewResponse {
+        drive_id: req.drive_id,
+        confirm_label: drive.display_label(),
+        effects: discontinue_effects(
+            &drive,
+            passphrase_stored,
+            req.wipe.then_some(req.after_wipe),
+        ),
+        blocked_reason,
+    }))
+}
+
+/// `wipe` is None when the drive is kept as is, otherwise what is left on it after wiping.
+fn discontinue_effects(
+    drive: &TrustedDrive,
+    passphrase_stored: bool,
+    wipe: Option<PostWipeAction>,
+) -> Vec<DiscontinueEffect> {
+    let effect = |code: &str, message: String| DiscontinueEffect {
+        code: code.to_string(),
+        message,
+    };
+    let mut effects = vec![effect(
+        "drive_forgotten",
+        format!(
+            "\"{}\" is removed from Aegis and no longer backed up to, even when plugged in.",
+            drive.display_label()
+        ),
+    )];
+    if passphrase_stored {
+        effects.push(effect(
+            "passphrase_deleted",
+            "The passphrase saved in the keychain is deleted.".to_string(),
+        ));
+    }
+    if drive.last_backup_epoch.is_some() || !drive.free_space_history.is_empty() {
+        effects.push(effect(
+            "history_removed",
+            "Aegis's record of this drive (last backup time, free-space trend, data added) is removed."
+                .to_string(),
+        ));
+    }
+    if let Some(after_wipe) = wipe {
+        effects.push(effect(
+            "drive_wiped",
+            "The whole drive is overwritten with zeros. All backups on it are destroyed and cannot be recovered."
+                .to_string(),
+        ));
+        effects.push(match after_wipe {
+            PostWipeAction::LeaveBlank => effect(
+                "left_blank",
+                "The drive is left without a file system; format it before using it again."
+                    .to_string(),
+            ),
+            PostWipeAction::FormatExfat => effect(
+                "reformatted",
+                "A new, empty exFAT file system is created so the drive can be used right away."
+                    .to_string(),
+            ),
+        });
+    } else {
+        effects.push(effect(
+            "backups_kept",
+            "The backups on the drive are left intact. They can still be restored with the passphrase, by setting the drive up again or with restic directly."
+                .to_string(),
+        ));
+    }
+    effects
+}
+
 async fn update_drive(
     State(state): State<SharedState>,
     Json(req): Json<UpdateDriveRequest>,
@@ -747,6 +2071,8 @@ async fn update_drive(
             .map(|s| BackupSource {
                 label: sanitize_label(&s.label).unwrap_or_else(|| "Source".to_string()),
                 path: s.path.clone(),
+                follow_symlink: s.follow_symlink,
+                exclude_patterns: sanitize_patterns(&s.exclude_patterns),
             })
             .collect();
         if let Some(drive) = guard.config.trusted_drives.get_mut(&req.drive_id) {
@@ -754,7 +2080,13 @@ async fn update_drive(
         }
     }
 
-    guard.config.save().map_err(|_| {
+    guard.config.set_drive_patterns(
+        &req.drive_id,
+        req.include_patterns.as_deref(),
+        req.exclude_patterns.as_deref(),
+    );
+
+    guard.config.save_with_backup().map_err(|_| {
         (
             StatusCode::INTERNAL_SERVER_ERROR,
             "config save failed".to_string(),
@@ -772,10 +2104,8 @@ async fn mount_drive(
     let mount_path = devices::mount_partition(&req.devnode).map_err(|err| {
         let msg = err.to_string();
         tracing::error!("mount drive failed devnode={} error={}", req.devnode, msg);
-        if msg.to_lowercase().contains("not authorized")
-            || msg.to_lowercase().contains("authentication")
-        {
-            return (StatusCode::FORBIDDEN, "authorization required".to_string());
+        if let Some(failure) = devices::classify_auth_failure(&msg) {
+            return (StatusCode::FORBIDDEN, failure.guidance().to_string());
         }
         (StatusCode::INTERNAL_SERVER_ERROR, msg)
     })?;
@@ -788,55 +2118,145 @@ async fn mount_drive(
 }
 
 async fn format_drive(
-    State(_state): State<SharedState>,
+    State(state): State<SharedState>,
     Json(req): Json<FormatRequest>,
 ) -> Result<Json<FormatResponse>, (StatusCode, String)> {
-    debug!("format drive: request devnode={}", req.devnode);
-    devices::format_partition_exfat(&req.devnode).map_err(|err| {
+    debug!(
+        "format drive: request devnode={} target={:?} encrypted={}",
+        req.devnode,
+        req.target,
+        req.encryption.is_some()
+    );
+    let removable = tokio::task::spawn_blocking(devices::list_removable_devices)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    devices::check_format_target(&removable, &req.devnode, req.target).map_err(|reason| {
+        tracing::warn!(
+            "format drive: refused devnode={} target={:?}: {}",
+            req.devnode,
+            req.target,
+            reason
+        );
+        (StatusCode::BAD_REQUEST, reason.to_string())
+    })?;
+    let remember_unlock = {
+        let guard = state.read().await;
+        guard.config.remember_passphrase && !guard.config.paranoid_mode
+    };
+    let devnode = req.devnode.clone();
+    let mount_path = tokio::task::spawn_blocking(move || {
+        format_and_unlock(
+            &req.devnode,
+            req.target,
+            req.encryption.as_ref(),
+            remember_unlock,
+        )
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .map_err(|err| {
         let msg = err.to_string();
-        tracing::error!("format drive failed devnode={} error={}", req.devnode, msg);
-        if msg.to_lowercase().contains("not authorized")
-            || msg.to_lowercase().contains("authentication")
-        {
-            return (StatusCode::FORBIDDEN, "authorization required".to_string());
+        tracing::error!("format drive failed devnode={} error={}", devnode, msg);
+        if let Some(failure) = devices::classify_auth_failure(&msg) {
+            return (StatusCode::FORBIDDEN, failure.guidance().to_string());
         }
         (StatusCode::INTERNAL_SERVER_ERROR, msg)
     })?;
-    tracing::info!("format drive: success devnode={}", req.devnode);
     Ok(Json(FormatResponse {
         status: "ok".to_string(),
+        mount_path,
     }))
 }
 
+/// Format `devnode`; an encrypted one is then unlocked and mounted (its mount path returned),
+/// saving the unlock passphrase first when `remember_unlock`. Blocking: formatting, the keychain
+/// and udisks all wait on other processes.
+fn format_and_unlock(
+    devnode: &str,
+    target: devices::FormatTarget,
+    encryption: Option<&devices::LuksParams>,
+    remember_unlock: bool,
+) -> anyhow::Result<Option<String>> {
+    devices::format_drive(devnode, target, encryption)?;
+    tracing::info!("format drive: success devnode={}", devnode);
+    let Some(params) = encryption else {
+        return Ok(None);
+    };
+    let partition = match target {
+        devices::FormatTarget::Partition => devnode.to_string(),
+        devices::FormatTarget::WholeDisk => devices::first_partition_devnode(devnode),
+    };
+    if remember_unlock {
+        match devices::luks_uuid(&partition) {
+            Some(uuid) => {
+                if let Err(err) = keychain::store_luks_passphrase(&uuid, &params.passphrase) {
+                    tracing::warn!(
+                        "format drive: could not save the unlock passphrase: {}",
+                        Redact::new(err)
+                    );
+                }
+            }
+            None => tracing::warn!("format drive: LUKS UUID of {} not found", partition),
+        }
+    }
+    let cleartext = devices::unlock_luks(&partition, &params.passphrase)?;
+    Ok(Some(devices::mount_partition(&cleartext)?))
+}
+
 async fn start_backup(
     State(state): State<SharedState>,
     Json(req): Json<BackupRequest>,
 ) -> Result<Json<BackupStartResponse>, (StatusCode, String)> {
     let config = { state.read().await.config.clone() };
-    {
-        let guard = state.read().await;
-        if guard.running_drive_ids.contains(&req.drive_id) {
-            return Err((
-                StatusCode::CONFLICT,
-                "backup already running for this drive".to_string(),
-            ));
-        }
-    }
     let Some(drive) = config.trusted_drives.get(&req.drive_id) else {
         return Err((StatusCode::BAD_REQUEST, "unknown drive".to_string()));
     };
-    let passphrase = resolve_passphrase(&config, &req.drive_id, req.passphrase)?;
+    let provided = req.passphrase.is_some();
+    let passphrase = resolve_passphrase(&state, &config, &req.drive_id, req.passphrase).await?;
     let mount_path = ensure_mounted_drive(&state, &req.drive_id).await?;
-
-    {
-        let mut guard = state.write().await;
-        guard.running_drive_ids.insert(req.drive_id.clone());
+    if devices::is_read_only_mount_async(FsPath::new(&mount_path)).await {
+        return Err((StatusCode::CONFLICT, devices::READ_ONLY_MESSAGE.to_string()));
     }
+    let repo_path = PathBuf::from(&mount_path).join(&drive.repository_path);
+    let is_first_backup = tokio::task::spawn_blocking(move || repository_is_empty(&repo_path))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    state
+        .write()
+        .await
+        .begin_backup(&req.drive_id)
+        .map_err(|reason| {
+            let message = match reason {
+                "already_running" => "backup already running for this drive",
+                "verify_running" => "a verification of this drive is running",
+                _ => "a repair of this drive is running",
+            };
+            (StatusCode::CONFLICT, message.to_string())
+        })?;
     let state_clone = state.clone();
     let drive_id = drive.drive_id.clone();
     let mount = PathBuf::from(mount_path);
+    let dry_run = req.dry_run;
     tokio::spawn(async move {
-        let result = run_backup(state_clone.clone(), drive_id.clone(), mount, passphrase).await;
+        let result = run_backup(
+            state_clone.clone(),
+            drive_id.clone(),
+            mount,
+            passphrase.clone(),
+            dry_run,
+        )
+        .await;
+        settle_passphrase(
+            &state_clone,
+            &config,
+            &drive_id,
+            &passphrase,
+            provided,
+            &result,
+        )
+        .await;
         {
             let mut guard = state_clone.write().await;
             guard.running_drive_ids.remove(&drive_id);
@@ -850,6 +2270,7 @@ async fn start_backup(
 
     Ok(Json(BackupStartResponse {
         status: "started".to_string(),
+        is_first_backup,
     }))
 }
 
@@ -865,7 +2286,8 @@ async fn list_snapshots(
         .clone();
 
     let mount_path = ensure_mounted_drive(&state, &req.drive_id).await?;
-    let passphrase = resolve_passphrase(&config, &req.drive_id, req.passphrase)?;
+    let provided = req.passphrase.is_some();
+    let passphrase = resolve_passphrase(&state, &config, &req.drive_id, req.passphrase).await?;
 
     let restic = Restic::resolve(config.restic_path.as_deref()).map_err(|_| {
         (
@@ -873,18 +2295,59 @@ async fn list_snapshots(
             "restic not available".to_string(),
         )
     })?;
-    let repo_path = PathBuf::from(mount_path).join(&drive.repository_path);
-    let snapshots = restic
-        .snapshots(&repo_path, &passphrase)
+    let mount_path = PathBuf::from(mount_path);
+    let repo_path = mount_path.join(&drive.repository_path);
+    let result = restic
+        .snapshots(&repo_path, &passphrase, config.no_lock_reads)
+        .await;
+    settle_passphrase(
+        &state,
+        &config,
+        &req.drive_id,
+        &passphrase,
+        provided,
+        &result,
+    )
+    .await;
+    let mut snapshots = result.map_err(|_| {
+        (
+            StatusCode::BAD_REQUEST,
+            "unable to list snapshots".to_string(),
+        )
+    })?;
+    if let Err(err) = sync_descriptions(&mount_path, &mut snapshots) {
+        tracing::warn!("list snapshots: descriptions unavailable: {}", err);
+    }
+    if let Err(err) = sync_protected(&mount_path, &mut snapshots) {
+        tracing::warn!("list snapshots: protected snapshots unavailable: {}", err);
+    }
+    state
+        .write()
         .await
-        .map_err(|_| {
-            (
-                StatusCode::BAD_REQUEST,
-                "unable to list snapshots".to_string(),
-            )
-        })?;
+        .record_snapshots(&req.drive_id, &snapshots);
+    if req.newest_first {
+        snapshots.reverse();
+    }
+    let total = snapshots.len();
+    let snapshots = paginate(snapshots, req.offset, req.limit);
+
+    Ok(Json(SnapshotsResponse { snapshots, total }))
+}
+
+fn now_epoch() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
 
-    Ok(Json(SnapshotsResponse { snapshots }))
+/// Slice out one page: skip `offset` items, then take up to `limit` (all remaining if None).
+fn paginate<T>(items: Vec<T>, offset: usize, limit: Option<usize>) -> Vec<T> {
+    let page = items.into_iter().skip(offset);
+    match limit {
+        Some(limit) => page.take(limit).collect(),
+        None => page.collect(),
+    }
 }
 
 async fn snapshot_stats(
@@ -899,7 +2362,8 @@ async fn snapshot_stats(
         .clone();
 
     let mount_path = ensure_mounted_drive(&state, &req.drive_id).await?;
-    let passphrase = resolve_passphrase(&config, &req.drive_id, req.passphrase)?;
+    let provided = req.passphrase.is_some();
+    let passphrase = resolve_passphrase(&state, &config, &req.drive_id, req.passphrase).await?;
 
     let restic = Restic::resolve(config.restic_path.as_deref()).map_err(|_| {
         (
@@ -908,15 +2372,29 @@ async fn snapshot_stats(
         )
     })?;
     let repo_path = PathBuf::from(mount_path).join(&drive.repository_path);
-    let stats = restic
-        .snapshot_stats(&repo_path, &passphrase, &req.snapshot_id)
-        .await
-        .map_err(|_| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "stats failed".to_string(),
-            )
-        })?;
+    let result = restic
+        .snapshot_stats(
+            &repo_path,
+            &passphrase,
+            &req.snapshot_id,
+            config.no_lock_reads,
+        )
+        .await;
+    settle_passphrase(
+        &state,
+        &config,
+        &req.drive_id,
+        &passphrase,
+        provided,
+        &result,
+    )
+    .await;
+    let stats = result.map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "stats failed".to_string(),
+        )
+    })?;
 
     Ok(Json(SnapshotStatsResponse {
         total_size: stats.total_size,
@@ -924,145 +2402,1040 @@ async fn snapshot_stats(
     }))
 }
 
-async fn restore_snapshot(
+async fn set_snapshot_description(
     State(state): State<SharedState>,
-    Json(req): Json<RestoreRequest>,
-) -> Result<Json<RestoreResponse>, (StatusCode, String)> {
-    if req.target_path.trim().is_empty() {
-        return Err((StatusCode::BAD_REQUEST, "target path required".to_string()));
-    }
-
-    let config = { state.read().await.config.clone() };
-    let drive = config
-        .trusted_drives
-        .get(&req.drive_id)
-        .ok_or_else(|| (StatusCode::BAD_REQUEST, "unknown drive".to_string()))?
-        .clone();
+    Json(req): Json<SnapshotDescriptionRequest>,
+) -> Result<Json<SnapshotDescriptionResponse>, (StatusCode, String)> {
+    let description = req.description.as_deref().and_then(sanitize_label);
+    write_snapshot_description(&state, &req.drive_id, &req.snapshot_id, description).await
+}
 
-    let mount_path = ensure_mounted_drive(&state, &req.drive_id).await?;
-    let passphrase = resolve_passphrase(&config, &req.drive_id, req.passphrase)?;
+async fn clear_snapshot_description(
+    State(state): State<SharedState>,
+    Json(req): Json<SnapshotDescriptionRequest>,
+) -> Result<Json<SnapshotDescriptionResponse>, (StatusCode, String)> {
+    write_snapshot_description(&state, &req.drive_id, &req.snapshot_id, None).await
+}
 
-    let restic = Restic::resolve(config.restic_path.as_deref()).map_err(|_| {
+async fn write_snapshot_description(
+    state: &SharedState,
+    drive_id: &str,
+    snapshot_id: &str,
+    description: Option<String>,
+) -> Result<Json<SnapshotDescriptionResponse>, (StatusCode, String)> {
+    let snapshot_id = snapshot_id.trim().to_string();
+    if !is_snapshot_id(&snapshot_id) {
+        return Err((StatusCode::BAD_REQUEST, "invalid snapshot id".to_string()));
+    }
+    {
+        let guard = state.read().await;
+        if !guard.config.trusted_drives.contains_key(drive_id) {
+            return Err((StatusCode::BAD_REQUEST, "unknown drive".to_string()));
+        }
+    }
+    let mount_path = PathBuf::from(ensure_mounted_drive(state, drive_id).await?);
+    let snapshot_id = existing_snapshot_id(state, drive_id, &mount_path, &snapshot_id).await?;
+    let mut descriptions = read_descriptions(&mount_path).map_err(|_| {
         (
             StatusCode::INTERNAL_SERVER_ERROR,
-            "restic not available".to_string(),
+            "descriptions read failed".to_string(),
         )
     })?;
-
-    let repo_path = PathBuf::from(mount_path.clone()).join(&drive.repository_path);
-    let cancel = CancellationToken::new();
-    {
-        let mut guard = state.write().await;
-        guard.restore_drive_id = Some(req.drive_id.clone());
-        guard.restore_cancel_token = Some(cancel.clone());
+    // Short and full IDs of the same snapshot name the same description.
+    descriptions.retain(|id, _| !snapshot_id.starts_with(id.as_str()));
+    if let Some(d) = &description {
+        descriptions.insert(snapshot_id.clone(), d.clone());
     }
-    let result = restic
-        .restore_cancellable(
-            &repo_path,
-            &passphrase,
-            &req.snapshot_id,
-            FsPath::new(&req.target_path),
-            &req.include_paths,
-            cancel,
+    write_descriptions(&mount_path, &descriptions).map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "descriptions write failed".to_string(),
         )
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("restore failed: {}", e),
-            )
-        });
-    {
-        let mut guard = state.write().await;
-        guard.restore_drive_id = None;
-        guard.restore_cancel_token = None;
-    }
-    result?;
-    Ok(Json(RestoreResponse {
-        status: "completed".to_string(),
+    })?;
+    Ok(Json(SnapshotDescriptionResponse {
+        snapshot_id,
+        description,
     }))
 }
 
-async fn export_recovery(
+async fn protect_snapshot(
     State(state): State<SharedState>,
-    Json(req): Json<RecoveryKitRequest>,
-) -> Result<Json<RecoveryKitResponse>, (StatusCode, String)> {
-    let config = { state.read().await.config.clone() };
-    let drive = config
-        .trusted_drives
-        .get(&req.drive_id)
+    Json(req): Json<SnapshotProtectRequest>,
+) -> Result<Json<SnapshotProtectResponse>, (StatusCode, String)> {
+    write_snapshot_protection(&state, &req.drive_id, &req.snapshot_id, true).await
+}
+
+async fn unprotect_snapshot(
+    State(state): State<SharedState>,
+    Json(req): Json<SnapshotProtectRequest>,
+) -> Result<Json<SnapshotProtectResponse>, (StatusCode, String)> {
+    write_snapshot_protection(&state, &req.drive_id, &req.snapshot_id, false).await
+}
+
+async fn write_snapshot_protection(
+    state: &SharedState,
+    drive_id: &str,
+    snapshot_id: &str,
+    protected: bool,
+) -> Result<Json<SnapshotProtectResponse>, (StatusCode, String)> {
+    let snapshot_id = snapshot_id.trim().to_string();
+    if !is_snapshot_id(&snapshot_id) {
+        return Err((StatusCode::BAD_REQUEST, "invalid snapshot id".to_string()));
+    }
+    {
+        let guard = state.read().await;
+        if !guard.config.trusted_drives.contains_key(drive_id) {
+            return Err((StatusCode::BAD_REQUEST, "unknown drive".to_string()));
+        }
+    }
+    let mount_path = PathBuf::from(ensure_mounted_drive(state, drive_id).await?);
+    let snapshot_id = existing_snapshot_id(state, drive_id, &mount_path, &snapshot_id).await?;
+    let mut ids = read_protected(&mount_path).map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "protected snapshots read failed".to_string(),
+        )
+    })?;
+    // Short and full IDs of the same snapshot match each other.
+    ids.retain(|id| !id.starts_with(&snapshot_id) && !snapshot_id.starts_with(id.as_str()));
+    if protected {
+        ids.push(snapshot_id.clone());
+    }
+    write_protected(&mount_path, &ids).map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "protected snapshots write failed".to_string(),
+        )
+    })?;
+    Ok(Json(SnapshotProtectResponse {
+        snapshot_id,
+        protected,
+    }))
+}
+
+/// Full ID of the one snapshot on the mounted drive that `snapshot_id` names; 404 if there is
+/// none, 400 if it names several.
+async fn existing_snapshot_id(
+    state: &SharedState,
+    drive_id: &str,
+    mount_path: &FsPath,
+    snapshot_id: &str,
+) -> Result<String, (StatusCode, String)> {
+    let repo_path = state
+        .read()
+        .await
+        .config
+        .repository_path_for(drive_id, mount_path)
         .ok_or_else(|| (StatusCode::BAD_REQUEST, "unknown drive".to_string()))?;
+    let snapshot_id = snapshot_id.to_string();
+    tokio::task::spawn_blocking(move || resolve_snapshot_id(&repo_path, &snapshot_id))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|msg| {
+            let status = if msg == "no such snapshot" {
+                StatusCode::NOT_FOUND
+            } else {
+                StatusCode::BAD_REQUEST
+            };
+            (status, msg.to_string())
+        })
+}
 
-    export_recovery_kit(drive, FsPath::new(&req.destination_dir)).map_err(|_| {
+async fn restore_snapshot(
+    State(state): State<SharedState>,
+    Json(req): Json<RestoreRequest>,
+) -> Result<Json<RestoreResponse>, (StatusCode, String)> {
+    if req.target_path.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "target path required".to_string()));
+    }
+
+    let config = { state.read().await.config.clone() };
+    let drive = config
+        .trusted_drives
+        .get(&req.drive_id)
+        .ok_or_else(|| (StatusCode::BAD_REQUEST, "unknown drive".to_string()))?
+        .clone();
+
+    let mount_path = ensure_mounted_drive(&state, &req.drive_id).await?;
+    let provided = req.passphrase.is_some();
+    let passphrase = resolve_passphrase(&state, &config, &req.drive_id, req.passphrase).await?;
+
+    let restic = Restic::resolve(config.restic_path.as_deref()).map_err(|_| {
         (
             StatusCode::INTERNAL_SERVER_ERROR,
-            "recovery export failed".to_string(),
+            "restic not available".to_string(),
         )
     })?;
 
-    Ok(Json(RecoveryKitResponse {
-        status: "created".to_string(),
+    let elevation = if req.preserve_ownership {
+        let elevation = restore_elevation(
+            req.confirm_privileged,
+            devices::running_as_root(),
+            which::which("pkexec").is_ok(),
+            devices::polkit_agent_running(),
+        )
+        .map_err(|msg| (StatusCode::BAD_REQUEST, msg.to_string()))?;
+        Some(elevation)
+    } else {
+        None
+    };
+
+    let repo_path = PathBuf::from(mount_path.clone()).join(&drive.repository_path);
+    let target = FsPath::new(&req.target_path);
+    // A restore only reads the repository, so it may run during a backup to the same drive, but
+    // not into files that backup is reading.
+    let backup_running = state.read().await.running_drive_ids.contains(&req.drive_id);
+    if backup_running {
+        let sources = expand_sources(&config, &req.drive_id).map_err(|e| {
+            (
+                StatusCode::CONFLICT,
+                format!(
+                    "a backup is running and its sources can't be checked: {}",
+                    e
+                ),
+            )
+        })?;
+        let resolve_target = target.to_path_buf();
+        let overlaps = tokio::task::spawn_blocking(move || {
+            let sources: Vec<PathBuf> = sources.iter().map(|s| resolve_path(s)).collect();
+            restore_target_overlaps(&resolve_path(&resolve_target), &sources)
+        })
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        if overlaps {
+            return Err((
+                StatusCode::CONFLICT,
+                "a backup of this folder to the drive is running; restore somewhere else or wait for it to finish"
+                    .to_string(),
+            ));
+        }
+        debug!("restore: backup running on the drive, reading without a lock");
+    }
+    // Files restic writes or overwrites get a newer change time (ctime) than this; restic
+    // restores the mtime from the snapshot, so that can't tell them apart.
+    let restore_started = now_epoch() as i64;
+    let (restore_id, cancel) = state
+        .write()
+        .await
+        .begin_restore(&req.drive_id)
+        .ok_or_else(|| {
+            (
+                StatusCode::CONFLICT,
+                "a restore from this drive is already running".to_string(),
+            )
+        })?;
+    // Already root: a plain restic run keeps ownership and can be cancelled like any other.
+    let result = match elevation {
+        Some(Elevation::Pkexec) => {
+            restic
+                .restore_privileged(
+                    &repo_path,
+                    &passphrase,
+                    &req.snapshot_id,
+                    target,
+                    &req.include_paths,
+                    backup_running,
+                    config.bandwidth_limits(),
+                    Elevation::Pkexec,
+                    cancel,
+                )
+                .await
+        }
+        Some(Elevation::AlreadyRoot) | None => {
+            restic
+                .restore_cancellable(
+                    &repo_path,
+                    &passphrase,
+                    &req.snapshot_id,
+                    target,
+                    &req.include_paths,
+                    backup_running,
+                    config.bandwidth_limits(),
+                    cancel,
+                )
+                .await
+        }
+    };
+    state
+        .write()
+        .await
+        .finish_restore(&req.drive_id, restore_id);
+    settle_passphrase(
+        &state,
+        &config,
+        &req.drive_id,
+        &passphrase,
+        provided,
+        &result,
+    )
+    .await;
+    match result {
+        Ok(()) => Ok(Json(RestoreResponse {
+            status: "completed".to_string(),
+            partial_files: None,
+        })),
+        Err(e) if is_cancelled(&e) => {
+            let root = target.to_path_buf();
+            let partial_files =
+                tokio::task::spawn_blocking(move || count_changed_files(&root, restore_started))
+                    .await
+                    .unwrap_or(0);
+            tracing::info!("restore: cancelled partial_files={}", partial_files);
+            Ok(Json(RestoreResponse {
+                status: "cancelled".to_string(),
+                partial_files: Some(partial_files),
+            }))
+        }
+        Err(e) => {
+            state
+                .write()
+                .await
+                .record_error(&req.drive_id, "restore", &e, now_epoch());
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("restore failed: {}", e),
+            ))
+        }
+    }
+}
+
+/// True if restoring into `target` would write inside a backup source, or over one. Compares
+/// paths as given; resolve them with `resolve_path` first.
+fn restore_target_overlaps(target: &FsPath, sources: &[PathBuf]) -> bool {
+    sources
+        .iter()
+        .any(|source| target.starts_with(source) || source.starts_with(target))
+}
+
+/// `path` with symlinks and `..` resolved as far as it exists; the part that doesn't exist yet
+/// is appended with `.` and `..` applied to the text.
+fn resolve_path(path: &FsPath) -> PathBuf {
+    let mut existing = path.to_path_buf();
+    let mut missing = Vec::new();
+    let mut resolved = loop {
+        if let Ok(canonical) = existing.canonicalize() {
+            break canonical;
+        }
+        let Some(last) = existing.components().next_back() else {
+            return path.to_path_buf();
+        };
+        missing.push(last.as_os_str().to_owned());
+        existing.pop();
+    };
+    for part in missing.iter().rev() {
+        match part.to_str() {
+            Some("..") => {
+                resolved.pop();
+            }
+            Some(".") => {}
+            _ => resolved.push(part),
+        }
+    }
+    resolved
+}
+
+/// How a restore that keeps original ownership gets root, or why it can't.
+fn restore_elevation(
+    confirmed: bool,
+    is_root: bool,
+    pkexec_available: bool,
+    polkit_agent: bool,
+) -> Result<Elevation, &'static str> {
+    if !confirmed {
+        return Err(
+            "restoring original ownership runs restic as root; confirm_privileged is required",
+        );
+    }
+    if is_root {
+        return Ok(Elevation::AlreadyRoot);
+    }
+    if !pkexec_available {
+        return Err("pkexec is not installed, so the restore can't run as root");
+    }
+    if !polkit_agent {
+        return Err(devices::AuthFailure::NoAgent.guidance());
+    }
+    Ok(Elevation::Pkexec)
+}
+
+async fn cancel_restore(
+    State(state): State<SharedState>,
+    Json(req): Json<RestoreCancelRequest>,
+) -> Result<Json<RestoreResponse>, (StatusCode, String)> {
+    if !state.write().await.cancel_restore(&req.drive_id) {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "no restore running for this drive".to_string(),
+        ));
+    }
+    debug!("restore: cancel requested drive_id={}", req.drive_id);
+    Ok(Json(RestoreResponse {
+        status: "cancelling".to_string(),
+        partial_files: None,
     }))
 }
 
-async fn eject_drive(
-    State(_state): State<SharedState>,
-    Json(req): Json<EjectRequest>,
-) -> Result<Json<BackupStartResponse>, (StatusCode, String)> {
-    let mount_path = PathBuf::from(req.mount_path);
-    let Some(device) = resolve_device_for_mount(&mount_path) else {
-        return Err((StatusCode::BAD_REQUEST, "device not found".to_string()));
+/// Cancel an auto-backup that hasn't started yet because it waits for the keyring to unlock.
+async fn cancel_waiting_backup(
+    State(state): State<SharedState>,
+    Json(req): Json<RestoreCancelRequest>,
+) -> Result<Json<CancelWaitingResponse>, (StatusCode, String)> {
+    if !state
+        .write()
+        .await
+        .cancel_waiting_backup(&req.drive_id, now_epoch())
+    {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "no backup waiting to start for this drive".to_string(),
+        ));
+    }
+    debug!("backup: waiting backup cancelled drive_id={}", req.drive_id);
+    Ok(Json(CancelWaitingResponse {
+        status: "cancelled".to_string(),
+    }))
+}
+
+/// Check the repository on a drive without making a new snapshot.
+async fn verify_repo(
+    State(state): State<SharedState>,
+    Json(req): Json<VerifyRequest>,
+) -> Result<Json<VerifyResponse>, (StatusCode, String)> {
+    let run = verify_drive(&state, &req.drive_id, req.passphrase, &[req.mode]).await?;
+    let status = if run.passed { "passed" } else { "failed" };
+    tracing::info!(
+        "verify: {} mode={:?} duration_secs={}",
+        status,
+        req.mode,
+        run.duration_secs
+    );
+    Ok(Json(VerifyResponse {
+        mode: req.mode,
+        status: status.to_string(),
+        duration_secs: run.duration_secs,
+        message: run.message,
+    }))
+}
+
+/// How `verify_drive` went.
+struct VerifyRun {
+    passed: bool,
+    duration_secs: u64,
+    /// Why it failed, as recorded in the drive's last error.
+    message: Option<String>,
+}
+
+/// Run the verification `modes` on a connected drive in order, stopping at the first failure.
+/// Shows in `verify_progress` as the last mode; a failure is recorded as the drive's last error.
+async fn verify_drive(
+    state: &SharedState,
+    drive_id: &str,
+    passphrase: Option<String>,
+    modes: &[VerifyMode],
+) -> Result<VerifyRun, (StatusCode, String)> {
+    let config = { state.read().await.config.clone() };
+    let drive = config
+        .trusted_drives
+        .get(drive_id)
+        .ok_or_else(|| (StatusCode::BAD_REQUEST, "unknown drive".to_string()))?
+        .clone();
+    let mount_path = ensure_mounted_drive(state, drive_id).await?;
+    let provided = passphrase.is_some();
+    let passphrase = resolve_passphrase(state, &config, drive_id, passphrase).await?;
+    let restic = Restic::resolve(config.restic_path.as_deref()).map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "restic not available".to_string(),
+        )
+    })?;
+    let repo_path = PathBuf::from(&mount_path).join(&drive.repository_path);
+    let progress_mode = *modes.last().unwrap_or(&VerifyMode::Quick);
+    let estimate_secs = match progress_mode {
+        VerifyMode::Quick => None,
+        VerifyMode::Deep => deep_verify_estimate(&repo_path).await,
     };
+    state
+        .write()
+        .await
+        .begin_verify(drive_id, progress_mode, estimate_secs, now_epoch())
+        .map_err(|msg| (StatusCode::CONFLICT, msg.to_string()))?;
+    let verify_guard = FinishGuard::new(state, drive_id, AgentRuntimeState::finish_verify);
+    debug!("verify: starting drive_id={} modes={:?}", drive_id, modes);
+    let started = std::time::Instant::now();
+    let mut result = Ok(());
+    for &mode in modes {
+        result = verify(&restic, &repo_path, &passphrase, mode).await;
+        if result.is_err() {
+            break;
+        }
+    }
+    let duration_secs = started.elapsed().as_secs();
+    settle_passphrase(state, &config, drive_id, &passphrase, provided, &result).await;
+    drop(verify_guard);
+    let message = match result {
+        Ok(()) => None,
+        Err(err) => {
+            let mut guard = state.write().await;
+            guard.record_error(drive_id, "verify", &err, now_epoch());
+            guard.last_errors.get(drive_id).map(|e| e.message.clone())
+        }
+    };
+    Ok(VerifyRun {
+        passed: message.is_none(),
+        duration_secs,
+        message,
+    })
+}
 
-    let status = tokio::process::Command::new("udisksctl")
-        .arg("unmount")
-        .arg("-b")
-        .arg(&device)
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status()
+/// Repair the repository on a connected drive: rebuild the index, then rewrite snapshots that
+/// reference missing data. Refused while a backup or verification of the drive runs.
+async fn repair_repo(
+    State(state): State<SharedState>,
+    Json(req): Json<RepairRequest>,
+) -> Result<Json<RepairSummary>, (StatusCode, String)> {
+    let config = { state.read().await.config.clone() };
+    let drive = config
+        .trusted_drives
+        .get(&req.drive_id)
+        .ok_or_else(|| (StatusCode::BAD_REQUEST, "unknown drive".to_string()))?
+        .clone();
+    let mount_path = ensure_mounted_drive(&state, &req.drive_id).await?;
+    if devices::is_read_only_mount_async(FsPath::new(&mount_path)).await {
+        return Err((StatusCode::CONFLICT, devices::READ_ONLY_MESSAGE.to_string()));
+    }
+    let provided = req.passphrase.is_some();
+    let passphrase = resolve_passphrase(&state, &config, &req.drive_id, req.passphrase).await?;
+    let restic = Restic::resolve(config.restic_path.as_deref()).map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "restic not available".to_string(),
+        )
+    })?;
+    let repo_path = PathBuf::from(&mount_path).join(&drive.repository_path);
+    state
+        .write()
         .await
-        .context("unmount")
-        .map_err(|_| {
-            (
+        .begin_repair(&req.drive_id)
+        .map_err(|msg| (StatusCode::CONFLICT, msg.to_string()))?;
+    let repair_guard = FinishGuard::new(&state, &req.drive_id, AgentRuntimeState::finish_repair);
+    debug!("repair: starting drive_id={}", req.drive_id);
+    let result = async {
+        let index = restic.repair_index(&repo_path, &passphrase).await?;
+        let snapshots = restic.repair_snapshots(&repo_path, &passphrase).await?;
+        anyhow::Ok(RepairSummary {
+            packs_reindexed: index.packs_reindexed,
+            ..snapshots
+        })
+    }
+    .await;
+    settle_passphrase(
+        &state,
+        &config,
+        &req.drive_id,
+        &passphrase,
+        provided,
+        &result,
+    )
+    .await;
+    drop(repair_guard);
+    let mut guard = state.write().await;
+    match result {
+        Ok(summary) => {
+            tracing::info!(
+                "repair: done packs_reindexed={} snapshots_modified={} snapshots_removed={}",
+                summary.packs_reindexed,
+                summary.snapshots_modified,
+                summary.snapshots_removed
+            );
+            // The counts and time range may have changed; refreshed on the next listing.
+            guard.snapshot_stats.remove(&req.drive_id);
+            Ok(Json(summary))
+        }
+        Err(err) => {
+            guard.record_error(&req.drive_id, "repair", &err, now_epoch());
+            tracing::error!("repair: failed: {}", Redact::new(err));
+            Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
-                "eject failed".to_string(),
+                "repository repair failed".to_string(),
+            ))
+        }
+    }
+}
+
+/// Approximate file count and size of a backup to this drive, from walking its sources.
+async fn estimate_backup(
+    State(state): State<SharedState>,
+    Json(req): Json<EstimateRequest>,
+) -> Result<Json<estimate::SourceEstimate>, (StatusCode, String)> {
+    let config = { state.read().await.config.clone() };
+    if !config.trusted_drives.contains_key(&req.drive_id) {
+        return Err((StatusCode::BAD_REQUEST, "unknown drive".to_string()));
+    }
+    let sources = expand_sources(&config, &req.drive_id)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let mut excludes = config.effective_exclude_patterns(&req.drive_id);
+    excludes.extend(expand_source_excludes(&config, &req.drive_id).unwrap_or_default());
+    let deadline = std::time::Instant::now() + estimate::ESTIMATE_TIME_LIMIT;
+    let estimate = tokio::task::spawn_blocking(move || {
+        estimate::estimate_sources(
+            &sources,
+            &excludes,
+            estimate::ESTIMATE_MAX_ENTRIES,
+            deadline,
+        )
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    debug!(
+        "estimate: drive_id={} files={} bytes={} complete={}",
+        req.drive_id, estimate.files, estimate.bytes, estimate.complete
+    );
+    Ok(Json(estimate))
+}
+
+/// Run the configured verification steps again (no new snapshot) and, if they pass, clear a
+/// verification failure from the last run.
+async fn reverify_last_backup(
+    State(state): State<SharedState>,
+    Json(req): Json<ReverifyRequest>,
+) -> Result<Json<ReverifyResponse>, (StatusCode, String)> {
+    let config = { state.read().await.config.clone() };
+    let repository_id = config
+        .trusted_drives
+        .get(&req.drive_id)
+        .ok_or_else(|| (StatusCode::BAD_REQUEST, "unknown drive".to_string()))?
+        .repository_id
+        .clone();
+    // The steps a backup runs; a quick check if both are turned off.
+    let mut modes = Vec::new();
+    if config.quick_verify || !config.deep_verify {
+        modes.push(VerifyMode::Quick);
+    }
+    if config.deep_verify {
+        modes.push(VerifyMode::Deep);
+    }
+    let run = verify_drive(&state, &req.drive_id, req.passphrase, &modes).await?;
+    let last_run_upgraded = run.passed
+        && state
+            .write()
+            .await
+            .upgrade_reverified_run(repository_id.as_deref());
+    let status = if run.passed { "passed" } else { "failed" };
+    tracing::info!(
+        "reverify: {} duration_secs={} last_run_upgraded={}",
+        status,
+        run.duration_secs,
+        last_run_upgraded
+    );
+    Ok(Json(ReverifyResponse {
+        status: status.to_string(),
+        duration_secs: run.duration_secs,
+        message: run.message,
+        last_run_upgraded,
+    }))
+}
+
+async fn export_snapshot(
+    State(state): State<SharedState>,
+    Json(req): Json<ExportRequest>,
+) -> Result<Json<ExportResponse>, (StatusCode, String)> {
+    let config = { state.read().await.config.clone() };
+    let drive = config
+        .trusted_drives
+        .get(&req.drive_id)
+        .ok_or_else(|| (StatusCode::BAD_REQUEST, "unknown drive".to_string()))?
+        .clone();
+
+    let mount_path = ensure_mounted_drive(&state, &req.drive_id).await?;
+    let target = PathBuf::from(&req.target_path);
+    let format = validate_export_target(&target, FsPath::new(&mount_path))
+        .map_err(|msg| (StatusCode::BAD_REQUEST, msg))?;
+    let provided = req.passphrase.is_some();
+    let passphrase = resolve_passphrase(&state, &config, &req.drive_id, req.passphrase).await?;
+    let restic = Restic::resolve(config.restic_path.as_deref()).map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "restic not available".to_string(),
+        )
+    })?;
+    let repo_path = PathBuf::from(&mount_path).join(&drive.repository_path);
+
+    let Some(cancel) =
+        state
+            .write()
+            .await
+            .begin_export(&req.drive_id, &req.snapshot_id, now_epoch())
+    else {
+        return Err((
+            StatusCode::CONFLICT,
+            "an export from this drive is already running".to_string(),
+        ));
+    };
+    let mut export_guard = ExportGuard {
+        target: None,
+        _finish: FinishGuard::new(&state, &req.drive_id, AgentRuntimeState::finish_export),
+    };
+    let create_target = target.clone();
+    let writer = tokio::task::spawn_blocking(move || ArchiveWriter::create(&create_target, format))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("cannot create target file: {}", e),
             )
         })?;
+    export_guard.target = Some(target.clone());
+    debug!(
+        "export: starting drive_id={} snapshot_id={} format={:?}",
+        req.drive_id, req.snapshot_id, format
+    );
+    let progress_state = state.clone();
+    let progress_drive_id = req.drive_id.clone();
+    let result = restic
+        .dump_archive(
+            &repo_path,
+            &passphrase,
+            &req.snapshot_id,
+            config.no_lock_reads,
+            writer,
+            cancel,
+            |bytes| {
+                // Progress is best effort: skip an update rather than wait for the lock.
+                if let Ok(mut guard) = progress_state.try_write() {
+                    guard.set_export_progress(&progress_drive_id, bytes);
+                }
+            },
+        )
+        .await;
+    let result = match result {
+        Ok((bytes, writer)) => tokio::task::spawn_blocking(move || writer.finish())
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+            .map(|()| bytes)
+            .map_err(|e| anyhow::Error::new(e).context("finish archive")),
+        Err(e) => Err(e),
+    };
+    if result.is_ok() {
+        export_guard.target = None;
+    }
+    // Clears the export state and, unless it completed, removes the partial archive.
+    drop(export_guard);
+    settle_passphrase(
+        &state,
+        &config,
+        &req.drive_id,
+        &passphrase,
+        provided,
+        &result,
+    )
+    .await;
+    match result {
+        Ok(bytes_written) => {
+            tracing::info!("export: completed bytes_written={}", bytes_written);
+            Ok(Json(ExportResponse {
+                status: "completed".to_string(),
+                bytes_written,
+            }))
+        }
+        Err(e) => {
+            if is_cancelled(&e) {
+                tracing::info!("export: cancelled");
+                return Ok(Json(ExportResponse {
+                    status: "cancelled".to_string(),
+                    bytes_written: 0,
+                }));
+            }
+            state
+                .write()
+                .await
+                .record_error(&req.drive_id, "export", &e, now_epoch());
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("export failed: {}", e),
+            ))
+        }
+    }
+}
 
-    if !status.success() {
+/// Runs `finish` (e.g. `finish_verify`) for a drive when dropped, so an operation's state is
+/// cleared however its handler ends, including when the request is dropped mid-way (client
+/// gone) or returns early with an error.
+struct FinishGuard {
+    state: SharedState,
+    drive_id: String,
+    finish: fn(&mut AgentRuntimeState, &str),
+}
+
+impl FinishGuard {
+    fn new(state: &SharedState, drive_id: &str, finish: fn(&mut AgentRuntimeState, &str)) -> Self {
+        Self {
+            state: state.clone(),
+            drive_id: drive_id.to_string(),
+            finish,
+        }
+    }
+}
+
+impl Drop for FinishGuard {
+    fn drop(&mut self) {
+        // Drop can't wait for the lock; if it's busy, finish from a task instead.
+        match self.state.try_write() {
+            Ok(mut guard) => (self.finish)(&mut guard, &self.drive_id),
+            Err(_) => {
+                let state = self.state.clone();
+                let drive_id = std::mem::take(&mut self.drive_id);
+                let finish = self.finish;
+                tokio::spawn(async move { finish(&mut *state.write().await, &drive_id) });
+            }
+        }
+    }
+}
+
+/// Ends an export however `export_snapshot` leaves it: clears the export state and removes
+/// `target`, the partial archive, if set. A partial archive is useless, so it is never left
+/// behind.
+struct ExportGuard {
+    target: Option<PathBuf>,
+    _finish: FinishGuard,
+}
+
+impl Drop for ExportGuard {
+    fn drop(&mut self) {
+        if let Some(target) = &self.target {
+            let _ = std::fs::remove_file(target);
+        }
+    }
+}
+
+/// Estimated duration of a deep verification of `repo`, from its size.
+async fn deep_verify_estimate(repo: &FsPath) -> Option<u64> {
+    repository_size_async(repo)
+        .await
+        .map(|bytes| estimate_deep_verify_secs(bytes, ASSUMED_READ_THROUGHPUT))
+}
+
+async fn cancel_export(
+    State(state): State<SharedState>,
+    Json(req): Json<RestoreCancelRequest>,
+) -> Result<Json<ExportResponse>, (StatusCode, String)> {
+    let mut guard = state.write().await;
+    let bytes_written = guard
+        .export_progress
+        .get(&req.drive_id)
+        .map(|p| p.bytes_written)
+        .unwrap_or(0);
+    if !guard.cancel_export(&req.drive_id) {
         return Err((
+            StatusCode::NOT_FOUND,
+            "no export running for this drive".to_string(),
+        ));
+    }
+    debug!("export: cancel requested drive_id={}", req.drive_id);
+    Ok(Json(ExportResponse {
+        status: "cancelling".to_string(),
+        bytes_written,
+    }))
+}
+
+/// Count regular files under `root` (recursively, not following symlinks) whose inode changed
+/// at or after `since` (Unix seconds): new files and overwritten ones. Missing root = 0.
+fn count_changed_files(root: &FsPath, since: i64) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    let Ok(entries) = std::fs::read_dir(root) else {
+        return 0;
+    };
+    let mut count = 0;
+    for entry in entries.flatten() {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if metadata.is_dir() {
+            count += count_changed_files(&entry.path(), since);
+        } else if metadata.is_file() && metadata.ctime() >= since {
+            count += 1;
+        }
+    }
+    count
+}
+
+async fn export_recovery(
+    State(state): State<SharedState>,
+    Json(req): Json<RecoveryKitRequest>,
+) -> Result<Json<RecoveryKitResponse>, (StatusCode, String)> {
+    let config = { state.read().await.config.clone() };
+    let drive = config
+        .trusted_drives
+        .get(&req.drive_id)
+        .ok_or_else(|| (StatusCode::BAD_REQUEST, "unknown drive".to_string()))?;
+
+    export_recovery_kit(drive, FsPath::new(&req.destination_dir)).map_err(|_| {
+        (
             StatusCode::INTERNAL_SERVER_ERROR,
-            "eject failed".to_string(),
+            "recovery export failed".to_string(),
+        )
+    })?;
+
+    Ok(Json(RecoveryKitResponse {
+        status: "created".to_string(),
+    }))
+}
+
+async fn identify_repo(
+    State(state): State<SharedState>,
+    Json(req): Json<RepoIdentifyRequest>,
+) -> Result<Json<RepoIdentifyResponse>, (StatusCode, String)> {
+    let mount_path = PathBuf::from(&req.mount_path);
+    if resolve_device_for_mount(&mount_path).await.is_none() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "mount path is not a mounted drive".to_string(),
+        ));
+    }
+    let repo_rel = req.repository_path.as_deref().unwrap_or(".aegis/repo");
+    let repo_rel_path = FsPath::new(repo_rel);
+    if repo_rel_path.is_absolute()
+        || repo_rel_path
+            .components()
+            .any(|c| matches!(c, std::path::Component::ParentDir))
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "invalid repository path".to_string(),
         ));
     }
 
-    let status = tokio::process::Command::new("udisksctl")
-        .arg("power-off")
+    let (source, repository_id) = match req.passphrase.filter(|p| !p.trim().is_empty()) {
+        Some(passphrase) => {
+            let restic = {
+                let guard = state.read().await;
+                Restic::resolve(guard.config.restic_path.as_deref()).map_err(|_| {
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "restic not available".to_string(),
+                    )
+                })?
+            };
+            let id = restic
+                .repository_id(&mount_path.join(repo_rel_path), &passphrase)
+                .await
+                .map_err(|_| {
+                    (
+                        StatusCode::BAD_REQUEST,
+                        "unable to open repository".to_string(),
+                    )
+                })?;
+            (Some("restic".to_string()), Some(id))
+        }
+        None => {
+            let id = read_marker(&mount_path)
+                .ok()
+                .flatten()
+                .and_then(|m| m.repository_id);
+            (id.as_ref().map(|_| "marker".to_string()), id)
+        }
+    };
+    let result = compare_repository_ids(&req.expected_repository_id, repository_id.as_deref());
+    debug!("repo identify: result={:?} source={:?}", result, source);
+    Ok(Json(RepoIdentifyResponse {
+        result,
+        source,
+        repository_id,
+    }))
+}
+
+/// Hardware serial and LUKS UUID (for an encrypted drive) of a mounted drive's `device`. Asks
+/// udev and lsblk, so runs off the runtime.
+async fn drive_identity(device: Option<PathBuf>) -> (Option<String>, Option<String>) {
+    let Some(device) = device else {
+        return (None, None);
+    };
+    tokio::task::spawn_blocking(move || {
+        let device = device.to_string_lossy().to_string();
+        let luks_uuid = devices::luks_backing_device(&device)
+            .and_then(|partition| devices::luks_uuid(&partition));
+        (devices::device_serial(&device), luks_uuid)
+    })
+    .await
+    .unwrap_or_default()
+}
+
+async fn eject_drive(
+    State(state): State<SharedState>,
+    Json(req): Json<EjectRequest>,
+) -> Result<Json<EjectResponse>, (StatusCode, String)> {
+    let mount_path = PathBuf::from(req.mount_path);
+    let Some(device) = resolve_device_for_mount(&mount_path).await else {
+        return Err((StatusCode::BAD_REQUEST, "device not found".to_string()));
+    };
+    let attempts = { state.read().await.config.eject_power_off_attempts.max(1) };
+    // An encrypted drive is mounted from its unlocked device: lock the container afterwards so
+    // the mapping is closed, and power off through the partition holding it.
+    let cleartext = device.to_string_lossy().to_string();
+    let luks_partition =
+        tokio::task::spawn_blocking(move || devices::luks_backing_device(&cleartext))
+            .await
+            .ok()
+            .flatten()
+            .map(PathBuf::from);
+
+    let mut unmounted = udisksctl_succeeds("unmount", &device).await;
+    if let (true, Some(partition)) = (unmounted, &luks_partition) {
+        unmounted = udisksctl_succeeds("lock", partition).await;
+        if !unmounted {
+            tracing::warn!("eject: locking the encrypted drive failed");
+        }
+    }
+    let power_off_device = luks_partition.as_deref().unwrap_or(&device);
+    let mut powered_off = false;
+    if unmounted {
+        for attempt in 1..=attempts {
+            if udisksctl_succeeds("power-off", power_off_device).await {
+                powered_off = true;
+                break;
+            }
+            tracing::warn!("eject: power-off failed (attempt {}/{})", attempt, attempts);
+            if attempt < attempts {
+                tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+            }
+        }
+    }
+    eject_outcome(unmounted, powered_off).map(Json)
+}
+
+async fn udisksctl_succeeds(action: &str, device: &FsPath) -> bool {
+    tokio::process::Command::new("udisksctl")
+        .arg(action)
         .arg("-b")
-        .arg(&device)
+        .arg(device)
         .stdout(Stdio::null())
         .stderr(Stdio::null())
         .status()
         .await
-        .context("power off")
-        .map_err(|_| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "eject failed".to_string(),
-            )
-        })?;
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
 
-    if !status.success() {
+/// Only a failed unmount fails the eject; many hubs and enclosures can't power off, and once
+/// unmounted the drive is safe to remove anyway.
+fn eject_outcome(
+    unmounted: bool,
+    powered_off: bool,
+) -> Result<EjectResponse, (StatusCode, String)> {
+    if !unmounted {
         return Err((
             StatusCode::INTERNAL_SERVER_ERROR,
             "eject failed".to_string(),
         ));
     }
-
-    Ok(Json(BackupStartResponse {
-        status: "ejected".to_string(),
-    }))
+    Ok(EjectResponse {
+        status: if powered_off {
+            "ejected".to_string()
+        } else {
+            "safe_to_remove".to_string()
+        },
+        powered_off,
+    })
 }
 
 async fn ensure_mounted_drive(
@@ -1086,20 +3459,35 @@ async fn ensure_mounted_drive(
         .ok_or_else(|| (StatusCode::BAD_REQUEST, "drive not mounted".to_string()))
 }
 
-fn resolve_passphrase(
+/// Passphrase from the request, else the in-memory cache, else the keychain. A provided
+/// passphrase is only cached once restic accepts it (see `settle_passphrase`).
+async fn resolve_passphrase(
+    state: &SharedState,
     config: &crate::config::AgentConfig,
     drive_id: &str,
     provided: Option<String>,
 ) -> Result<String, (StatusCode, String)> {
+    let now = now_epoch();
     if let Some(pass) = provided {
         if pass.trim().is_empty() {
             return Err((StatusCode::BAD_REQUEST, "passphrase required".to_string()));
         }
         return Ok(pass);
     }
+    if !config.paranoid_mode {
+        if let Some(pass) = state.read().await.cached_passphrase(drive_id, now) {
+            return Ok(pass.to_string());
+        }
+    }
     if config.remember_passphrase && !config.paranoid_mode {
         return keychain::get_passphrase(drive_id)
-            .map_err(|_| {
+            .map_err(|err| {
+                if keychain::is_locked(&err) {
+                    return (
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "keyring is locked; unlock it or enter the passphrase".to_string(),
+                    );
+                }
                 (
                     StatusCode::INTERNAL_SERVER_ERROR,
                     "keychain error".to_string(),
@@ -1109,3 +3497,290 @@ fn resolve_passphrase(
     }
     Err((StatusCode::BAD_REQUEST, "passphrase required".to_string()))
 }
+
+/// After restic used `passphrase`: cache it for `passphrase_cache_secs` (never in paranoid mode)
+/// if the client sent it and restic accepted it, or drop it from the cache if restic rejected
+/// it, so a wrong entry never shadows the keychain.
+async fn settle_passphrase<T>(
+    state: &SharedState,
+    config: &crate::config::AgentConfig,
+    drive_id: &str,
+    passphrase: &str,
+    provided: bool,
+    result: &anyhow::Result<T>,
+) {
+    match result {
+        Ok(_) if provided && !config.paranoid_mode => state.write().await.cache_passphrase(
+            drive_id,
+            passphrase,
+            config.passphrase_cache_secs,
+            now_epoch(),
+        ),
+        Err(err) if is_wrong_password(err) => state
+            .write()
+            .await
+            .forget_cached_passphrase(drive_id, passphrase),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{AgentEvent, BackupProgress};
+    use futures_util::StreamExt;
+
+    #[tokio::test]
+    async fn events_stream_published_events_and_unsubscribes_on_drop() {
+        let state: SharedState = std::sync::Arc::new(tokio::sync::RwLock::new(
+            crate::state::AgentRuntimeState::new(AgentConfig::default()),
+        ));
+        let response = events(State(state.clone())).await.into_response();
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/event-stream"
+        );
+        assert_eq!(state.read().await.events.subscriber_count(), 1);
+        state.read().await.events.publish(AgentEvent::Progress {
+            drive_id: "d1".to_string(),
+            progress: BackupProgress {
+                percent_done: 0.5,
+                ..BackupProgress::default()
+            },
+        });
+        let mut body = response.into_body().into_data_stream();
+        let frame = tokio::time::timeout(Duration::from_secs(5), body.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let text = String::from_utf8_lossy(&frame).into_owned();
+        assert!(text.starts_with("event: progress\n"), "{}", text);
+        let data = text
+            .lines()
+            .find_map(|line| line.strip_prefix("data: "))
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_str(data).unwrap();
+        assert_eq!(json["type"], "progress");
+        assert_eq!(json["drive_id"], "d1");
+        assert_eq!(json["progress"]["percent_done"], 0.5);
+
+        drop(body);
+        assert_eq!(state.read().await.events.subscriber_count(), 0);
+    }
+
+    #[test]
+    fn privileged_restore_is_gated() {
+        assert!(restore_elevation(false, true, true, true).is_err());
+        assert_eq!(
+            restore_elevation(true, true, false, false),
+            Ok(Elevation::AlreadyRoot)
+        );
+        assert_eq!(
+            restore_elevation(true, false, true, true),
+            Ok(Elevation::Pkexec)
+        );
+        assert!(restore_elevation(true, false, false, true).is_err());
+        assert!(restore_elevation(true, false, true, false).is_err());
+    }
+
+    #[test]
+    fn error_report_lists_each_drive() {
+        let drives = vec![DriveErrorReport {
+            drive_id: "abcdef123456".to_string(),
+            label: "Backup".to_string(),
+            error: LastError {
+                operation: "backup".to_string(),
+                code: "drive_full".to_string(),
+                message: "write <path>: no space left on device".to_string(),
+                epoch: 1_700_000_000,
+            },
+        }];
+        assert_eq!(
+            format_error_report("0.1.0", 1_700_000_100, &drives),
+            "Aegis agent 0.1.0, report at 1700000100\n\
+             Backup (abcdef123456): backup failed at 1700000000 [drive_full] write <path>: no space left on device\n"
+        );
+        assert!(format_error_report("0.1.0", 1, &[]).contains("No errors recorded."));
+    }
+
+    fn effect_codes(effects: &[DiscontinueEffect]) -> Vec<&str> {
+        effects.iter().map(|e| e.code.as_str()).collect()
+    }
+
+    #[test]
+    fn discontinue_preview_lists_effects() {
+        let mut drive: TrustedDrive = serde_json::from_value(serde_json::json!({
+            "drive_id": "abcdef123456",
+            "label": "Backup",
+            "repository_path": ".aegis/repo",
+            "repository_id": null,
+            "last_seen_epoch": null
+        }))
+        .unwrap();
+        assert_eq!(
+            effect_codes(&discontinue_effects(&drive, false, None)),
+            ["drive_forgotten", "backups_kept"]
+        );
+
+        drive.last_backup_epoch = Some(1_700_000_000);
+        let effects = discontinue_effects(&drive, true, Some(PostWipeAction::LeaveBlank));
+        assert_eq!(
+            effect_codes(&effects),
+            [
+                "drive_forgotten",
+                "passphrase_deleted",
+                "history_removed",
+                "drive_wiped",
+                "left_blank"
+            ]
+        );
+        assert!(effects[0].message.contains("\"Backup\""));
+        let effects = discontinue_effects(&drive, false, Some(PostWipeAction::FormatExfat));
+        assert_eq!(effect_codes(&effects)[2..], ["drive_wiped", "reformatted"]);
+    }
+
+    #[tokio::test]
+    async fn status_carries_repository_id() {
+        let mut config = AgentConfig::default();
+        config.trusted_drives.insert(
+            "drive-1".to_string(),
+            serde_json::from_value(serde_json::json!({
+                "drive_id": "drive-1",
+                "label": "Backup",
+                "repository_path": ".aegis/repo",
+                "repository_id": "5f1a2b3c",
+                "last_seen_epoch": null
+            }))
+            .unwrap(),
+        );
+        let mut runtime = crate::state::AgentRuntimeState::new(config);
+        runtime.drive_status.connected = true;
+        runtime.drive_status.trusted = true;
+        runtime.drive_status.drive_id = Some("drive-1".to_string());
+        let state: SharedState = std::sync::Arc::new(tokio::sync::RwLock::new(runtime));
+
+        let Json(status) = get_status(State(state)).await;
+        let status = serde_json::to_value(&status).unwrap();
+        assert_eq!(status["trusted_drives"][0]["repository_id"], "5f1a2b3c");
+        assert_eq!(status["drive"]["repository_id"], "5f1a2b3c");
+    }
+
+    #[test]
+    fn eject_outcome_power_off_failure_is_not_fatal() {
+        let ok = eject_outcome(true, true).unwrap();
+        assert_eq!((ok.status.as_str(), ok.powered_off), ("ejected", true));
+        let unsupported = eject_outcome(true, false).unwrap();
+        assert_eq!(
+            (unsupported.status.as_str(), unsupported.powered_off),
+            ("safe_to_remove", false)
+        );
+        let (code, _) = eject_outcome(false, false).unwrap_err();
+        assert_eq!(code, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn restore_target_must_not_overlap_running_backup_sources() {
+        let sources = [
+            PathBuf::from("/home/u/Documents"),
+            PathBuf::from("/srv/data"),
+        ];
+        assert!(restore_target_overlaps(
+            FsPath::new("/home/u/Documents/restored"),
+            &sources
+        ));
+        assert!(restore_target_overlaps(FsPath::new("/home/u"), &sources));
+        assert!(restore_target_overlaps(FsPath::new("/srv/data"), &sources));
+        assert!(!restore_target_overlaps(
+            FsPath::new("/home/u/Restored"),
+            &sources
+        ));
+        assert!(!restore_target_overlaps(
+            FsPath::new("/home/u/Documents2"),
+            &sources
+        ));
+        assert!(!restore_target_overlaps(FsPath::new("/tmp/restore"), &[]));
+    }
+
+    #[test]
+    fn resolved_restore_target_sees_through_dotdot_and_symlinks() {
+        let root = std::env::temp_dir().join(format!("aegis-resolve-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("Documents")).unwrap();
+        std::fs::create_dir_all(root.join("Restore")).unwrap();
+        std::os::unix::fs::symlink(root.join("Documents"), root.join("docs-link")).unwrap();
+        let sources = [resolve_path(&root.join("Documents"))];
+        let dotdot = resolve_path(&root.join("Restore/../Documents/new/./x"));
+        let linked = resolve_path(&root.join("docs-link/new"));
+        let elsewhere = resolve_path(&root.join("Restore/new/.."));
+        std::fs::remove_dir_all(&root).unwrap();
+        assert!(restore_target_overlaps(&dotdot, &sources));
+        assert!(restore_target_overlaps(&linked, &sources));
+        assert!(!restore_target_overlaps(&elsewhere, &sources));
+    }
+
+    #[test]
+    fn changed_files_count_new_and_overwritten_files() {
+        let root = std::env::temp_dir().join(format!("aegis-changed-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("a"), b"x").unwrap();
+        std::fs::write(root.join("sub").join("b"), b"x").unwrap();
+        let everything = count_changed_files(&root, 0);
+        let nothing = count_changed_files(&root, now_epoch() as i64 + 60);
+        std::fs::remove_dir_all(&root).unwrap();
+        assert_eq!(everything, 2);
+        assert_eq!(nothing, 0);
+        assert_eq!(count_changed_files(&root, 0), 0);
+    }
+
+    #[test]
+    fn paginate_slices_pages() {
+        let items: Vec<u32> = (0..10).collect();
+        assert_eq!(paginate(items.clone(), 0, Some(3)), vec![0, 1, 2]);
+        assert_eq!(paginate(items.clone(), 3, Some(3)), vec![3, 4, 5]);
+        assert_eq!(paginate(items.clone(), 9, Some(3)), vec![9]);
+        assert_eq!(paginate(items.clone(), 8, None), vec![8, 9]);
+        assert!(paginate(items.clone(), 10, Some(3)).is_empty());
+        assert!(paginate(items.clone(), 50, None).is_empty());
+        assert!(paginate(items, 0, Some(0)).is_empty());
+    }
+
+    #[tokio::test]
+    async fn ensure_steps_reuse_marker_and_repository() {
+        let (restic, dir) = crate::restic::tests::script_restic(
+            "ensure",
+            r#"#!/bin/sh
+repo="$2"; shift 2
+case "$1" in
+  init) echo init >> "$repo/../../inits"; touch "$repo/config" ;;
+  cat)
+    [ -f "$repo/config" ] || { echo "Fatal: repository does not exist" >&2; exit 10; }
+    echo '{"id":"repo-abc","version":2}' ;;
+esac
+"#,
+        );
+        let mount = dir.join("mount");
+        std::fs::create_dir_all(&mount).unwrap();
+        let repo_path = mount.join(".aegis/repo");
+
+        assert!(read_drive_marker(&mount).unwrap().is_none());
+        let mut first = DriveMarker::new(Some("Backup".to_string()));
+        let (first_repo, created) = open_or_init_repo(&restic, &repo_path, "pw").await.unwrap();
+        assert!(created);
+        first.repository_id = Some(first_repo.clone());
+        write_drive_marker(&mount, &first).unwrap();
+        let second = read_drive_marker(&mount).unwrap().unwrap();
+        let (second_repo, created) = open_or_init_repo(&restic, &repo_path, "pw").await.unwrap();
+        assert!(!created);
+
+        assert_eq!(first.drive_id, second.drive_id);
+        assert_eq!(second.label.as_deref(), Some("Backup"));
+        assert_eq!(second.repository_id.as_deref(), Some("repo-abc"));
+        assert_eq!(first_repo, "repo-abc");
+        assert_eq!(first_repo, second_repo);
+        let inits = std::fs::read_to_string(mount.join("inits")).unwrap();
+        assert_eq!(inits.lines().count(), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
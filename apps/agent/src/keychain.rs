@@ -1,28 +1,370 @@
+use crate::config::TrustedDrive;
+use crate::profile;
 use anyhow::Context;
 use keyring::Entry;
+use thiserror::Error;
 
-const SERVICE: &str = "Aegis";
+/// Keychain service for the active profile (see `profile::keychain_service`).
+pub fn service() -> String {
+    profile::keychain_service(profile::active())
+}
+
+/// The OS keyring exists but can't be read right now (e.g. screen locked, no login session).
+#[derive(Error, Debug)]
+#[error("keyring is locked")]
+pub struct KeyringLocked;
+
+/// True if the keyring error means "locked/unreachable for now" rather than a hard failure.
+fn is_locked_error(err: &keyring::Error) -> bool {
+    match err {
+        keyring::Error::NoStorageAccess(_) => true,
+        keyring::Error::PlatformFailure(inner) => {
+            let msg = inner.to_string().to_lowercase();
+            msg.contains("locked") || msg.contains("is not unlocked")
+        }
+        _ => false,
+    }
+}
+
+/// True if `err` came from a locked keyring (see [`KeyringLocked`]).
+pub fn is_locked(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<KeyringLocked>().is_some()
+}
 
 pub fn store_passphrase(drive_id: &str, passphrase: &str) -> anyhow::Result<()> {
-    let entry = Entry::new(SERVICE, drive_id).context("create keychain entry")?;
-    entry.set_password(passphrase).context("store passphrase")?;
-    Ok(())
+    KeyringStore.set(&service(), drive_id, passphrase)
 }
 
 pub fn get_passphrase(drive_id: &str) -> anyhow::Result<Option<String>> {
-    let entry = Entry::new(SERVICE, drive_id).context("create keychain entry")?;
-    match entry.get_password() {
-        Ok(value) => Ok(Some(value)),
-        Err(keyring::Error::NoEntry) => Ok(None),
-        Err(err) => Err(err).context("load passphrase"),
+    KeyringStore.get(&service(), drive_id)
+}
+
+/// Keychain account of the unlock passphrase of an encrypted drive, by LUKS UUID. The drive ID
+/// is in the marker inside the container, so it can't be used before unlocking.
+fn luks_account(luks_uuid: &str) -> String {
+    format!("luks-{}", luks_uuid)
+}
+
+pub fn store_luks_passphrase(luks_uuid: &str, passphrase: &str) -> anyhow::Result<()> {
+    KeyringStore.set(&service(), &luks_account(luks_uuid), passphrase)
+}
+
+pub fn get_luks_passphrase(luks_uuid: &str) -> anyhow::Result<Option<String>> {
+    KeyringStore.get(&service(), &luks_account(luks_uuid))
+}
+
+pub fn delete_luks_passphrase(luks_uuid: &str) -> anyhow::Result<()> {
+    KeyringStore.delete(&service(), &luks_account(luks_uuid))
+}
+
+/// Passphrase storage keyed by (service, drive ID); the OS keyring, or a fake in tests.
+trait PassphraseStore {
+    fn get(&self, service: &str, drive_id: &str) -> anyhow::Result<Option<String>>;
+    fn set(&self, service: &str, drive_id: &str, passphrase: &str) -> anyhow::Result<()>;
+    fn delete(&self, service: &str, drive_id: &str) -> anyhow::Result<()>;
+}
+
+struct KeyringStore;
+
+impl PassphraseStore for KeyringStore {
+    fn get(&self, service: &str, drive_id: &str) -> anyhow::Result<Option<String>> {
+        let entry = Entry::new(service, drive_id).context("create keychain entry")?;
+        match entry.get_password() {
+            Ok(value) => Ok(Some(value)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(err) if is_locked_error(&err) => Err(KeyringLocked.into()),
+            Err(err) => Err(err).context("load passphrase"),
+        }
+    }
+
+    fn set(&self, service: &str, drive_id: &str, passphrase: &str) -> anyhow::Result<()> {
+        let entry = Entry::new(service, drive_id).context("create keychain entry")?;
+        entry.set_password(passphrase).context("store passphrase")
+    }
+
+    fn delete(&self, service: &str, drive_id: &str) -> anyhow::Result<()> {
+        let entry = Entry::new(service, drive_id).context("create keychain entry")?;
+        match entry.delete_password() {
+            Ok(_) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(err) => Err(err).context("delete passphrase"),
+        }
+    }
+}
+
+/// Move the passphrases of `drives` (repository and LUKS unlock passphrases) stored under
+/// `old_service` to the current service, e.g. after the profile's service name changed. Returns
+/// how many moved. Stops at the first error (such as a locked keyring); entries already moved
+/// stay moved, the rest are left untouched.
+pub fn migrate_service<'a>(
+    old_service: &str,
+    drives: impl IntoIterator<Item = (&'a String, &'a TrustedDrive)>,
+) -> anyhow::Result<usize> {
+    migrate_with(
+        &KeyringStore,
+        old_service,
+        &service(),
+        &migration_accounts(drives),
+    )
+}
+
+/// Keychain accounts held for `drives`: each drive ID, plus `luks-<uuid>` for encrypted drives.
+fn migration_accounts<'a>(
+    drives: impl IntoIterator<Item = (&'a String, &'a TrustedDrive)>,
+) -> Vec<String> {
+    let mut accounts = Vec::new();
+    for (id, drive) in drives {
+        accounts.push(id.clone());
+        if let Some(uuid) = &drive.luks_uuid {
+            accounts.push(luks_account(uuid));
+        }
+    }
+    accounts
+}
+
+fn migrate_with<'a>(
+    store: &impl PassphraseStore,
+    old_service: &str,
+    new_service: &str,
+    accounts: impl IntoIterator<Item = &'a String>,
+) -> anyhow::Result<usize> {
+    if old_service == new_service {
+        return Ok(0);
+    }
+    let mut migrated = 0;
+    for id in accounts {
+        let Some(passphrase) = store.get(old_service, id)? else {
+            continue;
+        };
+        // A passphrase already saved under the new service is newer; keep it.
+        if store.get(new_service, id)?.is_none() {
+            store.set(new_service, id, &passphrase)?;
+        }
+        store.delete(old_service, id)?;
+        migrated += 1;
     }
+    Ok(migrated)
 }
 
 pub fn delete_passphrase(drive_id: &str) -> anyhow::Result<()> {
-    let entry = Entry::new(SERVICE, drive_id).context("create keychain entry")?;
-    match entry.delete_password() {
-        Ok(_) => Ok(()),
-        Err(keyring::Error::NoEntry) => Ok(()),
-        Err(err) => Err(err).context("delete passphrase"),
+    KeyringStore.delete(&service(), drive_id)
+}
+
+/// Delete stored passphrases for `drive_ids`; returns how many existed and were removed.
+pub fn purge_passphrases<'a>(drive_ids: impl IntoIterator<Item = &'a String>) -> usize {
+    purge_with(
+        drive_ids,
+        |id| matches!(get_passphrase(id), Ok(Some(_))),
+        |id| delete_passphrase(id).is_ok(),
+    )
+}
+
+/// Delete stored unlock passphrases of encrypted drives; returns how many were removed.
+pub fn purge_luks_passphrases<'a>(luks_uuids: impl IntoIterator<Item = &'a String>) -> usize {
+    purge_with(
+        luks_uuids,
+        |uuid| matches!(get_luks_passphrase(uuid), Ok(Some(_))),
+        |uuid| delete_luks_passphrase(uuid).is_ok(),
+    )
+}
+
+fn purge_with<'a>(
+    drive_ids: impl IntoIterator<Item = &'a String>,
+    mut exists: impl FnMut(&str) -> bool,
+    mut delete: impl FnMut(&str) -> bool,
+) -> usize {
+    let mut removed = 0;
+    for id in drive_ids {
+        let had_entry = exists(id);
+        if delete(id) && had_entry {
+            removed += 1;
+        }
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn boxed(msg: &str) -> Box<dyn std::error::Error + Send + Sync> {
+        Box::new(std::io::Error::other(msg.to_string()))
+    }
+
+    #[test]
+    fn no_storage_access_is_locked() {
+        assert!(is_locked_error(&keyring::Error::NoStorageAccess(boxed(
+            "access denied"
+        ))));
+    }
+
+    #[test]
+    fn platform_failure_mentioning_locked_is_locked() {
+        assert!(is_locked_error(&keyring::Error::PlatformFailure(boxed(
+            "Collection is locked"
+        ))));
+    }
+
+    #[test]
+    fn other_errors_are_not_locked() {
+        assert!(!is_locked_error(&keyring::Error::NoEntry));
+        assert!(!is_locked_error(&keyring::Error::PlatformFailure(boxed(
+            "dbus connection failed"
+        ))));
+        assert!(!is_locked_error(&keyring::Error::BadEncoding(vec![0xff])));
+    }
+
+    #[test]
+    fn purge_counts_only_existing_entries() {
+        let ids = ["a", "b", "c"].map(String::from);
+        let mut deleted = Vec::new();
+        let removed = purge_with(
+            &ids,
+            |id| id != "b",
+            |id| {
+                deleted.push(id.to_string());
+                true
+            },
+        );
+        assert_eq!(removed, 2);
+        assert_eq!(deleted, ids);
+    }
+
+    #[test]
+    fn purge_ignores_failed_deletes() {
+        let ids = ["a", "b"].map(String::from);
+        assert_eq!(purge_with(&ids, |_| true, |id| id == "a"), 1);
+    }
+
+    /// In-memory keyring keyed by (service, drive ID); `locked` makes every read fail.
+    #[derive(Default)]
+    struct FakeStore {
+        entries: std::cell::RefCell<std::collections::HashMap<(String, String), String>>,
+        locked: bool,
+    }
+
+    impl FakeStore {
+        fn with(entries: &[(&str, &str, &str)]) -> Self {
+            let store = Self::default();
+            for (service, id, pass) in entries {
+                store.set(service, id, pass).unwrap();
+            }
+            store
+        }
+
+        fn value(&self, service: &str, id: &str) -> Option<String> {
+            self.entries
+                .borrow()
+                .get(&(service.to_string(), id.to_string()))
+                .cloned()
+        }
+    }
+
+    impl PassphraseStore for FakeStore {
+        fn get(&self, service: &str, drive_id: &str) -> anyhow::Result<Option<String>> {
+            if self.locked {
+                return Err(KeyringLocked.into());
+            }
+            Ok(self.value(service, drive_id))
+        }
+
+        fn set(&self, service: &str, drive_id: &str, passphrase: &str) -> anyhow::Result<()> {
+            self.entries.borrow_mut().insert(
+                (service.to_string(), drive_id.to_string()),
+                passphrase.to_string(),
+            );
+            Ok(())
+        }
+
+        fn delete(&self, service: &str, drive_id: &str) -> anyhow::Result<()> {
+            self.entries
+                .borrow_mut()
+                .remove(&(service.to_string(), drive_id.to_string()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn migrate_moves_entries_to_new_service() {
+        let store = FakeStore::with(&[("Aegis", "a", "pw-a"), ("Aegis", "b", "pw-b")]);
+        let ids = ["a", "b", "c"].map(String::from);
+        assert_eq!(
+            migrate_with(&store, "Aegis", "Aegis-work", &ids).unwrap(),
+            2
+        );
+        assert_eq!(store.value("Aegis-work", "a").as_deref(), Some("pw-a"));
+        assert_eq!(store.value("Aegis-work", "b").as_deref(), Some("pw-b"));
+        assert_eq!(store.value("Aegis", "a"), None);
+        assert_eq!(store.value("Aegis", "b"), None);
+        assert_eq!(store.value("Aegis-work", "c"), None);
+    }
+
+    #[test]
+    fn migrate_keeps_existing_entry_under_new_service() {
+        let store = FakeStore::with(&[("Aegis", "a", "old"), ("Aegis-work", "a", "new")]);
+        let ids = ["a".to_string()];
+        assert_eq!(
+            migrate_with(&store, "Aegis", "Aegis-work", &ids).unwrap(),
+            1
+        );
+        assert_eq!(store.value("Aegis-work", "a").as_deref(), Some("new"));
+        assert_eq!(store.value("Aegis", "a"), None);
+    }
+
+    #[test]
+    fn migrate_fails_on_locked_keyring_without_deleting() {
+        let mut store = FakeStore::with(&[("Aegis", "a", "pw-a")]);
+        store.locked = true;
+        let err = migrate_with(&store, "Aegis", "Aegis-work", &["a".to_string()]).unwrap_err();
+        assert!(is_locked(&err));
+        assert_eq!(store.value("Aegis", "a").as_deref(), Some("pw-a"));
+    }
+
+    #[test]
+    fn migrate_moves_luks_passphrases_too() {
+        let store = FakeStore::with(&[
+            ("Aegis", "a", "pw-a"),
+            ("Aegis", "luks-1234-abcd", "unlock-a"),
+        ]);
+        let drive = |json: &str| serde_json::from_str::<TrustedDrive>(json).unwrap();
+        let drives = std::collections::HashMap::from([
+            (
+                "a".to_string(),
+                drive(
+                    r#"{"drive_id":"a","repository_path":".aegis/repo","luks_uuid":"1234-abcd"}"#,
+                ),
+            ),
+            (
+                "b".to_string(),
+                drive(r#"{"drive_id":"b","repository_path":".aegis/repo"}"#),
+            ),
+        ]);
+        let accounts = migration_accounts(&drives);
+        assert_eq!(
+            migrate_with(&store, "Aegis", "Aegis-work", &accounts).unwrap(),
+            2
+        );
+        assert_eq!(
+            store.value("Aegis-work", "luks-1234-abcd").as_deref(),
+            Some("unlock-a")
+        );
+        assert_eq!(store.value("Aegis", "luks-1234-abcd"), None);
+        assert_eq!(store.value("Aegis-work", "a").as_deref(), Some("pw-a"));
+    }
+
+    #[test]
+    fn migrate_same_service_is_noop() {
+        let store = FakeStore::with(&[("Aegis", "a", "pw-a")]);
+        assert_eq!(
+            migrate_with(&store, "Aegis", "Aegis", &["a".to_string()]).unwrap(),
+            0
+        );
+        assert_eq!(store.value("Aegis", "a").as_deref(), Some("pw-a"));
+    }
+
+    #[test]
+    fn keyring_locked_survives_anyhow() {
+        let err: anyhow::Error = KeyringLocked.into();
+        assert!(is_locked(&err));
+        assert!(!is_locked(&anyhow::anyhow!("other")));
     }
 }
@@ -1,3 +1,4 @@
+use crate::config::{dir_override, DATA_DIR_ENV};
 use directories::ProjectDirs;
 use std::path::PathBuf;
 use tracing_appender::non_blocking::WorkerGuard;
@@ -33,9 +34,38 @@ pub fn init_logging() -> Option<WorkerGuard> {
     }
 }
 
+/// The last `max` lines of the newest agent log file; empty if there is none.
+pub fn recent_log_lines(max: usize) -> Vec<String> {
+    let Some(dir) = log_dir() else {
+        return Vec::new();
+    };
+    let newest = std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("agent.log"))
+        })
+        .max();
+    let Some(contents) = newest.and_then(|path| std::fs::read(path).ok()) else {
+        return Vec::new();
+    };
+    let text = String::from_utf8_lossy(&contents);
+    let lines: Vec<&str> = text.lines().collect();
+    lines[lines.len().saturating_sub(max)..]
+        .iter()
+        .map(|line| line.to_string())
+        .collect()
+}
+
 fn log_dir() -> Option<PathBuf> {
-    let proj = ProjectDirs::from("com", "aegis", "Aegis")?;
-    let dir = proj.data_local_dir().join("logs");
+    let dir = crate::profile::log_dir(
+        &data_dir(std::env::var_os(DATA_DIR_ENV))?,
+        crate::profile::active(),
+    );
     if let Err(err) = std::fs::create_dir_all(&dir) {
         eprintln!("Failed to create log dir: {}", err);
         return None;
@@ -43,6 +73,18 @@ fn log_dir() -> Option<PathBuf> {
     Some(dir)
 }
 
+/// The data directory: `value` of `AEGIS_DATA_DIR` when set, else the platform's.
+fn data_dir(value: Option<std::ffi::OsString>) -> Option<PathBuf> {
+    match dir_override(value) {
+        Some(dir) => Some(dir),
+        None => Some(
+            ProjectDirs::from("com", "aegis", "Aegis")?
+                .data_local_dir()
+                .to_path_buf(),
+        ),
+    }
+}
+
 /// Wrapper to avoid leaking secrets or sensitive paths in logs.
 pub struct Redact<T>(T);
 
@@ -57,3 +99,125 @@ impl<T> std::fmt::Display for Redact<T> {
         write!(f, "<redacted>")
     }
 }
+
+/// Longest error text kept by [`redact_message`], in characters.
+const MAX_REDACTED_LEN: usize = 500;
+
+/// Error text that is safe to show in support reports: paths are redacted as in
+/// [`redact_paths`] and long output is cut short.
+pub fn redact_message(text: &str) -> String {
+    let mut out = redact_paths(text);
+    if out.chars().count() > MAX_REDACTED_LEN {
+        out = out.chars().take(MAX_REDACTED_LEN).collect();
+        out.push('…');
+    }
+    out
+}
+
+/// Replace every absolute or home-relative path in `text` with `<path>`, so source paths and
+/// user names don't leak into support reports. A path runs from its root up to the next
+/// delimiter, spaces included (`/media/u/My Drive`); trailing spaces, and a `key` before a
+/// following `key=value`, are kept.
+pub fn redact_paths(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::with_capacity(text.len());
+    let mut at_boundary = true;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let starts_path = at_boundary && (c == '/' || (c == '~' && chars.get(i + 1) == Some(&'/')));
+        if starts_path {
+            let mut end = i;
+            while end < chars.len() && !ends_path(chars[end]) {
+                end += 1;
+            }
+            let mut keep_from = end;
+            // `mount_path=/a b dry_run=false`: the path stops before the next key.
+            if chars.get(end) == Some(&'=') {
+                if let Some(space) = chars[i..end].iter().rposition(|c| c.is_whitespace()) {
+                    keep_from = i + space + 1;
+                }
+            }
+            while keep_from > i + 1 && chars[keep_from - 1].is_whitespace() {
+                keep_from -= 1;
+            }
+            out.push_str("<path>");
+            out.extend(&chars[keep_from..end]);
+            at_boundary = false;
+            i = end;
+            continue;
+        }
+        at_boundary = c.is_whitespace() || matches!(c, '"' | '\'' | '(' | '[' | '=' | '`');
+        out.push(c);
+        i += 1;
+    }
+    out
+}
+
+/// Characters that end a path. A space doesn't: file and volume names often have them.
+fn ends_path(c: char) -> bool {
+    matches!(
+        c,
+        '\n' | '\r' | '\t' | '"' | '\'' | '(' | ')' | '[' | ']' | ',' | ';' | ':' | '=' | '`'
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_dir_honors_the_override() {
+        assert_eq!(
+            data_dir(Some("/tmp/aegis-data-env".into())),
+            Some(PathBuf::from("/tmp/aegis-data-env"))
+        );
+        assert_eq!(data_dir(Some("".into())), data_dir(None));
+    }
+
+    #[test]
+    fn redact_message_hides_paths() {
+        assert_eq!(
+            redact_message("open /home/alice/Documents/taxes.pdf: permission denied"),
+            "open <path>: permission denied"
+        );
+        assert_eq!(
+            redact_message("Fatal: unable to open config file: stat \"/media/alice/aegis-x/.aegis/repo/config\": no such file"),
+            "Fatal: unable to open config file: stat \"<path>\": no such file"
+        );
+        assert_eq!(
+            redact_message("error: ~/Pictures (x) and path=/tmp/y"),
+            "error: <path> (x) and path=<path>"
+        );
+        assert_eq!(
+            redact_message("wrong password or no key found"),
+            "wrong password or no key found"
+        );
+    }
+
+    #[test]
+    fn redact_paths_covers_names_with_spaces() {
+        assert_eq!(
+            redact_paths("open /media/alice/My Drive/tax return.pdf: permission denied"),
+            "open <path>: permission denied"
+        );
+        assert_eq!(
+            redact_paths("backup: starting mount_path=/Volumes/My Drive dry_run=false"),
+            "backup: starting mount_path=<path> dry_run=false"
+        );
+        assert_eq!(
+            redact_paths("stat \"/home/alice/Old Photos\"\nnext line /tmp/x"),
+            "stat \"<path>\"\nnext line <path>"
+        );
+    }
+
+    #[test]
+    fn redact_message_keeps_urls_and_caps_length() {
+        assert_eq!(
+            redact_message("see https://restic.net/x"),
+            "see https://restic.net/x"
+        );
+        let long = "x".repeat(MAX_REDACTED_LEN + 10);
+        assert_eq!(redact_message(&long).chars().count(), MAX_REDACTED_LEN + 1);
+    }
+}
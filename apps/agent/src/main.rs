@@ -1,16 +1,31 @@
+mod attention;
 mod backup;
+mod cli;
 mod config;
+mod config_backups;
 mod devices;
 mod drive;
+mod durations;
 mod errors;
+mod estimate;
+mod export;
+mod i18n;
 mod ipc;
 mod keychain;
 mod logging;
 mod notifications;
+mod processes;
+mod profile;
 mod recovery;
 mod restic;
 mod retention;
+mod schedule;
+mod selftest;
+mod space;
 mod state;
+mod support;
+#[cfg(test)]
+mod test_util;
 mod usb;
 mod verify;
 
@@ -25,10 +40,33 @@ use tracing::{error, info, warn};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    match cli::parse_args(std::env::args().skip(1)) {
+        Ok(None) => {}
+        Ok(Some(command)) => std::process::exit(cli::run(command).await),
+        Err(cli::ArgsError::Help) => {
+            println!("{}", cli::USAGE);
+            return Ok(());
+        }
+        Err(cli::ArgsError::Invalid(message)) => {
+            eprintln!("aegis-agent: {}\n\n{}", message, cli::USAGE);
+            std::process::exit(2);
+        }
+    }
+    // The profile picks the log directory, so select it before logging starts.
+    let profile = profile::init_from_env().context("select profile")?;
     let _log_guard = init_logging();
-    info!("Aegis agent starting");
+    info!(
+        "Aegis agent starting profile={}",
+        profile.unwrap_or("default")
+    );
+    // Bound before anything else so a second agent (e.g. another profile) stops here.
+    let listener = ipc::bind().await?;
 
-    let config = AgentConfig::load().context("load config")?;
+    let mut config = AgentConfig::load().context("load config")?;
+    migrate_keychain_service(&mut config);
+    if let Some(err) = config.restic_path_error() {
+        warn!("{}; falling back to the bundled restic or PATH", err);
+    }
     if Restic::resolve(config.restic_path.as_deref()).is_err() {
         warn!("Restic not available; backups will fail until restic is installed or bundled.");
     }
@@ -38,11 +76,13 @@ async fn main() -> anyhow::Result<()> {
 
     let ipc_state = shared_state.clone();
     tokio::spawn(async move {
-        if let Err(err) = ipc::serve(ipc_state).await {
+        if let Err(err) = ipc::serve(ipc_state, listener).await {
             error!("IPC server failed: {}", Redact::new(err));
         }
     });
 
+    tokio::spawn(schedule::run_scheduler(shared_state.clone()));
+
     let usb_state = shared_state.clone();
     tokio::spawn(async move {
         if let Err(err) = usb_watcher.run(usb_state).await {
@@ -54,3 +94,35 @@ async fn main() -> anyhow::Result<()> {
     info!("Aegis agent shutting down");
     Ok(())
 }
+
+/// Move stored passphrases over if the keychain service changed since the last start. On failure
+/// (e.g. keyring locked) the old service stays recorded so the migration is retried next start.
+fn migrate_keychain_service(config: &mut AgentConfig) {
+    let current = keychain::service();
+    if config.keychain_service.as_deref() == Some(current.as_str()) {
+        return;
+    }
+    if let Some(old) = config.keychain_service.clone() {
+        match keychain::migrate_service(&old, &config.trusted_drives) {
+            Ok(migrated) => info!(
+                "keychain: migrated {} passphrase(s) from {} to {}",
+                migrated, old, current
+            ),
+            Err(err) => {
+                warn!(
+                    "keychain: migrating passphrases from {} failed, will retry: {}",
+                    old,
+                    Redact::new(err)
+                );
+                return;
+            }
+        }
+    }
+    config.keychain_service = Some(current);
+    if let Err(err) = config.save() {
+        warn!(
+            "keychain: could not record service name: {}",
+            Redact::new(err)
+        );
+    }
+}
@@ -1,15 +1,88 @@
 //! Desktop notifications for backup events and trusted device detection.
 //! Uses `notify-send` on Linux when available; no-op otherwise.
 
+use crate::config::AgentConfig;
+use crate::i18n;
+use serde::Serialize;
+use std::sync::OnceLock;
+
+/// Desktop notification settings taken from the config.
+#[derive(Debug, Clone)]
+pub struct NotificationSettings {
+    pub enabled: bool,
+    pub app_name: String,
+    pub icon: Option<String>,
+    /// Locale notification text is written in (see `i18n`).
+    pub locale: String,
+}
+
+impl NotificationSettings {
+    pub fn from_config(config: &AgentConfig) -> Self {
+        Self {
+            enabled: config.notifications_enabled,
+            app_name: config.notification_app_name.clone(),
+            icon: config.notification_icon.clone(),
+            locale: config
+                .notification_locale
+                .clone()
+                .or_else(i18n::system_locale)
+                .unwrap_or_else(|| "en".to_string()),
+        }
+    }
+
+    /// Localized text for `key`; see `i18n::message`.
+    fn text(&self, key: &str, args: &[(&str, &str)]) -> String {
+        i18n::message(&self.locale, key, args)
+    }
+}
+
+/// Arguments for `notify-send`, or None when desktop notifications are disabled.
+fn desktop_args(settings: &NotificationSettings, title: &str, body: &str) -> Option<Vec<String>> {
+    if !settings.enabled {
+        return None;
+    }
+    let mut args = vec!["-a".to_string(), settings.app_name.clone()];
+    if let Some(icon) = settings.icon.as_deref().filter(|i| !i.trim().is_empty()) {
+        args.push("-i".to_string());
+        args.push(icon.to_string());
+    }
+    args.push(title.to_string());
+    args.push(body.to_string());
+    Some(args)
+}
+
+/// Whether `notify-send` is installed. Checked once per process, like `udisksctl_supports_format`.
+#[cfg(target_os = "linux")]
+fn notify_send_available() -> bool {
+    static AVAILABLE: OnceLock<bool> = OnceLock::new();
+    check_once(&AVAILABLE, || which::which("notify-send").is_ok())
+}
+
+/// Run `probe` the first time only and cache the answer; log once if the tool is missing.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn check_once(cell: &OnceLock<bool>, probe: impl FnOnce() -> bool) -> bool {
+    *cell.get_or_init(|| {
+        let available = probe();
+        if !available {
+            tracing::info!("notify-send not found; desktop notifications are disabled");
+        }
+        available
+    })
+}
+
 /// Send a desktop notification (fire-and-forget). Does not block.
-pub fn notify(title: &str, body: &str) {
+pub fn notify(settings: &NotificationSettings, title: &str, body: &str) {
+    let Some(args) = desktop_args(settings, title, body) else {
+        return;
+    };
     #[cfg(target_os = "linux")]
     {
-        let title = title.to_string();
-        let body = body.to_string();
+        if !notify_send_available() {
+            return;
+        }
         std::thread::spawn(move || {
             let _ = std::process::Command::new("notify-send")
-                .args(["-a", "Aegis", &title, &body])
+                .args(&args)
                 .stdin(std::process::Stdio::null())
                 .stdout(std::process::Stdio::null())
                 .stderr(std::process::Stdio::null())
@@ -18,43 +91,248 @@ pub fn notify(title: &str, body: &str) {
     }
     #[cfg(not(target_os = "linux"))]
     {
-        let _ = (title, body);
+        let _ = args;
+    }
+}
+
+/// Result of a test notification on one channel.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct ChannelResult {
+    pub channel: String,
+    pub enabled: bool,
+    /// True if the notifier accepted the notification (not necessarily that it was seen).
+    pub sent: bool,
+    pub error: Option<String>,
+}
+
+/// Send a sample notification through every channel and wait for each outcome. Blocks while
+/// the notifier runs; call from a blocking task.
+pub fn send_test(settings: &NotificationSettings) -> Vec<ChannelResult> {
+    test_channels(settings, run_notify_send)
+}
+
+fn test_channels(
+    settings: &NotificationSettings,
+    send_desktop: impl FnOnce(&[String]) -> Result<(), String>,
+) -> Vec<ChannelResult> {
+    let desktop = match desktop_args(
+        settings,
+        &settings.text("test.title", &[]),
+        &settings.text("test.body", &[]),
+    ) {
+        None => ChannelResult {
+            channel: "desktop".to_string(),
+            enabled: false,
+            sent: false,
+            error: None,
+        },
+        Some(args) => {
+            let outcome = send_desktop(&args);
+            ChannelResult {
+                channel: "desktop".to_string(),
+                enabled: true,
+                sent: outcome.is_ok(),
+                error: outcome.err(),
+            }
+        }
+    };
+    vec![desktop]
+}
+
+/// Run `notify-send` and wait for it, reporting why it failed.
+fn run_notify_send(args: &[String]) -> Result<(), String> {
+    #[cfg(target_os = "linux")]
+    {
+        if !notify_send_available() {
+            return Err("notify-send is not installed".to_string());
+        }
+        let status = std::process::Command::new("notify-send")
+            .args(args)
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .map_err(|err| format!("could not run notify-send: {}", err))?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(format!("notify-send exited with {}", status))
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = args;
+        Err("desktop notifications are not supported on this platform".to_string())
     }
 }
 
-pub fn notify_backup_started(drive_label: &str) {
+pub fn notify_backup_started(settings: &NotificationSettings, drive_label: &str) {
+    let args = [("drive", drive_label)];
     notify(
-        "Backup started",
-        &format!("Backing up to \"{}\"…", drive_label),
+        settings,
+        &settings.text("backup_started.title", &args),
+        &settings.text("backup_started.body", &args),
     );
 }
 
-pub fn notify_backup_finished(drive_label: &str, success: bool, interrupted: bool) {
-    let (title, body) = if interrupted {
-        (
-            "Backup interrupted",
-            format!(
-                "Backup to \"{}\" was interrupted (drive disconnected).",
-                drive_label
-            ),
-        )
+pub fn notify_backup_finished(
+    settings: &NotificationSettings,
+    drive_label: &str,
+    success: bool,
+    interrupted: bool,
+) {
+    let outcome = if interrupted {
+        "backup_interrupted"
     } else if success {
-        (
-            "Backup completed",
-            format!("Backup to \"{}\" completed successfully.", drive_label),
-        )
+        "backup_completed"
     } else {
-        (
-            "Backup failed",
-            format!("Backup to \"{}\" failed.", drive_label),
-        )
+        "backup_failed"
     };
-    notify(title, &body);
+    let args = [("drive", drive_label)];
+    notify(
+        settings,
+        &settings.text(&format!("{}.title", outcome), &args),
+        &settings.text(&format!("{}.body", outcome), &args),
+    );
+}
+
+pub fn notify_drive_full(settings: &NotificationSettings, drive_label: &str) {
+    let args = [("drive", drive_label)];
+    notify(
+        settings,
+        &settings.text("drive_full.title", &args),
+        &settings.text("drive_full.body", &args),
+    );
+}
+
+pub fn notify_trusted_device(settings: &NotificationSettings, drive_label: &str) {
+    let args = [("drive", drive_label)];
+    notify(
+        settings,
+        &settings.text("trusted_device.title", &args),
+        &settings.text("trusted_device.body", &args),
+    );
 }
 
-pub fn notify_trusted_device(drive_label: &str) {
+pub fn notify_drive_filling(settings: &NotificationSettings, drive_label: &str, backups_left: u64) {
+    let backups_left = backups_left.to_string();
+    let args = [("drive", drive_label), ("count", backups_left.as_str())];
     notify(
-        "Aegis drive connected",
-        &format!("\"{}\" is connected and ready to back up.", drive_label),
+        settings,
+        &settings.text("drive_filling.title", &args),
+        &settings.text("drive_filling.body", &args),
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(enabled: bool) -> NotificationSettings {
+        NotificationSettings {
+            enabled,
+            app_name: "Aegis".to_string(),
+            icon: None,
+            locale: "en".to_string(),
+        }
+    }
+
+    #[test]
+    fn disabled_suppresses_desktop_notification() {
+        assert_eq!(desktop_args(&settings(false), "t", "b"), None);
+    }
+
+    #[test]
+    fn enabled_uses_configured_app_name_and_icon() {
+        let mut s = settings(true);
+        s.app_name = "Backups".to_string();
+        s.icon = Some("drive-removable-media".to_string());
+        assert_eq!(
+            desktop_args(&s, "t", "b").unwrap(),
+            ["-a", "Backups", "-i", "drive-removable-media", "t", "b"].map(String::from)
+        );
+    }
+
+    #[test]
+    fn blank_icon_is_omitted() {
+        let mut s = settings(true);
+        s.icon = Some("  ".to_string());
+        assert_eq!(
+            desktop_args(&s, "t", "b").unwrap(),
+            ["-a", "Aegis", "t", "b"].map(String::from)
+        );
+    }
+
+    #[test]
+    fn test_notification_uses_each_enabled_channel() {
+        let mut calls = Vec::new();
+        let results = test_channels(&settings(true), |args| {
+            calls.push(args.to_vec());
+            Err("no notification daemon".to_string())
+        });
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0][..2], ["-a", "Aegis"].map(String::from));
+        assert_eq!(
+            results,
+            vec![ChannelResult {
+                channel: "desktop".to_string(),
+                enabled: true,
+                sent: false,
+                error: Some("no notification daemon".to_string()),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_notification_skips_disabled_channel() {
+        let results = test_channels(&settings(false), |_| panic!("disabled channel was used"));
+        assert!(!results[0].enabled);
+        assert!(!results[0].sent);
+    }
+
+    #[test]
+    fn availability_is_probed_once() {
+        let cell = OnceLock::new();
+        let mut probes = 0;
+        assert!(!check_once(&cell, || {
+            probes += 1;
+            false
+        }));
+        // Cached: a later probe is never run, even if it would now succeed.
+        assert!(!check_once(&cell, || {
+            probes += 1;
+            true
+        }));
+        assert_eq!(probes, 1);
+    }
+
+    #[test]
+    fn from_config_defaults() {
+        let s = NotificationSettings::from_config(&AgentConfig::default());
+        assert!(s.enabled);
+        assert_eq!(s.app_name, "Aegis");
+        assert_eq!(s.icon, None);
+    }
+
+    #[test]
+    fn configured_locale_translates_the_test_notification() {
+        let config = AgentConfig {
+            notification_locale: Some("de_DE.UTF-8".to_string()),
+            ..AgentConfig::default()
+        };
+        let s = NotificationSettings::from_config(&config);
+        let mut calls = Vec::new();
+        test_channels(&s, |args| {
+            calls.push(args.to_vec());
+            Ok(())
+        });
+        assert_eq!(
+            calls[0][2..],
+            [
+                "Aegis-Testbenachrichtigung",
+                "Benachrichtigungen funktionieren."
+            ]
+            .map(String::from)
+        );
+    }
+}
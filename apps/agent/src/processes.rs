@@ -0,0 +1,171 @@
+//! Registry of the restic processes the agent has spawned, so support can see (and stop) one
+//! that is stuck. Entries are added when restic starts and removed when its guard is dropped.
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::process::Command;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A running restic child. Only the subcommand is kept: arguments can contain source paths.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct TrackedProcess {
+    pub pid: u32,
+    pub operation: String,
+    pub started_epoch: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct ProcessRegistry {
+    processes: HashMap<u32, TrackedProcess>,
+}
+
+impl ProcessRegistry {
+    fn register(&mut self, pid: u32, operation: &str, now: u64) {
+        self.processes.insert(
+            pid,
+            TrackedProcess {
+                pid,
+                operation: operation.to_string(),
+                started_epoch: now,
+            },
+        );
+    }
+
+    fn unregister(&mut self, pid: u32) {
+        self.processes.remove(&pid);
+    }
+
+    /// Tracked processes, oldest first.
+    pub fn list(&self) -> Vec<TrackedProcess> {
+        let mut processes: Vec<TrackedProcess> = self.processes.values().cloned().collect();
+        processes.sort_by_key(|p| (p.started_epoch, p.pid));
+        processes
+    }
+
+    /// Signal a tracked process with `kill`. Returns false (and signals nothing) for a pid the
+    /// agent didn't spawn, so this can't be used to stop arbitrary processes. The entry stays
+    /// until the process exits and its guard is dropped.
+    pub fn terminate(&self, pid: u32, kill: impl FnOnce(u32) -> bool) -> Option<bool> {
+        if !self.processes.contains_key(&pid) {
+            return None;
+        }
+        Some(kill(pid))
+    }
+}
+
+pub fn registry() -> MutexGuard<'static, ProcessRegistry> {
+    static REGISTRY: OnceLock<Mutex<ProcessRegistry>> = OnceLock::new();
+    REGISTRY
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+/// Removes the process from the registry when dropped (after the child has been waited on).
+pub struct ProcessGuard {
+    pid: Option<u32>,
+}
+
+impl Drop for ProcessGuard {
+    fn drop(&mut self) {
+        if let Some(pid) = self.pid {
+            registry().unregister(pid);
+        }
+    }
+}
+
+/// Record a freshly spawned child. `pid` is None if the child already exited.
+pub fn track(pid: Option<u32>, operation: &str) -> ProcessGuard {
+    if let Some(pid) = pid {
+        registry().register(pid, operation, now_epoch());
+    }
+    ProcessGuard { pid }
+}
+
+/// Ask a process to stop (SIGTERM) via the `kill` utility.
+pub fn send_terminate(pid: u32) -> bool {
+    Command::new("kill")
+        .arg("-TERM")
+        .arg(pid.to_string())
+        .status()
+        .map(|s| s.success())
+        .unwrap_or(false)
+}
+
+fn now_epoch() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register_list_and_unregister() {
+        let mut registry = ProcessRegistry::default();
+        registry.register(200, "check", 20);
+        registry.register(100, "backup", 10);
+        let listed: Vec<(u32, String)> = registry
+            .list()
+            .into_iter()
+            .map(|p| (p.pid, p.operation))
+            .collect();
+        assert_eq!(
+            listed,
+            vec![(100, "backup".to_string()), (200, "check".to_string())]
+        );
+        registry.unregister(100);
+        assert_eq!(registry.list().len(), 1);
+    }
+
+    #[test]
+    fn terminate_only_signals_tracked_processes() {
+        let mut registry = ProcessRegistry::default();
+        registry.register(100, "backup", 10);
+        let mut signalled = Vec::new();
+        assert_eq!(
+            registry.terminate(100, |pid| {
+                signalled.push(pid);
+                true
+            }),
+            Some(true)
+        );
+        assert_eq!(
+            registry.terminate(1, |pid| {
+                signalled.push(pid);
+                true
+            }),
+            None
+        );
+        assert_eq!(signalled, vec![100]);
+    }
+
+    #[test]
+    fn guard_removes_entry_when_dropped() {
+        // A pid far above pid_max so it can't collide with a real restic child in other tests.
+        let pid = u32::MAX - 7;
+        let guard = track(Some(pid), "prune");
+        assert!(registry().list().iter().any(|p| p.pid == pid));
+        drop(guard);
+        assert!(!registry().list().iter().any(|p| p.pid == pid));
+    }
+
+    #[tokio::test]
+    async fn terminate_stops_a_real_child() {
+        let mut child = tokio::process::Command::new("sleep")
+            .arg("30")
+            .spawn()
+            .unwrap();
+        let guard = track(child.id(), "backup");
+        let pid = child.id().unwrap();
+        assert_eq!(registry().terminate(pid, send_terminate), Some(true));
+        let status = child.wait().await.unwrap();
+        drop(guard);
+        assert!(!status.success());
+        assert!(!registry().list().iter().any(|p| p.pid == pid));
+    }
+}
@@ -0,0 +1,151 @@
+//! Named agent profiles (e.g. "work" and "personal"), each with its own config file, log
+//! directory and keychain namespace. Selected with `--profile <name>` or `AEGIS_PROFILE`; without
+//! either, the default profile uses the same paths and keychain service as before profiles existed.
+//! All profiles share the IPC port, so only one runs at a time.
+
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Keychain service name of the default profile.
+pub const DEFAULT_SERVICE: &str = "Aegis";
+
+const MAX_PROFILE_LEN: usize = 32;
+
+static ACTIVE: OnceLock<Option<String>> = OnceLock::new();
+
+/// Select the profile for this process from CLI args (`--profile name` / `--profile=name`) or the
+/// `AEGIS_PROFILE` env var. Call once at startup, before the config is loaded.
+pub fn init_from_env() -> anyhow::Result<Option<&'static str>> {
+    let requested =
+        profile_from_args(std::env::args().skip(1)).or_else(|| std::env::var("AEGIS_PROFILE").ok());
+    let profile = match requested {
+        Some(raw) => parse_profile(&raw)?,
+        None => None,
+    };
+    Ok(ACTIVE.get_or_init(|| profile).as_deref())
+}
+
+/// The selected profile; None for the default profile.
+pub fn active() -> Option<&'static str> {
+    ACTIVE.get().and_then(|p| p.as_deref())
+}
+
+fn profile_from_args(mut args: impl Iterator<Item = String>) -> Option<String> {
+    while let Some(arg) = args.next() {
+        if arg == "--profile" {
+            return args.next();
+        }
+        if let Some(value) = arg.strip_prefix("--profile=") {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
+
+/// Validate a profile name. Empty or "default" select the default profile. Names are used in
+/// paths and service names, so only ASCII letters, digits, '-' and '_' are allowed.
+pub fn parse_profile(raw: &str) -> anyhow::Result<Option<String>> {
+    let name = raw.trim();
+    if name.is_empty() || name.eq_ignore_ascii_case("default") {
+        return Ok(None);
+    }
+    let valid = name.len() <= MAX_PROFILE_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(anyhow::anyhow!(
+            "invalid profile name (use up to {} letters, digits, '-' or '_')",
+            MAX_PROFILE_LEN
+        ));
+    }
+    Ok(Some(name.to_string()))
+}
+
+/// Config file for `profile` under the platform config dir.
+pub fn config_path(config_dir: &Path, profile: Option<&str>) -> PathBuf {
+    match profile {
+        None => config_dir.join("config.json"),
+        Some(name) => config_dir.join("profiles").join(name).join("config.json"),
+    }
+}
+
+/// Log directory for `profile` under the platform data dir, so one profile's support bundle
+/// doesn't carry another's log lines.
+pub fn log_dir(data_dir: &Path, profile: Option<&str>) -> PathBuf {
+    match profile {
+        None => data_dir.join("logs"),
+        Some(name) => data_dir.join("profiles").join(name).join("logs"),
+    }
+}
+
+/// Keychain service under which `profile` stores passphrases.
+pub fn keychain_service(profile: Option<&str>) -> String {
+    match profile {
+        None => DEFAULT_SERVICE.to_string(),
+        Some(name) => format!("{}-{}", DEFAULT_SERVICE, name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_profile_keeps_existing_locations() {
+        let dir = Path::new("/home/u/.config/aegis");
+        assert_eq!(config_path(dir, None), dir.join("config.json"));
+        assert_eq!(
+            log_dir(Path::new("/home/u/.local/share/aegis"), None),
+            Path::new("/home/u/.local/share/aegis/logs")
+        );
+        assert_eq!(keychain_service(None), "Aegis");
+    }
+
+    #[test]
+    fn profiles_resolve_to_distinct_paths_and_services() {
+        let dir = Path::new("/home/u/.config/aegis");
+        let work = config_path(dir, Some("work"));
+        let personal = config_path(dir, Some("personal"));
+        assert_eq!(work, dir.join("profiles/work/config.json"));
+        assert_ne!(work, personal);
+        assert_ne!(work, config_path(dir, None));
+        let data = Path::new("/home/u/.local/share/aegis");
+        assert_eq!(log_dir(data, Some("work")), data.join("profiles/work/logs"));
+        assert_ne!(log_dir(data, Some("work")), log_dir(data, None));
+        assert_eq!(keychain_service(Some("work")), "Aegis-work");
+        assert_ne!(
+            keychain_service(Some("work")),
+            keychain_service(Some("personal"))
+        );
+    }
+
+    #[test]
+    fn parse_profile_validates_names() {
+        assert_eq!(parse_profile("").unwrap(), None);
+        assert_eq!(parse_profile("Default").unwrap(), None);
+        assert_eq!(parse_profile(" work ").unwrap().as_deref(), Some("work"));
+        assert!(parse_profile("../etc").is_err());
+        assert!(parse_profile("a b").is_err());
+        assert!(parse_profile(&"x".repeat(MAX_PROFILE_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn profile_from_args_forms() {
+        let args = |v: &[&str]| {
+            v.iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>()
+                .into_iter()
+        };
+        assert_eq!(
+            profile_from_args(args(&["--profile", "work"])).as_deref(),
+            Some("work")
+        );
+        assert_eq!(
+            profile_from_args(args(&["--profile=home"])).as_deref(),
+            Some("home")
+        );
+        assert_eq!(profile_from_args(args(&["--verbose"])), None);
+    }
+}
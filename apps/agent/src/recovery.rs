@@ -38,6 +38,43 @@ pub fn export_recovery_kit(
     Ok(kit)
 }
 
+/// Result of comparing a drive's repository ID to the one recorded in a recovery kit.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RepoIdMatch {
+    Match,
+    Mismatch,
+    /// The drive's repository ID could not be determined.
+    Unknown,
+}
+
+/// Minimum length for an abbreviated repository ID (restic's short form) to count as a match.
+const SHORT_ID_MIN_LEN: usize = 8;
+
+/// Compare IDs case-insensitively; a short-form ID (8+ chars) matches its full form.
+pub fn compare_repository_ids(expected: &str, actual: Option<&str>) -> RepoIdMatch {
+    let expected = expected.trim().to_lowercase();
+    let Some(actual) = actual
+        .map(|a| a.trim().to_lowercase())
+        .filter(|a| !a.is_empty())
+    else {
+        return RepoIdMatch::Unknown;
+    };
+    if expected == actual {
+        return RepoIdMatch::Match;
+    }
+    let (short, long) = if expected.len() <= actual.len() {
+        (&expected, &actual)
+    } else {
+        (&actual, &expected)
+    };
+    if short.len() >= SHORT_ID_MIN_LEN && long.starts_with(short.as_str()) {
+        RepoIdMatch::Match
+    } else {
+        RepoIdMatch::Mismatch
+    }
+}
+
 fn default_instructions() -> String {
     let mut text = String::new();
     text.push_str("Aegis Recovery Kit\n\n");
@@ -61,3 +98,60 @@ fn now_epoch() -> u64 {
         .unwrap_or_default()
         .as_secs()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare_exact_and_case_insensitive() {
+        assert_eq!(
+            compare_repository_ids("ABCDEF0123456789", Some("abcdef0123456789")),
+            RepoIdMatch::Match
+        );
+        assert_eq!(
+            compare_repository_ids(" abcdef0123456789 ", Some("abcdef0123456789")),
+            RepoIdMatch::Match
+        );
+    }
+
+    #[test]
+    fn compare_short_form_matches_full() {
+        assert_eq!(
+            compare_repository_ids("abcdef01", Some("abcdef0123456789")),
+            RepoIdMatch::Match
+        );
+        assert_eq!(
+            compare_repository_ids("abcdef0123456789", Some("abcdef01")),
+            RepoIdMatch::Match
+        );
+    }
+
+    #[test]
+    fn compare_too_short_prefix_is_mismatch() {
+        assert_eq!(
+            compare_repository_ids("abc", Some("abcdef0123456789")),
+            RepoIdMatch::Mismatch
+        );
+    }
+
+    #[test]
+    fn compare_different_ids_mismatch() {
+        assert_eq!(
+            compare_repository_ids("abcdef0123456789", Some("123456789abcdef0")),
+            RepoIdMatch::Mismatch
+        );
+    }
+
+    #[test]
+    fn compare_missing_actual_is_unknown() {
+        assert_eq!(
+            compare_repository_ids("abcdef01", None),
+            RepoIdMatch::Unknown
+        );
+        assert_eq!(
+            compare_repository_ids("abcdef01", Some("  ")),
+            RepoIdMatch::Unknown
+        );
+    }
+}
@@ -1,12 +1,17 @@
+use crate::errors::AgentError;
+use crate::processes;
 use anyhow::{anyhow, Context};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::process::Stdio;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use std::sync::{Arc, Mutex as StdMutex, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::process::Command;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, Mutex, OwnedMutexGuard};
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 use which::which;
 
 #[derive(Debug, Clone)]
@@ -17,9 +22,22 @@ pub struct Restic {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SnapshotInfo {
     pub id: String,
+    /// restic's raw timestamp (RFC 3339, local offset, nanoseconds).
     pub time: String,
+    /// `time` as Unix seconds (fraction dropped); None if restic's string couldn't be parsed.
+    #[serde(default)]
+    pub time_epoch: Option<u64>,
     pub hostname: Option<String>,
     pub tags: Option<Vec<String>>,
+    /// ID of the snapshot this one was made incrementally from; absent for the first backup of a path set.
+    #[serde(default)]
+    pub parent: Option<String>,
+    /// Free-text description from the drive's sidecar file (not stored by restic).
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Protected from retention (listed in the drive's sidecar file, see `drive::read_protected`).
+    #[serde(default)]
+    pub protected: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -27,6 +45,11 @@ pub struct BackupSummary {
     pub snapshot_id: Option<String>,
     pub data_added: Option<u64>,
     pub files_processed: Option<u64>,
+    /// Files not in the parent snapshot / changed since it.
+    pub files_new: Option<u64>,
+    pub files_changed: Option<u64>,
+    /// restic saved the snapshot but couldn't read some of the source files.
+    pub incomplete: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -41,6 +64,8 @@ pub(crate) struct ResticSummaryLine {
     snapshot_id: Option<String>,
     data_added: Option<u64>,
     total_files_processed: Option<u64>,
+    files_new: Option<u64>,
+    files_changed: Option<u64>,
 }
 
 /// Progress update from a single restic --json status line.
@@ -51,7 +76,6 @@ pub struct BackupProgressReport {
     pub total_files: u64,
     pub bytes_done: u64,
     pub total_bytes: u64,
-    #[allow(dead_code)]
     pub current_file: Option<String>,
 }
 
@@ -68,20 +92,858 @@ pub(crate) struct ResticStatusLine {
     total_bytes: Option<u64>,
     #[serde(default)]
     bytes_done: Option<u64>,
-    current_file: Option<String>,
+    /// Files being read right now (one per worker); absent between files.
+    #[serde(default)]
+    current_files: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub(crate) struct ResticConfig {
     id: String,
+    /// Repository format version (2 = compression support, restic 0.14+).
+    #[serde(default = "default_repo_version")]
+    version: u32,
+}
+
+fn default_repo_version() -> u32 {
+    1
+}
+
+/// restic release version, e.g. 0.16.4.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ResticVersion(pub u32, pub u32, pub u32);
+
+impl ResticVersion {
+    /// Parse `restic version` output ("restic 0.16.4 compiled with go1.21.6 on linux/amd64").
+    pub fn parse(output: &str) -> Option<Self> {
+        let version = output.split_whitespace().nth(1)?;
+        let mut parts = version.split('.').map(|part| {
+            part.chars()
+                .take_while(|c| c.is_ascii_digit())
+                .collect::<String>()
+                .parse::<u32>()
+                .ok()
+        });
+        Some(Self(
+            parts.next()??,
+            parts.next()??,
+            parts.next().flatten().unwrap_or(0),
+        ))
+    }
+
+    /// Newest repository format this restic can open.
+    pub fn max_repo_version(self) -> u32 {
+        if self >= ResticVersion(0, 14, 0) {
+            2
+        } else {
+            1
+        }
+    }
+
+    pub fn supports_repo_version(self, repo_version: u32) -> bool {
+        repo_version <= self.max_repo_version()
+    }
+}
+
+impl std::fmt::Display for ResticVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+/// Named performance preset; sets compression, pack size and read concurrency together.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum PerformanceProfile {
+    Fast,
+    #[default]
+    Balanced,
+    MaxSpaceSavings,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Auto,
+    Off,
+    Max,
+}
+
+impl Compression {
+    fn as_arg(self) -> &'static str {
+        match self {
+            Compression::Auto => "auto",
+            Compression::Off => "off",
+            Compression::Max => "max",
+        }
+    }
+}
+
+/// Advanced per-flag overrides applied on top of the selected profile.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PerformanceOverrides {
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    /// restic --pack-size in MiB.
+    #[serde(default)]
+    pub pack_size_mib: Option<u32>,
+    /// restic --read-concurrency; unset follows the profile, scaled up for multi-source backups.
+    #[serde(default)]
+    pub read_concurrency: Option<u32>,
+}
+
+/// Ceiling for the read concurrency picked from the number of sources.
+const MAX_SCALED_READ_CONCURRENCY: u32 = 8;
+
+/// restic walks the sources one after another with a single scanner and has no flag for that, but
+/// its file readers are concurrent: give a backup of several sources (often on different disks)
+/// at least one reader per source, up to a ceiling.
+fn scaled_read_concurrency(profile_default: u32, source_count: usize) -> u32 {
+    let per_source = u32::try_from(source_count)
+        .unwrap_or(u32::MAX)
+        .min(MAX_SCALED_READ_CONCURRENCY);
+    profile_default.max(per_source)
+}
+
+/// `--host` for the backup, so snapshots carry a stable name instead of the machine's hostname.
+/// None (or blank) leaves restic's default, the system hostname.
+pub fn host_args(hostname: Option<&str>) -> Vec<String> {
+    match hostname.map(str::trim).filter(|h| !h.is_empty()) {
+        Some(host) => vec!["--host".to_string(), host.to_string()],
+        None => Vec::new(),
+    }
+}
+
+/// `--dry-run` for a backup that only reports what it would add: restic scans and prints its
+/// summary but writes no data and creates no snapshot.
+pub fn dry_run_args(dry_run: bool) -> Vec<String> {
+    if dry_run {
+        vec!["--dry-run".to_string()]
+    } else {
+        Vec::new()
+    }
+}
+
+/// restic's `--limit-upload` / `--limit-download`, in KiB/s; 0 is unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BandwidthLimits {
+    pub upload_kb: u32,
+    pub download_kb: u32,
+}
+
+/// The limit flags for the non-zero limits; nothing when both are unlimited.
+pub fn bandwidth_args(limits: BandwidthLimits) -> Vec<String> {
+    let mut args = Vec::new();
+    if limits.upload_kb > 0 {
+        args.push("--limit-upload".to_string());
+        args.push(limits.upload_kb.to_string());
+    }
+    if limits.download_kb > 0 {
+        args.push("--limit-download".to_string());
+        args.push(limits.download_kb.to_string());
+    }
+    args
+}
+
+/// Longest tag derived from a source label.
+const SOURCE_TAG_MAX_LEN: usize = 64;
+
+/// A source label as a snapshot tag: whitespace runs become `-` and commas (restic's tag list
+/// separator) and control characters are dropped. None if nothing is left.
+pub fn source_tag(label: &str) -> Option<String> {
+    let words: Vec<String> = label
+        .split_whitespace()
+        .map(|word| {
+            word.chars()
+                .filter(|c| *c != ',' && !c.is_control())
+                .collect::<String>()
+        })
+        .filter(|word| !word.is_empty())
+        .collect();
+    let tag: String = words.join("-").chars().take(SOURCE_TAG_MAX_LEN).collect();
+    (!tag.is_empty()).then_some(tag)
+}
+
+/// `--tag` for each source label in the backup (sanitized, duplicates dropped), so snapshots can
+/// be filtered by the sources they contain.
+pub fn source_tag_args<'a>(labels: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+    for tag in labels.into_iter().filter_map(source_tag) {
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    tags.into_iter()
+        .flat_map(|tag| ["--tag".to_string(), tag])
+        .collect()
+}
+
+/// restic's own `--pack-size` (MiB) and `--read-concurrency` defaults.
+const RESTIC_DEFAULT_PACK_SIZE_MIB: u32 = 16;
+const RESTIC_DEFAULT_READ_CONCURRENCY: u32 = 2;
+
+/// Expand a profile (plus overrides) into restic backup arguments for `source_count` sources.
+/// Only flags that differ from restic's defaults are passed: restic before 0.14 has no
+/// `--compression`/`--pack-size` and before 0.15 no `--read-concurrency`, so `balanced` on one
+/// source passes nothing and still works there.
+pub fn performance_args(
+    profile: PerformanceProfile,
+    overrides: &PerformanceOverrides,
+    source_count: usize,
+) -> Vec<String> {
+    let (compression, pack_size_mib, read_concurrency) = match profile {
+        PerformanceProfile::Fast => (Compression::Off, 16, 4),
+        PerformanceProfile::Balanced => (
+            Compression::Auto,
+            RESTIC_DEFAULT_PACK_SIZE_MIB,
+            RESTIC_DEFAULT_READ_CONCURRENCY,
+        ),
+        PerformanceProfile::MaxSpaceSavings => (Compression::Max, 64, 2),
+    };
+    let compression = overrides.compression.unwrap_or(compression);
+    let pack_size_mib = overrides.pack_size_mib.unwrap_or(pack_size_mib);
+    let read_concurrency = overrides
+        .read_concurrency
+        .unwrap_or_else(|| scaled_read_concurrency(read_concurrency, source_count));
+    let mut args = Vec::new();
+    if compression != Compression::Auto {
+        args.push("--compression".to_string());
+        args.push(compression.as_arg().to_string());
+    }
+    if pack_size_mib > 0 && pack_size_mib != RESTIC_DEFAULT_PACK_SIZE_MIB {
+        args.push("--pack-size".to_string());
+        args.push(pack_size_mib.to_string());
+    }
+    if read_concurrency > 0 && read_concurrency != RESTIC_DEFAULT_READ_CONCURRENCY {
+        args.push("--read-concurrency".to_string());
+        args.push(read_concurrency.to_string());
+    }
+    args
+}
+
+/// restic's exit code for "wrong password or no key found" (restic >= 0.17).
+const EXIT_WRONG_PASSWORD: i32 = 12;
+
+/// restic's exit code for "repository does not exist" (restic >= 0.17).
+const EXIT_REPOSITORY_MISSING: i32 = 10;
+
+/// restic's exit code for "failed to lock repository" (restic >= 0.17).
+const EXIT_REPOSITORY_LOCKED: i32 = 11;
+
+/// Turn a failed restic run into an error, singling out a wrong repository password.
+fn classify_failure(code: Option<i32>, stderr: &str) -> anyhow::Error {
+    let stderr = stderr.trim();
+    if code == Some(EXIT_WRONG_PASSWORD) || stderr.contains("wrong password or no key found") {
+        return anyhow::Error::new(AgentError::WrongPassword).context("restic failed");
+    }
+    if is_repository_missing(code, stderr) {
+        return anyhow::Error::new(AgentError::RepositoryMissing).context("restic failed");
+    }
+    if stderr.contains("unsupported repository version") {
+        return anyhow::Error::new(AgentError::ResticTooOld).context("restic failed");
+    }
+    if code == Some(EXIT_REPOSITORY_LOCKED) || stderr.contains("repository is already locked") {
+        return anyhow::Error::new(AgentError::RepositoryLocked)
+            .context(format!("restic failed: {}", stderr));
+    }
+    if is_disk_full(stderr) {
+        return anyhow::Error::new(AgentError::DriveFull).context("restic failed");
+    }
+    if is_disk_io_error(stderr) {
+        return anyhow::Error::new(AgentError::DriveIoError)
+            .context(format!("restic failed: {}", stderr));
+    }
+    anyhow!("restic failed: {}", stderr)
+}
+
+/// True if restic's stderr says the target filesystem ran out of space (ENOSPC, or Windows'
+/// ERROR_DISK_FULL wording).
+fn is_disk_full(stderr: &str) -> bool {
+    let stderr = stderr.to_lowercase();
+    stderr.contains("no space left on device") || stderr.contains("not enough space on the disk")
+}
+
+/// True if restic found no repository at all, as opposed to one it couldn't open. Older restic
+/// has no exit code for it and asks whether there is a repository at that location.
+fn is_repository_missing(code: Option<i32>, stderr: &str) -> bool {
+    code == Some(EXIT_REPOSITORY_MISSING)
+        || stderr.contains("repository does not exist")
+        || stderr.contains("Is there a repository at the following location?")
+}
+
+/// True if restic's stderr carries a kernel I/O error (EIO, or the device vanishing mid-write)
+/// rather than a problem with the repository's contents.
+fn is_disk_io_error(stderr: &str) -> bool {
+    let stderr = stderr.to_lowercase();
+    stderr.contains("input/output error")
+        || stderr.contains("i/o error")
+        || stderr.contains("no such device")
+        || stderr.contains("device not configured")
+}
+
+/// `--no-lock` for a read-only command when enabled. Never used for commands that write.
+fn read_only_args(mut args: Vec<String>, no_lock: bool) -> Vec<String> {
+    if no_lock {
+        args.push("--no-lock".to_string());
+    }
+    args
+}
+
+fn snapshots_args(no_lock: bool) -> Vec<String> {
+    read_only_args(vec!["snapshots".to_string(), "--json".to_string()], no_lock)
+}
+
+/// `restore` only reads the repository, so with `no_lock` it can run next to a backup (or a
+/// prune holding restic's exclusive lock).
+fn restore_args(
+    snapshot_id: &str,
+    target: &Path,
+    includes: &[String],
+    no_lock: bool,
+) -> Vec<String> {
+    let mut args = vec![
+        "restore".to_string(),
+        snapshot_id.to_string(),
+        "--target".to_string(),
+        target.to_string_lossy().to_string(),
+    ];
+    for include in includes {
+        args.push("--include".to_string());
+        args.push(include.clone());
+    }
+    read_only_args(args, no_lock)
+}
+
+/// How a privileged restore gets root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Elevation {
+    /// The agent already runs as root.
+    AlreadyRoot,
+    /// Ask through pkexec (polkit prompts the user for their password).
+    Pkexec,
+}
+
+/// Program and arguments for restic run with `elevation`.
+fn privileged_argv(
+    binary: &Path,
+    repo: &Path,
+    args: &[String],
+    elevation: Elevation,
+) -> Vec<String> {
+    let mut argv = Vec::new();
+    if elevation == Elevation::Pkexec {
+        argv.push("pkexec".to_string());
+    }
+    argv.push(binary.to_string_lossy().to_string());
+    argv.push("--repo".to_string());
+    argv.push(repo.to_string_lossy().to_string());
+    argv.extend(args.iter().cloned());
+    argv
+}
+
+/// What a restic run does, for debug logs: the environment variable names, the program, the
+/// subcommand (`args[0]`) and the flag names. Values (the repository, paths, snapshot IDs,
+/// patterns) are left out, since they can be private; the passphrase never is an argument.
+fn render_command(env: &[&str], program: &[&str], args: &[String]) -> String {
+    let flags = args
+        .iter()
+        .skip(1)
+        .filter(|arg| arg.starts_with('-'))
+        .map(|arg| arg.split('=').next().unwrap_or_default());
+    env.iter()
+        .map(|var| format!("{}=<redacted>", var))
+        .chain(program.iter().map(|p| p.to_string()))
+        .chain(args.first().cloned())
+        .chain(flags.map(str::to_string))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// `restic dump` of a whole snapshot as a tar archive on stdout.
+fn dump_args(snapshot_id: &str, no_lock: bool) -> Vec<String> {
+    read_only_args(
+        vec![
+            "dump".to_string(),
+            "--archive".to_string(),
+            "tar".to_string(),
+            snapshot_id.to_string(),
+            "/".to_string(),
+        ],
+        no_lock,
+    )
+}
+
+fn stats_args(snapshot_id: &str, no_lock: bool) -> Vec<String> {
+    read_only_args(
+        vec![
+            "stats".to_string(),
+            "--json".to_string(),
+            "--snapshot".to_string(),
+            snapshot_id.to_string(),
+        ],
+        no_lock,
+    )
+}
+
+/// restic subcommand of an argument list, for the process registry.
+fn operation_name(args: &[String]) -> &str {
+    args.first().map(String::as_str).unwrap_or("restic")
+}
+
+/// What `forget --prune` removed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PruneSummary {
+    pub removed_snapshots: u64,
+    /// Size of the data the prune deleted or repacked away; None if restic didn't report it.
+    pub freed_bytes: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ForgetGroup {
+    #[serde(default)]
+    remove: Option<Vec<ForgetSnapshot>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ForgetSnapshot {
+    id: String,
+}
+
+/// IDs of the snapshots a `forget --dry-run --json` run would remove, over all snapshot groups.
+fn parse_forget_plan(stdout: &[u8]) -> anyhow::Result<Vec<String>> {
+    let groups: Vec<ForgetGroup> = serde_json::from_slice(stdout).context("parse forget plan")?;
+    Ok(groups
+        .into_iter()
+        .flat_map(|group| group.remove.unwrap_or_default())
+        .map(|snapshot| snapshot.id)
+        .collect())
+}
+
+/// Parse restic's snapshot time, e.g. `2024-03-09T14:05:33.123456789+01:00` or `...Z`, into Unix
+/// seconds. The fraction is optional and dropped; the offset is required.
+pub fn parse_snapshot_time(time: &str) -> Option<u64> {
+    let time = time.trim();
+    let (date, rest) = time.split_once(['T', 't', ' '])?;
+    let mut date_parts = date.splitn(3, '-');
+    let year: i64 = parse_digits(date_parts.next()?, 4)?;
+    let month: u32 = parse_digits(date_parts.next()?, 2)?;
+    let day: u32 = parse_digits(date_parts.next()?, 2)?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let offset_at = rest.find(['Z', 'z', '+', '-'])?;
+    let (clock, offset) = rest.split_at(offset_at);
+    let clock = clock.split_once('.').map_or(clock, |(whole, fraction)| {
+        if fraction.is_empty() || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+            ""
+        } else {
+            whole
+        }
+    });
+    let mut clock_parts = clock.splitn(3, ':');
+    let hour: i64 = parse_digits(clock_parts.next()?, 2)?;
+    let minute: i64 = parse_digits(clock_parts.next()?, 2)?;
+    let second: i64 = parse_digits(clock_parts.next()?, 2)?;
+    if hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let offset_secs = match offset {
+        "Z" | "z" => 0,
+        _ => {
+            let sign = if offset.starts_with('-') { -1 } else { 1 };
+            let (h, m) = offset[1..].split_once(':')?;
+            let h: i64 = parse_digits(h, 2)?;
+            let m: i64 = parse_digits(m, 2)?;
+            sign * (h * 3600 + m * 60)
+        }
+    };
+    let epoch = days_from_civil(year, month, day) * 86_400 + hour * 3600 + minute * 60 + second
+        - offset_secs;
+    u64::try_from(epoch).ok()
+}
+
+/// A fixed-width run of ASCII digits.
+fn parse_digits<T: std::str::FromStr>(text: &str, width: usize) -> Option<T> {
+    if text.len() != width || !text.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    text.parse().ok()
+}
+
+/// (year, month, day) in the proleptic Gregorian calendar to days since 1970-01-01.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let month = i64::from(month);
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Read the text output of `forget --prune`: one "remove N snapshots:" line per snapshot group
+/// and the prune's "total prune: X blobs / 1.021 GiB" line.
+fn parse_prune_output(stdout: &str) -> PruneSummary {
+    let mut summary = PruneSummary::default();
+    for line in stdout.lines().map(str::trim) {
+        if let Some(count) = parse_remove_count(line) {
+            summary.removed_snapshots += count;
+        } else if let Some(rest) = line.strip_prefix("total prune:") {
+            summary.freed_bytes = rest.split_once('/').and_then(|(_, size)| parse_size(size));
+        }
+    }
+    summary
+}
+
+/// N from forget's "remove N snapshots:" heading.
+fn parse_remove_count(line: &str) -> Option<u64> {
+    let rest = line.strip_prefix("remove ")?;
+    rest.strip_suffix(" snapshots:")
+        .or_else(|| rest.strip_suffix(" snapshot:"))
+        .and_then(|n| n.trim().parse::<u64>().ok())
+}
+
+/// Progress updates per second asked of restic while it prunes.
+const PRUNE_PROGRESS_FPS: &str = "1";
+
+/// How far a `forget --prune` has got, from restic's progress counters.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PruneProgress {
+    pub snapshots_to_remove: u64,
+    pub snapshots_removed: u64,
+    /// What restic is counting now (e.g. "packs repacked") and how far it is.
+    pub stage: String,
+    pub stage_done: u64,
+    pub stage_total: u64,
+    /// Size restic plans to free ("total prune"), once it has said.
+    pub bytes_to_free: Option<u64>,
+    /// Estimate of the bytes freed so far: the planned size scaled by the share of old packs
+    /// deleted.
+    pub bytes_freed: Option<u64>,
+}
+
+/// A progress counter line such as "[0:02] 45.00%  9 / 20 packs repacked": (done, total, what).
+fn parse_progress_counter(line: &str) -> Option<(u64, u64, &str)> {
+    let (_, rest) = line.strip_prefix('[')?.split_once(']')?;
+    let mut rest = rest.trim_start();
+    if let Some((percent, tail)) = rest.split_once('%') {
+        if percent.parse::<f64>().is_ok() {
+            rest = tail.trim_start();
+        }
+    }
+    let (done, rest) = rest.split_once(" / ")?;
+    let (total, what) = rest.trim_start().split_once(' ')?;
+    Some((done.trim().parse().ok()?, total.parse().ok()?, what.trim()))
+}
+
+/// Follows `forget --prune` output line by line.
+#[derive(Debug, Default)]
+struct PruneProgressParser {
+    progress: PruneProgress,
+    /// Past the forget step: counters are about packs and index files, not snapshots.
+    pruning: bool,
+    /// In the step deleting old packs, whose counter tracks the space being freed.
+    deleting_packs: bool,
+}
+
+impl PruneProgressParser {
+    fn new(snapshots_to_remove: u64) -> Self {
+        Self {
+            progress: PruneProgress {
+                snapshots_to_remove,
+                ..PruneProgress::default()
+            },
+            ..Self::default()
+        }
+    }
+
+    /// Take one line of output; true if the progress changed.
+    fn feed(&mut self, line: &str) -> bool {
+        let line = line.trim();
+        let progress = &mut self.progress;
+        if let Some((done, total, what)) = parse_progress_counter(line) {
+            progress.stage = what.to_string();
+            progress.stage_done = done;
+            progress.stage_total = total;
+            if !self.pruning && what == "files deleted" {
+                progress.snapshots_removed = done;
+                progress.snapshots_to_remove = progress.snapshots_to_remove.max(total);
+            } else if self.deleting_packs && total > 0 {
+                if let Some(bytes) = progress.bytes_to_free {
+                    let freed = (bytes as f64 * done.min(total) as f64 / total as f64) as u64;
+                    progress.bytes_freed = Some(freed.max(progress.bytes_freed.unwrap_or(0)));
+                }
+            }
+            return true;
+        }
+        if let Some(count) = parse_remove_count(line).filter(|_| !self.pruning) {
+            progress.snapshots_to_remove += count;
+            return true;
+        }
+        if let Some(rest) = line.strip_prefix("total prune:") {
+            progress.bytes_to_free = rest.split_once('/').and_then(|(_, size)| parse_size(size));
+            return true;
+        }
+        if line == "loading indexes..." {
+            self.pruning = true;
+        } else if line == "deleting unreferenced packs"
+            || (line.starts_with("removing ") && line.ends_with(" old packs"))
+        {
+            self.deleting_packs = true;
+        } else if line == "rebuilding index" || line == "deleting obsolete index files" {
+            self.deleting_packs = false;
+        }
+        false
+    }
+}
+
+/// What `repair index` and `repair snapshots --forget` changed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct RepairSummary {
+    /// Pack files restic had to read back into the index (it lists each one it adds).
+    pub packs_reindexed: u64,
+    /// Snapshots rewritten without the data that was missing.
+    pub snapshots_modified: u64,
+    /// Damaged originals removed after being rewritten (`--forget`).
+    pub snapshots_removed: u64,
+}
+
+/// Pick the counts out of `repair index` / `repair snapshots` output; restic has no JSON for
+/// these, so lines it doesn't recognize are ignored.
+fn parse_repair_output(stdout: &str) -> RepairSummary {
+    let mut summary = RepairSummary::default();
+    for line in stdout.lines().map(str::trim) {
+        if line.starts_with("adding pack file to index") {
+            summary.packs_reindexed += 1;
+        } else if line.starts_with("removed old snapshot") {
+            summary.snapshots_removed += 1;
+        } else if let Some(rest) = line.strip_prefix("modified ") {
+            if let Some(count) = rest
+                .strip_suffix(" snapshots")
+                .or_else(|| rest.strip_suffix(" snapshot"))
+                .and_then(|n| n.trim().parse::<u64>().ok())
+            {
+                summary.snapshots_modified = count;
+            }
+        }
+    }
+    summary
+}
+
+/// Parse a restic size such as "1.021 GiB" or "0 B" into bytes.
+fn parse_size(text: &str) -> Option<u64> {
+    let (value, unit) = text.trim().split_once(' ')?;
+    let value: f64 = value.parse().ok()?;
+    let multiplier: u64 = match unit.trim() {
+        "B" => 1,
+        "KiB" => 1 << 10,
+        "MiB" => 1 << 20,
+        "GiB" => 1 << 30,
+        "TiB" => 1 << 40,
+        _ => return None,
+    };
+    Some((value * multiplier as f64).round() as u64)
+}
+
+/// True if the repository at `repo` has no snapshots yet (or isn't initialized), i.e. the next
+/// backup is a full one. Reads the repository layout only, so no passphrase is needed.
+pub fn repository_is_empty(repo: &Path) -> bool {
+    match std::fs::read_dir(repo.join("snapshots")) {
+        Ok(mut entries) => !entries.any(|e| e.is_ok_and(|e| e.path().is_file())),
+        Err(_) => true,
+    }
+}
+
+/// Shortest snapshot ID prefix taken from the user or the drive (restic's short ID); shorter
+/// ones match too many snapshots to be safe.
+pub const MIN_SNAPSHOT_ID_LEN: usize = 8;
+
+/// True if `id` is a full snapshot ID (64 hex characters) or a prefix of at least
+/// `MIN_SNAPSHOT_ID_LEN`.
+pub fn is_snapshot_id(id: &str) -> bool {
+    (MIN_SNAPSHOT_ID_LEN..=64).contains(&id.len()) && id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Full ID of the one snapshot in `repo` that `id` names. Reads the repository layout only
+/// (snapshot files are named by their ID), so no passphrase is needed.
+pub fn resolve_snapshot_id(repo: &Path, id: &str) -> Result<String, &'static str> {
+    if !is_snapshot_id(id) {
+        return Err("invalid snapshot id");
+    }
+    let entries = std::fs::read_dir(repo.join("snapshots")).map_err(|_| "no such snapshot")?;
+    let mut matches = entries
+        .filter_map(|e| e.ok()?.file_name().into_string().ok())
+        .filter(|name| name.starts_with(&id.to_ascii_lowercase()));
+    match (matches.next(), matches.next()) {
+        (Some(full), None) => Ok(full),
+        (None, _) => Err("no such snapshot"),
+        (Some(_), Some(_)) => Err("snapshot id matches more than one snapshot"),
+    }
+}
+
+/// Wait for exclusive use of `repo` within this process. Held by operations that write to the
+/// repository or take restic's exclusive lock (init, backup, forget/prune, check), so they queue
+/// instead of failing on restic's own lock; read-only listings and stats don't take it.
+async fn lock_repo(repo: &Path) -> OwnedMutexGuard<()> {
+    static LOCKS: OnceLock<StdMutex<HashMap<PathBuf, Arc<Mutex<()>>>>> = OnceLock::new();
+    let lock = {
+        let mut locks = LOCKS
+            .get_or_init(Default::default)
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        locks.entry(repo.to_path_buf()).or_default().clone()
+    };
+    lock.lock_owned().await
+}
+
+/// How often a running backup checks whether restic is still alive.
+const LIVENESS_POLL: Duration = Duration::from_millis(500);
+
+/// restic's exit code for a backup that created a snapshot but couldn't read every file.
+const EXIT_INCOMPLETE: i32 = 3;
+
+/// How long to keep reading after restic exited, for output still in the pipe.
+const EXIT_DRAIN_GRACE: Duration = Duration::from_secs(2);
+
+/// Progress delivery that never makes restic wait: when the channel is full the report is held
+/// back and replaced by the next one, so a slow consumer only misses intermediate updates.
+struct ProgressSender {
+    tx: mpsc::Sender<BackupProgressReport>,
+    pending: Option<BackupProgressReport>,
+}
+
+impl ProgressSender {
+    fn new(tx: mpsc::Sender<BackupProgressReport>) -> Self {
+        Self { tx, pending: None }
+    }
+
+    /// Queue `report` if there is room, else keep it (dropping any older held-back one).
+    fn offer(&mut self, report: BackupProgressReport) {
+        self.pending = None;
+        if let Err(mpsc::error::TrySendError::Full(report)) = self.tx.try_send(report) {
+            self.pending = Some(report);
+        }
+    }
+
+    /// Deliver the held-back report, if any, so the consumer sees the final state. Waits at most
+    /// `EXIT_DRAIN_GRACE` for room in the channel.
+    async fn flush(&mut self) {
+        if let Some(report) = self.pending.take() {
+            let _ = tokio::time::timeout(EXIT_DRAIN_GRACE, self.tx.send(report)).await;
+        }
+    }
+}
+
+/// Above this many sources (or this many bytes of paths) they go in a file instead of argv.
+const FILES_FROM_MIN_SOURCES: usize = 32;
+const FILES_FROM_MIN_BYTES: usize = 16 * 1024;
+
+fn needs_files_from(sources: &[PathBuf]) -> bool {
+    let total_len: usize = sources.iter().map(|s| s.as_os_str().len() + 1).sum();
+    sources.len() > FILES_FROM_MIN_SOURCES || total_len > FILES_FROM_MIN_BYTES
+}
+
+fn build_backup_args(
+    sources: &[PathBuf],
+    includes: &[String],
+    excludes: &[String],
+    extra_args: &[String],
+    files_from: Option<&Path>,
+) -> Vec<String> {
+    let mut args = vec!["backup".to_string(), "--json".to_string()];
+    args.extend(extra_args.iter().cloned());
+    for include in includes {
+        args.push("--include".to_string());
+        args.push(include.clone());
+    }
+    for exclude in excludes {
+        args.push("--exclude".to_string());
+        args.push(exclude.clone());
+    }
+    match files_from {
+        Some(path) => {
+            args.push("--files-from-verbatim".to_string());
+            args.push(path.to_string_lossy().to_string());
+        }
+        None => {
+            for source in sources {
+                args.push(source.to_string_lossy().to_string());
+            }
+        }
+    }
+    args
+}
+
+/// Temporary newline-separated source list for `--files-from-verbatim`; removed on drop.
+struct SourceListFile {
+    path: PathBuf,
+}
+
+impl SourceListFile {
+    fn create(sources: &[PathBuf]) -> anyhow::Result<Self> {
+        use std::io::Write;
+        let name = format!(
+            "aegis-sources-{}-{:016x}",
+            std::process::id(),
+            rand::random::<u64>()
+        );
+        let path = std::env::temp_dir().join(name);
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            // Source paths are sensitive; keep the list private to this user.
+            options.mode(0o600);
+        }
+        let guard = Self { path };
+        let mut file = options.open(&guard.path).context("create source list")?;
+        for source in sources {
+            file.write_all(source.to_string_lossy().as_bytes())
+                .context("write source list")?;
+            file.write_all(b"\n").context("write source list")?;
+        }
+        Ok(guard)
+    }
+}
+
+impl Drop for SourceListFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Why `path` can't be run as the restic binary: it doesn't exist, isn't a regular file (after
+/// following symlinks) or has no execute bit.
+pub fn check_binary(path: &Path) -> Result<(), String> {
+    use std::os::unix::fs::PermissionsExt;
+    let meta = std::fs::metadata(path)
+        .map_err(|_| format!("restic_path {} does not exist", path.display()))?;
+    if !meta.is_file() {
+        return Err(format!("restic_path {} is not a file", path.display()));
+    }
+    if meta.permissions().mode() & 0o111 == 0 {
+        return Err(format!("restic_path {} is not executable", path.display()));
+    }
+    Ok(())
 }
 
 impl Restic {
+    /// The configured `restic_path` if it is usable, else the bundled binary, else `restic` in
+    /// PATH. An unusable override is skipped rather than returned, so a typo in the config doesn't
+    /// make every operation fail to spawn (preflight reports it, see `check_binary`).
     pub fn resolve(override_path: Option<&str>) -> anyhow::Result<Self> {
         if let Some(path) = override_path {
-            return Ok(Self {
-                binary: PathBuf::from(path),
-            });
+            match check_binary(Path::new(path)) {
+                Ok(()) => {
+                    return Ok(Self {
+                        binary: PathBuf::from(path),
+                    })
+                }
+                Err(reason) => debug!("restic: ignoring restic_path: {}", reason),
+            }
         }
 
         if let Ok(exe) = std::env::current_exe() {
@@ -102,22 +964,64 @@ impl Restic {
 
     pub async fn init_repo(&self, repo: &Path, passphrase: &str) -> anyhow::Result<String> {
         debug!("restic: init_repo repo={}", repo.display());
+        let _repo_lock = lock_repo(repo).await;
         self.run_capture(repo, passphrase, &["init".to_string()])
             .await?;
         self.repository_id(repo, passphrase).await
     }
 
     pub async fn repository_id(&self, repo: &Path, passphrase: &str) -> anyhow::Result<String> {
-        debug!("restic: repository_id repo={}", repo.display());
+        Ok(self.repository_config(repo, passphrase).await?.id)
+    }
+
+    /// Repository ID and format version from the (encrypted) repository config.
+    async fn repository_config(
+        &self,
+        repo: &Path,
+        passphrase: &str,
+    ) -> anyhow::Result<ResticConfig> {
+        debug!("restic: repository_config repo={}", repo.display());
         let output = self
             .run_capture(repo, passphrase, &["cat".to_string(), "config".to_string()])
             .await?;
-        let config: ResticConfig =
-            serde_json::from_slice(&output.stdout).context("parse restic config")?;
-        Ok(config.id)
+        serde_json::from_slice(&output.stdout).context("parse restic config")
+    }
+
+    /// Version of the restic binary.
+    pub async fn version(&self) -> anyhow::Result<ResticVersion> {
+        let output = Command::new(&self.binary)
+            .arg("version")
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .context("run restic version")?;
+        ResticVersion::parse(&String::from_utf8_lossy(&output.stdout))
+            .ok_or_else(|| anyhow!("unrecognized restic version output"))
+    }
+
+    /// Open the repository and return its ID, failing with `AgentError::ResticTooOld` if its
+    /// format is newer than this restic supports (also when restic itself refuses to open it).
+    pub async fn repository_id_checked(
+        &self,
+        repo: &Path,
+        passphrase: &str,
+    ) -> anyhow::Result<String> {
+        let config = self.repository_config(repo, passphrase).await?;
+        match self.version().await {
+            Ok(version) if !version.supports_repo_version(config.version) => Err(
+                anyhow::Error::new(AgentError::ResticTooOld).context(format!(
+                    "repository version {} needs a newer restic than {}.{}.{}",
+                    config.version, version.0, version.1, version.2
+                )),
+            ),
+            Ok(_) => Ok(config.id),
+            Err(err) => {
+                debug!("restic: version unknown, skipping format check: {}", err);
+                Ok(config.id)
+            }
+        }
     }
 
-    #[allow(dead_code)]
     pub async fn backup(
         &self,
         repo: &Path,
@@ -143,12 +1047,16 @@ impl Restic {
         for source in sources {
             args.push(source.to_string_lossy().to_string());
         }
+        let _repo_lock = lock_repo(repo).await;
         let output = self.run_capture(repo, passphrase, &args).await?;
         let stdout = String::from_utf8_lossy(&output.stdout);
         let mut summary = BackupSummary {
             snapshot_id: None,
             data_added: None,
             files_processed: None,
+            files_new: None,
+            files_changed: None,
+            incomplete: false,
         };
         for line in stdout.lines() {
             if let Ok(parsed) = serde_json::from_str::<ResticSummaryLine>(line) {
@@ -156,13 +1064,16 @@ impl Restic {
                     summary.snapshot_id = parsed.snapshot_id;
                     summary.data_added = parsed.data_added;
                     summary.files_processed = parsed.total_files_processed;
+                    summary.files_new = parsed.files_new;
+                    summary.files_changed = parsed.files_changed;
                 }
             }
         }
         Ok(summary)
     }
 
-    /// Run backup while streaming progress to `progress_tx`. If `cancel` is triggered (e.g. drive unplugged), the restic process is killed and an error is returned.
+    /// Run backup while streaming progress to `progress_tx`; when it is full, intermediate reports
+    /// are dropped rather than slowing restic down (the last one is still delivered). If `cancel` is triggered (e.g. drive unplugged), the restic process is killed and an error is returned.
     #[allow(clippy::too_many_arguments)]
     pub async fn backup_with_progress(
         &self,
@@ -171,37 +1082,39 @@ impl Restic {
         sources: &[PathBuf],
         includes: &[String],
         excludes: &[String],
+        extra_args: &[String],
         progress_tx: mpsc::Sender<BackupProgressReport>,
         cancel: CancellationToken,
     ) -> anyhow::Result<BackupSummary> {
-        let mut args = vec!["backup".to_string(), "--json".to_string()];
-        for include in includes {
-            args.push("--include".to_string());
-            args.push(include.clone());
-        }
-        for exclude in excludes {
-            args.push("--exclude".to_string());
-            args.push(exclude.clone());
-        }
-        for source in sources {
-            args.push(source.to_string_lossy().to_string());
-        }
+        // Keep the guard alive until restic exits; dropping it removes the file.
+        let files_from = if needs_files_from(sources) {
+            debug!(
+                "restic: {} sources, passing them via --files-from-verbatim",
+                sources.len()
+            );
+            Some(SourceListFile::create(sources)?)
+        } else {
+            None
+        };
+        let args = build_backup_args(
+            sources,
+            includes,
+            excludes,
+            extra_args,
+            files_from.as_ref().map(|f| f.path.as_path()),
+        );
 
-        let mut command = Command::new(&self.binary);
-        command
-            .arg("--repo")
-            .arg(repo)
-            .args(&args)
-            .env("RESTIC_PASSWORD", passphrase)
-            .env("RESTIC_PROGRESS_FPS", "0")
-            .stdin(Stdio::null())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped());
+        let _repo_lock = tokio::select! {
+            guard = lock_repo(repo) => guard,
+            _ = cancel.cancelled() => return Err(anyhow!("backup cancelled (drive disconnected)")),
+        };
+        let mut command = self.command(repo, passphrase, &args);
         let mut child = command.spawn().context("spawn restic")?;
+        let _tracked = processes::track(child.id(), "backup");
         let stdout = child.stdout.take().context("stdout")?;
         let stderr = child.stderr.take().context("stderr")?;
 
-        let stderr_handle = tokio::spawn(async move {
+        let mut stderr_handle = tokio::spawn(async move {
             let mut v = Vec::new();
             let _ = AsyncReadExt::read_to_end(&mut tokio::io::BufReader::new(stderr), &mut v).await;
             v
@@ -211,27 +1124,44 @@ impl Restic {
             snapshot_id: None,
             data_added: None,
             files_processed: None,
+            files_new: None,
+            files_changed: None,
+            incomplete: false,
         };
         let mut last_log_percent: f64 = -1.0;
-        let mut reader = BufReader::new(stdout);
-        let mut line = String::new();
+        let mut progress = ProgressSender::new(progress_tx);
+        let mut lines = BufReader::new(stdout).lines();
+        // restic can go quiet for a long time while it scans; poll whether it is still alive so an
+        // exit is noticed even if something else keeps its stdout open.
+        let mut liveness = tokio::time::interval(LIVENESS_POLL);
+        let mut exited_at: Option<std::time::Instant> = None;
         loop {
-            line.clear();
-            tokio::select! {
+            let line = tokio::select! {
                 _ = cancel.cancelled() => {
                     let _ = child.kill().await;
                     let _ = child.wait().await;
-                    let _ = stderr_handle.await;
+                    stderr_handle.abort();
                     return Err(anyhow!("backup cancelled (drive disconnected)"));
                 }
-                result = reader.read_line(&mut line) => {
-                    let n = result?;
-                    if n == 0 {
-                        break;
+                _ = liveness.tick() => {
+                    match exited_at {
+                        None if child.try_wait()?.is_some() => {
+                            exited_at = Some(std::time::Instant::now());
+                        }
+                        Some(at) if at.elapsed() >= EXIT_DRAIN_GRACE => {
+                            debug!("restic: backup exited but its output stayed open");
+                            break;
+                        }
+                        _ => {}
                     }
+                    continue;
                 }
-            }
-            let line_trim = line.trim_end_matches('\n').trim();
+                result = lines.next_line() => match result? {
+                    Some(line) => line,
+                    None => break,
+                },
+            };
+            let line_trim = line.trim();
             if line_trim.is_empty() {
                 continue;
             }
@@ -248,9 +1178,9 @@ impl Restic {
                         total_files,
                         bytes_done,
                         total_bytes,
-                        current_file: parsed.current_file,
+                        current_file: parsed.current_files.into_iter().next(),
                     };
-                    let _ = progress_tx.send(report.clone()).await;
+                    progress.offer(report);
                     if (percent - last_log_percent >= 0.05)
                         || (percent >= 1.0 && last_log_percent < 1.0)
                     {
@@ -271,35 +1201,61 @@ impl Restic {
                     summary.snapshot_id = parsed.snapshot_id;
                     summary.data_added = parsed.data_added;
                     summary.files_processed = parsed.total_files_processed;
+                    summary.files_new = parsed.files_new;
+                    summary.files_changed = parsed.files_changed;
                 }
             }
         }
+        progress.flush().await;
 
         let status = child.wait().await?;
-        let _stderr = stderr_handle.await?;
+        let stderr = match tokio::time::timeout(EXIT_DRAIN_GRACE, &mut stderr_handle).await {
+            Ok(stderr) => stderr?,
+            Err(_) => {
+                stderr_handle.abort();
+                Vec::new()
+            }
+        };
+        if status.code() == Some(EXIT_INCOMPLETE) {
+            warn!("restic: backup is incomplete, some files could not be read");
+            debug!(
+                "restic: incomplete backup stderr: {}",
+                String::from_utf8_lossy(&stderr).trim()
+            );
+            summary.incomplete = true;
+            return Ok(summary);
+        }
         if !status.success() {
-            return Err(anyhow!(
+            let stderr = String::from_utf8_lossy(&stderr);
+            error!(
+                "restic: backup failed status={:?} stderr={}",
+                status.code(),
+                crate::logging::redact_paths(stderr.trim())
+            );
+            return Err(classify_failure(status.code(), &stderr).context(format!(
                 "restic backup failed with exit code {:?}",
                 status.code()
-            ));
+            )));
         }
         Ok(summary)
     }
 
+    /// List snapshots. With `no_lock` restic doesn't take a repository lock, so the list works
+    /// while a backup holds one.
     pub async fn snapshots(
         &self,
         repo: &Path,
         passphrase: &str,
+        no_lock: bool,
     ) -> anyhow::Result<Vec<SnapshotInfo>> {
         let output = self
-            .run_capture(
-                repo,
-                passphrase,
-                &["snapshots".to_string(), "--json".to_string()],
-            )
+            .run_capture(repo, passphrase, &snapshots_args(no_lock))
             .await?;
-        let snapshots: Vec<SnapshotInfo> =
+        let mut snapshots: Vec<SnapshotInfo> =
             serde_json::from_slice(&output.stdout).context("parse snapshots")?;
+        for snapshot in &mut snapshots {
+            snapshot.time_epoch = parse_snapshot_time(&snapshot.time);
+        }
         Ok(snapshots)
     }
 
@@ -308,24 +1264,17 @@ impl Restic {
         repo: &Path,
         passphrase: &str,
         snapshot_id: &str,
+        no_lock: bool,
     ) -> anyhow::Result<SnapshotStats> {
         let output = self
-            .run_capture(
-                repo,
-                passphrase,
-                &[
-                    "stats".to_string(),
-                    "--json".to_string(),
-                    "--snapshot".to_string(),
-                    snapshot_id.to_string(),
-                ],
-            )
+            .run_capture(repo, passphrase, &stats_args(snapshot_id, no_lock))
             .await?;
         let stats: SnapshotStats = serde_json::from_slice(&output.stdout).context("parse stats")?;
         Ok(stats)
     }
 
     pub async fn check_quick(&self, repo: &Path, passphrase: &str) -> anyhow::Result<()> {
+        let _repo_lock = lock_repo(repo).await;
         self.run_capture(
             repo,
             passphrase,
@@ -336,6 +1285,7 @@ impl Restic {
     }
 
     pub async fn check_deep(&self, repo: &Path, passphrase: &str) -> anyhow::Result<()> {
+        let _repo_lock = lock_repo(repo).await;
         self.run_capture(
             repo,
             passphrase,
@@ -350,17 +1300,182 @@ impl Restic {
         repo: &Path,
         passphrase: &str,
         retention_args: &[String],
-    ) -> anyhow::Result<()> {
+        progress_tx: &mpsc::Sender<PruneProgress>,
+    ) -> anyhow::Result<PruneSummary> {
         if retention_args.is_empty() {
-            return Ok(());
+            return Ok(PruneSummary::default());
         }
         let mut args = vec!["forget".to_string(), "--prune".to_string()];
         args.extend(retention_args.iter().cloned());
-        self.run_capture(repo, passphrase, &args).await?;
+        let _repo_lock = lock_repo(repo).await;
+        let stdout = self
+            .run_prune(
+                repo,
+                passphrase,
+                &args,
+                PruneProgressParser::new(0),
+                progress_tx,
+            )
+            .await?;
+        Ok(parse_prune_output(&stdout))
+    }
+
+    /// Run a `forget --prune` with restic's progress counters on, offering each update to
+    /// `progress_tx` (skipped when it is full, like backup progress). Returns restic's stdout.
+    async fn run_prune(
+        &self,
+        repo: &Path,
+        passphrase: &str,
+        args: &[String],
+        mut parser: PruneProgressParser,
+        progress_tx: &mpsc::Sender<PruneProgress>,
+    ) -> anyhow::Result<String> {
+        let mut command = self.command(repo, passphrase, args);
+        command.env("RESTIC_PROGRESS_FPS", PRUNE_PROGRESS_FPS);
+        let mut child = command.spawn().context("spawn restic")?;
+        let _tracked = processes::track(child.id(), operation_name(args));
+        let stdout = child.stdout.take().context("stdout")?;
+        let mut stderr = child.stderr.take().context("stderr")?;
+        let stderr_task = tokio::spawn(async move {
+            let mut buffer = Vec::new();
+            let _ = stderr.read_to_end(&mut buffer).await;
+            buffer
+        });
+        let mut output = String::new();
+        let mut lines = BufReader::new(stdout).lines();
+        while let Some(line) = lines.next_line().await? {
+            // Counters may be redrawn in place with carriage returns.
+            for part in line.split('\r') {
+                if parser.feed(part) {
+                    let _ = progress_tx.try_send(parser.progress.clone());
+                }
+            }
+            output.push_str(&line);
+            output.push('\n');
+        }
+        let status = child.wait().await?;
+        let stderr = stderr_task.await.unwrap_or_default();
+        if !status.success() {
+            let stderr = String::from_utf8_lossy(&stderr);
+            error!(
+                "restic: command failed args={:?} status={:?} stderr={}",
+                args,
+                status.code(),
+                stderr.trim()
+            );
+            return Err(classify_failure(status.code(), &stderr));
+        }
+        Ok(output)
+    }
+
+    /// IDs of the snapshots the retention policy would remove, without removing anything.
+    pub async fn forget_plan(
+        &self,
+        repo: &Path,
+        passphrase: &str,
+        retention_args: &[String],
+    ) -> anyhow::Result<Vec<String>> {
+        if retention_args.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut args = vec![
+            "forget".to_string(),
+            "--dry-run".to_string(),
+            "--json".to_string(),
+        ];
+        args.extend(retention_args.iter().cloned());
+        let output = self.run_capture(repo, passphrase, &args).await?;
+        parse_forget_plan(&output.stdout)
+    }
+
+    /// Remove exactly these snapshots and prune their data.
+    pub async fn forget_snapshots_prune(
+        &self,
+        repo: &Path,
+        passphrase: &str,
+        snapshot_ids: &[String],
+        progress_tx: &mpsc::Sender<PruneProgress>,
+    ) -> anyhow::Result<PruneSummary> {
+        if snapshot_ids.is_empty() {
+            return Ok(PruneSummary::default());
+        }
+        let mut args = vec!["forget".to_string(), "--prune".to_string()];
+        args.extend(snapshot_ids.iter().cloned());
+        let _repo_lock = lock_repo(repo).await;
+        let parser = PruneProgressParser::new(snapshot_ids.len() as u64);
+        let stdout = self
+            .run_prune(repo, passphrase, &args, parser, progress_tx)
+            .await?;
+        Ok(PruneSummary {
+            removed_snapshots: snapshot_ids.len() as u64,
+            ..parse_prune_output(&stdout)
+        })
+    }
+
+    /// Rebuild the index from the pack files actually in the repository, dropping entries for
+    /// packs that are missing (e.g. after the drive was unplugged mid-write).
+    pub async fn repair_index(
+        &self,
+        repo: &Path,
+        passphrase: &str,
+    ) -> anyhow::Result<RepairSummary> {
+        let _repo_lock = lock_repo(repo).await;
+        let output = self
+            .run_capture(
+                repo,
+                passphrase,
+                &["repair".to_string(), "index".to_string()],
+            )
+            .await?;
+        Ok(parse_repair_output(&String::from_utf8_lossy(
+            &output.stdout,
+        )))
+    }
+
+    /// Rewrite snapshots that reference missing data without it, removing the damaged originals.
+    pub async fn repair_snapshots(
+        &self,
+        repo: &Path,
+        passphrase: &str,
+    ) -> anyhow::Result<RepairSummary> {
+        let _repo_lock = lock_repo(repo).await;
+        let output = self
+            .run_capture(
+                repo,
+                passphrase,
+                &[
+                    "repair".to_string(),
+                    "snapshots".to_string(),
+                    "--forget".to_string(),
+                ],
+            )
+            .await?;
+        Ok(parse_repair_output(&String::from_utf8_lossy(
+            &output.stdout,
+        )))
+    }
+
+    /// Remove a single snapshot and prune its data.
+    pub async fn forget_snapshot(
+        &self,
+        repo: &Path,
+        passphrase: &str,
+        snapshot_id: &str,
+    ) -> anyhow::Result<()> {
+        let _repo_lock = lock_repo(repo).await;
+        self.run_capture(
+            repo,
+            passphrase,
+            &[
+                "forget".to_string(),
+                snapshot_id.to_string(),
+                "--prune".to_string(),
+            ],
+        )
+        .await?;
         Ok(())
     }
 
-    #[allow(dead_code)]
     pub async fn restore(
         &self,
         repo: &Path,
@@ -369,21 +1484,84 @@ impl Restic {
         target: &Path,
         includes: &[String],
     ) -> anyhow::Result<()> {
-        let mut args = vec![
-            "restore".to_string(),
-            snapshot_id.to_string(),
-            "--target".to_string(),
-            target.to_string_lossy().to_string(),
-        ];
-        for include in includes {
-            args.push("--include".to_string());
-            args.push(include.clone());
-        }
+        let args = restore_args(snapshot_id, target, includes, false);
         self.run_capture(repo, passphrase, &args).await?;
         Ok(())
     }
 
+    /// Restore as root so files get their original owner and group back. Through pkexec the
+    /// environment is cleared, so the passphrase goes over stdin (restic reads its first line
+    /// when stdin isn't a terminal); a passphrase with a line break can't be passed that way.
+    /// Cancelling kills pkexec while it waits for authorization; once restic runs as root the
+    /// agent may not be allowed to signal it, and it then finishes on its own.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn restore_privileged(
+        &self,
+        repo: &Path,
+        passphrase: &str,
+        snapshot_id: &str,
+        target: &Path,
+        includes: &[String],
+        no_lock: bool,
+        limits: BandwidthLimits,
+        elevation: Elevation,
+        cancel: CancellationToken,
+    ) -> anyhow::Result<()> {
+        if passphrase.contains(['\n', '\r']) {
+            return Err(anyhow!(
+                "passphrase contains a line break; it can't be passed to a privileged restore"
+            ));
+        }
+        let mut args = restore_args(snapshot_id, target, includes, no_lock);
+        args.extend(bandwidth_args(limits));
+        let argv = privileged_argv(&self.binary, repo, &args, elevation);
+        let binary = self.binary.to_string_lossy();
+        let program = match elevation {
+            Elevation::Pkexec => vec!["pkexec", &*binary],
+            Elevation::AlreadyRoot => vec![&*binary],
+        };
+        debug!(
+            "restic: privileged restore elevation={:?} command: {} (passphrase on stdin)",
+            elevation,
+            render_command(&[], &program, &args)
+        );
+        let mut child = Command::new(&argv[0])
+            .args(&argv[1..])
+            .env_remove("RESTIC_PASSWORD")
+            .env("RESTIC_PROGRESS_FPS", "0")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .context("spawn privileged restic")?;
+        let _tracked = processes::track(child.id(), operation_name(&args));
+        let mut stdin = child.stdin.take().context("open restic stdin")?;
+        stdin
+            .write_all(format!("{}\n", passphrase).as_bytes())
+            .await
+            .context("send passphrase")?;
+        drop(stdin);
+        let output = tokio::select! {
+            output = child.wait_with_output() => output?,
+            _ = cancel.cancelled() => {
+                return Err(AgentError::Cancelled).context("restic cancelled");
+            }
+        };
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            error!(
+                "restic: privileged restore failed status={:?} stderr={}",
+                output.status.code(),
+                stderr.trim()
+            );
+            return Err(classify_failure(output.status.code(), &stderr));
+        }
+        Ok(())
+    }
+
     /// Restore with cancellation support (e.g. when drive is unplugged).
+    #[allow(clippy::too_many_arguments)]
     pub async fn restore_cancellable(
         &self,
         repo: &Path,
@@ -391,41 +1569,134 @@ impl Restic {
         snapshot_id: &str,
         target: &Path,
         includes: &[String],
+        no_lock: bool,
+        limits: BandwidthLimits,
         cancel: CancellationToken,
     ) -> anyhow::Result<()> {
-        let mut args = vec![
-            "restore".to_string(),
-            snapshot_id.to_string(),
-            "--target".to_string(),
-            target.to_string_lossy().to_string(),
-        ];
-        for include in includes {
-            args.push("--include".to_string());
-            args.push(include.clone());
-        }
+        let mut args = restore_args(snapshot_id, target, includes, no_lock);
+        args.extend(bandwidth_args(limits));
         self.run_capture_cancellable(repo, passphrase, &args, cancel)
             .await?;
         Ok(())
     }
 
-    async fn run_capture(
+    /// Stream a snapshot as a tar archive into `out`, calling `on_progress` with the bytes
+    /// written so far, and hand `out` back with the byte count. The archive never passes through
+    /// memory as a whole, so any snapshot size works; `out` is written on a blocking thread.
+    /// Cancelling kills restic and fails with `AgentError::Cancelled`.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn dump_archive<W: std::io::Write + Send + 'static>(
         &self,
         repo: &Path,
         passphrase: &str,
-        args: &[String],
-    ) -> anyhow::Result<std::process::Output> {
+        snapshot_id: &str,
+        no_lock: bool,
+        mut out: W,
+        cancel: CancellationToken,
+        mut on_progress: impl FnMut(u64),
+    ) -> anyhow::Result<(u64, W)> {
+        let args = dump_args(snapshot_id, no_lock);
+        let mut command = self.command(repo, passphrase, &args);
+        let mut child = command.spawn().context("spawn restic")?;
+        let _tracked = processes::track(child.id(), operation_name(&args));
+        let mut stdout = child.stdout.take().context("capture stdout")?;
+        let mut stderr = child.stderr.take().context("capture stderr")?;
+        let stderr_task = tokio::spawn(async move {
+            let mut buffer = Vec::new();
+            stderr.read_to_end(&mut buffer).await?;
+            Ok::<Vec<u8>, std::io::Error>(buffer)
+        });
+
+        // Chunks go to a blocking thread that owns `out`; it stops at the first write error,
+        // which closes the channel and ends the copy loop below.
+        let (chunks, mut chunk_rx) = tokio::sync::mpsc::channel::<Vec<u8>>(4);
+        let writer = tokio::task::spawn_blocking(move || {
+            while let Some(chunk) = chunk_rx.blocking_recv() {
+                out.write_all(&chunk)?;
+            }
+            out.flush()?;
+            Ok::<W, std::io::Error>(out)
+        });
+        let copy = async {
+            let mut buffer = vec![0u8; 256 * 1024];
+            let mut written = 0u64;
+            loop {
+                let n = stdout.read(&mut buffer).await.context("read restic dump")?;
+                if n == 0 || chunks.send(buffer[..n].to_vec()).await.is_err() {
+                    break;
+                }
+                written += n as u64;
+                on_progress(written);
+            }
+            Ok::<u64, anyhow::Error>(written)
+        };
+        let copied = tokio::select! {
+            copied = copy => copied,
+            _ = cancel.cancelled() => Err(AgentError::Cancelled).context("restic cancelled"),
+        };
+        drop(chunks);
+        let out = writer
+            .await
+            .context("join archive writer")?
+            .context("write archive");
+        let (written, out) = match (copied, out) {
+            (Ok(written), Ok(out)) => (written, out),
+            (Err(err), _) | (Ok(_), Err(err)) => {
+                let _ = child.kill().await;
+                let _ = child.wait().await;
+                stderr_task.abort();
+                return Err(err);
+            }
+        };
+        let status = child.wait().await?;
+        let stderr = stderr_task.await.context("join stderr task")??;
+        if !status.success() {
+            let stderr = String::from_utf8_lossy(&stderr);
+            error!(
+                "restic: dump failed status={:?} stderr={}",
+                status.code(),
+                stderr.trim()
+            );
+            return Err(classify_failure(status.code(), &stderr));
+        }
+        Ok((written, out))
+    }
+
+    /// restic invocation with piped output. The passphrase is passed byte-for-byte (no trimming)
+    /// via env to keep it out of CLI args and logs. restic is killed if its handle is dropped
+    /// (e.g. the request awaiting it goes away), so it never outlives the operation.
+    fn command(&self, repo: &Path, passphrase: &str, args: &[String]) -> Command {
+        debug!(
+            "restic: command: {}",
+            render_command(
+                &["RESTIC_PASSWORD"],
+                &[&self.binary.to_string_lossy()],
+                args
+            )
+        );
         let mut command = Command::new(&self.binary);
         command
             .arg("--repo")
             .arg(repo)
             .args(args)
-            // Passphrase is provided via env to avoid CLI args and logs.
             .env("RESTIC_PASSWORD", passphrase)
             .env("RESTIC_PROGRESS_FPS", "0")
             .stdin(Stdio::null())
             .stdout(Stdio::piped())
-            .stderr(Stdio::piped());
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        command
+    }
+
+    async fn run_capture(
+        &self,
+        repo: &Path,
+        passphrase: &str,
+        args: &[String],
+    ) -> anyhow::Result<std::process::Output> {
+        let mut command = self.command(repo, passphrase, args);
         let child = command.spawn().context("spawn restic")?;
+        let _tracked = processes::track(child.id(), operation_name(args));
         let output = child.wait_with_output().await?;
         if !output.status.success() {
             let stderr = String::from_utf8_lossy(&output.stderr);
@@ -437,7 +1708,7 @@ impl Restic {
                 stdout.trim(),
                 stderr.trim()
             );
-            return Err(anyhow!("restic failed: {}", stderr.trim()));
+            return Err(classify_failure(output.status.code(), &stderr));
         }
         Ok(output)
     }
@@ -449,17 +1720,9 @@ impl Restic {
         args: &[String],
         cancel: CancellationToken,
     ) -> anyhow::Result<std::process::Output> {
-        let mut command = Command::new(&self.binary);
-        command
-            .arg("--repo")
-            .arg(repo)
-            .args(args)
-            .env("RESTIC_PASSWORD", passphrase)
-            .env("RESTIC_PROGRESS_FPS", "0")
-            .stdin(Stdio::null())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped());
+        let mut command = self.command(repo, passphrase, args);
         let mut child = command.spawn().context("spawn restic")?;
+        let _tracked = processes::track(child.id(), operation_name(args));
         let mut stdout = child.stdout.take().context("capture stdout")?;
         let mut stderr = child.stderr.take().context("capture stderr")?;
 
@@ -490,19 +1753,19 @@ impl Restic {
                 let _ = child.wait().await;
                 stdout_task.abort();
                 stderr_task.abort();
-                Err(anyhow!("restic cancelled"))
+                Err(AgentError::Cancelled).context("restic cancelled")
             }
         }
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
 
     #[test]
     fn parse_restic_status_line() {
-        let json = r#"{"message_type":"status","percent_done":0.5,"total_files":100,"files_done":50,"total_bytes":1000,"bytes_done":500,"current_file":"/some/file"}"#;
+        let json = r#"{"message_type":"status","percent_done":0.5,"total_files":100,"files_done":50,"total_bytes":1000,"bytes_done":500,"current_files":["/some/file","/other/file"]}"#;
         let parsed: ResticStatusLine = serde_json::from_str(json).unwrap();
         assert_eq!(parsed.message_type.as_deref(), Some("status"));
         assert_eq!(parsed.percent_done, Some(0.5));
@@ -510,17 +1773,19 @@ mod tests {
         assert_eq!(parsed.files_done, Some(50));
         assert_eq!(parsed.bytes_done, Some(500));
         assert_eq!(parsed.total_bytes, Some(1000));
-        assert_eq!(parsed.current_file.as_deref(), Some("/some/file"));
+        assert_eq!(parsed.current_files, ["/some/file", "/other/file"]);
     }
 
     #[test]
     fn parse_restic_summary_line() {
-        let json = r#"{"message_type":"summary","snapshot_id":"abc123","data_added":1024,"total_files_processed":42}"#;
+        let json = r#"{"message_type":"summary","snapshot_id":"abc123","data_added":1024,"total_files_processed":42,"files_new":3,"files_changed":2}"#;
         let parsed: ResticSummaryLine = serde_json::from_str(json).unwrap();
         assert_eq!(parsed.message_type.as_deref(), Some("summary"));
         assert_eq!(parsed.snapshot_id.as_deref(), Some("abc123"));
         assert_eq!(parsed.data_added, Some(1024));
         assert_eq!(parsed.total_files_processed, Some(42));
+        assert_eq!(parsed.files_new, Some(3));
+        assert_eq!(parsed.files_changed, Some(2));
     }
 
     #[test]
@@ -530,6 +1795,1198 @@ mod tests {
         assert_eq!(parsed.id, "abc123def456");
     }
 
+    #[test]
+    fn performance_args_fast() {
+        let args = performance_args(
+            PerformanceProfile::Fast,
+            &PerformanceOverrides::default(),
+            1,
+        );
+        assert_eq!(
+            args,
+            &["--compression", "off", "--read-concurrency", "4"].map(String::from)
+        );
+    }
+
+    #[test]
+    fn performance_args_balanced() {
+        let args = performance_args(
+            PerformanceProfile::Balanced,
+            &PerformanceOverrides::default(),
+            1,
+        );
+        assert!(args.is_empty(), "{:?}", args);
+    }
+
+    #[test]
+    fn performance_args_max_space_savings() {
+        let args = performance_args(
+            PerformanceProfile::MaxSpaceSavings,
+            &PerformanceOverrides::default(),
+            1,
+        );
+        assert_eq!(
+            args,
+            &["--compression", "max", "--pack-size", "64"].map(String::from)
+        );
+    }
+
+    #[test]
+    fn read_concurrency_scales_with_sources() {
+        let read_concurrency = |profile, sources| {
+            let args = performance_args(profile, &PerformanceOverrides::default(), sources);
+            args.iter()
+                .position(|a| a == "--read-concurrency")
+                .map(|i| args[i + 1].clone())
+        };
+        // restic's default of 2 is left to restic.
+        assert_eq!(read_concurrency(PerformanceProfile::Balanced, 0), None);
+        assert_eq!(read_concurrency(PerformanceProfile::Balanced, 2), None);
+        assert_eq!(
+            read_concurrency(PerformanceProfile::Balanced, 3).as_deref(),
+            Some("3")
+        );
+        assert_eq!(
+            read_concurrency(PerformanceProfile::Balanced, 50).as_deref(),
+            Some("8")
+        );
+        assert_eq!(
+            read_concurrency(PerformanceProfile::Fast, 3).as_deref(),
+            Some("4")
+        );
+        let zero = PerformanceOverrides {
+            read_concurrency: Some(0),
+            ..PerformanceOverrides::default()
+        };
+        let args = performance_args(PerformanceProfile::Balanced, &zero, 5);
+        assert!(!args.contains(&"--read-concurrency".to_string()));
+    }
+
+    #[test]
+    fn no_lock_only_on_read_operations() {
+        assert_eq!(
+            snapshots_args(true),
+            ["snapshots", "--json", "--no-lock"].map(String::from)
+        );
+        assert!(stats_args("abc", true).ends_with(&["--no-lock".to_string()]));
+        assert!(restore_args("abc", Path::new("/tmp/r"), &[], true)
+            .ends_with(&["--no-lock".to_string()]));
+        assert!(!restore_args("abc", Path::new("/tmp/r"), &[], false)
+            .contains(&"--no-lock".to_string()));
+        assert!(!snapshots_args(false).contains(&"--no-lock".to_string()));
+        assert!(!stats_args("abc", false).contains(&"--no-lock".to_string()));
+        assert_eq!(
+            dump_args("abc123", true),
+            ["dump", "--archive", "tar", "abc123", "/", "--no-lock"].map(String::from)
+        );
+        let backup = build_backup_args(&[PathBuf::from("/home/u")], &[], &[], &[], None);
+        assert!(!backup.contains(&"--no-lock".to_string()));
+    }
+
+    #[test]
+    fn bandwidth_args_only_for_positive_limits() {
+        assert!(bandwidth_args(BandwidthLimits::default()).is_empty());
+        assert_eq!(
+            bandwidth_args(BandwidthLimits {
+                upload_kb: 512,
+                download_kb: 0
+            }),
+            ["--limit-upload", "512"].map(String::from)
+        );
+        assert_eq!(
+            bandwidth_args(BandwidthLimits {
+                upload_kb: 0,
+                download_kb: 2048
+            }),
+            ["--limit-download", "2048"].map(String::from)
+        );
+        assert_eq!(
+            bandwidth_args(BandwidthLimits {
+                upload_kb: 1,
+                download_kb: 2
+            }),
+            ["--limit-upload", "1", "--limit-download", "2"].map(String::from)
+        );
+    }
+
+    #[test]
+    fn source_tags_from_labels() {
+        assert_eq!(source_tag("Documents").as_deref(), Some("Documents"));
+        assert_eq!(
+            source_tag("  Family  Photos ").as_deref(),
+            Some("Family-Photos")
+        );
+        assert_eq!(source_tag("a,b").as_deref(), Some("ab"));
+        assert_eq!(source_tag("tab\there\u{7}").as_deref(), Some("tab-here"));
+        assert_eq!(source_tag(" , \u{0}"), None);
+        assert_eq!(source_tag(&"x".repeat(100)).unwrap().len(), 64);
+        assert_eq!(source_tag("Fotos Über").as_deref(), Some("Fotos-Über"));
+
+        assert_eq!(
+            source_tag_args(["Documents", "Pictures", "Documents", ","]),
+            ["--tag", "Documents", "--tag", "Pictures"].map(String::from)
+        );
+        assert!(source_tag_args([]).is_empty());
+    }
+
+    #[test]
+    fn host_args_only_for_configured_hostname() {
+        assert_eq!(
+            host_args(Some("family-laptop")),
+            ["--host", "family-laptop"].map(String::from)
+        );
+        assert!(host_args(None).is_empty());
+        assert!(host_args(Some("  ")).is_empty());
+        let args = build_backup_args(
+            &[PathBuf::from("/home/u")],
+            &[],
+            &[],
+            &host_args(Some("family-laptop")),
+            None,
+        );
+        assert_eq!(
+            args[..4],
+            ["backup", "--json", "--host", "family-laptop"].map(String::from)
+        );
+    }
+
+    #[test]
+    fn performance_args_overrides_win() {
+        let overrides = PerformanceOverrides {
+            compression: Some(Compression::Max),
+            pack_size_mib: None,
+            read_concurrency: Some(8),
+        };
+        let args = performance_args(PerformanceProfile::Fast, &overrides, 20);
+        assert_eq!(
+            args,
+            &["--compression", "max", "--read-concurrency", "8"].map(String::from)
+        );
+        // Overrides back to restic's defaults pass nothing.
+        let defaults = PerformanceOverrides {
+            compression: Some(Compression::Auto),
+            pack_size_mib: Some(16),
+            read_concurrency: Some(2),
+        };
+        assert!(performance_args(PerformanceProfile::MaxSpaceSavings, &defaults, 1).is_empty());
+    }
+
+    #[test]
+    fn performance_profile_serde_names() {
+        let parsed: PerformanceProfile = serde_json::from_str(r#""max-space-savings""#).unwrap();
+        assert_eq!(parsed, PerformanceProfile::MaxSpaceSavings);
+        assert_eq!(
+            serde_json::to_string(&PerformanceProfile::Fast).unwrap(),
+            r#""fast""#
+        );
+    }
+
+    #[test]
+    fn rendered_command_shows_only_subcommand_and_flags() {
+        let passphrase = "correct horse battery";
+        let args = [
+            "backup".to_string(),
+            "--exclude".to_string(),
+            format!("/home/u/{}", passphrase),
+            "--files-from-verbatim=/tmp/My Files".to_string(),
+            "/home/u/Documents".to_string(),
+        ];
+        let rendered = render_command(&["RESTIC_PASSWORD"], &["/usr/bin/restic"], &args);
+        assert!(!rendered.contains(passphrase), "{}", rendered);
+        assert_eq!(
+            rendered,
+            "RESTIC_PASSWORD=<redacted> /usr/bin/restic backup --exclude --files-from-verbatim"
+        );
+        assert_eq!(
+            render_command(&[], &["pkexec", "restic"], &[]),
+            "pkexec restic"
+        );
+    }
+
+    #[test]
+    fn privileged_argv_wraps_restic_in_pkexec() {
+        let args = restore_args("abc123", Path::new("/"), &["/etc".to_string()], false);
+        assert_eq!(
+            privileged_argv(
+                Path::new("/usr/bin/restic"),
+                Path::new("/media/u/aegis/.aegis/repo"),
+                &args,
+                Elevation::Pkexec
+            ),
+            [
+                "pkexec",
+                "/usr/bin/restic",
+                "--repo",
+                "/media/u/aegis/.aegis/repo",
+                "restore",
+                "abc123",
+                "--target",
+                "/",
+                "--include",
+                "/etc"
+            ]
+            .map(String::from)
+        );
+        let as_root = privileged_argv(
+            Path::new("/usr/bin/restic"),
+            Path::new("/repo"),
+            &args,
+            Elevation::AlreadyRoot,
+        );
+        assert_eq!(as_root[0], "/usr/bin/restic");
+    }
+
+    #[tokio::test]
+    async fn privileged_restore_sends_passphrase_on_stdin() {
+        let (restic, dir) = script_restic(
+            "priv",
+            "#!/bin/sh\nIFS= read -r pw\nprintf '%s|%s' \"$pw\" \"$RESTIC_PASSWORD\" > \"$2/pw\"\n",
+        );
+
+        restic
+            .restore_privileged(
+                &dir,
+                " two  words ",
+                "abc",
+                &dir,
+                &[],
+                false,
+                BandwidthLimits::default(),
+                Elevation::AlreadyRoot,
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        let received = std::fs::read_to_string(dir.join("pw")).unwrap();
+        let refused = restic
+            .restore_privileged(
+                &dir,
+                "line\nbreak",
+                "abc",
+                &dir,
+                &[],
+                false,
+                BandwidthLimits::default(),
+                Elevation::AlreadyRoot,
+                CancellationToken::new(),
+            )
+            .await;
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(received, " two  words |");
+        assert!(refused.is_err());
+    }
+
+    #[tokio::test]
+    async fn dump_archive_streams_stdout_with_progress() {
+        let (restic, dir) = script_restic("dump", "#!/bin/sh\nprintf 'tar-bytes'\n");
+
+        let mut progress = Vec::new();
+        let (written, out) = restic
+            .dump_archive(
+                &dir,
+                "pw",
+                "abc123",
+                true,
+                Vec::new(),
+                CancellationToken::new(),
+                |bytes| progress.push(bytes),
+            )
+            .await
+            .unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(written, 9);
+        assert_eq!(out, b"tar-bytes");
+        assert_eq!(progress.last(), Some(&9));
+    }
+
+    #[tokio::test]
+    async fn restore_cancel_kills_restic_and_reports_cancelled() {
+        let (restic, dir) = script_restic("restore-cancel", "#!/bin/sh\nsleep 30\n");
+
+        let cancel = CancellationToken::new();
+        let trigger = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            trigger.cancel();
+        });
+        let started = std::time::Instant::now();
+        let result = restic
+            .restore_cancellable(
+                &dir,
+                "pw",
+                "latest",
+                &dir.join("target"),
+                &[],
+                false,
+                BandwidthLimits::default(),
+                cancel,
+            )
+            .await;
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let err = result.expect_err("restore should be cancelled");
+        assert!(crate::errors::is_cancelled(&err));
+        assert!(started.elapsed() < std::time::Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn operations_on_same_repo_serialize() {
+        let (restic, dir) = script_restic(
+            "lock",
+            "#!/bin/sh\necho start >> \"$2/log\"\nsleep 0.2\necho end >> \"$2/log\"\n",
+        );
+
+        let (quick, prune) = tokio::join!(
+            restic.check_quick(&dir, "pw"),
+            restic.forget_snapshot(&dir, "pw", "abc123"),
+        );
+        let log = std::fs::read_to_string(dir.join("log")).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        quick.unwrap();
+        prune.unwrap();
+        assert_eq!(log, "start\nend\nstart\nend\n");
+    }
+
+    /// Fake restic that stores the passphrase it received in `<repo>/pw`.
+    fn passphrase_echo_restic(name: &str) -> (Restic, PathBuf) {
+        script_restic(
+            name,
+            "#!/bin/sh\nprintf '%s' \"$RESTIC_PASSWORD\" > \"$2/pw\"\n",
+        )
+    }
+
+    const TRICKY_PASSPHRASE: &str = "  two  spaces \"dq\" 'sq' $HOME `id` \\ ; | & ünï\t\n\n";
+
+    #[tokio::test]
+    async fn passphrase_reaches_restic_byte_for_byte() {
+        let (restic, dir) = passphrase_echo_restic("pw");
+        restic.check_quick(&dir, TRICKY_PASSPHRASE).await.unwrap();
+        let received = std::fs::read(dir.join("pw")).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(received, TRICKY_PASSPHRASE.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn passphrase_round_trips_through_cancellable_runs() {
+        let (restic, dir) = passphrase_echo_restic("pw-c");
+        restic
+            .restore_cancellable(
+                &dir,
+                TRICKY_PASSPHRASE,
+                "latest",
+                &dir.join("target"),
+                &[],
+                false,
+                BandwidthLimits::default(),
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        let received = std::fs::read(dir.join("pw")).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(received, TRICKY_PASSPHRASE.as_bytes());
+    }
+
+    #[test]
+    fn repository_is_empty_until_first_snapshot() {
+        let repo = std::env::temp_dir().join(format!("aegis-repo-empty-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&repo);
+        assert!(repository_is_empty(&repo));
+        std::fs::create_dir_all(repo.join("snapshots")).unwrap();
+        assert!(repository_is_empty(&repo));
+        std::fs::write(repo.join("snapshots").join("a1b2c3"), b"x").unwrap();
+        let populated = !repository_is_empty(&repo);
+        std::fs::remove_dir_all(&repo).unwrap();
+        assert!(populated);
+    }
+
+    #[test]
+    fn snapshot_ids_resolve_to_exactly_one_snapshot() {
+        let repo = std::env::temp_dir().join(format!("aegis-snapshot-ids-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&repo);
+        std::fs::create_dir_all(repo.join("snapshots")).unwrap();
+        let full_a = format!("aaaa1111{}", "0".repeat(56));
+        let full_b = format!("aaaa1111b{}", "0".repeat(55));
+        let full_c = format!("cccc3333{}", "0".repeat(56));
+        for id in [&full_a, &full_b, &full_c] {
+            std::fs::write(repo.join("snapshots").join(id), b"x").unwrap();
+        }
+        let results = [
+            resolve_snapshot_id(&repo, "CCCC3333"),
+            resolve_snapshot_id(&repo, &full_a),
+            resolve_snapshot_id(&repo, "aaaa1111"),
+            resolve_snapshot_id(&repo, "dddd4444"),
+            resolve_snapshot_id(&repo, "cccc"),
+            resolve_snapshot_id(&repo, "cccc3333zz"),
+        ];
+        std::fs::remove_dir_all(&repo).unwrap();
+        assert_eq!(results[0], Ok(full_c));
+        assert_eq!(results[1], Ok(full_a));
+        assert_eq!(
+            results[2],
+            Err("snapshot id matches more than one snapshot")
+        );
+        assert_eq!(results[3], Err("no such snapshot"));
+        assert_eq!(results[4], Err("invalid snapshot id"));
+        assert_eq!(results[5], Err("invalid snapshot id"));
+    }
+
+    #[test]
+    fn restic_version_parse() {
+        assert_eq!(
+            ResticVersion::parse("restic 0.16.4 compiled with go1.21.6 on linux/amd64\n"),
+            Some(ResticVersion(0, 16, 4))
+        );
+        assert_eq!(
+            ResticVersion::parse("restic 0.13.1-dev (compiled manually) compiled with go1.18"),
+            Some(ResticVersion(0, 13, 1))
+        );
+        assert_eq!(ResticVersion::parse("garbage"), None);
+    }
+
+    #[test]
+    fn repo_version_compatibility() {
+        let old = ResticVersion(0, 13, 1);
+        let new = ResticVersion(0, 14, 0);
+        assert!(old.supports_repo_version(1));
+        assert!(!old.supports_repo_version(2));
+        assert!(new.supports_repo_version(1));
+        assert!(new.supports_repo_version(2));
+        assert!(!ResticVersion(0, 17, 0).supports_repo_version(3));
+    }
+
+    #[test]
+    fn repo_config_version_defaults_to_1() {
+        let config: ResticConfig =
+            serde_json::from_str(r#"{"id":"abc","chunker_polynomial":"x"}"#).unwrap();
+        assert_eq!(config.version, 1);
+        let config: ResticConfig = serde_json::from_str(r#"{"version":2,"id":"abc"}"#).unwrap();
+        assert_eq!(config.version, 2);
+    }
+
+    #[test]
+    fn classify_unsupported_repo_version() {
+        let err = classify_failure(
+            Some(1),
+            "Fatal: config cannot be loaded: unsupported repository version\n",
+        );
+        assert!(crate::errors::is_restic_too_old(&err));
+    }
+
+    #[test]
+    fn parse_forget_plan_collects_removals_of_all_groups() {
+        let stdout = br#"[
+            {"host":"h","paths":["/a"],"keep":[{"id":"k1"}],"remove":[{"id":"r1","short_id":"r1"},{"id":"r2"}]},
+            {"host":"h","paths":["/b"],"keep":[{"id":"k2"}],"remove":null}
+        ]"#;
+        assert_eq!(parse_forget_plan(stdout).unwrap(), ["r1", "r2"]);
+        assert!(parse_forget_plan(b"not json").is_err());
+    }
+
+    #[test]
+    fn parse_forget_prune_summary() {
+        let stdout = "Applying Policy: keep 3 latest snapshots
+keep 3 snapshots:
+ID        Time                 Host        Tags        Reasons    Paths
+...
+remove 2 snapshots:
+ID        Time                 Host        Tags        Paths
+...
+remove 1 snapshots:
+ID        Time                 Host        Tags        Paths
+...
+[0:00] 100.00%  3 / 3 files deleted
+loading indexes...
+to repack:            69 blobs / 1.078 MiB
+this removes:         67 blobs / 1.047 MiB
+to delete:          7539 blobs / 1.020 GiB
+total prune:        7606 blobs / 1.021 GiB
+remaining:         16484 blobs / 2.076 GiB
+unused size after prune: 0 B (0.00% of remaining size)
+done
+";
+        let summary = parse_prune_output(stdout);
+        assert_eq!(summary.removed_snapshots, 3);
+        assert_eq!(summary.freed_bytes, Some(1_096_290_402));
+    }
+
+    #[test]
+    fn parse_prune_progress_counters() {
+        assert_eq!(
+            parse_progress_counter("[0:02] 45.00%  9 / 20 packs repacked"),
+            Some((9, 20, "packs repacked"))
+        );
+        assert_eq!(
+            parse_progress_counter("[0:00] 3 / 3 files deleted"),
+            Some((3, 3, "files deleted"))
+        );
+        assert_eq!(parse_progress_counter("[0:00] 12 files deleted"), None);
+        assert_eq!(parse_progress_counter("total prune: 1.0 GiB"), None);
+
+        let output = "remove 2 snapshots:
+ID        Time                 Host        Tags        Paths
+remove 1 snapshots:
+[0:00] 33.33%  1 / 3 files deleted
+[0:00] 100.00%  3 / 3 files deleted
+loading indexes...
+total prune:        7606 blobs / 1.000 GiB
+repacking packs
+[0:01] 50.00%  1 / 2 packs repacked
+rebuilding index
+[0:00] 100.00%  4 / 4 files deleted
+removing 8 old packs
+[0:00] 25.00%  2 / 8 files deleted
+[0:01] 100.00%  8 / 8 files deleted
+done
+";
+        let mut parser = PruneProgressParser::new(0);
+        let mut seen = Vec::new();
+        for line in output.lines() {
+            if parser.feed(line) {
+                seen.push(parser.progress.clone());
+            }
+        }
+        // After the forget step: three snapshots, all removed, nothing freed yet.
+        assert_eq!(seen[3].snapshots_to_remove, 3);
+        assert_eq!(seen[3].snapshots_removed, 3);
+        assert_eq!(seen[3].bytes_freed, None);
+        assert_eq!(seen[4].bytes_to_free, Some(1 << 30));
+        assert_eq!(seen[5].stage, "packs repacked");
+        assert_eq!((seen[5].stage_done, seen[5].stage_total), (1, 2));
+        // Index files deleted while rebuilding don't count as freed space.
+        assert_eq!(seen[6].bytes_freed, None);
+        assert_eq!(seen[7].bytes_freed, Some(1 << 28));
+        let last = seen.last().unwrap();
+        assert_eq!(last.bytes_freed, Some(1 << 30));
+        assert_eq!(last.snapshots_removed, 3);
+    }
+
+    #[test]
+    fn parse_forget_with_nothing_to_remove() {
+        let summary = parse_prune_output("keep 3 snapshots:\nno unused data to prune\n");
+        assert_eq!(summary, PruneSummary::default());
+    }
+
+    #[test]
+    fn parse_repair_index_and_snapshots_output() {
+        let index = "loading indexes...
+getting pack files to read...
+adding pack file to index 3f2a9c10
+adding pack file to index 8b71d4e2
+rebuilding index
+deleting obsolete index files
+done
+";
+        assert_eq!(
+            parse_repair_output(index),
+            RepairSummary {
+                packs_reindexed: 2,
+                ..RepairSummary::default()
+            }
+        );
+
+        let snapshots = "snapshot 1a2b3c4d of [/home/user] at 2024-05-01 10:00:00 +0200 CEST)
+  file \"/home/user/a.bin\": removed missing content
+removed old snapshot 1a2b3c4d
+saved new snapshot 9e8f7a6b
+
+modified 1 snapshots
+";
+        assert_eq!(
+            parse_repair_output(snapshots),
+            RepairSummary {
+                snapshots_modified: 1,
+                snapshots_removed: 1,
+                ..RepairSummary::default()
+            }
+        );
+        assert_eq!(
+            parse_repair_output("\nno snapshots were modified\n"),
+            RepairSummary::default()
+        );
+    }
+
+    #[test]
+    fn parse_sizes() {
+        assert_eq!(parse_size("0 B"), Some(0));
+        assert_eq!(parse_size(" 512 B"), Some(512));
+        assert_eq!(parse_size("1.500 KiB"), Some(1536));
+        assert_eq!(parse_size("2.000 TiB"), Some(2 << 40));
+        assert_eq!(parse_size("12 parsecs"), None);
+    }
+
+    #[test]
+    fn classify_disk_full() {
+        let err = classify_failure(
+            Some(1),
+            "Fatal: unable to save snapshot: write /media/usb/.aegis/repo/data/ab/abcd: no space left on device\n",
+        );
+        assert!(crate::errors::is_drive_full(&err));
+        let err = classify_failure(Some(1), "There is not enough space on the disk.");
+        assert!(crate::errors::is_drive_full(&err));
+        assert!(!crate::errors::is_drive_full(&classify_failure(
+            Some(1),
+            "Fatal: unable to open config file"
+        )));
+    }
+
+    #[test]
+    fn classify_missing_repository() {
+        use crate::errors::is_repository_missing;
+        let missing = [
+            (
+                Some(10),
+                "Fatal: repository does not exist: unable to open config file: stat /media/u/d/.aegis/repo/config: no such file or directory",
+            ),
+            (
+                Some(1),
+                "Fatal: unable to open config file: Stat: stat /media/u/d/.aegis/repo/config: no such file or directory\nIs there a repository at the following location?\n/media/u/d/.aegis/repo",
+            ),
+        ];
+        for (code, stderr) in missing {
+            assert!(
+                is_repository_missing(&classify_failure(code, stderr)),
+                "{}",
+                stderr
+            );
+        }
+        let existing = [
+            (Some(12), "Fatal: wrong password or no key found"),
+            (
+                Some(1),
+                "Fatal: unable to open config file: permission denied",
+            ),
+            (
+                Some(1),
+                "Fatal: unable to create lock in backend: repository is already locked",
+            ),
+        ];
+        for (code, stderr) in existing {
+            assert!(
+                !is_repository_missing(&classify_failure(code, stderr)),
+                "{}",
+                stderr
+            );
+        }
+        for (code, stderr) in [
+            (
+                Some(1),
+                "Fatal: unable to create lock in backend: repository is already locked by PID 42",
+            ),
+            (Some(11), "Fatal: failed to lock repository"),
+        ] {
+            let err = classify_failure(code, stderr);
+            assert!(crate::errors::is_repository_locked(&err), "{}", stderr);
+            assert_eq!(crate::errors::error_code(&err), "repository_locked");
+        }
+    }
+
+    #[tokio::test]
+    async fn repository_id_reports_missing_repository() {
+        let (restic, dir) = script_restic(
+            "probe",
+            r#"#!/bin/sh
+repo="$2"
+if [ -f "$repo/present" ]; then
+  echo '{"version":2,"id":"abc123","chunker_polynomial":"1"}'
+  exit 0
+fi
+echo "Fatal: repository does not exist: unable to open config file" >&2
+exit 10
+"#,
+        );
+        let missing = restic.repository_id(&dir, "pw").await.unwrap_err();
+        std::fs::write(dir.join("present"), "").unwrap();
+        let found = restic.repository_id(&dir, "pw").await;
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(crate::errors::is_repository_missing(&missing));
+        assert_eq!(found.unwrap(), "abc123");
+    }
+
+    #[test]
+    fn classify_disk_io_error() {
+        let err = classify_failure(
+            Some(1),
+            "Save(<data/4f2a1c>) returned error, retrying after 552ms: write /media/usb/.aegis/repo/data/4f/4f2a1c: input/output error\n",
+        );
+        assert!(crate::errors::is_drive_io_error(&err));
+        assert_eq!(crate::errors::error_code(&err), "drive_io_error");
+        let err = classify_failure(Some(1), "Fatal: unable to open repository: read: I/O error");
+        assert!(crate::errors::is_drive_io_error(&err));
+        // Disk full is the more specific diagnosis.
+        let err = classify_failure(Some(1), "no space left on device\ninput/output error");
+        assert!(crate::errors::is_drive_full(&err));
+        for logical in [
+            "Fatal: unable to open config file: no such file",
+            "error: load <snapshot/ab12>: ciphertext verification failed",
+        ] {
+            assert!(!crate::errors::is_drive_io_error(&classify_failure(
+                Some(1),
+                logical
+            )));
+        }
+    }
+
+    #[tokio::test]
+    async fn backup_reports_drive_full() {
+        let (restic, dir) = script_restic(
+            "full",
+            "#!/bin/sh\necho 'Fatal: write data/ab: no space left on device' >&2\nexit 1\n",
+        );
+        let (tx, _rx) = mpsc::channel(8);
+        let result = restic
+            .backup_with_progress(
+                &dir,
+                "pw",
+                std::slice::from_ref(&dir),
+                &[],
+                &[],
+                &[],
+                tx,
+                CancellationToken::new(),
+            )
+            .await;
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(crate::errors::is_drive_full(&result.unwrap_err()));
+    }
+
+    #[tokio::test]
+    async fn dry_run_backup_streams_progress_and_reports_without_a_snapshot() {
+        // Only answers a dry run; a real backup would fail.
+        let (restic, dir) = script_restic(
+            "dry-run",
+            r#"#!/bin/sh
+case "$*" in *--dry-run*) ;; *) exit 1 ;; esac
+echo '{"message_type":"status","percent_done":0.5,"total_files":4,"files_done":2}'
+echo '{"message_type":"summary","files_new":3,"files_changed":1,"data_added":2048,"total_files_processed":4,"dry_run":true}'
+"#,
+        );
+        let (tx, mut rx) = mpsc::channel(8);
+        let summary = restic
+            .backup_with_progress(
+                &dir,
+                "pw",
+                std::slice::from_ref(&dir),
+                &[],
+                &[],
+                &dry_run_args(true),
+                tx,
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(rx.recv().await.unwrap().files_done, 2);
+        assert_eq!(summary.snapshot_id, None);
+        assert_eq!(summary.files_new, Some(3));
+        assert_eq!(summary.files_changed, Some(1));
+        assert_eq!(summary.data_added, Some(2048));
+        assert!(dry_run_args(false).is_empty());
+    }
+
+    #[test]
+    fn check_binary_rejects_missing_and_non_executable_paths() {
+        use std::os::unix::fs::PermissionsExt;
+        let (restic, dir) = script_restic("check-binary", "#!/bin/sh\n");
+        assert_eq!(check_binary(&restic.binary), Ok(()));
+
+        let missing = dir.join("nope");
+        let err = check_binary(&missing).unwrap_err();
+        assert!(err.ends_with("does not exist"), "{}", err);
+        assert!(check_binary(&dir).unwrap_err().ends_with("is not a file"));
+
+        let plain = dir.join("plain");
+        std::fs::write(&plain, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&plain, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let err = check_binary(&plain).unwrap_err();
+        assert!(err.ends_with("is not executable"), "{}", err);
+
+        // An unusable override is never handed back; resolve falls back to bundled/PATH.
+        for bad in [&missing, &plain] {
+            if let Ok(resolved) = Restic::resolve(bad.to_str()) {
+                assert_ne!(&resolved.binary, bad);
+            }
+        }
+        assert_eq!(
+            Restic::resolve(restic.binary.to_str()).unwrap().binary,
+            restic.binary
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A fake restic running the shell script `body`, in a fresh temp dir (also returned) that the
+    /// test removes when done. restic is called as `<script> --repo <repo> <subcommand> ...`.
+    pub(crate) fn script_restic(name: &str, body: &str) -> (Restic, PathBuf) {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = crate::test_util::temp_dir(&format!("restic-{}", name));
+        let script = dir.join("restic");
+        std::fs::write(&script, body).unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        (Restic { binary: script }, dir)
+    }
+
+    #[tokio::test]
+    async fn backup_cancel_during_silent_scan_is_prompt() {
+        let (restic, dir) = script_restic(
+            "scan-cancel",
+            "#!/bin/sh
+exec sleep 30
+",
+        );
+        let (tx, _rx) = mpsc::channel(8);
+        let cancel = CancellationToken::new();
+        let canceller = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            canceller.cancel();
+        });
+        let started = std::time::Instant::now();
+        let result = restic
+            .backup_with_progress(
+                &dir,
+                "pw",
+                std::slice::from_ref(&dir),
+                &[],
+                &[],
+                &[],
+                tx,
+                cancel,
+            )
+            .await;
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(result.unwrap_err().to_string().contains("cancelled"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn privileged_restore_cancel_is_prompt() {
+        let (restic, dir) = script_restic(
+            "priv-cancel",
+            "#!/bin/sh
+exec sleep 30
+",
+        );
+        let cancel = CancellationToken::new();
+        let canceller = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            canceller.cancel();
+        });
+        let started = std::time::Instant::now();
+        let result = restic
+            .restore_privileged(
+                &dir,
+                "pw",
+                "abc",
+                &dir,
+                &[],
+                false,
+                BandwidthLimits::default(),
+                Elevation::AlreadyRoot,
+                cancel,
+            )
+            .await;
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(crate::errors::is_cancelled(&result.unwrap_err()));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn backup_notices_exit_when_output_stays_open() {
+        // The background sleep inherits stdout, so the pipe stays open after restic exits.
+        let (restic, dir) = script_restic(
+            "exit-open",
+            "#!/bin/sh
+sleep 30 2>/dev/null &
+exit 4
+",
+        );
+        let (tx, _rx) = mpsc::channel(8);
+        let started = std::time::Instant::now();
+        let result = restic
+            .backup_with_progress(
+                &dir,
+                "pw",
+                std::slice::from_ref(&dir),
+                &[],
+                &[],
+                &[],
+                tx,
+                CancellationToken::new(),
+            )
+            .await;
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("exit code Some(4)"));
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn backup_io_error_is_a_drive_io_error() {
+        let (restic, dir) = script_restic(
+            "backup-eio",
+            "#!/bin/sh
+echo 'Fatal: unable to save snapshot: Save(<data/0a1b2c3d>) returned error: write /media/usb/.aegis/repo/data/0a/0a1b2c3d: input/output error' >&2
+exit 1
+",
+        );
+        let (tx, _rx) = mpsc::channel(8);
+        let result = restic
+            .backup_with_progress(
+                &dir,
+                "pw",
+                std::slice::from_ref(&dir),
+                &[],
+                &[],
+                &[],
+                tx,
+                CancellationToken::new(),
+            )
+            .await;
+        let _ = std::fs::remove_dir_all(&dir);
+        let err = result.unwrap_err();
+        assert!(crate::errors::is_drive_io_error(&err));
+        assert!(err.to_string().contains("exit code Some(1)"));
+    }
+
+    #[tokio::test]
+    async fn slow_progress_consumer_does_not_stall_backup() {
+        let (restic, dir) = script_restic(
+            "slow-progress",
+            r#"#!/bin/sh
+i=1
+while [ $i -le 200 ]; do
+  echo "{\"message_type\":\"status\",\"percent_done\":0.$((i / 100))$((i / 10 % 10))$((i % 10)),\"files_done\":$i}"
+  i=$((i + 1))
+done
+echo '{"message_type":"status","percent_done":1.0,"files_done":201}'
+echo '{"message_type":"summary","snapshot_id":"abcd1234"}'
+"#,
+        );
+        let (tx, mut rx) = mpsc::channel::<BackupProgressReport>(1);
+        let consumer = tokio::spawn(async move {
+            let mut received = Vec::new();
+            while let Some(report) = rx.recv().await {
+                received.push(report.files_done);
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            }
+            received
+        });
+        let started = std::time::Instant::now();
+        let summary = restic
+            .backup_with_progress(
+                &dir,
+                "pw",
+                std::slice::from_ref(&dir),
+                &[],
+                &[],
+                &[],
+                tx,
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        // Delivering all 201 reports at this pace would take 40 s.
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(summary.snapshot_id.as_deref(), Some("abcd1234"));
+        let received = consumer.await.unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(received.len() < 201, "{:?}", received);
+        assert_eq!(received.last(), Some(&201));
+    }
+
+    #[tokio::test]
+    async fn retention_forgets_only_the_given_snapshots() {
+        let (restic, dir) = script_restic(
+            "forget-ids",
+            r#"#!/bin/sh
+repo="$2"; shift 2
+echo "$*" >> "$repo/args"
+case "$*" in
+  *--dry-run*) echo '[{"keep":[{"id":"k1"}],"remove":[{"id":"aaaa1111ffff"},{"id":"bbbb2222ffff"}]}]' ;;
+  *) echo "total prune: 1.000 MiB" ;;
+esac
+"#,
+        );
+        let policy = ["--keep-last".to_string(), "1".to_string()];
+        let plan = restic.forget_plan(&dir, "pw", &policy).await.unwrap();
+        assert_eq!(plan, ["aaaa1111ffff", "bbbb2222ffff"]);
+        let keep = crate::retention::unprotected(plan, &["aaaa1111".to_string()]);
+        let (tx, _rx) = mpsc::channel(8);
+        let summary = restic
+            .forget_snapshots_prune(&dir, "pw", &keep, &tx)
+            .await
+            .unwrap();
+        let args = std::fs::read_to_string(dir.join("args")).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(summary.removed_snapshots, 1);
+        assert_eq!(
+            args,
+            "forget --dry-run --json --keep-last 1\nforget --prune bbbb2222ffff\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn backup_exit_code_3_keeps_the_snapshot() {
+        let body = |code: i32| {
+            format!(
+                "#!/bin/sh\necho '{{\"message_type\":\"summary\",\"snapshot_id\":\"abcd1234\"}}'\necho 'error: open /home/u/locked: permission denied' >&2\nexit {}\n",
+                code
+            )
+        };
+        let run = |restic: Restic, dir: PathBuf| async move {
+            let (tx, _rx) = mpsc::channel(8);
+            let result = restic
+                .backup_with_progress(
+                    &dir,
+                    "pw",
+                    std::slice::from_ref(&dir),
+                    &[],
+                    &[],
+                    &[],
+                    tx,
+                    CancellationToken::new(),
+                )
+                .await;
+            let _ = std::fs::remove_dir_all(&dir);
+            result
+        };
+
+        let (restic, dir) = script_restic("exit-incomplete", &body(3));
+        let summary = run(restic, dir).await.unwrap();
+        assert!(summary.incomplete);
+        assert_eq!(summary.snapshot_id.as_deref(), Some("abcd1234"));
+
+        let (restic, dir) = script_restic("exit-fatal", &body(1));
+        assert!(run(restic, dir).await.is_err());
+    }
+
+    #[test]
+    fn parse_snapshots_with_and_without_parent() {
+        let json = r#"[
+            {"time":"2024-01-01T10:00:00Z","tree":"t1","paths":["/home/u"],"hostname":"h","id":"aaaa1111","short_id":"aaaa1111"},
+            {"time":"2024-01-02T10:00:00Z","parent":"aaaa1111","tree":"t2","paths":["/home/u"],"hostname":"h","id":"bbbb2222","short_id":"bbbb2222"}
+        ]"#;
+        let snapshots: Vec<SnapshotInfo> = serde_json::from_str(json).unwrap();
+        assert_eq!(snapshots[0].parent, None);
+        assert_eq!(snapshots[1].parent.as_deref(), Some("aaaa1111"));
+        assert_eq!(
+            serde_json::to_value(&snapshots[1]).unwrap()["parent"],
+            "aaaa1111"
+        );
+    }
+
+    #[test]
+    fn parses_restic_snapshot_times() {
+        // 2024-01-01T10:00:00Z
+        let base = 1_704_103_200;
+        assert_eq!(parse_snapshot_time("2024-01-01T10:00:00Z"), Some(base));
+        assert_eq!(
+            parse_snapshot_time("2024-01-01T10:00:00.123456789Z"),
+            Some(base)
+        );
+        assert_eq!(
+            parse_snapshot_time("2024-01-01T11:00:00.5+01:00"),
+            Some(base)
+        );
+        assert_eq!(
+            parse_snapshot_time("2024-01-01T04:30:00.987654321-05:30"),
+            Some(base)
+        );
+        // As restic writes it from a machine in CEST, crossing midnight UTC.
+        assert_eq!(
+            parse_snapshot_time("2024-07-15T01:12:09.381220347+02:00"),
+            Some(1_720_998_729)
+        );
+        // Leap day.
+        assert_eq!(
+            parse_snapshot_time("2024-02-29T00:00:00Z"),
+            Some(1_709_164_800)
+        );
+        assert_eq!(parse_snapshot_time("1970-01-01T00:00:00Z"), Some(0));
+
+        for bad in [
+            "",
+            "2024-01-01",
+            "2024-01-01T10:00:00",
+            "2024-13-01T10:00:00Z",
+            "2024-01-01T10:00:00.Z",
+            "2024-01-01T10:00Z",
+            "1969-12-31T23:59:59Z",
+            "yesterday",
+        ] {
+            assert_eq!(parse_snapshot_time(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn classify_wrong_password_from_stderr() {
+        let err = classify_failure(Some(1), "Fatal: wrong password or no key found\n");
+        assert!(crate::errors::is_wrong_password(&err));
+    }
+
+    #[test]
+    fn classify_wrong_password_from_exit_code() {
+        let err = classify_failure(Some(12), "");
+        assert!(crate::errors::is_wrong_password(&err));
+    }
+
+    #[test]
+    fn classify_other_failure() {
+        let err = classify_failure(Some(1), "Fatal: unable to open config file: no such file");
+        assert!(!crate::errors::is_wrong_password(&err));
+        assert!(err.to_string().contains("unable to open config file"));
+    }
+
+    #[test]
+    fn few_sources_are_positional() {
+        let sources = vec![
+            PathBuf::from("/home/u/Documents"),
+            PathBuf::from("/home/u/Pictures"),
+        ];
+        assert!(!needs_files_from(&sources));
+        let args = build_backup_args(&sources, &[], &[], &[], None);
+        assert_eq!(
+            args,
+            ["backup", "--json", "/home/u/Documents", "/home/u/Pictures"].map(String::from)
+        );
+    }
+
+    #[test]
+    fn many_sources_use_files_from() {
+        let sources: Vec<PathBuf> = (0..100)
+            .map(|i| PathBuf::from(format!("/home/u/folder-{}", i)))
+            .collect();
+        assert!(needs_files_from(&sources));
+        let list = SourceListFile::create(&sources).unwrap();
+        let args = build_backup_args(&sources, &[], &["*.tmp".to_string()], &[], Some(&list.path));
+        assert_eq!(
+            args[..4],
+            ["backup", "--json", "--exclude", "*.tmp"].map(String::from)
+        );
+        assert_eq!(args[4], "--files-from-verbatim");
+        assert_eq!(args[5], list.path.to_string_lossy());
+        assert_eq!(args.len(), 6);
+        let content = std::fs::read_to_string(&list.path).unwrap();
+        assert_eq!(content.lines().count(), 100);
+        assert_eq!(content.lines().next(), Some("/home/u/folder-0"));
+        let path = list.path.clone();
+        drop(list);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn long_source_paths_use_files_from() {
+        let sources = vec![PathBuf::from(format!(
+            "/{}",
+            "a".repeat(FILES_FROM_MIN_BYTES)
+        ))];
+        assert!(needs_files_from(&sources));
+    }
+
     #[test]
     fn parse_status_line_minimal() {
         let json = r#"{"message_type":"status"}"#;
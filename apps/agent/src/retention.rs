@@ -1,3 +1,5 @@
+use crate::restic::{is_snapshot_id, PruneSummary};
+use crate::space::format_bytes;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -56,10 +58,56 @@ impl RetentionPolicy {
     }
 }
 
+/// True if `snapshot_id` is protected; the list may hold full or short IDs. Entries too short
+/// to name one snapshot (see `is_snapshot_id`) protect nothing.
+pub fn is_protected(snapshot_id: &str, protected: &[String]) -> bool {
+    protected
+        .iter()
+        .any(|p| is_snapshot_id(p) && snapshot_id.starts_with(p.as_str()))
+}
+
+/// The snapshots retention would remove, minus the protected ones.
+pub fn unprotected(remove: Vec<String>, protected: &[String]) -> Vec<String> {
+    remove
+        .into_iter()
+        .filter(|id| !is_protected(id, protected))
+        .collect()
+}
+
+/// "retention removed 5 snapshots, freed 3.2 GB", or None if nothing was removed.
+pub fn describe_prune(summary: &PruneSummary) -> Option<String> {
+    if summary.removed_snapshots == 0 {
+        return None;
+    }
+    let plural = if summary.removed_snapshots == 1 {
+        ""
+    } else {
+        "s"
+    };
+    let mut text = format!(
+        "retention removed {} snapshot{}",
+        summary.removed_snapshots, plural
+    );
+    if let Some(bytes) = summary.freed_bytes.filter(|&b| b > 0) {
+        text.push_str(&format!(", freed {}", format_bytes(bytes)));
+    }
+    Some(text)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn unprotected_keeps_protected_snapshots_out() {
+        let remove = vec!["aaaa1111ffff".to_string(), "bbbb2222ffff".to_string()];
+        let protected = vec!["aaaa1111".to_string(), String::new(), "bbbb".to_string()];
+        assert_eq!(unprotected(remove.clone(), &protected), ["bbbb2222ffff"]);
+        assert_eq!(unprotected(remove.clone(), &[]), remove);
+        assert!(is_protected("aaaa1111ffff", &protected));
+        assert!(!is_protected("cccc", &protected));
+    }
+
     #[test]
     fn to_forget_args_disabled_returns_empty() {
         let p = RetentionPolicy {
@@ -131,4 +179,25 @@ mod tests {
         let args = p.to_forget_args();
         assert_eq!(args, &["--keep-last".to_string(), "3".to_string()]);
     }
+
+    #[test]
+    fn describe_prune_reports_snapshots_and_space() {
+        let summary = PruneSummary {
+            removed_snapshots: 5,
+            freed_bytes: Some(3_435_973_837),
+        };
+        assert_eq!(
+            describe_prune(&summary).as_deref(),
+            Some("retention removed 5 snapshots, freed 3.2 GB")
+        );
+        let summary = PruneSummary {
+            removed_snapshots: 1,
+            freed_bytes: None,
+        };
+        assert_eq!(
+            describe_prune(&summary).as_deref(),
+            Some("retention removed 1 snapshot")
+        );
+        assert_eq!(describe_prune(&PruneSummary::default()), None);
+    }
 }
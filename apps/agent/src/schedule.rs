@@ -0,0 +1,314 @@
+use crate::state::SharedState;
+use crate::usb::{self, AutoBackupTrigger};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Mutex as StdMutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info};
+
+/// How often the scheduler wakes to see whether a scheduled time has passed.
+pub const SCHEDULE_TICK: Duration = Duration::from_secs(30);
+
+/// Most entries a schedule may have.
+pub const MAX_SCHEDULE_ENTRIES: usize = 100;
+
+/// How long a looked-up UTC offset is reused before asking `date` again (DST changes).
+const OFFSET_REFRESH: Duration = Duration::from_secs(600);
+
+const DAY_SECS: i64 = 86_400;
+const WEEK_SECS: i64 = 7 * DAY_SECS;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+impl Weekday {
+    /// Days after Monday.
+    fn index(self) -> i64 {
+        match self {
+            Weekday::Monday => 0,
+            Weekday::Tuesday => 1,
+            Weekday::Wednesday => 2,
+            Weekday::Thursday => 3,
+            Weekday::Friday => 4,
+            Weekday::Saturday => 5,
+            Weekday::Sunday => 6,
+        }
+    }
+}
+
+/// A weekly backup time, in the machine's local time.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ScheduleEntry {
+    pub weekday: Weekday,
+    pub hour: u8,
+    pub minute: u8,
+}
+
+impl ScheduleEntry {
+    pub fn is_valid(&self) -> bool {
+        self.hour < 24 && self.minute < 60
+    }
+
+    /// Seconds from local Monday 00:00.
+    fn week_offset(&self) -> i64 {
+        self.weekday.index() * DAY_SECS + i64::from(self.hour) * 3600 + i64::from(self.minute) * 60
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ScheduleConfig {
+    #[serde(default)]
+    pub entries: Vec<ScheduleEntry>,
+    /// On insert, back up if a scheduled time passed since the drive's last backup (e.g. it was
+    /// unplugged then), even when auto-backup on insert is off.
+    #[serde(default)]
+    pub catch_up_missed: bool,
+}
+
+impl ScheduleConfig {
+    /// True if catch-up is on and a scheduled time passed after `last_backup_epoch` (or there was
+    /// never a backup).
+    pub fn missed_since(&self, last_backup_epoch: Option<u64>, now: u64, offset_secs: i64) -> bool {
+        if !self.catch_up_missed {
+            return false;
+        }
+        match previous_run(&self.entries, now, offset_secs) {
+            Some(slot) => last_backup_epoch.is_none_or(|last| slot > last),
+            None => false,
+        }
+    }
+}
+
+/// Seconds into the local week (from Monday 00:00) of UTC epoch `epoch`. 1970-01-01 was a
+/// Thursday.
+fn local_week_secs(epoch: u64, offset_secs: i64) -> i64 {
+    (epoch as i64 + offset_secs + 3 * DAY_SECS).rem_euclid(WEEK_SECS)
+}
+
+/// The first scheduled time strictly after `now` (UTC epochs; entries in local time at
+/// `offset_secs` east of UTC).
+pub fn next_run(entries: &[ScheduleEntry], now: u64, offset_secs: i64) -> Option<u64> {
+    let current = local_week_secs(now, offset_secs);
+    entries
+        .iter()
+        .filter(|e| e.is_valid())
+        .map(
+            |e| match (e.week_offset() - current).rem_euclid(WEEK_SECS) {
+                0 => WEEK_SECS,
+                delta => delta,
+            },
+        )
+        .min()
+        .map(|delta| now + delta as u64)
+}
+
+/// The last scheduled time at or before `now`.
+pub fn previous_run(entries: &[ScheduleEntry], now: u64, offset_secs: i64) -> Option<u64> {
+    let current = local_week_secs(now, offset_secs);
+    entries
+        .iter()
+        .filter(|e| e.is_valid())
+        .map(|e| (current - e.week_offset()).rem_euclid(WEEK_SECS))
+        .min()
+        .and_then(|delta| now.checked_sub(delta as u64))
+}
+
+/// True if a scheduled time fell in `(since, now]`.
+pub fn due_between(entries: &[ScheduleEntry], since: u64, now: u64, offset_secs: i64) -> bool {
+    next_run(entries, since, offset_secs).is_some_and(|slot| slot <= now)
+}
+
+/// The machine's current UTC offset in seconds, from `date +%z`; 0 if it can't be read. `date`
+/// runs in `spawn_blocking`, and the cache lock is never held while it does: two callers that
+/// both find the cache stale may each ask, which is harmless.
+pub async fn local_offset_secs() -> i64 {
+    static CACHE: StdMutex<Option<(Instant, i64)>> = StdMutex::new(None);
+    let cached = *CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((at, offset)) = cached {
+        if at.elapsed() < OFFSET_REFRESH {
+            return offset;
+        }
+    }
+    let offset = tokio::task::spawn_blocking(read_utc_offset)
+        .await
+        .ok()
+        .flatten()
+        .unwrap_or(0);
+    *CACHE.lock().unwrap_or_else(|e| e.into_inner()) = Some((Instant::now(), offset));
+    offset
+}
+
+fn read_utc_offset() -> Option<i64> {
+    Command::new("date")
+        .arg("+%z")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| parse_utc_offset(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parse `date +%z` output such as `+0200` or `-0530` into seconds.
+fn parse_utc_offset(text: &str) -> Option<i64> {
+    let text = text.trim();
+    let (sign, digits) = match text.split_at_checked(1)? {
+        ("+", digits) => (1, digits),
+        ("-", digits) => (-1, digits),
+        _ => return None,
+    };
+    if digits.len() != 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let hours: i64 = digits[..2].parse().ok()?;
+    let minutes: i64 = digits[2..].parse().ok()?;
+    Some(sign * (hours * 3600 + minutes * 60))
+}
+
+/// Wake every `SCHEDULE_TICK` and start a backup of the connected trusted drive when a scheduled
+/// time has passed since the last tick. Runs for the life of the agent.
+pub async fn run_scheduler(state: SharedState) {
+    let mut last_check = now_epoch();
+    loop {
+        tokio::time::sleep(SCHEDULE_TICK).await;
+        let now = now_epoch();
+        let entries = state.read().await.config.schedule.entries.clone();
+        if due_between(&entries, last_check, now, local_offset_secs().await) {
+            start_scheduled_backup(&state).await;
+        }
+        last_check = now;
+    }
+}
+
+async fn start_scheduled_backup(state: &SharedState) {
+    let target = {
+        let guard = state.read().await;
+        let status = &guard.drive_status;
+        match (&status.drive_id, &status.mount_path) {
+            (Some(drive_id), Some(mount_path)) if status.connected && status.trusted => {
+                Some((drive_id.clone(), PathBuf::from(mount_path)))
+            }
+            _ => None,
+        }
+    };
+    match target {
+        Some((drive_id, mount_path)) => {
+            debug!("schedule: backup due drive_id={}", drive_id);
+            usb::attempt_auto_backup(state, &drive_id, &mount_path, AutoBackupTrigger::Schedule)
+                .await;
+        }
+        None => info!("Scheduled backup due, but no trusted drive is connected"),
+    }
+}
+
+fn now_epoch() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(weekday: Weekday, hour: u8, minute: u8) -> ScheduleEntry {
+        ScheduleEntry {
+            weekday,
+            hour,
+            minute,
+        }
+    }
+
+    // Monday 2024-01-01 00:00 UTC.
+    const MONDAY: u64 = 1_704_067_200;
+
+    #[test]
+    fn next_and_previous_run_in_utc() {
+        let entries = [entry(Weekday::Monday, 9, 30), entry(Weekday::Friday, 18, 0)];
+        let monday_0930 = MONDAY + 9 * 3600 + 1800;
+        let friday_1800 = MONDAY + 4 * 86_400 + 18 * 3600;
+        assert_eq!(next_run(&entries, MONDAY, 0), Some(monday_0930));
+        // Strictly after: at the slot itself the next one is chosen.
+        assert_eq!(next_run(&entries, monday_0930, 0), Some(friday_1800));
+        assert_eq!(
+            next_run(&entries, friday_1800, 0),
+            Some(monday_0930 + 7 * 86_400)
+        );
+        assert_eq!(previous_run(&entries, monday_0930, 0), Some(monday_0930));
+        assert_eq!(
+            previous_run(&entries, MONDAY, 0),
+            Some(friday_1800 - 7 * 86_400)
+        );
+        assert_eq!(next_run(&[], MONDAY, 0), None);
+        assert_eq!(next_run(&[entry(Weekday::Monday, 24, 0)], MONDAY, 0), None);
+    }
+
+    #[test]
+    fn entries_are_local_time() {
+        let entries = [entry(Weekday::Monday, 9, 0)];
+        // 09:00 at UTC+2 is 07:00 UTC.
+        assert_eq!(next_run(&entries, MONDAY, 7200), Some(MONDAY + 7 * 3600));
+        // Sunday 23:00 UTC is already Monday 01:00 at UTC+2.
+        let sunday_late = MONDAY - 3600;
+        assert_eq!(
+            next_run(&entries, sunday_late, 7200),
+            Some(MONDAY + 7 * 3600)
+        );
+        // At UTC-5 the slot is 14:00 UTC.
+        assert_eq!(
+            next_run(&entries, MONDAY, -5 * 3600),
+            Some(MONDAY + 14 * 3600)
+        );
+    }
+
+    #[test]
+    fn due_only_when_a_slot_passed_since_the_last_tick() {
+        let entries = [entry(Weekday::Monday, 9, 0)];
+        let slot = MONDAY + 9 * 3600;
+        assert!(due_between(&entries, slot - 30, slot, 0));
+        assert!(due_between(&entries, slot - 30, slot + 10, 0));
+        assert!(!due_between(&entries, slot, slot + 30, 0));
+        assert!(!due_between(&entries, slot - 60, slot - 30, 0));
+        // A clock that went backwards doesn't fire.
+        assert!(!due_between(&entries, slot + 30, slot - 30, 0));
+    }
+
+    #[test]
+    fn catch_up_when_a_slot_passed_since_the_last_backup() {
+        let mut schedule = ScheduleConfig {
+            entries: vec![entry(Weekday::Monday, 9, 0)],
+            catch_up_missed: true,
+        };
+        let slot = MONDAY + 9 * 3600;
+        let now = slot + 3 * 86_400;
+        assert!(schedule.missed_since(Some(slot - 60), now, 0));
+        assert!(schedule.missed_since(None, now, 0));
+        assert!(!schedule.missed_since(Some(slot + 60), now, 0));
+        schedule.catch_up_missed = false;
+        assert!(!schedule.missed_since(Some(slot - 60), now, 0));
+        let empty = ScheduleConfig {
+            catch_up_missed: true,
+            ..ScheduleConfig::default()
+        };
+        assert!(!empty.missed_since(None, now, 0));
+    }
+
+    #[test]
+    fn parses_date_utc_offsets() {
+        assert_eq!(parse_utc_offset("+0200\n"), Some(7200));
+        assert_eq!(parse_utc_offset("-0530"), Some(-(5 * 3600 + 1800)));
+        assert_eq!(parse_utc_offset("+0000"), Some(0));
+        assert_eq!(parse_utc_offset("0200"), None);
+        assert_eq!(parse_utc_offset("+02"), None);
+        assert_eq!(parse_utc_offset(""), None);
+    }
+}
@@ -0,0 +1,178 @@
+//! Post-setup self-test: back up a tiny canary file, restore it and compare the bytes, so
+//! passphrase/repository/filesystem problems show up right after setup instead of at restore time.
+
+use crate::restic::Restic;
+use anyhow::Context;
+use rand::Rng;
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+const CANARY_FILE: &str = "aegis-canary.txt";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SelfTestResult {
+    pub passed: bool,
+    pub message: String,
+}
+
+/// Run the canary round-trip against `repo`. Never fails outright; problems are reported in the result.
+pub async fn run_restore_self_test(
+    restic: &Restic,
+    repo: &Path,
+    passphrase: &str,
+) -> SelfTestResult {
+    let workdir = std::env::temp_dir().join(format!(
+        "aegis-selftest-{:016x}",
+        rand::thread_rng().gen::<u64>()
+    ));
+    let result = canary_round_trip(restic, repo, passphrase, &workdir).await;
+    let _ = fs::remove_dir_all(&workdir);
+    match result {
+        Ok(()) => SelfTestResult {
+            passed: true,
+            message: "Test file was backed up and restored successfully".to_string(),
+        },
+        Err(err) => {
+            warn!("self-test: failed: {}", err);
+            SelfTestResult {
+                passed: false,
+                message: format!("Restore self-test failed: {}", err),
+            }
+        }
+    }
+}
+
+async fn canary_round_trip(
+    restic: &Restic,
+    repo: &Path,
+    passphrase: &str,
+    workdir: &Path,
+) -> anyhow::Result<()> {
+    let source_dir = workdir.join("source");
+    let restore_dir = workdir.join("restore");
+    fs::create_dir_all(&source_dir).context("create canary dir")?;
+    let canary: [u8; 32] = rand::thread_rng().gen();
+    let contents = hex::encode(canary);
+    fs::write(source_dir.join(CANARY_FILE), &contents).context("write canary")?;
+
+    let summary = restic
+        .backup(
+            repo,
+            passphrase,
+            std::slice::from_ref(&source_dir),
+            &[],
+            &[],
+        )
+        .await
+        .context("back up canary")?;
+    let snapshot_id = summary
+        .snapshot_id
+        .ok_or_else(|| anyhow::anyhow!("backup reported no snapshot"))?;
+    debug!("self-test: canary snapshot={}", snapshot_id);
+
+    let restored = async {
+        restic
+            .restore(repo, passphrase, &snapshot_id, &restore_dir, &[])
+            .await
+            .context("restore canary")?;
+        let restored_path = restored_location(&restore_dir, &source_dir.join(CANARY_FILE));
+        fs::read_to_string(&restored_path).context("read restored canary")
+    }
+    .await;
+
+    // Remove the canary snapshot whatever happened, so it never shows up in the user's list.
+    if let Err(err) = restic.forget_snapshot(repo, passphrase, &snapshot_id).await {
+        warn!("self-test: could not remove canary snapshot: {}", err);
+    }
+
+    if restored? != contents {
+        return Err(anyhow::anyhow!("restored test file does not match"));
+    }
+    Ok(())
+}
+
+/// restic restores absolute source paths underneath the target directory.
+fn restored_location(target: &Path, original: &Path) -> PathBuf {
+    let relative: PathBuf = original
+        .components()
+        .filter(|c| matches!(c, std::path::Component::Normal(_)))
+        .collect();
+    target.join(relative)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::restic::tests::script_restic;
+
+    /// Minimal stand-in for restic, in a fresh dir with an empty `repo`: `backup` copies the
+    /// source dir into the repo, `restore` copies it back under the target using the original
+    /// absolute path, `forget` succeeds. If `corrupt` is set, restored bytes are altered.
+    fn fake_restic(name: &str, corrupt: bool) -> (Restic, PathBuf) {
+        let restore_cmd = if corrupt {
+            r#"echo garbage > "$target$src/aegis-canary.txt""#
+        } else {
+            r#"cp "$repo/store/aegis-canary.txt" "$target$src/aegis-canary.txt""#
+        };
+        let body = format!(
+            r#"#!/bin/sh
+repo="$2"; shift 2
+cmd="$1"; shift
+case "$cmd" in
+  backup)
+    for a in "$@"; do src="$a"; done
+    mkdir -p "$repo/store"
+    cp "$src/aegis-canary.txt" "$repo/store/"
+    echo "$src" > "$repo/store/src"
+    echo '{{"message_type":"summary","snapshot_id":"cafe0001","data_added":64,"total_files_processed":1}}'
+    ;;
+  restore)
+    target="$3"
+    src=$(cat "$repo/store/src")
+    mkdir -p "$target$src"
+    {restore_cmd}
+    ;;
+  forget)
+    touch "$repo/forgot-$1"
+    ;;
+esac
+"#
+        );
+        let (restic, dir) = script_restic(name, &body);
+        fs::create_dir_all(dir.join("repo")).unwrap();
+        (restic, dir)
+    }
+
+    #[test]
+    fn restored_location_nests_absolute_path() {
+        assert_eq!(
+            restored_location(Path::new("/tmp/r"), Path::new("/tmp/s/canary")),
+            PathBuf::from("/tmp/r/tmp/s/canary")
+        );
+    }
+
+    #[tokio::test]
+    async fn canary_round_trip_passes_and_forgets_snapshot() {
+        let (restic, dir) = fake_restic("selftest-pass", false);
+        let repo = dir.join("repo");
+        let result = run_restore_self_test(&restic, &repo, "pw").await;
+        let forgot = repo.join("forgot-cafe0001").exists();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(result.passed, "{}", result.message);
+        assert!(forgot);
+    }
+
+    #[tokio::test]
+    async fn canary_round_trip_detects_mismatch() {
+        let (restic, dir) = fake_restic("selftest-corrupt", true);
+        let repo = dir.join("repo");
+        let result = run_restore_self_test(&restic, &repo, "pw").await;
+        let forgot = repo.join("forgot-cafe0001").exists();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(!result.passed);
+        assert!(result.message.contains("does not match"));
+        assert!(forgot);
+    }
+}
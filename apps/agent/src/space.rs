@@ -0,0 +1,163 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Default for `history_max_entries`: free-space samples kept per drive (one per completed backup).
+pub const FREE_SPACE_HISTORY_MAX: usize = 30;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DiskUsage {
+    pub total_bytes: u64,
+    pub free_bytes: u64,
+}
+
+/// Free space on the drive recorded after a backup.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FreeSpaceSample {
+    pub epoch: u64,
+    pub free_bytes: u64,
+    pub total_bytes: u64,
+}
+
+/// Query total and available bytes for the filesystem containing `path` (via `statvfs`, which
+/// unlike `df` flags works the same on Linux, macOS and BusyBox systems).
+pub fn disk_usage(path: &Path) -> anyhow::Result<DiskUsage> {
+    let stat = nix::sys::statvfs::statvfs(path).context("statvfs")?;
+    let fragment_size = stat.fragment_size() as u64;
+    Ok(DiskUsage {
+        total_bytes: (stat.blocks() as u64).saturating_mul(fragment_size),
+        free_bytes: (stat.blocks_available() as u64).saturating_mul(fragment_size),
+    })
+}
+
+/// Bytes to keep free on the drive: the larger of the absolute and the percentage reserve.
+pub fn reserve_bytes(total_bytes: u64, min_free_bytes: u64, min_free_percent: u8) -> u64 {
+    let percent = (total_bytes as u128 * min_free_percent.min(100) as u128 / 100) as u64;
+    min_free_bytes.max(percent)
+}
+
+/// True if a backup must stop because free space is at or below the reserve. A zero reserve
+/// never triggers.
+pub fn violates_reserve(usage: &DiskUsage, reserve: u64) -> bool {
+    reserve > 0 && usage.free_bytes <= reserve
+}
+
+/// Binary units with one decimal, matching the UI's formatBytes.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Project how many more backups fit on the drive at the average growth per backup seen in
+/// `samples` (oldest first). None if there is not enough history or free space is not shrinking.
+pub fn project_backups_until_full(samples: &[FreeSpaceSample]) -> Option<u64> {
+    if samples.len() < 2 {
+        return None;
+    }
+    let first = samples.first()?;
+    let last = samples.last()?;
+    if last.free_bytes >= first.free_bytes {
+        return None;
+    }
+    let intervals = (samples.len() - 1) as u64;
+    let growth_per_backup = (first.free_bytes - last.free_bytes) / intervals;
+    if growth_per_backup == 0 {
+        return None;
+    }
+    Some(last.free_bytes / growth_per_backup)
+}
+
+/// True if the projection falls within the warning window (`threshold` backups; 0 disables).
+pub fn is_space_warning(backups_until_full: Option<u64>, threshold: u32) -> bool {
+    threshold > 0 && backups_until_full.is_some_and(|n| n <= u64::from(threshold))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(free_bytes: u64) -> FreeSpaceSample {
+        FreeSpaceSample {
+            epoch: 0,
+            free_bytes,
+            total_bytes: 1_000,
+        }
+    }
+
+    #[test]
+    fn disk_usage_reads_the_file_system() {
+        let usage = disk_usage(&std::env::temp_dir()).unwrap();
+        assert!(usage.total_bytes > 0);
+        assert!(usage.free_bytes <= usage.total_bytes);
+        assert!(disk_usage(Path::new("/nonexistent/aegis-space")).is_err());
+    }
+
+    #[test]
+    fn reserve_is_larger_of_bytes_and_percent() {
+        assert_eq!(reserve_bytes(1_000_000, 0, 0), 0);
+        assert_eq!(reserve_bytes(1_000_000, 50_000, 0), 50_000);
+        assert_eq!(reserve_bytes(1_000_000, 50_000, 10), 100_000);
+        assert_eq!(reserve_bytes(1_000_000, 200_000, 10), 200_000);
+        assert_eq!(reserve_bytes(u64::MAX, 0, 100), u64::MAX);
+    }
+
+    #[test]
+    fn reserve_violation_decision() {
+        let usage = |free_bytes| DiskUsage {
+            total_bytes: 1_000_000,
+            free_bytes,
+        };
+        assert!(!violates_reserve(&usage(0), 0));
+        assert!(!violates_reserve(&usage(100_001), 100_000));
+        assert!(violates_reserve(&usage(100_000), 100_000));
+        assert!(violates_reserve(&usage(5), 100_000));
+    }
+
+    #[test]
+    fn projection_needs_two_samples() {
+        assert_eq!(project_backups_until_full(&[]), None);
+        assert_eq!(project_backups_until_full(&[sample(500)]), None);
+    }
+
+    #[test]
+    fn projection_linear_growth() {
+        // 100 bytes consumed per backup, 400 left -> 4 more backups.
+        let samples = [sample(700), sample(600), sample(500), sample(400)];
+        assert_eq!(project_backups_until_full(&samples), Some(4));
+    }
+
+    #[test]
+    fn projection_uses_average_over_uneven_series() {
+        // 900 -> 300 over 3 backups = 200 per backup; 300 left -> 1.
+        let samples = [sample(900), sample(850), sample(500), sample(300)];
+        assert_eq!(project_backups_until_full(&samples), Some(1));
+    }
+
+    #[test]
+    fn space_warning_threshold() {
+        assert!(is_space_warning(Some(3), 5));
+        assert!(is_space_warning(Some(5), 5));
+        assert!(!is_space_warning(Some(6), 5));
+        assert!(!is_space_warning(None, 5));
+        assert!(!is_space_warning(Some(0), 0));
+    }
+
+    #[test]
+    fn projection_none_when_space_stable_or_growing() {
+        assert_eq!(
+            project_backups_until_full(&[sample(500), sample(500)]),
+            None
+        );
+        // Pruning freed space overall.
+        assert_eq!(
+            project_backups_until_full(&[sample(400), sample(300), sample(600)]),
+            None
+        );
+    }
+}
@@ -1,8 +1,12 @@
 use crate::config::AgentConfig;
+use crate::errors::{error_code, is_cancelled};
+use crate::logging::redact_message;
+use crate::restic::SnapshotInfo;
+use crate::verify::{VerifyMode, DEEP_VERIFY_FAILED_MESSAGE, VERIFY_FAILED_MESSAGE};
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, RwLock};
 use tokio_util::sync::CancellationToken;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -33,6 +37,17 @@ pub struct BackupProgress {
     pub total_files: u64,
     pub bytes_done: u64,
     pub total_bytes: u64,
+    /// Label of the configured source restic is currently reading (never the file path).
+    #[serde(default)]
+    pub current_source_label: Option<String>,
+    /// While retention prunes: snapshots forgotten so far and how many will be.
+    #[serde(default)]
+    pub snapshots_removed: Option<u64>,
+    #[serde(default)]
+    pub snapshots_to_remove: Option<u64>,
+    /// While retention prunes: estimate of the space reclaimed so far.
+    #[serde(default)]
+    pub bytes_reclaimed: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -47,6 +62,30 @@ pub struct RunResult {
     pub repository_id: Option<String>,
     pub data_added: Option<u64>,
     pub files_processed: Option<u64>,
+    /// Estimated duration of the deep verification, set while it runs.
+    #[serde(default)]
+    pub deep_verify_estimate_secs: Option<u64>,
+    /// Snapshots removed and bytes freed by retention in this run; None if retention didn't run.
+    #[serde(default)]
+    pub retention_removed_snapshots: Option<u64>,
+    #[serde(default)]
+    pub retention_freed_bytes: Option<u64>,
+    /// True if this backup took much longer than the drive's recent average, which can point to
+    /// a failing drive or a slow connection.
+    #[serde(default)]
+    pub slow_run: bool,
+    /// Files new/changed since the parent snapshot (what a dry run would back up).
+    #[serde(default)]
+    pub files_new: Option<u64>,
+    #[serde(default)]
+    pub files_changed: Option<u64>,
+    /// A preview run: restic wrote nothing and no snapshot exists, and the drive's last backup
+    /// time wasn't updated.
+    #[serde(default)]
+    pub dry_run: bool,
+    /// The quick or deep verification after the backup failed (see `verify_failure_policy`).
+    #[serde(default)]
+    pub verify_failed: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -57,6 +96,10 @@ pub struct DriveStatus {
     pub label: Option<String>,
     pub mount_path: Option<String>,
     pub devnode: Option<String>,
+    /// restic repository ID of the connected trusted drive (non-secret); filled in from the config
+    /// when reported, see [`AgentRuntimeState::drive_status_view`].
+    #[serde(default)]
+    pub repository_id: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -66,16 +109,201 @@ pub struct AgentRuntimeState {
     pub last_run: Option<RunResult>,
     /// Drive IDs that currently have a backup in progress. Enables parallel backup per drive.
     pub running_drive_ids: HashSet<String>,
+    /// Drive IDs whose auto-backup is deferred because the OS keyring is locked; retried until it unlocks.
+    pub keyring_waiting_drive_ids: HashSet<String>,
+    /// Drive IDs whose on-drive repository differs from the one recorded at setup; backups are refused until reconfigured.
+    pub repository_mismatch_drive_ids: HashSet<String>,
+    /// Drive IDs whose repository format is newer than the installed restic can open.
+    pub restic_too_old_drive_ids: HashSet<String>,
+    /// Drive IDs whose last backup found the repository locked by another restic process;
+    /// cleared by the next successful backup.
+    pub repository_locked_drive_ids: HashSet<String>,
+    /// Drive IDs whose repository is being repaired (`/v1/repair`).
+    #[serde(default)]
+    pub repairing_drive_ids: HashSet<String>,
     /// Progress per drive (key = drive_id). Cleared when that drive's backup finishes.
     pub backup_progress: HashMap<String, BackupProgress>,
     /// Cancel tokens for in-progress backups; cancelling aborts restic when the drive is unplugged.
     #[serde(skip)]
     pub running_cancel_tokens: HashMap<String, CancellationToken>,
-    /// If a restore is in progress, the drive being restored and its cancel token (so unplug aborts it).
+    /// Cancel tokens for in-progress restores, keyed by the drive restored from (so unplug aborts it),
+    /// each with the ID of the restore that owns it.
+    #[serde(skip)]
+    pub restore_cancel_tokens: HashMap<String, (u64, CancellationToken)>,
+    /// ID handed to the next restore by `begin_restore`.
+    #[serde(skip)]
+    next_restore_id: u64,
+    /// On-demand verifications in progress (key = drive_id).
+    #[serde(default)]
+    pub verify_progress: HashMap<String, VerifyProgress>,
+    /// Snapshot exports in progress, keyed by the drive exported from.
+    pub export_progress: HashMap<String, ExportProgress>,
+    /// Cancel tokens for in-progress exports (same keys as `export_progress`).
     #[serde(skip)]
-    pub restore_drive_id: Option<String>,
+    pub export_cancel_tokens: HashMap<String, CancellationToken>,
+    /// Passphrases typed into the UI, kept in memory only until they expire (see
+    /// `AgentConfig::passphrase_cache_secs`). Never persisted or serialized.
     #[serde(skip)]
-    pub restore_cancel_token: Option<CancellationToken>,
+    pub passphrase_cache: HashMap<String, CachedPassphrase>,
+    /// Most recent failure per drive (key = drive_id), redacted so it can be copied into a bug
+    /// report. Kept until the next failure replaces it; not persisted across restarts.
+    #[serde(default)]
+    pub last_errors: HashMap<String, LastError>,
+    /// What the auto-backup did when each drive was last inserted (key = drive_id).
+    #[serde(default)]
+    pub auto_backup: HashMap<String, AutoBackupState>,
+    /// Bumped on every USB block device udev adds or removes; the UI refetches `/v1/devices` when
+    /// it changes instead of waiting for its next device poll.
+    #[serde(default)]
+    pub devices_revision: u64,
+    /// Backup progress, phase and completion events for `/v1/events`.
+    #[serde(skip)]
+    pub events: EventBus,
+    /// Snapshot count and time range per drive (key = drive_id), from its last snapshot listing
+    /// (on connect, after a backup, or when the UI lists snapshots).
+    #[serde(default)]
+    pub snapshot_stats: HashMap<String, SnapshotStats>,
+}
+
+/// Aggregates of a drive's snapshot listing for the dashboard.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SnapshotStats {
+    pub snapshot_count: u64,
+    /// Unix seconds; None when there are no snapshots or none had a parseable time.
+    pub oldest_snapshot_epoch: Option<u64>,
+    pub newest_snapshot_epoch: Option<u64>,
+}
+
+impl SnapshotStats {
+    pub fn from_snapshots(snapshots: &[SnapshotInfo]) -> Self {
+        let times = || snapshots.iter().filter_map(|s| s.time_epoch);
+        Self {
+            snapshot_count: snapshots.len() as u64,
+            oldest_snapshot_epoch: times().min(),
+            newest_snapshot_epoch: times().max(),
+        }
+    }
+}
+
+/// A change pushed to `/v1/events` subscribers.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AgentEvent {
+    /// A backup's progress was updated.
+    Progress {
+        drive_id: String,
+        progress: BackupProgress,
+    },
+    /// A backup moved to another phase (`run` is the new `last_run`).
+    Phase { drive_id: String, run: RunResult },
+    /// A backup finished, successfully or not.
+    Completed { drive_id: String, run: RunResult },
+}
+
+impl AgentEvent {
+    /// SSE event name, the same as the `type` field.
+    pub fn name(&self) -> &'static str {
+        match self {
+            AgentEvent::Progress { .. } => "progress",
+            AgentEvent::Phase { .. } => "phase",
+            AgentEvent::Completed { .. } => "completed",
+        }
+    }
+}
+
+/// Events buffered per subscriber; one that falls further behind skips the oldest.
+const EVENT_BUFFER: usize = 64;
+
+/// Fan-out of [`AgentEvent`]s to the open `/v1/events` streams.
+#[derive(Debug, Clone)]
+pub struct EventBus(broadcast::Sender<AgentEvent>);
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self(broadcast::channel(EVENT_BUFFER).0)
+    }
+}
+
+impl EventBus {
+    /// Send to every current subscriber; a no-op when there are none.
+    pub fn publish(&self, event: AgentEvent) {
+        let _ = self.0.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<AgentEvent> {
+        self.0.subscribe()
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.0.receiver_count()
+    }
+}
+
+/// Whether the auto-backup for an inserted drive started.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AutoBackupOutcome {
+    Started,
+    /// Not started; see the reason. The user has to start the backup.
+    Skipped,
+    /// Waiting for something (e.g. the keyring to unlock) and will start by itself.
+    Delayed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AutoBackupState {
+    pub outcome: AutoBackupOutcome,
+    /// Why it was skipped or delayed: `disabled`, `paranoid_mode`, `already_running`,
+    /// `verify_running`, `repair_running`, `no_stored_passphrase`, `keychain_error`, `keyring_locked` or `cancelled` (the user
+    /// cancelled the wait for the keyring). None when started.
+    pub reason: Option<String>,
+    pub epoch: u64,
+}
+
+/// A failed operation on a drive, with paths stripped from the message.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LastError {
+    /// "backup", "verify", "retention", "restore" or "export".
+    pub operation: String,
+    /// See [`crate::errors::error_code`].
+    pub code: String,
+    pub message: String,
+    pub epoch: u64,
+}
+
+/// An on-demand verification (not part of a backup). restic check reports no progress, so this
+/// carries the phase and, for a deep check, the estimated duration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerifyProgress {
+    pub mode: VerifyMode,
+    pub phase: RunPhase,
+    pub started_epoch: u64,
+    pub estimate_secs: Option<u64>,
+}
+
+/// Progress of a snapshot export. The archive size isn't known up front, so only the bytes
+/// written so far are reported.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ExportProgress {
+    pub snapshot_id: String,
+    pub bytes_written: u64,
+    pub started_epoch: u64,
+}
+
+/// A passphrase held in memory for a drive until `expires_epoch`.
+#[derive(Clone)]
+pub struct CachedPassphrase {
+    pub passphrase: String,
+    pub expires_epoch: u64,
+}
+
+impl std::fmt::Debug for CachedPassphrase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CachedPassphrase")
+            .field("passphrase", &"<redacted>")
+            .field("expires_epoch", &self.expires_epoch)
+            .finish()
+    }
 }
 
 impl AgentRuntimeState {
@@ -89,15 +317,643 @@ impl AgentRuntimeState {
                 label: None,
                 mount_path: None,
                 devnode: None,
+                repository_id: None,
             },
             last_run: None,
             running_drive_ids: HashSet::new(),
+            keyring_waiting_drive_ids: HashSet::new(),
+            repairing_drive_ids: HashSet::new(),
+            repository_mismatch_drive_ids: HashSet::new(),
+            restic_too_old_drive_ids: HashSet::new(),
+            repository_locked_drive_ids: HashSet::new(),
             backup_progress: HashMap::new(),
             running_cancel_tokens: HashMap::new(),
-            restore_drive_id: None,
-            restore_cancel_token: None,
+            restore_cancel_tokens: HashMap::new(),
+            next_restore_id: 0,
+            verify_progress: HashMap::new(),
+            export_progress: HashMap::new(),
+            export_cancel_tokens: HashMap::new(),
+            passphrase_cache: HashMap::new(),
+            last_errors: HashMap::new(),
+            auto_backup: HashMap::new(),
+            devices_revision: 0,
+            events: EventBus::default(),
+            snapshot_stats: HashMap::new(),
+        }
+    }
+
+    /// Note that the list of removable devices changed; returns the new revision.
+    pub fn note_devices_changed(&mut self) -> u64 {
+        self.devices_revision += 1;
+        self.devices_revision
+    }
+
+    /// Remember `err` as this drive's last error. Cancellations aren't failures and are ignored.
+    pub fn record_error(&mut self, drive_id: &str, operation: &str, err: &anyhow::Error, now: u64) {
+        if is_cancelled(err) {
+            return;
+        }
+        self.last_errors.insert(
+            drive_id.to_string(),
+            LastError {
+                operation: operation.to_string(),
+                code: error_code(err).to_string(),
+                message: redact_message(&format!("{:#}", err)),
+                epoch: now,
+            },
+        );
+    }
+
+    /// Replace this drive's snapshot aggregates with those of a fresh listing.
+    pub fn record_snapshots(&mut self, drive_id: &str, snapshots: &[SnapshotInfo]) {
+        self.snapshot_stats.insert(
+            drive_id.to_string(),
+            SnapshotStats::from_snapshots(snapshots),
+        );
+    }
+
+    /// Record what the auto-backup did for this drive.
+    pub fn set_auto_backup(
+        &mut self,
+        drive_id: &str,
+        outcome: AutoBackupOutcome,
+        reason: Option<&str>,
+        now: u64,
+    ) {
+        self.auto_backup.insert(
+            drive_id.to_string(),
+            AutoBackupState {
+                outcome,
+                reason: reason.map(String::from),
+                epoch: now,
+            },
+        );
+    }
+
+    /// Keep `passphrase` for `ttl_secs` from `now`; a TTL of 0 caches nothing.
+    pub fn cache_passphrase(&mut self, drive_id: &str, passphrase: &str, ttl_secs: u64, now: u64) {
+        if ttl_secs == 0 {
+            return;
+        }
+        self.passphrase_cache.insert(
+            drive_id.to_string(),
+            CachedPassphrase {
+                passphrase: passphrase.to_string(),
+                expires_epoch: now.saturating_add(ttl_secs),
+            },
+        );
+    }
+
+    /// Drop the cached passphrase for this drive if it is `passphrase` (e.g. restic rejected it).
+    pub fn forget_cached_passphrase(&mut self, drive_id: &str, passphrase: &str) {
+        if self.cached_passphrase(drive_id, 0) == Some(passphrase) {
+            self.passphrase_cache.remove(drive_id);
+        }
+    }
+
+    /// The cached passphrase for this drive, if it hasn't expired yet.
+    pub fn cached_passphrase(&self, drive_id: &str, now: u64) -> Option<&str> {
+        self.passphrase_cache
+            .get(drive_id)
+            .filter(|c| c.expires_epoch > now)
+            .map(|c| c.passphrase.as_str())
+    }
+
+    /// When the cached passphrase for this drive expires; None if nothing usable is cached.
+    pub fn passphrase_cache_expires(&self, drive_id: &str, now: u64) -> Option<u64> {
+        self.passphrase_cache
+            .get(drive_id)
+            .map(|c| c.expires_epoch)
+            .filter(|&expires| expires > now)
+    }
+
+    /// The current drive status with the trusted drive's repository ID from the config.
+    pub fn drive_status_view(&self) -> DriveStatus {
+        let mut status = self.drive_status.clone();
+        status.repository_id = status
+            .drive_id
+            .as_ref()
+            .filter(|_| status.trusted)
+            .and_then(|id| self.config.trusted_drives.get(id))
+            .and_then(|drive| drive.repository_id.clone());
+        status
+    }
+
+    /// Record the result of a repository ID check for a drive.
+    pub fn set_repository_mismatch(&mut self, drive_id: &str, mismatch: bool) {
+        if mismatch {
+            self.repository_mismatch_drive_ids
+                .insert(drive_id.to_string());
+        } else {
+            self.repository_mismatch_drive_ids.remove(drive_id);
+        }
+    }
+
+    /// Register a restore from this drive: its ID (for `finish_restore`) and cancel token. None if
+    /// one is already running for it, cancelled or not.
+    pub fn begin_restore(&mut self, drive_id: &str) -> Option<(u64, CancellationToken)> {
+        if self.restore_cancel_tokens.contains_key(drive_id) {
+            return None;
+        }
+        self.next_restore_id += 1;
+        let id = self.next_restore_id;
+        let cancel = CancellationToken::new();
+        self.restore_cancel_tokens
+            .insert(drive_id.to_string(), (id, cancel.clone()));
+        Some((id, cancel))
+    }
+
+    /// Unregister restore `restore_id` once restic has exited; another restore's entry is kept.
+    pub fn finish_restore(&mut self, drive_id: &str, restore_id: u64) {
+        if self
+            .restore_cancel_tokens
+            .get(drive_id)
+            .is_some_and(|(id, _)| *id == restore_id)
+        {
+            self.restore_cancel_tokens.remove(drive_id);
+        }
+    }
+
+    /// Why a backup to this drive can't start now (`already_running`, `verify_running` or
+    /// `repair_running`); None if it can.
+    pub fn backup_blocker(&self, drive_id: &str) -> Option<&'static str> {
+        if self.running_drive_ids.contains(drive_id) {
+            Some("already_running")
+        } else if self.verify_progress.contains_key(drive_id) {
+            Some("verify_running")
+        } else if self.repairing_drive_ids.contains(drive_id) {
+            Some("repair_running")
+        } else {
+            None
+        }
+    }
+
+    /// Register a backup to this drive: checks `backup_blocker` and marks the drive running in
+    /// one step, so two starts can't both get past the check.
+    pub fn begin_backup(&mut self, drive_id: &str) -> Result<(), &'static str> {
+        if let Some(reason) = self.backup_blocker(drive_id) {
+            return Err(reason);
+        }
+        self.running_drive_ids.insert(drive_id.to_string());
+        Ok(())
+    }
+
+    /// Register an on-demand verification of this drive. Refused while a backup to the drive or
+    /// another verification of it is running.
+    pub fn begin_verify(
+        &mut self,
+        drive_id: &str,
+        mode: VerifyMode,
+        estimate_secs: Option<u64>,
+        now: u64,
+    ) -> Result<(), &'static str> {
+        if self.running_drive_ids.contains(drive_id) {
+            return Err("a backup is running on this drive");
+        }
+        if self.verify_progress.contains_key(drive_id) {
+            return Err("a verification of this drive is already running");
+        }
+        if self.repairing_drive_ids.contains(drive_id) {
+            return Err("a repair of this drive is running");
+        }
+        self.verify_progress.insert(
+            drive_id.to_string(),
+            VerifyProgress {
+                mode,
+                phase: mode.phase(),
+                started_epoch: now,
+                estimate_secs,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn finish_verify(&mut self, drive_id: &str) {
+        self.verify_progress.remove(drive_id);
+    }
+
+    /// Register a repair of this drive's repository. Refused while a backup, a verification or
+    /// another repair of the drive is running.
+    pub fn begin_repair(&mut self, drive_id: &str) -> Result<(), &'static str> {
+        if self.running_drive_ids.contains(drive_id) {
+            return Err("a backup is running on this drive");
+        }
+        if self.verify_progress.contains_key(drive_id) {
+            return Err("a verification of this drive is running");
+        }
+        if !self.repairing_drive_ids.insert(drive_id.to_string()) {
+            return Err("a repair of this drive is already running");
+        }
+        Ok(())
+    }
+
+    pub fn finish_repair(&mut self, drive_id: &str) {
+        self.repairing_drive_ids.remove(drive_id);
+    }
+
+    /// After verification of the repository passed on a re-run, upgrade the last run to Success
+    /// if it was Partial only because its verification failed. True if it was upgraded.
+    pub fn upgrade_reverified_run(&mut self, repository_id: Option<&str>) -> bool {
+        let Some(run) = self.last_run.as_mut() else {
+            return false;
+        };
+        let same_repository =
+            repository_id.is_some() && run.repository_id.as_deref() == repository_id;
+        // Other problems (skipped sources, unreadable files) are appended to the verification
+        // message, and stay.
+        let nothing_else_failed =
+            run.message == VERIFY_FAILED_MESSAGE || run.message == DEEP_VERIFY_FAILED_MESSAGE;
+        if !same_repository
+            || run.status != RunStatus::Partial
+            || !run.verify_failed
+            || !nothing_else_failed
+        {
+            return false;
+        }
+        run.status = RunStatus::Success;
+        run.verify_failed = false;
+        run.message = "Backup completed; verification passed when run again".to_string();
+        true
+    }
+
+    /// Register an export from this drive. None if one is already running for it.
+    pub fn begin_export(
+        &mut self,
+        drive_id: &str,
+        snapshot_id: &str,
+        now: u64,
+    ) -> Option<CancellationToken> {
+        if self.export_cancel_tokens.contains_key(drive_id) {
+            return None;
+        }
+        let cancel = CancellationToken::new();
+        self.export_cancel_tokens
+            .insert(drive_id.to_string(), cancel.clone());
+        self.export_progress.insert(
+            drive_id.to_string(),
+            ExportProgress {
+                snapshot_id: snapshot_id.to_string(),
+                bytes_written: 0,
+                started_epoch: now,
+            },
+        );
+        Some(cancel)
+    }
+
+    pub fn set_export_progress(&mut self, drive_id: &str, bytes_written: u64) {
+        if let Some(progress) = self.export_progress.get_mut(drive_id) {
+            progress.bytes_written = bytes_written;
+        }
+    }
+
+    pub fn finish_export(&mut self, drive_id: &str) {
+        self.export_cancel_tokens.remove(drive_id);
+        self.export_progress.remove(drive_id);
+    }
+
+    /// Cancel the export running from this drive. False if there is none.
+    pub fn cancel_export(&mut self, drive_id: &str) -> bool {
+        match self.export_cancel_tokens.get(drive_id) {
+            Some(cancel) => {
+                cancel.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drop this drive's auto-backup that is waiting for the keyring to unlock, before it starts;
+    /// a running backup is untouched. False if none is waiting.
+    pub fn cancel_waiting_backup(&mut self, drive_id: &str, now: u64) -> bool {
+        if !self.keyring_waiting_drive_ids.remove(drive_id) {
+            return false;
+        }
+        self.set_auto_backup(drive_id, AutoBackupOutcome::Skipped, Some("cancelled"), now);
+        true
+    }
+
+    /// Cancel the restore running from this drive. False if there is none. It stays registered
+    /// until restic exits, so no other restore can start before then.
+    pub fn cancel_restore(&mut self, drive_id: &str) -> bool {
+        match self.restore_cancel_tokens.get(drive_id) {
+            Some((_, cancel)) => {
+                cancel.cancel();
+                true
+            }
+            None => false,
         }
     }
 }
 
 pub type SharedState = Arc<RwLock<AgentRuntimeState>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn verify_failed_run(status: RunStatus, message: &str) -> RunResult {
+        RunResult {
+            status,
+            phase: RunPhase::Completed,
+            started_epoch: 1,
+            finished_epoch: Some(2),
+            message: message.to_string(),
+            interrupted: false,
+            snapshot_id: Some("abcd1234".to_string()),
+            repository_id: Some("repo-1".to_string()),
+            data_added: None,
+            files_processed: None,
+            deep_verify_estimate_secs: None,
+            retention_removed_snapshots: None,
+            retention_freed_bytes: None,
+            slow_run: false,
+            files_new: None,
+            files_changed: None,
+            dry_run: false,
+            verify_failed: true,
+        }
+    }
+
+    #[test]
+    fn reverify_upgrades_only_runs_partial_because_of_verification() {
+        let mut state = AgentRuntimeState::new(AgentConfig::default());
+        assert!(!state.upgrade_reverified_run(Some("repo-1")));
+
+        state.last_run = Some(verify_failed_run(
+            RunStatus::Partial,
+            DEEP_VERIFY_FAILED_MESSAGE,
+        ));
+        assert!(!state.upgrade_reverified_run(Some("repo-2")));
+        assert!(!state.upgrade_reverified_run(None));
+        assert!(state.upgrade_reverified_run(Some("repo-1")));
+        let run = state.last_run.as_ref().unwrap();
+        assert_eq!(run.status, RunStatus::Success);
+        assert!(!run.verify_failed);
+        assert_eq!(run.snapshot_id.as_deref(), Some("abcd1234"));
+        // Already upgraded: the verification no longer counts as failed.
+        state.last_run.as_mut().unwrap().status = RunStatus::Partial;
+        state.last_run.as_mut().unwrap().message = DEEP_VERIFY_FAILED_MESSAGE.to_string();
+        assert!(!state.upgrade_reverified_run(Some("repo-1")));
+
+        // Partial for another reason too: the verification passing doesn't fix that.
+        let also_skipped = format!(
+            "{} (1 unavailable source(s) skipped)",
+            VERIFY_FAILED_MESSAGE
+        );
+        state.last_run = Some(verify_failed_run(RunStatus::Partial, &also_skipped));
+        assert!(!state.upgrade_reverified_run(Some("repo-1")));
+        // Failed under the "fail" policy: retention never ran, so it stays failed.
+        state.last_run = Some(verify_failed_run(RunStatus::Failed, VERIFY_FAILED_MESSAGE));
+        assert!(!state.upgrade_reverified_run(Some("repo-1")));
+        assert_eq!(state.last_run.unwrap().status, RunStatus::Failed);
+    }
+
+    #[test]
+    fn set_repository_mismatch_flags_and_clears() {
+        let mut state = AgentRuntimeState::new(AgentConfig::default());
+        state.set_repository_mismatch("drive-1", true);
+        assert!(state.repository_mismatch_drive_ids.contains("drive-1"));
+        state.set_repository_mismatch("drive-1", false);
+        assert!(state.repository_mismatch_drive_ids.is_empty());
+    }
+
+    #[test]
+    fn record_error_keeps_latest_redacted_failure() {
+        use crate::errors::AgentError;
+        let mut state = AgentRuntimeState::new(AgentConfig::default());
+        let err = anyhow::Error::new(AgentError::DriveFull)
+            .context("restic backup failed: write /media/alice/aegis-1/.aegis/repo/data/ab");
+        state.record_error("drive-1", "backup", &err, 1_000);
+        let recorded = &state.last_errors["drive-1"];
+        assert_eq!(recorded.operation, "backup");
+        assert_eq!(recorded.code, "drive_full");
+        assert_eq!(recorded.epoch, 1_000);
+        assert!(!recorded.message.contains("alice"));
+        assert!(recorded.message.contains("<path>"));
+
+        state.record_error("drive-1", "restore", &anyhow::anyhow!("boom"), 2_000);
+        assert_eq!(state.last_errors["drive-1"].operation, "restore");
+        assert_eq!(state.last_errors["drive-1"].code, "unknown");
+
+        state.record_error("drive-1", "backup", &AgentError::Cancelled.into(), 3_000);
+        assert_eq!(state.last_errors["drive-1"].epoch, 2_000);
+    }
+
+    #[test]
+    fn passphrase_cache_reports_expiry_until_it_lapses() {
+        let mut state = AgentRuntimeState::new(AgentConfig::default());
+        state.cache_passphrase("drive-1", "secret", 600, 1_000);
+        assert_eq!(state.cached_passphrase("drive-1", 1_000), Some("secret"));
+        assert_eq!(
+            state.passphrase_cache_expires("drive-1", 1_000),
+            Some(1_600)
+        );
+        assert_eq!(
+            state.passphrase_cache_expires("drive-1", 1_599),
+            Some(1_600)
+        );
+        assert_eq!(state.passphrase_cache_expires("drive-1", 1_600), None);
+        assert_eq!(state.cached_passphrase("drive-1", 1_600), None);
+        assert_eq!(state.passphrase_cache_expires("drive-2", 1_000), None);
+    }
+
+    #[test]
+    fn forgetting_a_rejected_passphrase_keeps_a_different_one() {
+        let mut state = AgentRuntimeState::new(AgentConfig::default());
+        state.cache_passphrase("drive-1", "secret", 600, 1_000);
+        state.forget_cached_passphrase("drive-1", "wrong");
+        assert_eq!(state.cached_passphrase("drive-1", 1_000), Some("secret"));
+        state.forget_cached_passphrase("drive-1", "secret");
+        assert!(state.passphrase_cache.is_empty());
+    }
+
+    #[test]
+    fn passphrase_cache_disabled_with_zero_ttl() {
+        let mut state = AgentRuntimeState::new(AgentConfig::default());
+        state.cache_passphrase("drive-1", "secret", 0, 1_000);
+        assert!(state.passphrase_cache.is_empty());
+        assert!(!format!("{:?}", state).contains("secret"));
+    }
+
+    #[test]
+    fn begin_backup_claims_the_drive_once() {
+        let mut state = AgentRuntimeState::new(AgentConfig::default());
+        assert_eq!(state.begin_backup("drive-a"), Ok(()));
+        assert_eq!(state.begin_backup("drive-a"), Err("already_running"));
+        state
+            .begin_verify("drive-b", VerifyMode::Quick, None, 1_000)
+            .unwrap();
+        assert_eq!(state.begin_backup("drive-b"), Err("verify_running"));
+        state.begin_repair("drive-c").unwrap();
+        assert_eq!(state.begin_backup("drive-c"), Err("repair_running"));
+        assert!(!state.running_drive_ids.contains("drive-b"));
+    }
+
+    #[test]
+    fn verify_refused_while_backup_or_verify_runs() {
+        let mut state = AgentRuntimeState::new(AgentConfig::default());
+        state.running_drive_ids.insert("drive-a".to_string());
+        assert!(state
+            .begin_verify("drive-a", VerifyMode::Quick, None, 1_000)
+            .is_err());
+        assert!(state.verify_progress.is_empty());
+
+        assert!(state
+            .begin_verify("drive-b", VerifyMode::Deep, Some(600), 1_000)
+            .is_ok());
+        assert!(matches!(
+            state.verify_progress["drive-b"].phase,
+            RunPhase::VerifyingDeep
+        ));
+        assert!(state
+            .begin_verify("drive-b", VerifyMode::Quick, None, 1_001)
+            .is_err());
+        state.finish_verify("drive-b");
+        assert!(state
+            .begin_verify("drive-b", VerifyMode::Quick, None, 1_002)
+            .is_ok());
+    }
+
+    #[test]
+    fn repair_refused_while_the_drive_is_busy() {
+        let mut state = AgentRuntimeState::new(AgentConfig::default());
+        state.running_drive_ids.insert("drive-a".to_string());
+        assert!(state.begin_repair("drive-a").is_err());
+        state
+            .begin_verify("drive-b", VerifyMode::Quick, None, 1_000)
+            .unwrap();
+        assert!(state.begin_repair("drive-b").is_err());
+
+        assert!(state.begin_repair("drive-c").is_ok());
+        assert!(state.begin_repair("drive-c").is_err());
+        assert!(state
+            .begin_verify("drive-c", VerifyMode::Quick, None, 1_000)
+            .is_err());
+        state.finish_repair("drive-c");
+        assert!(state.begin_repair("drive-c").is_ok());
+    }
+
+    #[test]
+    fn cancelling_a_waiting_backup_leaves_running_ones_alone() {
+        let mut state = AgentRuntimeState::new(AgentConfig::default());
+        state
+            .keyring_waiting_drive_ids
+            .insert("drive-a".to_string());
+        state.running_drive_ids.insert("drive-b".to_string());
+        let running = CancellationToken::new();
+        state
+            .running_cancel_tokens
+            .insert("drive-b".to_string(), running.clone());
+
+        assert!(state.cancel_waiting_backup("drive-a", 100));
+        assert!(state.keyring_waiting_drive_ids.is_empty());
+        let recorded = &state.auto_backup["drive-a"];
+        assert_eq!(recorded.outcome, AutoBackupOutcome::Skipped);
+        assert_eq!(recorded.reason.as_deref(), Some("cancelled"));
+
+        // Nothing waits for drive-b; its running backup is not cancelled.
+        assert!(!state.cancel_waiting_backup("drive-b", 100));
+        assert!(!state.cancel_waiting_backup("drive-a", 100));
+        assert!(state.running_drive_ids.contains("drive-b"));
+        assert!(!running.is_cancelled());
+    }
+
+    #[test]
+    fn export_tracks_progress_until_finished() {
+        let mut state = AgentRuntimeState::new(AgentConfig::default());
+        let cancel = state.begin_export("drive-a", "abc123", 1_000).unwrap();
+        assert!(state.begin_export("drive-a", "def456", 1_001).is_none());
+        state.set_export_progress("drive-a", 4096);
+        assert_eq!(state.export_progress["drive-a"].bytes_written, 4096);
+        assert_eq!(state.export_progress["drive-a"].snapshot_id, "abc123");
+
+        assert!(state.cancel_export("drive-a"));
+        assert!(cancel.is_cancelled());
+        state.finish_export("drive-a");
+        assert!(state.export_progress.is_empty());
+        assert!(!state.cancel_export("drive-a"));
+    }
+
+    #[test]
+    fn concurrent_restores_are_independent() {
+        let mut state = AgentRuntimeState::new(AgentConfig::default());
+        let (a_id, a) = state.begin_restore("drive-a").unwrap();
+        let (b_id, b) = state.begin_restore("drive-b").unwrap();
+        // A second restore from the same drive is refused and leaves the first untouched.
+        assert!(state.begin_restore("drive-a").is_none());
+
+        assert!(state.cancel_restore("drive-a"));
+        assert!(a.is_cancelled());
+        assert!(!b.is_cancelled());
+
+        state.finish_restore("drive-a", a_id);
+        assert!(state.restore_cancel_tokens.contains_key("drive-b"));
+        assert!(!state.cancel_restore("drive-a"));
+        state.finish_restore("drive-b", b_id);
+        assert!(!b.is_cancelled());
+        assert!(state.restore_cancel_tokens.is_empty());
+    }
+
+    #[test]
+    fn cancelled_restore_blocks_the_drive_until_it_finishes() {
+        let mut state = AgentRuntimeState::new(AgentConfig::default());
+        let (first_id, _) = state.begin_restore("drive-a").unwrap();
+        assert!(state.cancel_restore("drive-a"));
+        // restic is still winding down: a second restore has to wait.
+        assert!(state.begin_restore("drive-a").is_none());
+        state.finish_restore("drive-a", first_id);
+
+        let (second_id, second) = state.begin_restore("drive-a").unwrap();
+        // A late finish of the first restore doesn't unregister the second.
+        state.finish_restore("drive-a", first_id);
+        assert!(state.begin_restore("drive-a").is_none());
+        assert!(state.cancel_restore("drive-a"));
+        assert!(second.is_cancelled());
+        state.finish_restore("drive-a", second_id);
+        assert!(state.restore_cancel_tokens.is_empty());
+    }
+
+    fn snapshot(id: &str, time_epoch: Option<u64>) -> SnapshotInfo {
+        SnapshotInfo {
+            id: id.to_string(),
+            time: String::new(),
+            time_epoch,
+            hostname: None,
+            tags: None,
+            parent: None,
+            description: None,
+            protected: false,
+        }
+    }
+
+    #[test]
+    fn snapshot_stats_aggregate_count_and_time_range() {
+        let snapshots = [
+            snapshot("b", Some(2_000)),
+            snapshot("a", Some(1_000)),
+            snapshot("x", None),
+            snapshot("c", Some(3_000)),
+        ];
+        assert_eq!(
+            SnapshotStats::from_snapshots(&snapshots),
+            SnapshotStats {
+                snapshot_count: 4,
+                oldest_snapshot_epoch: Some(1_000),
+                newest_snapshot_epoch: Some(3_000),
+            }
+        );
+        assert_eq!(SnapshotStats::from_snapshots(&[]), SnapshotStats::default());
+        let unparsed = SnapshotStats::from_snapshots(&[snapshot("x", None)]);
+        assert_eq!(unparsed.snapshot_count, 1);
+        assert_eq!(unparsed.oldest_snapshot_epoch, None);
+
+        let mut state = AgentRuntimeState::new(AgentConfig::default());
+        state.record_snapshots("drive-a", &snapshots);
+        state.record_snapshots("drive-a", &snapshots[..1]);
+        assert_eq!(state.snapshot_stats["drive-a"].snapshot_count, 1);
+        assert_eq!(
+            state.snapshot_stats["drive-a"].oldest_snapshot_epoch,
+            Some(2_000)
+        );
+    }
+}
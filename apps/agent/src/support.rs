@@ -0,0 +1,207 @@
+//! Support bundle: a zip the user can attach to a bug report. Everything in it is redacted on the
+//! way in (paths become `<path>`, identifying fields are dropped) and passphrases are never read.
+
+use crate::config::AgentConfig;
+use crate::logging::redact_paths;
+use crate::state::AgentRuntimeState;
+use serde_json::{json, Value};
+use std::io::{Cursor, Write};
+use zip::write::SimpleFileOptions;
+use zip::ZipWriter;
+
+/// How many lines of the newest agent log go into the bundle.
+pub const LOG_LINES: usize = 2000;
+
+/// Config keys left out of the bundle entirely: they identify the machine or the drive rather
+/// than describe the setup.
+const DROPPED_CONFIG_KEYS: &[&str] = &["backup_hostname", "serial"];
+
+/// What goes into the bundle besides the agent's own state.
+pub struct BundleInputs {
+    pub agent_version: String,
+    pub generated_epoch: u64,
+    /// restic's version string; None if restic is missing or didn't answer.
+    pub restic_version: Option<String>,
+    pub preflight: Value,
+    pub log_lines: Vec<String>,
+}
+
+/// Build the zip: `summary.json` (versions, preflight), `config.json`, `status.json` and
+/// `agent.log`.
+pub fn build_bundle(state: &AgentRuntimeState, inputs: &BundleInputs) -> anyhow::Result<Vec<u8>> {
+    let summary = json!({
+        "agent_version": inputs.agent_version,
+        "generated_epoch": inputs.generated_epoch,
+        "restic_version": inputs.restic_version,
+        "preflight": inputs.preflight,
+    });
+    let log: String = inputs
+        .log_lines
+        .iter()
+        .map(|line| redact_paths(line) + "\n")
+        .collect();
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default();
+    for (name, contents) in [
+        ("summary.json", serde_json::to_string_pretty(&summary)?),
+        (
+            "config.json",
+            serde_json::to_string_pretty(&redacted_config(&state.config)?)?,
+        ),
+        (
+            "status.json",
+            serde_json::to_string_pretty(&redacted_status(state))?,
+        ),
+        ("agent.log", log),
+    ] {
+        zip.start_file(name, options)?;
+        zip.write_all(contents.as_bytes())?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+/// The config as JSON with identifying keys dropped and every path replaced by `<path>`.
+pub fn redacted_config(config: &AgentConfig) -> anyhow::Result<Value> {
+    let mut value = serde_json::to_value(config)?;
+    redact_value(&mut value);
+    Ok(value)
+}
+
+fn redact_value(value: &mut Value) {
+    match value {
+        Value::String(s) => *s = redact_paths(s),
+        Value::Array(items) => items.iter_mut().for_each(redact_value),
+        Value::Object(map) => {
+            map.retain(|key, v| {
+                let secret = key.contains("passphrase") && v.is_string();
+                !secret && !DROPPED_CONFIG_KEYS.contains(&key.as_str())
+            });
+            map.values_mut().for_each(redact_value);
+        }
+        _ => {}
+    }
+}
+
+/// Runtime status per drive. Mount paths and devnodes are left out; messages are redacted.
+fn redacted_status(state: &AgentRuntimeState) -> Value {
+    let mut drives: Vec<Value> = state
+        .config
+        .trusted_drives
+        .iter()
+        .map(|(id, drive)| {
+            json!({
+                "drive_id": id,
+                "label": drive.display_label(),
+                "last_backup_epoch": drive.last_backup_epoch,
+                "repository_id": drive.repository_id,
+                "total_data_added": drive.total_data_added,
+                "free_space_history": drive.free_space_history,
+                "running": state.running_drive_ids.contains(id),
+                "keyring_waiting": state.keyring_waiting_drive_ids.contains(id),
+                "repository_changed": state.repository_mismatch_drive_ids.contains(id),
+                "restic_too_old": state.restic_too_old_drive_ids.contains(id),
+                "last_error": state.last_errors.get(id),
+            })
+        })
+        .collect();
+    drives.sort_by_key(|d| d["drive_id"].as_str().unwrap_or_default().to_string());
+    let mut last_run = serde_json::to_value(&state.last_run).unwrap_or(Value::Null);
+    redact_value(&mut last_run);
+    json!({
+        "connected": {
+            "connected": state.drive_status.connected,
+            "trusted": state.drive_status.trusted,
+            "drive_id": state.drive_status.drive_id,
+        },
+        "last_run": last_run,
+        "drives": drives,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{BackupSource, TrustedDrive};
+    use std::io::Read;
+
+    fn unzip(bytes: &[u8]) -> Vec<(String, String)> {
+        let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+        (0..archive.len())
+            .map(|i| {
+                let mut file = archive.by_index(i).unwrap();
+                let mut contents = String::new();
+                file.read_to_string(&mut contents).unwrap();
+                (file.name().to_string(), contents)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bundle_omits_sensitive_fields() {
+        let mut config = AgentConfig {
+            backup_sources: vec![BackupSource {
+                label: "Documents".to_string(),
+                path: "/home/alice/Documents".to_string(),
+                follow_symlink: false,
+                exclude_patterns: Vec::new(),
+            }],
+            backup_hostname: Some("alices-laptop".to_string()),
+            restic_path: Some("/home/alice/bin/restic".to_string()),
+            ..AgentConfig::default()
+        };
+        let mut drive: TrustedDrive = serde_json::from_value(json!({
+            "drive_id": "abcdef123456",
+            "label": "Backup",
+            "repository_path": ".aegis/repo",
+            "repository_id": "repo-1",
+            "last_seen_epoch": null,
+            "serial": "WD-SERIAL-0042",
+        }))
+        .unwrap();
+        drive.populated_sources = vec!["/home/alice/Pictures".to_string()];
+        config.trusted_drives.insert(drive.drive_id.clone(), drive);
+        let mut state = AgentRuntimeState::new(config);
+        state.drive_status.mount_path = Some("/media/alice/aegis-1".to_string());
+        state.cache_passphrase("abcdef123456", "hunter2-secret", 600, 1_000);
+        state.record_error(
+            "abcdef123456",
+            "backup",
+            &anyhow::anyhow!("open /home/alice/Documents/x.txt: permission denied"),
+            1_000,
+        );
+
+        let bytes =
+            build_bundle(
+                &state,
+                &BundleInputs {
+                    agent_version: "0.1.0".to_string(),
+                    generated_epoch: 2_000,
+                    restic_version: Some("0.16.4".to_string()),
+                    preflight: json!({"restic": true}),
+                    log_lines: vec![
+                        "INFO backup: starting mount_path=/media/alice/aegis-1".to_string()
+                    ],
+                },
+            )
+            .unwrap();
+        let files = unzip(&bytes);
+        let names: Vec<&str> = files.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(
+            names,
+            ["summary.json", "config.json", "status.json", "agent.log"]
+        );
+        for (name, contents) in &files {
+            for secret in ["alice", "hunter2", "WD-SERIAL", "alices-laptop"] {
+                assert!(!contents.contains(secret), "{} leaks {}", name, secret);
+            }
+        }
+        let config: Value = serde_json::from_str(&files[1].1).unwrap();
+        assert_eq!(config["backup_sources"][0]["path"], "<path>");
+        assert_eq!(config["backup_sources"][0]["label"], "Documents");
+        assert!(config.get("remember_passphrase").is_some());
+        let status: Value = serde_json::from_str(&files[2].1).unwrap();
+        assert_eq!(status["drives"][0]["last_error"]["operation"], "backup");
+        assert!(files[3].1.contains("mount_path=<path>"));
+    }
+}
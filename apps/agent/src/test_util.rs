@@ -0,0 +1,12 @@
+//! Helpers shared by the unit tests.
+
+use std::path::PathBuf;
+
+/// A fresh, empty directory `aegis-<name>-<pid>` under the system temp dir; the test removes it
+/// when done.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("aegis-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -1,8 +1,15 @@
 use crate::backup::run_backup;
+use crate::devices;
 use crate::drive::read_marker;
 use crate::keychain;
 use crate::logging::Redact;
-use crate::state::{RunPhase, RunResult, RunStatus, SharedState};
+use crate::notifications::{self, NotificationSettings};
+use crate::restic::Restic;
+use crate::schedule;
+use crate::state::{
+    AgentRuntimeState, AutoBackupOutcome, RunPhase, RunResult, RunStatus, SharedState,
+};
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::sync::mpsc;
@@ -12,7 +19,9 @@ use tracing::{debug, error, info, warn};
 pub enum UsbWatcher {
     #[cfg(target_os = "linux")]
     Linux(LinuxWatcher),
-    #[cfg(not(target_os = "linux"))]
+    #[cfg(target_os = "macos")]
+    Darwin(DarwinWatcher),
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
     Stub(StubWatcher),
 }
 
@@ -21,7 +30,9 @@ impl UsbWatcher {
         match self {
             #[cfg(target_os = "linux")]
             UsbWatcher::Linux(watcher) => watcher.run(state).await,
-            #[cfg(not(target_os = "linux"))]
+            #[cfg(target_os = "macos")]
+            UsbWatcher::Darwin(watcher) => watcher.run(state).await,
+            #[cfg(not(any(target_os = "linux", target_os = "macos")))]
             UsbWatcher::Stub(watcher) => watcher.run(state).await,
         }
     }
@@ -32,13 +43,17 @@ pub fn build_watcher() -> anyhow::Result<UsbWatcher> {
     {
         Ok(UsbWatcher::Linux(LinuxWatcher::new()?))
     }
-    #[cfg(not(target_os = "linux"))]
+    #[cfg(target_os = "macos")]
+    {
+        Ok(UsbWatcher::Darwin(DarwinWatcher::new()?))
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
     {
         Ok(UsbWatcher::Stub(StubWatcher))
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 enum UsbEvent {
     Added(PathBuf),
     Removed(PathBuf),
@@ -61,29 +76,185 @@ impl LinuxWatcher {
             }
         });
 
-        // Scan existing mounts on startup.
-        scan_existing_mounts(&state).await;
-        // Scan block devices on startup to catch unmounted USB drives.
-        scan_existing_devices(&state).await;
+        // Scan existing mounts, then block devices (to catch unmounted USB drives), on startup.
+        // A mounted drive shows up in both, so handle each block device only once.
+        let mut startup_devnodes = existing_mount_devnodes(is_usb_devnode).await;
+        startup_devnodes.extend(existing_usb_devnodes());
+        for devnode in dedupe_devnodes(startup_devnodes) {
+            if let Err(err) = handle_added(&state, &devnode).await {
+                error!("Initial device scan failed: {}", Redact::new(err));
+            }
+        }
 
         while let Some(event) = rx.recv().await {
-            match event {
-                UsbEvent::Added(devnode) => {
-                    if let Err(err) = handle_added(&state, &devnode).await {
-                        error!("Handle add failed: {}", Redact::new(err));
-                    }
-                }
-                UsbEvent::Removed(devnode) => {
-                    if let Err(err) = handle_removed(&state, &devnode).await {
-                        error!("Handle remove failed: {}", Redact::new(err));
-                    }
-                }
-            }
+            handle_event(&state, event).await;
         }
         Ok(())
     }
 }
 
+/// Polls `diskutil` for external physical disks (there is no udev on macOS) and reports the ones
+/// that appeared or went away, like the udev monitor does on Linux.
+#[cfg(target_os = "macos")]
+pub struct DarwinWatcher;
+
+#[cfg(target_os = "macos")]
+impl DarwinWatcher {
+    pub fn new() -> anyhow::Result<Self> {
+        which::which("diskutil").map_err(|_| anyhow::anyhow!("diskutil not found"))?;
+        Ok(Self)
+    }
+
+    pub async fn run(self, state: SharedState) -> anyhow::Result<()> {
+        let mut known = darwin_external_devnodes_async().await.unwrap_or_default();
+
+        // Same startup scan as on Linux: mounted external volumes, then the rest.
+        let mut startup_devnodes =
+            existing_mount_devnodes(|dev| known.iter().any(|k| k == dev)).await;
+        startup_devnodes.extend(known.iter().cloned());
+        for devnode in dedupe_devnodes(startup_devnodes) {
+            if let Err(err) = handle_added(&state, &devnode).await {
+                error!("Initial device scan failed: {}", Redact::new(err));
+            }
+        }
+
+        loop {
+            sleep(DISKUTIL_POLL_INTERVAL).await;
+            // A failed listing says nothing about the drives; don't report them all removed.
+            let Some(current) = darwin_external_devnodes_async().await else {
+                continue;
+            };
+            for event in diff_devnodes(&known, &current) {
+                handle_event(&state, event).await;
+            }
+            known = current;
+        }
+    }
+}
+
+/// How often the macOS watcher asks `diskutil` for the attached external disks.
+#[cfg(target_os = "macos")]
+const DISKUTIL_POLL_INTERVAL: Duration = Duration::from_secs(3);
+
+#[cfg(target_os = "macos")]
+async fn darwin_external_devnodes_async() -> Option<Vec<PathBuf>> {
+    tokio::task::spawn_blocking(darwin_external_devnodes)
+        .await
+        .ok()
+        .flatten()
+}
+
+/// Device nodes of the volumes on external physical disks, from `diskutil list -plist`. None if
+/// diskutil failed.
+#[cfg(target_os = "macos")]
+fn darwin_external_devnodes() -> Option<Vec<PathBuf>> {
+    let output = std::process::Command::new("diskutil")
+        .args(["list", "-plist", "external", "physical"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        warn!(
+            "diskutil list failed status={:?} stderr={}",
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return None;
+    }
+    let ids = parse_diskutil_disks(&String::from_utf8_lossy(&output.stdout));
+    Some(volume_devnodes(&ids))
+}
+
+/// Identifiers in the `AllDisks` array of `diskutil list -plist` (e.g. `disk4`, `disk4s1`).
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+fn parse_diskutil_disks(plist: &str) -> Vec<String> {
+    let Some((_, rest)) = plist.split_once("<key>AllDisks</key>") else {
+        return Vec::new();
+    };
+    let Some(array) = rest
+        .trim_start()
+        .strip_prefix("<array>")
+        .and_then(|array| array.split_once("</array>"))
+        .map(|(array, _)| array)
+    else {
+        return Vec::new();
+    };
+    array
+        .split("<string>")
+        .skip(1)
+        .filter_map(|item| item.split_once("</string>"))
+        .map(|(id, _)| id.trim().to_string())
+        .filter(|id| !id.is_empty())
+        .collect()
+}
+
+/// Disk part of a diskutil identifier: `disk4s1` -> `disk4`, `disk4` -> `disk4`.
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+fn whole_disk_id(id: &str) -> &str {
+    let Some(rest) = id.strip_prefix("disk") else {
+        return id;
+    };
+    let digits = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    &id[.."disk".len() + digits]
+}
+
+/// `/dev` nodes worth checking for a marker: every partition, and a disk only when it has no
+/// partitions (a file system written straight to the disk).
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+fn volume_devnodes(ids: &[String]) -> Vec<PathBuf> {
+    ids.iter()
+        .filter(|id| {
+            whole_disk_id(id) != id.as_str()
+                || !ids
+                    .iter()
+                    .any(|other| other != *id && whole_disk_id(other) == id.as_str())
+        })
+        .map(|id| PathBuf::from("/dev").join(id))
+        .collect()
+}
+
+/// Events that turn `known` into `current`: removals first, then additions, each in list order.
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+fn diff_devnodes(known: &[PathBuf], current: &[PathBuf]) -> Vec<UsbEvent> {
+    let removed = known
+        .iter()
+        .filter(|devnode| !current.contains(devnode))
+        .map(|devnode| UsbEvent::Removed(devnode.clone()));
+    let added = current
+        .iter()
+        .filter(|devnode| !known.contains(devnode))
+        .map(|devnode| UsbEvent::Added(devnode.clone()));
+    removed.chain(added).collect()
+}
+
+#[cfg_attr(not(any(target_os = "linux", target_os = "macos")), allow(dead_code))]
+async fn handle_event(state: &SharedState, event: UsbEvent) {
+    note_device_event(state, &event).await;
+    match event {
+        UsbEvent::Added(devnode) => {
+            if let Err(err) = handle_added(state, &devnode).await {
+                error!("Handle add failed: {}", Redact::new(err));
+            }
+        }
+        UsbEvent::Removed(devnode) => {
+            if let Err(err) = handle_removed(state, &devnode).await {
+                error!("Handle remove failed: {}", Redact::new(err));
+            }
+        }
+    }
+}
+
+/// Bump the device list revision for an add/remove, before it is handled (mounting can be slow).
+#[cfg_attr(not(any(target_os = "linux", target_os = "macos")), allow(dead_code))]
+async fn note_device_event(state: &SharedState, event: &UsbEvent) {
+    let revision = state.write().await.note_devices_changed();
+    debug!(
+        "usb: device list changed revision={} event={:?}",
+        revision, event
+    );
+}
+
 #[cfg(target_os = "linux")]
 fn monitor_usb(sender: mpsc::Sender<UsbEvent>) -> anyhow::Result<()> {
     use udev::{EventType, MonitorBuilder};
@@ -143,94 +314,125 @@ fn monitor_usb(sender: mpsc::Sender<UsbEvent>) -> anyhow::Result<()> {
     Ok(())
 }
 
-#[cfg(not(target_os = "linux"))]
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
 pub struct StubWatcher;
 
-#[cfg(not(target_os = "linux"))]
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
 impl StubWatcher {
     pub async fn run(self, _state: SharedState) -> anyhow::Result<()> {
         Ok(())
     }
 }
 
-async fn scan_existing_mounts(state: &SharedState) {
+/// Mounted devices the watcher handles: `watched` says which (USB on Linux, the external disks
+/// the macOS watcher already listed).
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+async fn existing_mount_devnodes(watched: impl Fn(&Path) -> bool) -> Vec<PathBuf> {
     debug!("Scanning existing mounts for USB drives");
-    let mounts = mount_table();
-    for (dev, _mount) in mounts {
-        #[cfg(target_os = "linux")]
-        if !is_usb_devnode(&dev) {
+    let mut devnodes = Vec::new();
+    for (dev, _mount) in mount_table_async().await {
+        if !watched(&dev) {
             continue;
         }
         debug!("Existing USB mount detected for {}", dev.display());
-        if let Err(err) = handle_added(state, &dev).await {
-            error!("Initial mount scan failed: {}", Redact::new(err));
-        }
+        devnodes.push(dev);
     }
+    devnodes
 }
 
 #[cfg(target_os = "linux")]
-async fn scan_existing_devices(state: &SharedState) {
+fn existing_usb_devnodes() -> Vec<PathBuf> {
     debug!("Scanning existing USB block devices");
-    let devnodes: Vec<(PathBuf, String)> = {
-        let mut collected: Vec<(PathBuf, String)> = Vec::new();
-        let mut enumerator = match udev::Enumerator::new() {
-            Ok(enumerator) => enumerator,
-            Err(err) => {
-                error!("Failed to init udev enumerator: {}", Redact::new(err));
-                return;
-            }
-        };
-        if let Err(err) = enumerator.match_subsystem("block") {
-            error!("Failed to match block subsystem: {}", Redact::new(err));
-            return;
+    let mut collected = Vec::new();
+    let mut enumerator = match udev::Enumerator::new() {
+        Ok(enumerator) => enumerator,
+        Err(err) => {
+            error!("Failed to init udev enumerator: {}", Redact::new(err));
+            return collected;
         }
-        let devices = match enumerator.scan_devices() {
-            Ok(devices) => devices,
-            Err(err) => {
-                error!("Failed to scan udev devices: {}", Redact::new(err));
-                return;
-            }
-        };
-        for device in devices {
-            let devtype = device
-                .property_value("DEVTYPE")
-                .and_then(|v| v.to_str())
-                .unwrap_or("");
-            if devtype != "partition" && devtype != "disk" {
-                continue;
-            }
-            if !is_usb_device(&device) {
-                continue;
-            }
-            let removable = device
-                .attribute_value("removable")
-                .and_then(|v| v.to_str())
-                .unwrap_or("");
-            if let Some(devnode) = device.devnode() {
-                collected.push((PathBuf::from(devnode), removable.to_string()));
-            }
+    };
+    if let Err(err) = enumerator.match_subsystem("block") {
+        error!("Failed to match block subsystem: {}", Redact::new(err));
+        return collected;
+    }
+    let devices = match enumerator.scan_devices() {
+        Ok(devices) => devices,
+        Err(err) => {
+            error!("Failed to scan udev devices: {}", Redact::new(err));
+            return collected;
         }
-        collected
     };
-
-    for (devnode, removable) in devnodes {
-        debug!(
-            "Existing USB block device detected: {} removable={}",
-            devnode.display(),
-            removable
-        );
-        if let Err(err) = handle_added(state, &devnode).await {
-            error!("Existing device handling failed: {}", Redact::new(err));
+    for device in devices {
+        let devtype = device
+            .property_value("DEVTYPE")
+            .and_then(|v| v.to_str())
+            .unwrap_or("");
+        if devtype != "partition" && devtype != "disk" {
+            continue;
+        }
+        if !is_usb_device(&device) {
+            continue;
+        }
+        let removable = device
+            .attribute_value("removable")
+            .and_then(|v| v.to_str())
+            .unwrap_or("");
+        if let Some(devnode) = device.devnode() {
+            debug!(
+                "Existing USB block device detected: {} removable={}",
+                devnode.display(),
+                removable
+            );
+            collected.push(PathBuf::from(devnode));
         }
     }
+    collected
 }
 
-#[cfg(not(target_os = "linux"))]
-async fn scan_existing_devices(_state: &SharedState) {}
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn existing_usb_devnodes() -> Vec<PathBuf> {
+    Vec::new()
+}
+
+/// Drop repeated devnodes, keyed by the resolved block device (so /dev/disk/by-*/ links and the
+/// real node count once). Keeps first-seen order.
+fn dedupe_devnodes(devnodes: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut seen = HashSet::new();
+    devnodes
+        .into_iter()
+        .filter(|devnode| {
+            let key = std::fs::canonicalize(devnode).unwrap_or_else(|_| devnode.clone());
+            seen.insert(key)
+        })
+        .collect()
+}
 
 async fn handle_added(state: &SharedState, devnode: &Path) -> anyhow::Result<()> {
     debug!("Handling USB add for {}", devnode.display());
-    let mount_path = wait_for_mount(devnode).await;
+    let devnode_str = devnode.to_string_lossy().to_string();
+    let (encrypted, luks_uuid) = tokio::task::spawn_blocking(move || {
+        let encrypted = devices::is_luks(&devnode_str);
+        (
+            encrypted,
+            encrypted
+                .then(|| devices::luks_uuid(&devnode_str))
+                .flatten(),
+        )
+    })
+    .await
+    .unwrap_or_default();
+    // An encrypted drive is mounted from its unlocked device, so the marker can be read.
+    let mount_path = if encrypted {
+        match unlock_encrypted(state, devnode).await {
+            Some(cleartext) => wait_for_mount(&cleartext).await,
+            None => {
+                info!("Encrypted USB drive is locked: {}", devnode.display());
+                None
+            }
+        }
+    } else {
+        wait_for_mount(devnode).await
+    };
     let Some(mount_path) = mount_path else {
         info!("USB device present but not mounted: {}", devnode.display());
         let mut guard = state.write().await;
@@ -246,9 +448,43 @@ async fn handle_added(state: &SharedState, devnode: &Path) -> anyhow::Result<()>
 
     let marker = read_marker(&mount_path)?;
     if let Some(marker) = marker {
-        let trusted = {
+        // Only ask udev for the serial when an allowlist needs it.
+        let allowlist_set = !state.read().await.config.allowed_devices.is_empty();
+        let serial = if allowlist_set {
+            let devnode_str = devnode.to_string_lossy().to_string();
+            tokio::task::spawn_blocking(move || devices::device_serial(&devnode_str))
+                .await
+                .unwrap_or_default()
+        } else {
+            None
+        };
+        let (trusted, repository_changed) = {
             let guard = state.read().await;
-            guard.config.trusted_drives.contains_key(&marker.drive_id)
+            match guard.config.trusted_drives.get(&marker.drive_id) {
+                // Unauthenticated early check from the marker; backup re-checks against restic.
+                Some(drive) => (
+                    true,
+                    marker.repository_id.is_some()
+                        && drive.repository_changed(marker.repository_id.as_deref()),
+                ),
+                None => (false, false),
+            }
+        };
+        let trusted = trusted && {
+            let mut guard = state.write().await;
+            let allowed = guard
+                .config
+                .device_allowed(&marker.drive_id, serial.as_deref());
+            if !allowed {
+                info!("Trusted drive is not on the device allowlist; treating it as untrusted");
+                guard.set_auto_backup(
+                    &marker.drive_id,
+                    AutoBackupOutcome::Skipped,
+                    Some("not_allowed"),
+                    now_epoch(),
+                );
+            }
+            allowed
         };
         debug!(
             "USB handle_added: devnode={} drive_id={} trusted={}",
@@ -257,7 +493,7 @@ async fn handle_added(state: &SharedState, devnode: &Path) -> anyhow::Result<()>
             trusted
         );
 
-        {
+        let notify_settings = {
             let mut guard = state.write().await;
             guard.drive_status.connected = true;
             guard.drive_status.trusted = trusted;
@@ -266,14 +502,50 @@ async fn handle_added(state: &SharedState, devnode: &Path) -> anyhow::Result<()>
             guard.drive_status.mount_path = Some(mount_path.to_string_lossy().to_string());
             guard.drive_status.devnode = Some(devnode.to_string_lossy().to_string());
             if trusted {
+                if let (Some(drive), Some(uuid)) = (
+                    guard.config.trusted_drives.get_mut(&marker.drive_id),
+                    luks_uuid,
+                ) {
+                    // Remembered so the unlock passphrase can be purged with the drive's.
+                    drive.luks_uuid = Some(uuid);
+                }
                 guard.config.update_last_seen(&marker.drive_id);
                 let _ = guard.config.save();
+                guard.set_repository_mismatch(&marker.drive_id, repository_changed);
             }
-        }
+            NotificationSettings::from_config(&guard.config)
+        };
 
         if trusted {
-            crate::notifications::notify_trusted_device(marker.label.as_deref().unwrap_or("drive"));
-            attempt_auto_backup(state, &marker.drive_id, &mount_path).await;
+            notifications::notify_trusted_device(
+                &notify_settings,
+                marker.label.as_deref().unwrap_or("drive"),
+            );
+            if repository_changed {
+                warn!("Trusted drive has a different repository than expected; reconfiguration needed");
+            } else {
+                let offset_secs = schedule::local_offset_secs().await;
+                let missed_schedule = {
+                    let guard = state.read().await;
+                    let last_backup = guard
+                        .config
+                        .trusted_drives
+                        .get(&marker.drive_id)
+                        .and_then(|d| d.last_backup_epoch);
+                    guard
+                        .config
+                        .schedule
+                        .missed_since(last_backup, now_epoch(), offset_secs)
+                };
+                let trigger = if missed_schedule {
+                    info!("A scheduled backup was missed while the drive was away; catching up");
+                    AutoBackupTrigger::Schedule
+                } else {
+                    AutoBackupTrigger::Insert
+                };
+                attempt_auto_backup(state, &marker.drive_id, &mount_path, trigger).await;
+                refresh_snapshot_stats(state, &marker.drive_id, &mount_path).await;
+            }
         }
     } else {
         debug!(
@@ -294,22 +566,27 @@ async fn handle_added(state: &SharedState, devnode: &Path) -> anyhow::Result<()>
 }
 
 async fn handle_removed(state: &SharedState, devnode: &Path) -> anyhow::Result<()> {
-    let was_drive_id = {
+    let (was_drive_id, mount_path, same_partition) = {
         let guard = state.read().await;
-        let id = guard.drive_status.drive_id.clone();
-        if let Some(mount_path) = &guard.drive_status.mount_path {
-            if let Some(device) = resolve_device_for_mount(Path::new(mount_path)) {
-                if device != devnode {
-                    debug!(
-                        "USB handle_removed: devnode={} not current drive, ignoring",
-                        devnode.display()
-                    );
-                    return Ok(());
-                }
+        // An encrypted drive is mounted from its unlocked device; match it by the partition.
+        let same_partition = guard.drive_status.devnode.as_deref().map(Path::new) == Some(devnode);
+        (
+            guard.drive_status.drive_id.clone(),
+            guard.drive_status.mount_path.clone(),
+            same_partition,
+        )
+    };
+    if let Some(mount_path) = mount_path {
+        if let Some(device) = resolve_device_for_mount(Path::new(&mount_path)).await {
+            if device != devnode && !same_partition {
+                debug!(
+                    "USB handle_removed: devnode={} not current drive, ignoring",
+                    devnode.display()
+                );
+                return Ok(());
             }
         }
-        id
-    };
+    }
     debug!(
         "USB handle_removed: devnode={} drive_id={:?} clearing drive_status",
         devnode.display(),
@@ -325,15 +602,12 @@ async fn handle_removed(state: &SharedState, devnode: &Path) -> anyhow::Result<(
     guard.drive_status.devnode = None;
 
     if let Some(ref id) = was_drive_id {
+        guard.keyring_waiting_drive_ids.remove(id);
         if let Some(cancel) = guard.running_cancel_tokens.remove(id) {
             cancel.cancel();
         }
-        if guard.restore_drive_id.as_deref() == Some(id.as_str()) {
-            guard.restore_drive_id = None;
-            if let Some(cancel) = guard.restore_cancel_token.take() {
-                cancel.cancel();
-            }
-        }
+        guard.cancel_restore(id);
+        guard.cancel_export(id);
         if guard.running_drive_ids.remove(id) {
             guard.backup_progress.remove(id);
             guard.last_run = Some(RunResult {
@@ -347,55 +621,155 @@ async fn handle_removed(state: &SharedState, devnode: &Path) -> anyhow::Result<(
                 repository_id: None,
                 data_added: None,
                 files_processed: None,
+                deep_verify_estimate_secs: None,
+                retention_removed_snapshots: None,
+                retention_freed_bytes: None,
+                slow_run: false,
+                files_new: None,
+                files_changed: None,
+                dry_run: false,
+                verify_failed: false,
             });
         }
     }
     Ok(())
 }
 
-async fn attempt_auto_backup(state: &SharedState, drive_id: &str, mount_path: &Path) {
-    let config = { state.read().await.config.clone() };
-    {
-        let guard = state.read().await;
-        if guard.running_drive_ids.contains(drive_id) {
+/// How often (and how many times) to re-check a locked keyring before giving up on auto-backup.
+const KEYRING_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+const KEYRING_RETRY_ATTEMPTS: u32 = 120;
+
+/// What started an automatic backup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AutoBackupTrigger {
+    /// The drive was plugged in (subject to `auto_backup_on_insert`).
+    Insert,
+    /// A scheduled time, or catching up on one missed while the drive was away.
+    Schedule,
+}
+
+pub async fn attempt_auto_backup(
+    state: &SharedState,
+    drive_id: &str,
+    mount_path: &Path,
+    trigger: AutoBackupTrigger,
+) {
+    let remember_passphrase = {
+        let mut guard = state.write().await;
+        if let Some(reason) = auto_backup_blocker(&guard, drive_id, trigger) {
+            debug!("Auto-backup not started: {}", reason);
+            guard.set_auto_backup(
+                drive_id,
+                AutoBackupOutcome::Skipped,
+                Some(reason),
+                now_epoch(),
+            );
+            return;
+        }
+        if guard.keyring_waiting_drive_ids.contains(drive_id) {
             return;
         }
+        guard.config.remember_passphrase
+    };
+
+    let lookup = if remember_passphrase {
+        keychain::get_passphrase(drive_id)
+    } else {
+        Ok(None)
+    };
+    match stored_passphrase(lookup) {
+        Ok(passphrase) => spawn_auto_backup(state, drive_id, mount_path, passphrase).await,
+        Err((AutoBackupOutcome::Delayed, reason)) => {
+            info!("Keyring locked; auto-backup deferred until it is unlocked");
+            state.write().await.set_auto_backup(
+                drive_id,
+                AutoBackupOutcome::Delayed,
+                Some(reason),
+                now_epoch(),
+            );
+            defer_until_keyring_unlocked(state, drive_id, mount_path).await;
+        }
+        Err((outcome, reason)) => {
+            debug!("No stored passphrase; waiting for manual backup");
+            state
+                .write()
+                .await
+                .set_auto_backup(drive_id, outcome, Some(reason), now_epoch());
+        }
     }
-    if !config.auto_backup_on_insert {
-        return;
+}
+
+/// Why the auto-backup for `drive_id` can't start regardless of the passphrase; None if it may.
+fn auto_backup_blocker(
+    state: &AgentRuntimeState,
+    drive_id: &str,
+    trigger: AutoBackupTrigger,
+) -> Option<&'static str> {
+    if let Some(reason) = state.backup_blocker(drive_id) {
+        return Some(reason);
     }
-    if config.paranoid_mode {
+    if trigger == AutoBackupTrigger::Insert && !state.config.auto_backup_on_insert {
+        return Some("disabled");
+    }
+    if state.config.paranoid_mode {
         info!("Paranoid mode enabled; waiting for manual passphrase entry");
-        return;
+        return Some("paranoid_mode");
     }
+    None
+}
 
-    let passphrase = if config.remember_passphrase {
-        match keychain::get_passphrase(drive_id) {
-            Ok(Some(value)) => Some(value),
-            Ok(None) => None,
-            Err(err) => {
-                warn!("Keychain read failed: {}", Redact::new(err));
-                None
-            }
+/// The passphrase to back up with, or what happens instead (delayed while the keyring is locked,
+/// otherwise skipped) and why.
+fn stored_passphrase(
+    lookup: anyhow::Result<Option<String>>,
+) -> Result<String, (AutoBackupOutcome, &'static str)> {
+    match lookup {
+        Ok(Some(passphrase)) => Ok(passphrase),
+        Ok(None) => Err((AutoBackupOutcome::Skipped, "no_stored_passphrase")),
+        Err(err) if keychain::is_locked(&err) => {
+            Err((AutoBackupOutcome::Delayed, "keyring_locked"))
         }
-    } else {
-        None
-    };
-
-    let Some(passphrase) = passphrase else {
-        debug!("No stored passphrase; waiting for manual backup");
-        return;
-    };
+        Err(err) => {
+            warn!("Keychain read failed: {}", Redact::new(err));
+            Err((AutoBackupOutcome::Skipped, "keychain_error"))
+        }
+    }
+}
 
+async fn spawn_auto_backup(
+    state: &SharedState,
+    drive_id: &str,
+    mount_path: &Path,
+    passphrase: String,
+) {
     {
         let mut guard = state.write().await;
-        guard.running_drive_ids.insert(drive_id.to_string());
+        // Something may have started since the blocker check (the keychain lookup, the keyring
+        // wait); claim the drive and check in one step.
+        if let Err(reason) = guard.begin_backup(drive_id) {
+            debug!("Auto-backup not started: {}", reason);
+            guard.set_auto_backup(
+                drive_id,
+                AutoBackupOutcome::Skipped,
+                Some(reason),
+                now_epoch(),
+            );
+            return;
+        }
+        guard.set_auto_backup(drive_id, AutoBackupOutcome::Started, None, now_epoch());
     }
     let state_clone = state.clone();
     let drive_id = drive_id.to_string();
     let mount = mount_path.to_path_buf();
     tokio::spawn(async move {
-        let result = run_backup(state_clone.clone(), drive_id.clone(), mount, passphrase).await;
+        let result = run_backup(
+            state_clone.clone(),
+            drive_id.clone(),
+            mount,
+            passphrase,
+            false,
+        )
+        .await;
         {
             let mut guard = state_clone.write().await;
             guard.running_drive_ids.remove(&drive_id);
@@ -408,9 +782,159 @@ async fn attempt_auto_backup(state: &SharedState, drive_id: &str, mount_path: &P
     });
 }
 
+/// List the drive's snapshots in the background so status shows their count and time range.
+/// Skipped while a backup runs (it lists them when it finishes) or when the passphrase would have
+/// to be asked for.
+async fn refresh_snapshot_stats(state: &SharedState, drive_id: &str, mount_path: &Path) {
+    let (config, cached) = {
+        let guard = state.read().await;
+        if guard.running_drive_ids.contains(drive_id) {
+            return;
+        }
+        let cached = guard
+            .cached_passphrase(drive_id, now_epoch())
+            .map(str::to_string);
+        (guard.config.clone(), cached)
+    };
+    let Some(repo_path) = config.repository_path_for(drive_id, mount_path) else {
+        return;
+    };
+    let use_keychain = config.remember_passphrase && !config.paranoid_mode;
+    if cached.is_none() && !use_keychain {
+        return;
+    }
+    let Ok(restic) = Restic::resolve(config.restic_path.as_deref()) else {
+        return;
+    };
+    let state = state.clone();
+    let drive_id = drive_id.to_string();
+    tokio::spawn(async move {
+        // The keyring can block (D-Bus, an unlock prompt), so it's read off the runtime and in
+        // this task, never on the caller's path.
+        let passphrase = match cached {
+            Some(passphrase) => passphrase,
+            None => {
+                let id = drive_id.clone();
+                match tokio::task::spawn_blocking(move || keychain::get_passphrase(&id)).await {
+                    Ok(Ok(Some(passphrase))) => passphrase,
+                    _ => return,
+                }
+            }
+        };
+        match restic
+            .snapshots(&repo_path, &passphrase, config.no_lock_reads)
+            .await
+        {
+            Ok(snapshots) => state.write().await.record_snapshots(&drive_id, &snapshots),
+            Err(err) => debug!("Listing snapshots on connect failed: {}", Redact::new(err)),
+        }
+    });
+}
+
+/// Poll the keyring in the background while the drive stays connected; start the auto-backup once
+/// the passphrase can be read.
+async fn defer_until_keyring_unlocked(state: &SharedState, drive_id: &str, mount_path: &Path) {
+    {
+        let mut guard = state.write().await;
+        guard.keyring_waiting_drive_ids.insert(drive_id.to_string());
+    }
+    let state = state.clone();
+    let drive_id = drive_id.to_string();
+    let mount_path = mount_path.to_path_buf();
+    tokio::spawn(async move {
+        let mut passphrase = None;
+        // Still locked when retries run out, unless the drive left or a backup started meanwhile.
+        let mut skipped = Some("keyring_locked");
+        for _ in 0..KEYRING_RETRY_ATTEMPTS {
+            sleep(KEYRING_RETRY_INTERVAL).await;
+            let still_waiting = {
+                let guard = state.read().await;
+                guard.drive_status.connected
+                    && guard.drive_status.drive_id.as_deref() == Some(drive_id.as_str())
+                    && !guard.running_drive_ids.contains(&drive_id)
+                    && guard.keyring_waiting_drive_ids.contains(&drive_id)
+            };
+            if !still_waiting {
+                debug!("Keyring retry: drive gone, backup running or wait cancelled; stopping");
+                skipped = None;
+                break;
+            }
+            match stored_passphrase(keychain::get_passphrase(&drive_id)) {
+                Ok(value) => {
+                    passphrase = Some(value);
+                    break;
+                }
+                Err((AutoBackupOutcome::Delayed, _)) => continue,
+                Err((_, reason)) => {
+                    skipped = Some(reason);
+                    break;
+                }
+            }
+        }
+        {
+            let mut guard = state.write().await;
+            // Gone from the set: cancelled (or unplugged) after the last check.
+            if !guard.keyring_waiting_drive_ids.remove(&drive_id) {
+                passphrase = None;
+                skipped = None;
+            }
+            if let Some(reason) = skipped.filter(|_| passphrase.is_none()) {
+                guard.set_auto_backup(
+                    &drive_id,
+                    AutoBackupOutcome::Skipped,
+                    Some(reason),
+                    now_epoch(),
+                );
+            }
+        }
+        if let Some(passphrase) = passphrase {
+            info!("Keyring unlocked; starting deferred auto-backup");
+            spawn_auto_backup(&state, &drive_id, &mount_path, passphrase).await;
+        }
+    });
+}
+
+/// The unlocked device of the LUKS container on `devnode`: the one the desktop already opened,
+/// else one opened (and mounted) with the passphrase saved when Aegis formatted the drive. None
+/// while it stays locked.
+async fn unlock_encrypted(state: &SharedState, devnode: &Path) -> Option<PathBuf> {
+    let remember = {
+        let guard = state.read().await;
+        guard.config.remember_passphrase && !guard.config.paranoid_mode
+    };
+    let devnode = devnode.to_string_lossy().to_string();
+    let cleartext = tokio::task::spawn_blocking(move || {
+        if let Some(cleartext) = devices::cleartext_device(&devnode) {
+            return Some(cleartext);
+        }
+        if !remember {
+            return None;
+        }
+        let uuid = devices::luks_uuid(&devnode)?;
+        let passphrase = match keychain::get_luks_passphrase(&uuid) {
+            Ok(passphrase) => passphrase?,
+            Err(err) => {
+                warn!("Keychain read failed: {}", Redact::new(err));
+                return None;
+            }
+        };
+        let cleartext = devices::unlock_luks(&devnode, &passphrase)
+            .map_err(|err| warn!("Unlocking encrypted drive failed: {}", Redact::new(err)))
+            .ok()?;
+        if let Err(err) = devices::mount_partition(&cleartext) {
+            warn!("Mounting unlocked drive failed: {}", Redact::new(err));
+        }
+        Some(cleartext)
+    })
+    .await
+    .ok()
+    .flatten()?;
+    Some(PathBuf::from(cleartext))
+}
+
 async fn wait_for_mount(devnode: &Path) -> Option<PathBuf> {
     for _ in 0..25 {
-        if let Some(mount) = find_mount_for_device(devnode) {
+        if let Some(mount) = find_mount_for_device(devnode).await {
             return Some(mount);
         }
         sleep(Duration::from_millis(400)).await;
@@ -422,19 +946,67 @@ async fn wait_for_mount(devnode: &Path) -> Option<PathBuf> {
     None
 }
 
-pub fn find_mount_for_device(devnode: &Path) -> Option<PathBuf> {
-    let devnode_canon = std::fs::canonicalize(devnode).ok()?;
-    let mounts = mount_table();
-    for (device, mount) in mounts {
-        if std::fs::canonicalize(&device).ok().as_ref() == Some(&devnode_canon) {
-            return Some(mount);
+pub async fn find_mount_for_device(devnode: &Path) -> Option<PathBuf> {
+    let devnode = devnode.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        find_mount_in(
+            mount_table(),
+            &devnode,
+            |path| std::fs::canonicalize(path).ok(),
+            block_device_number,
+        )
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+/// Mount point of the first entry in `mounts` that is the same device as `devnode`; see
+/// [`same_device`].
+fn find_mount_in(
+    mounts: Vec<(PathBuf, PathBuf)>,
+    devnode: &Path,
+    canonicalize: impl Fn(&Path) -> Option<PathBuf>,
+    device_number: impl Fn(&Path) -> Option<u64>,
+) -> Option<PathBuf> {
+    mounts
+        .into_iter()
+        .find(|(device, _)| same_device(devnode, device, &canonicalize, &device_number))
+        .map(|(_, mount)| mount)
+}
+
+/// True if both paths name the same block device: the same path, the same path once symlinks
+/// are resolved, or the same device number. Resolving can fail (permissions, a device-mapper node
+/// that is going away), so each check is only a fallback for the others.
+fn same_device(
+    a: &Path,
+    b: &Path,
+    canonicalize: impl Fn(&Path) -> Option<PathBuf>,
+    device_number: impl Fn(&Path) -> Option<u64>,
+) -> bool {
+    if a == b {
+        return true;
+    }
+    if let (Some(a), Some(b)) = (canonicalize(a), canonicalize(b)) {
+        if a == b {
+            return true;
         }
     }
-    None
+    matches!((device_number(a), device_number(b)), (Some(a), Some(b)) if a == b)
+}
+
+/// Device number of a block device node; None for anything else (regular files have rdev 0).
+fn block_device_number(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    let metadata = std::fs::metadata(path).ok()?;
+    metadata
+        .file_type()
+        .is_block_device()
+        .then(|| metadata.rdev())
 }
 
-pub fn resolve_device_for_mount(mount: &Path) -> Option<PathBuf> {
-    let mounts = mount_table();
+pub async fn resolve_device_for_mount(mount: &Path) -> Option<PathBuf> {
+    let mounts = mount_table_async().await;
     for (device, mount_path) in mounts {
         if mount_path == mount {
             return Some(device);
@@ -443,6 +1015,14 @@ pub fn resolve_device_for_mount(mount: &Path) -> Option<PathBuf> {
     None
 }
 
+/// [`mount_table`] off the runtime: on macOS it runs `mount`.
+async fn mount_table_async() -> Vec<(PathBuf, PathBuf)> {
+    tokio::task::spawn_blocking(mount_table)
+        .await
+        .unwrap_or_default()
+}
+
+#[cfg(not(target_os = "macos"))]
 fn mount_table() -> Vec<(PathBuf, PathBuf)> {
     let content = std::fs::read_to_string("/proc/mounts").unwrap_or_default();
     content
@@ -460,7 +1040,32 @@ fn mount_table() -> Vec<(PathBuf, PathBuf)> {
         .collect()
 }
 
-fn unescape_mount(input: &str) -> String {
+/// macOS has no /proc/mounts; `mount` lists the same (device, mount point) pairs.
+#[cfg(target_os = "macos")]
+fn mount_table() -> Vec<(PathBuf, PathBuf)> {
+    let output = match std::process::Command::new("/sbin/mount").output() {
+        Ok(output) if output.status.success() => output,
+        _ => return Vec::new(),
+    };
+    parse_mount_output(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Lines of BSD `mount` output, e.g. `/dev/disk4s1 on /Volumes/My Drive (exfat, local, nodev)`.
+/// Volume names can contain spaces and parentheses, so the options are split off at the last ` (`.
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+fn parse_mount_output(output: &str) -> Vec<(PathBuf, PathBuf)> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (device, rest) = line.split_once(" on ")?;
+            let mount = rest.rsplit_once(" (").map_or(rest, |(mount, _)| mount);
+            Some((PathBuf::from(device), PathBuf::from(mount)))
+        })
+        .collect()
+}
+
+/// Undo the octal escaping of paths in the mount table (`\040` for a space, etc.).
+pub fn unescape_mount(input: &str) -> String {
     input
         .replace("\\040", " ")
         .replace("\\011", "\t")
@@ -571,3 +1176,296 @@ fn now_epoch() -> u64 {
         .unwrap_or_default()
         .as_secs()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AgentConfig;
+    use crate::state::AutoBackupState;
+
+    #[test]
+    fn dedupe_devnodes_same_device_from_both_scans_once() {
+        // Mount table and udev enumerate both report the same partition.
+        let from_mounts = vec![PathBuf::from("/nonexistent-aegis/dev/sdb1")];
+        let from_udev = vec![
+            PathBuf::from("/nonexistent-aegis/dev/sdb"),
+            PathBuf::from("/nonexistent-aegis/dev/sdb1"),
+        ];
+        let mut all = from_mounts;
+        all.extend(from_udev);
+        assert_eq!(
+            dedupe_devnodes(all),
+            vec![
+                PathBuf::from("/nonexistent-aegis/dev/sdb1"),
+                PathBuf::from("/nonexistent-aegis/dev/sdb"),
+            ]
+        );
+    }
+
+    #[test]
+    fn dedupe_devnodes_resolves_symlinks() {
+        let dir = std::env::temp_dir().join(format!("aegis-usb-dedupe-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let real = dir.join("sdc1");
+        std::fs::write(&real, b"").unwrap();
+        let link = dir.join("by-uuid-link");
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+        let out = dedupe_devnodes(vec![link.clone(), real.clone()]);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(out, vec![link]);
+    }
+
+    fn shared(config: AgentConfig) -> SharedState {
+        std::sync::Arc::new(tokio::sync::RwLock::new(AgentRuntimeState::new(config)))
+    }
+
+    #[tokio::test]
+    async fn device_add_and_remove_change_the_device_revision() {
+        let state = shared(AgentConfig::default());
+        let devnode = PathBuf::from("/nonexistent-aegis/dev/sdb1");
+        note_device_event(&state, &UsbEvent::Added(devnode.clone())).await;
+        assert_eq!(state.read().await.devices_revision, 1);
+        note_device_event(&state, &UsbEvent::Removed(devnode)).await;
+        assert_eq!(state.read().await.devices_revision, 2);
+    }
+
+    async fn auto_backup_state(
+        config: AgentConfig,
+        running: bool,
+        trigger: AutoBackupTrigger,
+    ) -> Option<AutoBackupState> {
+        let state = shared(config);
+        if running {
+            state
+                .write()
+                .await
+                .running_drive_ids
+                .insert("d1".to_string());
+        }
+        attempt_auto_backup(&state, "d1", Path::new("/nonexistent-aegis/mnt"), trigger).await;
+        let guard = state.read().await;
+        guard.auto_backup.get("d1").cloned()
+    }
+
+    #[tokio::test]
+    async fn auto_backup_skips_record_their_reason() {
+        let enabled = AgentConfig {
+            auto_backup_on_insert: true,
+            remember_passphrase: false,
+            ..AgentConfig::default()
+        };
+        for (config, running, reason) in [
+            (
+                AgentConfig {
+                    auto_backup_on_insert: false,
+                    ..enabled.clone()
+                },
+                false,
+                "disabled",
+            ),
+            (
+                AgentConfig {
+                    paranoid_mode: true,
+                    ..enabled.clone()
+                },
+                false,
+                "paranoid_mode",
+            ),
+            (enabled.clone(), true, "already_running"),
+            (enabled.clone(), false, "no_stored_passphrase"),
+        ] {
+            let recorded = auto_backup_state(config, running, AutoBackupTrigger::Insert)
+                .await
+                .unwrap();
+            assert_eq!(recorded.outcome, AutoBackupOutcome::Skipped);
+            assert_eq!(recorded.reason.as_deref(), Some(reason));
+        }
+    }
+
+    #[tokio::test]
+    async fn auto_backup_waits_out_a_repair() {
+        let state = shared(AgentConfig {
+            auto_backup_on_insert: true,
+            ..AgentConfig::default()
+        });
+        state.write().await.begin_repair("d1").unwrap();
+        attempt_auto_backup(
+            &state,
+            "d1",
+            Path::new("/nonexistent-aegis/mnt"),
+            AutoBackupTrigger::Schedule,
+        )
+        .await;
+        let guard = state.read().await;
+        assert_eq!(
+            guard.auto_backup["d1"].reason.as_deref(),
+            Some("repair_running")
+        );
+        assert!(!guard.running_drive_ids.contains("d1"));
+    }
+
+    #[tokio::test]
+    async fn scheduled_backup_ignores_the_insert_setting() {
+        let config = AgentConfig {
+            auto_backup_on_insert: false,
+            remember_passphrase: false,
+            ..AgentConfig::default()
+        };
+        let recorded = auto_backup_state(config.clone(), false, AutoBackupTrigger::Schedule)
+            .await
+            .unwrap();
+        // Past the "disabled" check; stops only for want of a stored passphrase.
+        assert_eq!(recorded.reason.as_deref(), Some("no_stored_passphrase"));
+        let recorded = auto_backup_state(config, true, AutoBackupTrigger::Schedule)
+            .await
+            .unwrap();
+        assert_eq!(recorded.reason.as_deref(), Some("already_running"));
+    }
+
+    #[test]
+    fn stored_passphrase_decides_start_delay_or_skip() {
+        assert_eq!(
+            stored_passphrase(Ok(Some("pw".to_string()))),
+            Ok("pw".to_string())
+        );
+        assert_eq!(
+            stored_passphrase(Ok(None)),
+            Err((AutoBackupOutcome::Skipped, "no_stored_passphrase"))
+        );
+        assert_eq!(
+            stored_passphrase(Err(anyhow::Error::new(keychain::KeyringLocked))),
+            Err((AutoBackupOutcome::Delayed, "keyring_locked"))
+        );
+        assert_eq!(
+            stored_passphrase(Err(anyhow::anyhow!("dbus went away"))),
+            Err((AutoBackupOutcome::Skipped, "keychain_error"))
+        );
+    }
+
+    fn mounts(entries: &[(&str, &str)]) -> Vec<(PathBuf, PathBuf)> {
+        entries
+            .iter()
+            .map(|(device, mount)| (PathBuf::from(device), PathBuf::from(mount)))
+            .collect()
+    }
+
+    #[test]
+    fn find_mount_follows_symlinked_device_names() {
+        let dir = std::env::temp_dir().join(format!("aegis-usb-mount-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let real = dir.join("dm-3");
+        std::fs::write(&real, b"").unwrap();
+        let mapper = dir.join("vg-backup");
+        std::os::unix::fs::symlink(&real, &mapper).unwrap();
+        let table = mounts(&[
+            ("/dev/sda2", "/"),
+            (mapper.to_str().unwrap(), "/media/u/Backup"),
+        ]);
+        let found = find_mount_in(table, &real, |p| std::fs::canonicalize(p).ok(), |_| None);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(found, Some(PathBuf::from("/media/u/Backup")));
+    }
+
+    #[test]
+    fn find_mount_falls_back_when_canonicalize_fails() {
+        let table = mounts(&[
+            ("/dev/sda2", "/"),
+            ("/dev/mapper/vg-backup", "/media/u/Backup"),
+            ("/dev/sdc1", "/media/u/Other"),
+        ]);
+        // Same path: found without resolving anything.
+        assert_eq!(
+            find_mount_in(table.clone(), Path::new("/dev/sdc1"), |_| None, |_| None),
+            Some(PathBuf::from("/media/u/Other"))
+        );
+        // Different names for the same device number.
+        let device_number = |p: &Path| match p.to_str() {
+            Some("/dev/dm-3") | Some("/dev/mapper/vg-backup") => Some(0xfd03),
+            Some("/dev/sda2") => Some(0x0802),
+            _ => None,
+        };
+        assert_eq!(
+            find_mount_in(
+                table.clone(),
+                Path::new("/dev/dm-3"),
+                |_| None,
+                device_number
+            ),
+            Some(PathBuf::from("/media/u/Backup"))
+        );
+        assert_eq!(
+            find_mount_in(table, Path::new("/dev/sdz1"), |_| None, device_number),
+            None
+        );
+    }
+
+    #[test]
+    fn diskutil_listing_yields_volume_devnodes() {
+        let plist = r#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0">
+<dict>
+	<key>AllDisks</key>
+	<array>
+		<string>disk4</string>
+		<string>disk4s1</string>
+		<string>disk4s2</string>
+		<string>disk5</string>
+	</array>
+	<key>AllDisksAndPartitions</key>
+	<array>
+		<dict>
+			<key>DeviceIdentifier</key>
+			<string>disk4</string>
+		</dict>
+	</array>
+</dict>
+</plist>"#;
+        let ids = parse_diskutil_disks(plist);
+        assert_eq!(ids, ["disk4", "disk4s1", "disk4s2", "disk5"]);
+        assert_eq!(whole_disk_id("disk4s2"), "disk4");
+        assert_eq!(whole_disk_id("disk12"), "disk12");
+        // disk4 is only the container of its partitions; disk5 has a file system of its own.
+        assert_eq!(
+            volume_devnodes(&ids),
+            ["/dev/disk4s1", "/dev/disk4s2", "/dev/disk5"].map(PathBuf::from)
+        );
+        assert!(parse_diskutil_disks("<plist></plist>").is_empty());
+    }
+
+    #[test]
+    fn polled_disks_become_add_and_remove_events() {
+        let known = ["/dev/disk4s1", "/dev/disk5s1"].map(PathBuf::from);
+        let current = ["/dev/disk5s1", "/dev/disk6s1"].map(PathBuf::from);
+        assert_eq!(
+            diff_devnodes(&known, &current),
+            vec![
+                UsbEvent::Removed(PathBuf::from("/dev/disk4s1")),
+                UsbEvent::Added(PathBuf::from("/dev/disk6s1")),
+            ]
+        );
+        assert!(diff_devnodes(&current, &current).is_empty());
+    }
+
+    #[test]
+    fn bsd_mount_output_keeps_spaces_in_volume_names() {
+        let output = "/dev/disk3s1s1 on / (apfs, sealed, local, read-only, journaled)
+/dev/disk4s1 on /Volumes/My Drive (2) (exfat, local, nodev, nosuid, noowners)
+map auto_home on /System/Volumes/Data/home (autofs, automounted, nobrowse)
+";
+        let table = parse_mount_output(output);
+        assert_eq!(table.len(), 3);
+        assert_eq!(
+            table[1],
+            (
+                PathBuf::from("/dev/disk4s1"),
+                PathBuf::from("/Volumes/My Drive (2)")
+            )
+        );
+        assert_eq!(
+            find_mount_in(table, Path::new("/dev/disk4s1"), |_| None, |_| None),
+            Some(PathBuf::from("/Volumes/My Drive (2)"))
+        );
+    }
+}
@@ -1,5 +1,124 @@
+use crate::errors::AgentError;
 use crate::restic::Restic;
+use crate::state::{RunPhase, RunStatus};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
 use std::path::Path;
+use std::time::Duration;
+
+/// What a failed quick or deep verification does to the backup run.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum VerifyFailurePolicy {
+    /// The run is Partial: the snapshot is kept and the failure is reported.
+    #[default]
+    Warn,
+    /// The run is Failed, which also skips retention and sends the failure notification.
+    Fail,
+    /// Verify once more (a flaky USB connection often passes the second time); Failed if the
+    /// retry fails too.
+    Retry,
+}
+
+impl VerifyFailurePolicy {
+    /// Run status after a verification that still failed under this policy.
+    pub fn failed_status(self) -> RunStatus {
+        match self {
+            VerifyFailurePolicy::Warn => RunStatus::Partial,
+            VerifyFailurePolicy::Fail | VerifyFailurePolicy::Retry => RunStatus::Failed,
+        }
+    }
+}
+
+/// Run messages when a backup's quick or deep verification failed (and nothing else went wrong).
+pub const VERIFY_FAILED_MESSAGE: &str = "Backup completed, but verification failed";
+pub const DEEP_VERIFY_FAILED_MESSAGE: &str = "Backup completed, but deep verification failed";
+
+/// Run message after a failed verification that left the run at `status`: a failed run (the
+/// `Fail`/`Retry` policies) doesn't count as a completed backup.
+pub fn verify_failed_message(deep: bool, status: &RunStatus) -> &'static str {
+    match (deep, status) {
+        (false, RunStatus::Failed) => "Backup verification failed",
+        (true, RunStatus::Failed) => "Backup deep verification failed",
+        (false, _) => VERIFY_FAILED_MESSAGE,
+        (true, _) => DEEP_VERIFY_FAILED_MESSAGE,
+    }
+}
+
+/// Run one verification under `policy`. On failure, returns the error together with the status
+/// the run should drop to.
+pub async fn verify_with_policy<F, Fut>(
+    policy: VerifyFailurePolicy,
+    mut verify: F,
+) -> Result<(), (anyhow::Error, RunStatus)>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    let mut result = verify().await;
+    if let (Err(err), VerifyFailurePolicy::Retry) = (&result, policy) {
+        tracing::warn!("verify: failed, retrying once: {}", err);
+        result = verify().await;
+    }
+    result.map_err(|err| (err, policy.failed_status()))
+}
+
+/// Quick check of the existing repository before a backup writes to it. Any failure (after the
+/// retry the policy may allow) aborts the backup, whatever the policy says about a failed
+/// post-backup verification: the point is not to add data to a damaged repository or drive.
+pub async fn pre_backup_check(
+    restic: &Restic,
+    repo: &Path,
+    passphrase: &str,
+    policy: VerifyFailurePolicy,
+) -> anyhow::Result<()> {
+    verify_with_policy(policy, || quick_verify(restic, repo, passphrase))
+        .await
+        .map_err(|(err, _)| err.context(AgentError::PreBackupCheckFailed))
+}
+
+/// Read throughput assumed for deep verify when nothing better was measured (bytes/s).
+/// Conservative for USB 3 flash drives and spinning disks.
+pub const ASSUMED_READ_THROUGHPUT: u64 = 40 * 1024 * 1024;
+
+/// Below this much data read, a quick verify is dominated by fixed overhead and its timing
+/// says little about read speed.
+const MIN_MEASURED_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Fraction of the data read by `quick_verify` (`--read-data-subset=1/20`).
+const QUICK_VERIFY_SUBSET: u64 = 20;
+
+/// Which check an on-demand verification runs.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VerifyMode {
+    /// Structure plus a 1/20 sample of the data.
+    Quick,
+    /// Structure plus every data pack; reads the whole repository.
+    Deep,
+}
+
+impl VerifyMode {
+    pub fn phase(self) -> RunPhase {
+        match self {
+            VerifyMode::Quick => RunPhase::VerifyingQuick,
+            VerifyMode::Deep => RunPhase::VerifyingDeep,
+        }
+    }
+}
+
+/// Run the check for `mode`.
+pub async fn verify(
+    restic: &Restic,
+    repo: &Path,
+    passphrase: &str,
+    mode: VerifyMode,
+) -> anyhow::Result<()> {
+    match mode {
+        VerifyMode::Quick => quick_verify(restic, repo, passphrase).await,
+        VerifyMode::Deep => deep_verify(restic, repo, passphrase).await,
+    }
+}
 
 pub async fn quick_verify(restic: &Restic, repo: &Path, passphrase: &str) -> anyhow::Result<()> {
     restic.check_quick(repo, passphrase).await
@@ -8,3 +127,234 @@ pub async fn quick_verify(restic: &Restic, repo: &Path, passphrase: &str) -> any
 pub async fn deep_verify(restic: &Restic, repo: &Path, passphrase: &str) -> anyhow::Result<()> {
     restic.check_deep(repo, passphrase).await
 }
+
+/// Seconds needed to read `total_bytes` at `throughput` bytes/s (rounded up).
+pub fn estimate_deep_verify_secs(total_bytes: u64, throughput: u64) -> u64 {
+    total_bytes.div_ceil(throughput.max(1))
+}
+
+/// Read throughput implied by a quick verify of a repository of `repo_bytes` that took
+/// `elapsed`. None if too little was read for the timing to be meaningful.
+pub fn quick_verify_throughput(repo_bytes: u64, elapsed: Duration) -> Option<u64> {
+    let bytes_read = repo_bytes / QUICK_VERIFY_SUBSET;
+    let millis = elapsed.as_millis() as u64;
+    if bytes_read < MIN_MEASURED_BYTES || millis == 0 {
+        return None;
+    }
+    Some(bytes_read.saturating_mul(1000) / millis)
+}
+
+/// Human-friendly rounding of an estimate, e.g. "~25 minutes".
+pub fn describe_estimate(secs: u64) -> String {
+    match secs {
+        0..=59 => "under a minute".to_string(),
+        60..=5399 => {
+            let minutes = (secs + 30) / 60;
+            format!("~{} minute{}", minutes, if minutes == 1 { "" } else { "s" })
+        }
+        _ => format!("~{:.1} hours", secs as f64 / 3600.0),
+    }
+}
+
+/// Total size of the files in the repository directory (what deep verify has to read).
+pub fn repository_size(repo: &Path) -> std::io::Result<u64> {
+    let mut total = 0;
+    let mut pending = vec![repo.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                pending.push(entry.path());
+            } else if file_type.is_file() {
+                total += entry.metadata()?.len();
+            }
+        }
+    }
+    Ok(total)
+}
+
+/// [`repository_size`] walked off the runtime; None if it couldn't be read.
+pub async fn repository_size_async(repo: &Path) -> Option<u64> {
+    let repo = repo.to_path_buf();
+    tokio::task::spawn_blocking(move || repository_size(&repo))
+        .await
+        .ok()?
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::restic::tests::script_restic;
+    use std::cell::Cell;
+
+    /// Verify step that fails the first `failures` times it is run.
+    async fn flaky(calls: &Cell<u32>, failures: u32) -> anyhow::Result<()> {
+        calls.set(calls.get() + 1);
+        if calls.get() <= failures {
+            anyhow::bail!("check failed");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_mode_selects_check() {
+        // Skip `--repo <repo>` and record the check arguments next to the script.
+        let (restic, dir) = script_restic(
+            "verify-mode",
+            "#!/bin/sh\nshift 2\necho \"$@\" > \"$(dirname \"$0\")/args\"\n",
+        );
+        let repo = dir.join("repo");
+
+        verify(&restic, &repo, "pw", VerifyMode::Quick)
+            .await
+            .unwrap();
+        let quick = std::fs::read_to_string(dir.join("args")).unwrap();
+        verify(&restic, &repo, "pw", VerifyMode::Deep)
+            .await
+            .unwrap();
+        let deep = std::fs::read_to_string(dir.join("args")).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(quick.trim(), "check --read-data-subset=1/20");
+        assert_eq!(deep.trim(), "check --read-data");
+        assert!(matches!(VerifyMode::Deep.phase(), RunPhase::VerifyingDeep));
+    }
+
+    #[tokio::test]
+    async fn pre_backup_check_aborts_on_failure_under_any_policy() {
+        // Count the checks; fail unless a "healthy" file exists next to the script.
+        let (restic, dir) = script_restic(
+            "pre-check",
+            "#!/bin/sh\nd=\"$(dirname \"$0\")\"\necho run >> \"$d/calls\"\n[ -e \"$d/healthy\" ] || { echo 'Fatal: repository contains errors' >&2; exit 1; }\n",
+        );
+        let repo = dir.join("repo");
+        let calls = || {
+            std::fs::read_to_string(dir.join("calls"))
+                .map(|c| c.lines().count())
+                .unwrap_or(0)
+        };
+
+        let err = pre_backup_check(&restic, &repo, "pw", VerifyFailurePolicy::Warn)
+            .await
+            .unwrap_err();
+        assert!(crate::errors::is_pre_backup_check_failed(&err));
+        assert_eq!(crate::errors::error_code(&err), "pre_backup_check_failed");
+        assert_eq!(calls(), 1);
+
+        let err = pre_backup_check(&restic, &repo, "pw", VerifyFailurePolicy::Retry)
+            .await
+            .unwrap_err();
+        assert!(crate::errors::is_pre_backup_check_failed(&err));
+        assert_eq!(calls(), 3);
+
+        std::fs::write(dir.join("healthy"), "").unwrap();
+        let ok = pre_backup_check(&restic, &repo, "pw", VerifyFailurePolicy::Fail).await;
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(ok.is_ok());
+    }
+
+    #[tokio::test]
+    async fn warn_policy_makes_run_partial() {
+        let calls = Cell::new(0);
+        let result = verify_with_policy(VerifyFailurePolicy::Warn, || flaky(&calls, 1)).await;
+        assert_eq!(result.unwrap_err().1, RunStatus::Partial);
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn fail_policy_makes_run_failed() {
+        let calls = Cell::new(0);
+        let result = verify_with_policy(VerifyFailurePolicy::Fail, || flaky(&calls, 1)).await;
+        assert_eq!(result.unwrap_err().1, RunStatus::Failed);
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn failed_run_message_does_not_claim_the_backup_completed() {
+        assert_eq!(
+            verify_failed_message(false, &RunStatus::Partial),
+            VERIFY_FAILED_MESSAGE
+        );
+        assert_eq!(
+            verify_failed_message(true, &RunStatus::Partial),
+            DEEP_VERIFY_FAILED_MESSAGE
+        );
+        assert!(!verify_failed_message(false, &RunStatus::Failed).contains("completed"));
+        assert!(!verify_failed_message(true, &RunStatus::Failed).contains("completed"));
+    }
+
+    #[tokio::test]
+    async fn retry_policy_retries_once() {
+        let calls = Cell::new(0);
+        assert!(
+            verify_with_policy(VerifyFailurePolicy::Retry, || flaky(&calls, 1))
+                .await
+                .is_ok()
+        );
+        assert_eq!(calls.get(), 2);
+
+        let calls = Cell::new(0);
+        let result = verify_with_policy(VerifyFailurePolicy::Retry, || flaky(&calls, 2)).await;
+        assert_eq!(result.unwrap_err().1, RunStatus::Failed);
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn passing_verify_runs_once_under_any_policy() {
+        for policy in [
+            VerifyFailurePolicy::Warn,
+            VerifyFailurePolicy::Fail,
+            VerifyFailurePolicy::Retry,
+        ] {
+            let calls = Cell::new(0);
+            assert!(verify_with_policy(policy, || flaky(&calls, 0))
+                .await
+                .is_ok());
+            assert_eq!(calls.get(), 1);
+        }
+    }
+
+    #[test]
+    fn estimate_rounds_up() {
+        assert_eq!(estimate_deep_verify_secs(0, 100), 0);
+        assert_eq!(estimate_deep_verify_secs(100, 100), 1);
+        assert_eq!(estimate_deep_verify_secs(101, 100), 2);
+        // 60 GiB at 40 MiB/s = 1536 s.
+        assert_eq!(
+            estimate_deep_verify_secs(60 * 1024 * 1024 * 1024, ASSUMED_READ_THROUGHPUT),
+            1536
+        );
+    }
+
+    #[test]
+    fn estimate_zero_throughput_does_not_panic() {
+        assert_eq!(estimate_deep_verify_secs(10, 0), 10);
+    }
+
+    #[test]
+    fn quick_verify_throughput_needs_enough_data() {
+        assert_eq!(
+            quick_verify_throughput(100 * 1024 * 1024, Duration::from_secs(1)),
+            None
+        );
+        // 20 GiB repo -> 1 GiB read in 10 s.
+        assert_eq!(
+            quick_verify_throughput(20 * 1024 * 1024 * 1024, Duration::from_secs(10)),
+            Some(1024 * 1024 * 1024 / 10)
+        );
+        assert_eq!(
+            quick_verify_throughput(20 * 1024 * 1024 * 1024, Duration::ZERO),
+            None
+        );
+    }
+
+    #[test]
+    fn describe_estimate_units() {
+        assert_eq!(describe_estimate(30), "under a minute");
+        assert_eq!(describe_estimate(60), "~1 minute");
+        assert_eq!(describe_estimate(1536), "~26 minutes");
+        assert_eq!(describe_estimate(9000), "~2.5 hours");
+    }
+}